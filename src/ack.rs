@@ -0,0 +1,151 @@
+//  Copyright 2025 PolyCrypt GmbH
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+
+//! Acknowledgments of off-chain states. A participant can record that they
+//! possess a state of a channel signed by all participants, by signing
+//! `ack_bytes` for its version. Once every participant has acknowledged a
+//! version, disputes with older states are rejected right away, as they can
+//! only be stale. A single participant's acknowledgments restrict nothing,
+//! so that no participant can block disputes by acknowledging a version that
+//! does not exist.
+
+use crate::error::*;
+use crate::receiver::TXQuerier;
+use crate::types::*;
+use crate::{CanisterState, mutate_state, read_state};
+use candid::candid_method;
+use ic_cdk::{query, update};
+
+#[update]
+#[candid_method(update)]
+/// Records that the participant whose signature over `ack_bytes` is given
+/// possesses the channel's state with the given version. The channel must be
+/// announced or registered. Acknowledging an older version than before has
+/// no effect.
+fn ack_state(channel_id: ChannelId, version: Version, sig: Vec<u8>) -> Result<()> {
+    mutate_state(|s| s.ack_state(&channel_id, version, &sig))
+}
+
+#[query]
+#[candid_method(query)]
+/// Returns the newest version acknowledged by all participants of a channel.
+/// Disputes with older states are rejected.
+fn query_acked_version(channel_id: ChannelId) -> Version {
+    read_state(|s| s.acked_version(&channel_id))
+}
+
+/// The message a participant signs to acknowledge a state version.
+pub fn ack_bytes(channel: &ChannelId, version: Version) -> Vec<u8> {
+    let mut data = b"ckLightning ack".to_vec();
+    data.extend_from_slice(&channel.0);
+    data.extend_from_slice(&version.to_le_bytes());
+    data
+}
+
+impl<Q: TXQuerier> CanisterState<Q> {
+    pub(crate) fn channel_params(&self, channel: &ChannelId) -> Option<&Params> {
+        self.params
+            .get(channel)
+            .or_else(|| self.funding.get(channel).map(|f| &f.intent.params))
+    }
+
+    pub fn ack_state(&mut self, channel: &ChannelId, version: Version, sig: &[u8]) -> Result<()> {
+        let params = self
+            .channel_params(channel)
+            .ok_or_else(|| Error::from(ErrorCode::NotFound).with("channel", channel))?;
+        let msg = ack_bytes(channel, version);
+        let n = params.participants.len();
+        let i = params
+            .participants
+            .iter()
+            .position(|p| p.verify(&msg, sig))
+            .ok_or(ErrorCode::Authentication {
+                participant_index: None,
+            })?;
+        let acks = self
+            .acks
+            .entry(channel.clone())
+            .or_insert_with(|| vec![0; n]);
+        if let Some(acked) = acks.get_mut(i) {
+            *acked = version.max(*acked);
+        }
+        Ok(())
+    }
+
+    pub fn acked_version(&self, channel: &ChannelId) -> Version {
+        self.acks
+            .get(channel)
+            .and_then(|acks| acks.iter().min().copied())
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+
+    #[test]
+    fn test_disputes_older_than_acked_are_rejected() {
+        let mut s = new_state();
+        let params = Params {
+            nonce: Nonce([6; 32]),
+            participants: vec![account(1), account(2)],
+            challenge_duration: 10,
+            expiry: None,
+        };
+        let id = params.id();
+        let intent = FundingIntent {
+            params: params.clone(),
+            allocation: vec![Amount::from(100u32), Amount::from(100u32)],
+            callback: None,
+        };
+        s.register_funding_intent(intent, 0).unwrap();
+        for p in [1, 2] {
+            s.deposit(Funding::new(id.clone(), account(p)), Amount::from(100u32))
+                .unwrap();
+        }
+        let state = |version| State {
+            channel: id.clone(),
+            version,
+            allocation: vec![Amount::from(90u32), Amount::from(110u32)],
+            finalized: false,
+            assets: vec![],
+        };
+        let sigs = |state: &State| [1, 2].map(|p| sign(p, &state.signing_bytes())).to_vec();
+
+        assert_eq!(
+            s.ack_state(&id, 5, &sign(3, &ack_bytes(&id, 5))),
+            Err(ErrorCode::Authentication {
+                participant_index: None
+            }
+            .into())
+        );
+        s.ack_state(&id, 5, &sign(1, &ack_bytes(&id, 5))).unwrap();
+        // One participant's acknowledgment alone restricts nothing.
+        assert_eq!(s.acked_version(&id), 0);
+        s.ack_state(&id, 4, &sign(2, &ack_bytes(&id, 4))).unwrap();
+        assert_eq!(s.acked_version(&id), 4);
+
+        assert_eq!(
+            s.dispute(&params, state(3), &sigs(&state(3)), 1),
+            Err(ErrorCode::OutdatedState {
+                registered_version: 4,
+                submitted_version: 3
+            }
+            .into())
+        );
+        s.dispute(&params, state(4), &sigs(&state(4)), 1).unwrap();
+    }
+}
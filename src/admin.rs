@@ -0,0 +1,106 @@
+//  Copyright 2025 PolyCrypt GmbH
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+
+//! Admins, the principals allowed to change the canister's configuration,
+//! such as its ledger, fees and pauses, and to act in emergencies, such as
+//! draining the canister or freezing channels. The installer becomes the
+//! first admin, and admins add and remove further ones. Controllers always
+//! count as admins, so that they cannot be locked out. The set lives in
+//! stable memory, so that it survives upgrades.
+
+use crate::audit;
+use crate::error::*;
+use crate::memory::{self, ADMINS, Memory};
+use crate::require;
+use candid::{Principal, candid_method};
+use ic_cdk::{query, update};
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+
+thread_local! {
+    static SET: RefCell<StableBTreeMap<Principal, (), Memory>> =
+        RefCell::new(StableBTreeMap::init(memory::get(ADMINS)));
+}
+
+#[update]
+#[candid_method(update)]
+/// Makes a principal an admin. Admin only.
+fn add_admin(principal: Principal) -> Result<()> {
+    audit::logged("add_admin", audit::args_hash((principal,)), || {
+        require_admin()?;
+        add(principal);
+        Ok(())
+    })
+}
+
+#[update]
+#[candid_method(update)]
+/// Revokes a principal's admin role. Admin only.
+fn remove_admin(principal: Principal) -> Result<()> {
+    audit::logged("remove_admin", audit::args_hash((principal,)), || {
+        require_admin()?;
+        remove(&principal)
+    })
+}
+
+#[query]
+#[candid_method(query)]
+/// The admins, besides the controllers.
+fn query_admins() -> Vec<Principal> {
+    SET.with(|s| s.borrow().keys().collect())
+}
+
+pub fn is_admin(principal: &Principal) -> bool {
+    SET.with(|s| s.borrow().contains_key(principal))
+}
+
+pub fn add(principal: Principal) {
+    SET.with(|s| s.borrow_mut().insert(principal, ()));
+}
+
+/// Fails with `NotFound` unless the principal is an admin.
+pub fn remove(principal: &Principal) -> Result<()> {
+    let removed = SET.with(|s| s.borrow_mut().remove(principal).is_some());
+    require!(
+        removed,
+        Error::from(ErrorCode::NotFound).with("admin", principal)
+    );
+    Ok(())
+}
+
+/// Fails unless the caller is an admin or a controller of this canister.
+pub fn require_admin() -> Result<()> {
+    let caller = ic_cdk::api::msg_caller();
+    require!(
+        is_admin(&caller) || ic_cdk::api::is_controller(&caller),
+        Unauthorized
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_admins_are_added_and_removed() {
+        let admin = Principal::from_slice(&[41]);
+        assert!(!is_admin(&admin));
+        add(admin);
+        assert!(is_admin(&admin));
+        remove(&admin).unwrap();
+        assert!(!is_admin(&admin));
+        assert_eq!(remove(&admin), Err(ErrorCode::NotFound.into()));
+    }
+}
@@ -0,0 +1,109 @@
+//  Copyright 2025 PolyCrypt GmbH
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+
+//! Anchoring of the audit log. The running hash over the audit log is
+//! periodically pushed to an independent, append-only anchor canister,
+//! configured at deployment, through its `append : (AuditHead) -> ()`
+//! method. Since the anchor keeps every head it received, a rewritten audit
+//! log no longer reproduces the anchored heads, even if the rewrite was done
+//! by the controllers.
+
+use crate::audit::{self, AuditHead};
+use crate::config;
+use crate::receiver::TXQuerier;
+use crate::types::*;
+use crate::{CanisterState, mutate_state, read_state};
+use candid::{CandidType, Principal, candid_method};
+use ic_cdk::api::time as blocktime;
+use ic_cdk::call::Call;
+use ic_cdk::query;
+
+/// How often the audit log head is anchored (one hour).
+pub const ANCHOR_INTERVAL: std::time::Duration = std::time::Duration::from_secs(3_600);
+
+#[derive(Clone, Deserialize, CandidType, PartialEq, Eq, Debug)]
+/// An audit log head accepted by the anchor canister.
+pub struct Anchor {
+    pub head: AuditHead,
+    pub anchored_at: Timestamp,
+}
+
+#[query]
+#[candid_method(query)]
+/// Returns the audit log head last accepted by the anchor canister.
+fn query_last_anchor() -> Option<Anchor> {
+    read_state(|s| s.last_anchor.clone())
+}
+
+/// Pushes the audit log head to the anchor canister if it grew since the
+/// last anchoring.
+pub fn anchor_audit_log() {
+    let Some(anchor) = config::current().audit_anchor else {
+        return;
+    };
+    let head = audit::head();
+    if read_state(|s| s.anchor_due(&head)) {
+        ic_cdk::futures::spawn(push(anchor, head));
+    }
+}
+
+/// Appends a head to the anchor canister. A failed push is retried with the
+/// then current head at the next interval.
+async fn push(anchor: Principal, head: AuditHead) {
+    match Call::unbounded_wait(anchor, "append").with_arg(&head).await {
+        Ok(_) => mutate_state(|s| s.record_anchor(head, blocktime())),
+        Err(e) => ic_cdk::println!("anchoring audit head failed: {:?}", e),
+    }
+}
+
+impl<Q: TXQuerier> CanisterState<Q> {
+    /// Whether the head extends past the last anchored one.
+    pub fn anchor_due(&self, head: &AuditHead) -> bool {
+        let anchored = self.last_anchor.as_ref().map_or(0, |a| a.head.length);
+        head.length > anchored
+    }
+
+    /// Records an accepted head, unless a longer one was accepted meanwhile.
+    pub fn record_anchor(&mut self, head: AuditHead, now: Timestamp) {
+        if self.anchor_due(&head) {
+            self.last_anchor = Some(Anchor {
+                head,
+                anchored_at: now,
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+
+    #[test]
+    fn test_only_longer_heads_are_anchored() {
+        let mut s = new_state();
+        let head = |length| AuditHead {
+            length,
+            hash: [length as u8; 32],
+        };
+        assert!(!s.anchor_due(&AuditHead::default()));
+        assert!(s.anchor_due(&head(2)));
+
+        s.record_anchor(head(2), 5);
+        s.record_anchor(head(1), 6);
+        assert_eq!(s.last_anchor.as_ref().unwrap().head, head(2));
+        assert!(!s.anchor_due(&head(2)));
+        assert!(s.anchor_due(&head(3)));
+    }
+}
@@ -0,0 +1,325 @@
+//  Copyright 2025 PolyCrypt GmbH
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+
+//! Registered assets and their decimals. Amounts are always handled in an
+//! asset's base units internally. Amounts crossing the boundary in another
+//! scale carry their decimals, so that a mix-up of scales is rejected instead
+//! of silently moving the wrong amount.
+//!
+//! Channels can hold registered assets besides ckBTC, such as stablecoins.
+//! Deposits of an asset are received on its own ledger, and its holdings are
+//! paid out there.
+
+use crate::audit;
+use crate::error::*;
+use crate::receiver::{Receiver, TXQuerier};
+use crate::types::*;
+use crate::validation;
+use crate::{CanisterState, mutate_state, read_state, require};
+use candid::{Principal, candid_method};
+use ic_cdk::{query, update};
+
+/// Identifies an asset by its ledger canister.
+pub type AssetId = Principal;
+
+/// The decimals of ckBTC, whose base unit is one satoshi.
+pub const CKBTC_DECIMALS: u8 = 8;
+
+/// The most decimals an asset may have.
+pub const MAX_DECIMALS: u8 = 18;
+
+#[derive(Clone, Deserialize, CandidType, PartialEq, Eq, Debug)]
+pub struct AssetInfo {
+    pub symbol: String,
+    /// How many decimal places one whole token has in base units.
+    pub decimals: u8,
+}
+
+#[derive(Clone, Deserialize, CandidType)]
+/// An amount together with the scale it is given in.
+pub struct ScaledAmount {
+    pub amount: Amount,
+    pub decimals: u8,
+}
+
+#[update]
+#[candid_method(update)]
+/// Registers an asset or updates its symbol. An asset's decimals can never
+/// change. Admin only.
+fn register_asset(ledger: AssetId, info: AssetInfo) -> Result<()> {
+    audit::logged("register_asset", audit::args_hash((ledger, &info)), || {
+        crate::admin::require_admin()?;
+        mutate_state(|s| s.register_asset(ledger, info))
+    })
+}
+
+#[query]
+#[candid_method(query)]
+fn query_assets() -> Vec<(AssetId, AssetInfo)> {
+    read_state(|s| s.assets.iter().map(|(id, a)| (*id, a.clone())).collect())
+}
+
+#[query]
+#[candid_method(query)]
+/// Formats an amount in base units as a decimal number of whole tokens.
+fn format_amount(asset: AssetId, amount: Amount) -> Result<String> {
+    read_state(|s| Ok(s.asset(&asset)?.format(&amount)))
+}
+
+#[query]
+#[candid_method(query)]
+/// Parses a decimal number of whole tokens into base units.
+fn parse_amount(asset: AssetId, text: String) -> Result<Amount> {
+    read_state(|s| s.asset(&asset)?.parse(&text))
+}
+
+impl AssetInfo {
+    pub fn ckbtc() -> Self {
+        Self {
+            symbol: "ckBTC".into(),
+            decimals: CKBTC_DECIMALS,
+        }
+    }
+
+    /// Converts a scaled amount into base units. Fails unless the amount is
+    /// given in this asset's scale.
+    pub fn base_units(&self, amount: &ScaledAmount) -> Result<Amount> {
+        require!(amount.decimals == self.decimals, InvalidInput);
+        Ok(amount.amount.clone())
+    }
+
+    /// Formats base units as whole tokens, e.g. 150000000 as "1.5".
+    pub fn format(&self, amount: &Amount) -> String {
+        let digits = amount.0.to_string();
+        let decimals = self.decimals as usize;
+        if decimals == 0 {
+            return digits;
+        }
+        let digits = format!("{digits:0>width$}", width = decimals + 1);
+        let (whole, frac) = digits.split_at(digits.len() - decimals);
+        match frac.trim_end_matches('0') {
+            "" => whole.to_string(),
+            frac => format!("{whole}.{frac}"),
+        }
+    }
+
+    /// Formats base units for humans, e.g. "1.5 ckBTC".
+    pub fn display(&self, amount: &Amount) -> String {
+        format!("{} {}", self.format(amount), self.symbol)
+    }
+
+    /// Parses whole tokens into base units. More fractional digits than the
+    /// asset's decimals are rejected rather than rounded.
+    pub fn parse(&self, text: &str) -> Result<Amount> {
+        let (whole, frac) = text.split_once('.').unwrap_or((text, ""));
+        let decimals = self.decimals as usize;
+        require!(!whole.is_empty() && frac.len() <= decimals, InvalidInput);
+        let digits = format!("{whole}{frac:0<decimals$}");
+        require!(digits.bytes().all(|b| b.is_ascii_digit()), InvalidInput);
+        digits
+            .parse::<Nat>()
+            .map_err(|_| Error::from(ErrorCode::InvalidInput).with("amount", text))
+    }
+}
+
+impl<Q: TXQuerier> CanisterState<Q> {
+    pub fn register_asset(&mut self, id: AssetId, info: AssetInfo) -> Result<()> {
+        validation::name(&info.symbol)?;
+        require!(info.decimals <= MAX_DECIMALS, InvalidInput);
+        if let Some(known) = self.assets.get(&id) {
+            require!(known.decimals == info.decimals, InvalidInput);
+        }
+        self.assets.insert(id, info);
+        self.liq_pools.entry(id).or_default();
+        if id != self.ckbtc() && !self.asset_receivers.contains_key(&id) {
+            let receiver = self.icrc_receiver.for_ledger(id);
+            self.asset_receivers.insert(id, receiver);
+        }
+        Ok(())
+    }
+
+    pub fn asset(&self, id: &AssetId) -> Result<&AssetInfo> {
+        self.assets
+            .get(id)
+            .ok_or_else(|| Error::from(ErrorCode::NotFound).with("asset", id))
+    }
+
+    /// The asset balances and pool withdrawals are held in, and channel
+    /// funds unless they name another asset.
+    pub fn ckbtc(&self) -> AssetId {
+        self.config.ledger
+    }
+
+    /// The asset funds are held in, given as none for ckBTC.
+    pub fn asset_or_ckbtc(&self, asset: Option<AssetId>) -> AssetId {
+        asset.unwrap_or(self.ckbtc())
+    }
+
+    /// Fails unless a channel's further asset is registered and not ckBTC,
+    /// which is allocated separately.
+    pub fn require_channel_asset(&self, asset: &AssetId) -> Result<()> {
+        self.asset(asset)?;
+        require!(
+            *asset != self.ckbtc(),
+            Error::from(ErrorCode::InvalidInput).with("asset", asset)
+        );
+        Ok(())
+    }
+
+    /// The receiver of deposits in an asset.
+    pub fn receiver(&self, asset: &AssetId) -> Result<&Receiver<Q>> {
+        if *asset == self.ckbtc() {
+            return Ok(&self.icrc_receiver);
+        }
+        self.asset_receivers
+            .get(asset)
+            .ok_or_else(|| Error::from(ErrorCode::NotFound).with("asset", asset))
+    }
+
+    pub fn receiver_mut(&mut self, asset: &AssetId) -> Result<&mut Receiver<Q>> {
+        if *asset == self.ckbtc() {
+            return Ok(&mut self.icrc_receiver);
+        }
+        self.asset_receivers
+            .get_mut(asset)
+            .ok_or_else(|| Error::from(ErrorCode::NotFound).with("asset", asset))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::receiver::IcrcTransfer;
+    use crate::testing::*;
+
+    #[test]
+    fn test_format_and_parse() {
+        let btc = AssetInfo::ckbtc();
+        assert_eq!(btc.format(&Amount::from(150_000_000u64)), "1.5");
+        assert_eq!(btc.format(&Amount::from(1u64)), "0.00000001");
+        assert_eq!(btc.format(&Amount::from(0u64)), "0");
+        assert_eq!(btc.parse("1.5").unwrap(), Amount::from(150_000_000u64));
+        assert_eq!(btc.parse("0.00000001").unwrap(), Amount::from(1u64));
+        assert_eq!(
+            btc.parse("0.000000001"),
+            Err(ErrorCode::InvalidInput.into())
+        );
+        assert_eq!(btc.parse("1e8"), Err(ErrorCode::InvalidInput.into()));
+        assert_eq!(btc.parse(".5"), Err(ErrorCode::InvalidInput.into()));
+        assert_eq!(btc.display(&Amount::from(12_345u64)), "0.00012345 ckBTC");
+    }
+
+    #[test]
+    fn test_scale_mismatch_is_rejected() {
+        let mut s = new_state();
+        let btc = s.asset(&s.ckbtc()).unwrap().clone();
+        let sats = ScaledAmount {
+            amount: Amount::from(5u32),
+            decimals: 8,
+        };
+        assert_eq!(btc.base_units(&sats).unwrap(), Amount::from(5u32));
+        let wei = ScaledAmount {
+            amount: Amount::from(5u32),
+            decimals: 18,
+        };
+        assert_eq!(btc.base_units(&wei), Err(ErrorCode::InvalidInput.into()));
+
+        let mut changed = btc.clone();
+        changed.decimals = 6;
+        assert_eq!(
+            s.register_asset(s.ckbtc(), changed),
+            Err(ErrorCode::InvalidInput.into())
+        );
+    }
+
+    #[test]
+    fn test_channel_holds_further_asset() {
+        let mut s = new_state();
+        let usdc = Principal::from_slice(&[31]);
+        let info = AssetInfo {
+            symbol: "ckUSDC".into(),
+            decimals: 6,
+        };
+        s.register_asset(usdc, info).unwrap();
+        let params = Params {
+            nonce: Nonce([101; 32]),
+            participants: vec![account(1), account(2)],
+            challenge_duration: 0,
+            expiry: None,
+        };
+        let id = params.id();
+        for p in [1, 2] {
+            s.deposit(Funding::new(id.clone(), account(p)), Amount::from(100u32))
+                .unwrap();
+        }
+        let funding = Funding::new(id.clone(), account(1)).in_asset(Some(usdc));
+        let tx = IcrcTransfer {
+            block: 5,
+            from: None,
+            to: s.icrc_receiver.icrc_account(),
+            amount: 50,
+            memo: Some(funding.memo().to_be_bytes().to_vec()),
+        };
+        s.process_icrc_tx(&tx, 50, funding.clone()).unwrap();
+        s.deposit_icrc(0, funding.clone()).unwrap();
+        assert!(crate::processed::is_processed(usdc, 5));
+        assert!(s.icrc_receiver.credited(5).is_none());
+
+        let mut state = State {
+            channel: id.clone(),
+            version: 1,
+            allocation: vec![Amount::from(100u32), Amount::from(100u32)],
+            assets: vec![AssetAllocation {
+                asset: usdc,
+                allocation: vec![Amount::from(40u32), Amount::from(30u32)],
+            }],
+            finalized: true,
+        };
+        let sigs = |state: &State| {
+            vec![
+                sign(1, &state.signing_bytes()),
+                sign(2, &state.signing_bytes()),
+            ]
+        };
+        assert_eq!(
+            s.conclude(&params, state.clone(), &sigs(&state), 1),
+            Err(ErrorCode::InsufficientFunding {
+                required: 70u32.into(),
+                available: 50u32.into()
+            }
+            .into())
+        );
+        state.assets[0].allocation[0] = Amount::from(20u32);
+        s.conclude(&params, state.clone(), &sigs(&state), 1)
+            .unwrap();
+        let theirs = Funding::new(id.clone(), account(2)).in_asset(Some(usdc));
+        assert_eq!(s.query_holdings(theirs), Some(Amount::from(30u32)));
+        assert_eq!(holdings(&s, &id, 2), Amount::from(100u32));
+
+        let req = WithdrawalReq {
+            channel: id,
+            participant: account(2),
+            amount: Amount::from(30u32),
+            receiver: Principal::from_slice(&[2]),
+            nonce: 0,
+            expiry: None,
+            asset: Some(usdc),
+        };
+        let sig = sign(2, &req.signing_bytes());
+        s.queue_withdrawal(req, &sig, 0u32.into(), 2).unwrap();
+        let w = s.take_queued_withdrawals().remove(0);
+        assert_eq!(w.ledger, usdc);
+        assert_eq!(w.arg.amount, Amount::from(30u32));
+    }
+}
@@ -0,0 +1,130 @@
+//  Copyright 2025 PolyCrypt GmbH
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+
+//! Operations with keys held by the canister. Attestations, and later Bitcoin
+//! signing and hub co-signing, go through the `Signer` injected into the
+//! canister state, so that the threshold ECDSA implementation can be replaced
+//! by a software key in tests or by another custody provider.
+
+use crate::error::*;
+use async_trait::async_trait;
+use ic_cdk::management_canister::{
+    EcdsaCurve, EcdsaKeyId, EcdsaPublicKeyArgs, SignWithEcdsaArgs, ecdsa_public_key,
+    sign_with_ecdsa,
+};
+use k256::ecdsa::{Signature, SigningKey, signature::hazmat::PrehashSigner};
+
+/// A secp256k1 key held on behalf of the canister.
+#[async_trait]
+pub trait Signer: Send + Sync {
+    /// Signs a 32-byte message hash. The signature is the 64-byte
+    /// concatenation of `r` and `s`.
+    async fn sign(&self, message_hash: [u8; 32]) -> Result<Vec<u8>>;
+
+    /// Returns the SEC1-encoded (compressed) public key matching the
+    /// signatures produced by `sign`.
+    async fn public_key(&self) -> Result<Vec<u8>>;
+}
+
+/// Threshold ECDSA signer using the management canister.
+pub struct ManagementCanisterSigner {
+    key_name: String,
+}
+
+/// Signer holding a plain secret key, for simulation and testing purposes.
+pub struct SoftwareSigner {
+    key: SigningKey,
+}
+
+impl ManagementCanisterSigner {
+    pub fn new(key_name: &str) -> Self {
+        Self {
+            key_name: key_name.to_string(),
+        }
+    }
+
+    fn key_id(&self) -> EcdsaKeyId {
+        EcdsaKeyId {
+            curve: EcdsaCurve::Secp256k1,
+            name: self.key_name.clone(),
+        }
+    }
+}
+
+#[async_trait]
+impl Signer for ManagementCanisterSigner {
+    async fn sign(&self, message_hash: [u8; 32]) -> Result<Vec<u8>> {
+        let args = SignWithEcdsaArgs {
+            message_hash: message_hash.to_vec(),
+            derivation_path: vec![],
+            key_id: self.key_id(),
+        };
+        sign_with_ecdsa(&args)
+            .await
+            .map(|res| res.signature)
+            .map_err(|e| Error::from(ErrorCode::SigningError).with("reason", e))
+    }
+
+    async fn public_key(&self) -> Result<Vec<u8>> {
+        let args = EcdsaPublicKeyArgs {
+            canister_id: None,
+            derivation_path: vec![],
+            key_id: self.key_id(),
+        };
+        ecdsa_public_key(&args)
+            .await
+            .map(|res| res.public_key)
+            .map_err(|e| Error::from(ErrorCode::SigningError).with("reason", e))
+    }
+}
+
+impl SoftwareSigner {
+    pub fn new(key: SigningKey) -> Self {
+        Self { key }
+    }
+}
+
+#[async_trait]
+impl Signer for SoftwareSigner {
+    async fn sign(&self, message_hash: [u8; 32]) -> Result<Vec<u8>> {
+        let sig: Signature = self
+            .key
+            .sign_prehash(&message_hash)
+            .map_err(|e| Error::from(ErrorCode::SigningError).with("reason", e))?;
+        Ok(sig.to_bytes().to_vec())
+    }
+
+    async fn public_key(&self) -> Result<Vec<u8>> {
+        let point = self.key.verifying_key().to_encoded_point(true);
+        Ok(point.as_bytes().to_vec())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+    use k256::ecdsa::{VerifyingKey, signature::hazmat::PrehashVerifier};
+
+    #[test]
+    fn test_software_signer_matches_public_key() {
+        let signer = SoftwareSigner::new(key(7));
+        let hash = [3u8; 32];
+        let sig = block_on(signer.sign(hash)).unwrap();
+        let pk = block_on(signer.public_key()).unwrap();
+        let pk = VerifyingKey::from_sec1_bytes(&pk).unwrap();
+        let sig = Signature::from_slice(&sig).unwrap();
+        assert!(pk.verify_prehash(&hash, &sig).is_ok());
+    }
+}
@@ -0,0 +1,216 @@
+//  Copyright 2025 PolyCrypt GmbH
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+
+//! Append-only log of privileged calls, kept in stable memory so that it
+//! survives upgrades. Unlike channel events, it is meant for post-incident
+//! forensics by the controllers. The log is hash-chained: every entry extends
+//! a running hash, whose head is anchored outside the canister (see
+//! `anchor`).
+
+use crate::error::*;
+use crate::memory::{self, AUDIT_HEAD, AUDIT_LOG_DATA, AUDIT_LOG_INDEX, Memory};
+use crate::page::MAX_PAGE_LIMIT;
+use crate::types::*;
+use candid::utils::ArgumentEncoder;
+use candid::{Decode, Encode, Principal, candid_method};
+use ic_cdk::query;
+use ic_stable_structures::storable::{Bound, Storable};
+use ic_stable_structures::{Log, StableCell};
+use k256::sha2::{Digest, Sha256};
+use std::borrow::Cow;
+use std::cell::RefCell;
+
+#[derive(Clone, Deserialize, CandidType, PartialEq, Eq, Debug)]
+/// A privileged call.
+pub struct AuditEntry {
+    /// The called endpoint.
+    pub method: String,
+    pub caller: Principal,
+    /// SHA-256 hash of the call's Candid-encoded arguments.
+    pub args_hash: [u8; 32],
+    pub timestamp: Timestamp,
+    /// The code of the error, if the call failed. Its context is not logged.
+    pub outcome: std::result::Result<(), ErrorCode>,
+}
+
+#[derive(Clone, Deserialize, CandidType, PartialEq, Eq, Debug, Default)]
+/// The running hash over the first `length` audit log entries.
+pub struct AuditHead {
+    pub length: u64,
+    pub hash: [u8; 32],
+}
+
+thread_local! {
+    static AUDIT_LOG: RefCell<Log<AuditEntry, Memory, Memory>> = RefCell::new(
+        Log::init(memory::get(AUDIT_LOG_INDEX), memory::get(AUDIT_LOG_DATA))
+            .expect("initializing audit log"),
+    );
+
+    static HEAD: RefCell<StableCell<AuditHead, Memory>> = RefCell::new(
+        StableCell::init(memory::get(AUDIT_HEAD), AuditHead::default())
+            .expect("initializing audit head"),
+    );
+}
+
+#[query]
+#[candid_method(query)]
+/// Returns up to `limit` audit log entries starting at `offset`, oldest
+/// first. Controller only.
+fn query_audit_log(offset: u64, limit: u32) -> Result<Vec<AuditEntry>> {
+    crate::require_controller()?;
+    Ok(entries(offset, limit))
+}
+
+#[query]
+#[candid_method(query)]
+/// Returns the running hash over the audit log.
+fn query_audit_head() -> AuditHead {
+    head()
+}
+
+impl Storable for AuditEntry {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).expect("encoding audit entry"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(&bytes, Self).expect("decoding audit entry")
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for AuditHead {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).expect("encoding audit head"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(&bytes, Self).expect("decoding audit head")
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl AuditHead {
+    /// The head after appending `entry`: the hash of the previous head's
+    /// hash and the entry's Candid encoding.
+    pub fn extend(&self, entry: &AuditEntry) -> Self {
+        let mut h = Sha256::new();
+        h.update(self.hash);
+        h.update(entry.to_bytes());
+        Self {
+            length: self.length + 1,
+            hash: h.finalize().into(),
+        }
+    }
+}
+
+/// Hashes a call's arguments for the audit log.
+pub fn args_hash(args: impl ArgumentEncoder) -> [u8; 32] {
+    let bytes = candid::encode_args(args).expect("encoding arguments");
+    Sha256::digest(bytes).into()
+}
+
+/// Appends a call and its outcome to the audit log.
+pub fn record<T>(method: &str, caller: Principal, args_hash: [u8; 32], outcome: &Result<T>) {
+    append(&AuditEntry {
+        method: method.into(),
+        caller,
+        args_hash,
+        timestamp: ic_cdk::api::time(),
+        outcome: outcome.as_ref().map(|_| ()).map_err(|e| e.code.clone()),
+    });
+}
+
+pub fn append(entry: &AuditEntry) {
+    let head = head().extend(entry);
+    AUDIT_LOG.with(|log| log.borrow().append(entry).expect("appending to audit log"));
+    HEAD.with(|h| h.borrow_mut().set(head).expect("storing audit head"));
+}
+
+/// The running hash over the audit log. Entries logged before the log was
+/// hash-chained are included when the head is first read.
+pub fn head() -> AuditHead {
+    let head = HEAD.with(|h| h.borrow().get().clone());
+    let len = AUDIT_LOG.with(|log| log.borrow().len());
+    if head.length == len {
+        return head;
+    }
+    let head = AUDIT_LOG.with(|log| {
+        let log = log.borrow();
+        (head.length..len)
+            .filter_map(|i| log.get(i))
+            .fold(head, |head, entry| head.extend(&entry))
+    });
+    HEAD.with(|h| {
+        h.borrow_mut()
+            .set(head.clone())
+            .expect("storing audit head")
+    });
+    head
+}
+
+/// Runs a synchronous privileged call and records it in the audit log.
+pub fn logged<T>(method: &str, args_hash: [u8; 32], call: impl FnOnce() -> Result<T>) -> Result<T> {
+    let outcome = call();
+    record(method, ic_cdk::api::msg_caller(), args_hash, &outcome);
+    outcome
+}
+
+pub fn entries(offset: u64, limit: u32) -> Vec<AuditEntry> {
+    let limit = match limit {
+        0 => MAX_PAGE_LIMIT,
+        l => l.min(MAX_PAGE_LIMIT),
+    } as u64;
+    AUDIT_LOG.with(|log| {
+        let log = log.borrow();
+        (offset..log.len().min(offset.saturating_add(limit)))
+            .filter_map(|i| log.get(i))
+            .collect()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_entries_are_paged_in_order() {
+        for (i, outcome) in [Ok(()), Err(ErrorCode::Unauthorized), Ok(())]
+            .into_iter()
+            .enumerate()
+        {
+            append(&AuditEntry {
+                method: "freeze_channel".into(),
+                caller: Principal::anonymous(),
+                args_hash: args_hash((i as u64,)),
+                timestamp: i as u64,
+                outcome,
+            });
+        }
+        let page = entries(1, 5);
+        assert_eq!(page.len(), 2);
+        assert_eq!(page[0].timestamp, 1);
+        assert_eq!(page[0].outcome, Err(ErrorCode::Unauthorized));
+        assert_ne!(page[0].args_hash, page[1].args_hash);
+        assert!(entries(3, 5).is_empty());
+
+        let chained = entries(0, 5)
+            .iter()
+            .fold(AuditHead::default(), |head, e| head.extend(e));
+        assert_eq!(head(), chained);
+        assert_eq!(head().length, 3);
+    }
+}
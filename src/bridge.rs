@@ -0,0 +1,439 @@
+//  Copyright 2025 PolyCrypt GmbH
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+
+//! Queue of commands from the canister to bridge operators. Operators poll
+//! for the commands addressed to them, acknowledge them within a deadline and
+//! respond through the endpoint of the respective flow. Commands that are not
+//! acknowledged in time move on to an alternative operator, up to a retry
+//! budget, and every attempt is recorded.
+//!
+//! Updates originating from the bridge carry a request id chosen by the
+//! submitter. Each id is processed once; resubmitting it returns the original
+//! result without repeating side effects. The results are kept in stable
+//! memory, so that this holds across upgrades. Transient failures are not
+//! recorded, so that the update can be retried under the same id.
+
+use crate::audit;
+use crate::error::*;
+use crate::invoice::{InvoiceId, InvoiceStatus};
+use crate::memory::{self, BRIDGE_REQUESTS, Memory};
+use crate::operator::Direction;
+use crate::permission::Scope;
+use crate::receiver::TXQuerier;
+use crate::types::*;
+use crate::{CanisterState, mutate_state, read_state, require};
+use candid::{Decode, Encode, Principal, candid_method};
+use ic_cdk::api::time as blocktime;
+use ic_cdk::{query, update};
+use ic_stable_structures::StableBTreeMap;
+use ic_stable_structures::storable::{Bound, Storable};
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+
+/// Identifies a queued command.
+pub type CommandId = u64;
+
+/// Identifies an update submitted by the bridge, unique per submitter.
+pub type BridgeRequestId = u64;
+
+/// How long an operator has to acknowledge a command (one minute).
+pub const COMMAND_ACK_WINDOW: Duration = 60_000_000_000;
+
+/// How many operators a command is sent to at most.
+pub const MAX_COMMAND_ATTEMPTS: usize = 3;
+
+/// How often commands are checked for missed deadlines (thirty seconds).
+pub const BRIDGE_CHECK_INTERVAL: std::time::Duration = std::time::Duration::from_secs(30);
+
+#[derive(Clone, Deserialize, CandidType, PartialEq, Eq, Debug)]
+pub enum BridgeCommand {
+    /// Issue a Lightning invoice whose payment credits the requester.
+    IssueInvoice {
+        request: InvoiceId,
+        amount: Amount,
+        memo: String,
+    },
+}
+
+#[derive(Clone, Copy, Deserialize, CandidType, PartialEq, Eq, Debug)]
+pub enum CommandStatus {
+    /// Waiting for the current operator's acknowledgement.
+    Pending,
+    /// The current operator acknowledged the command.
+    Acked,
+    /// The operator responded to the command.
+    Done,
+    /// No operator acknowledged or responded to the command in time.
+    Failed,
+}
+
+#[derive(Clone, Deserialize, CandidType)]
+/// One operator a command was sent to.
+pub struct CommandAttempt {
+    pub operator: Principal,
+    pub sent_at: Timestamp,
+    pub acked_at: Option<Timestamp>,
+}
+
+#[derive(Clone, Deserialize, CandidType)]
+pub struct QueuedCommand {
+    pub command: BridgeCommand,
+    pub status: CommandStatus,
+    /// The operators the command was sent to, the current one last.
+    pub attempts: Vec<CommandAttempt>,
+}
+
+#[derive(Default)]
+pub struct BridgeQueue {
+    commands: BTreeMap<CommandId, QueuedCommand>,
+    next_id: CommandId,
+}
+
+/// The results of processed bridge-originated updates, by submitter and
+/// request id.
+pub type ProcessedRequests = StableBTreeMap<(Principal, BridgeRequestId), RequestResult, Memory>;
+
+/// The recorded result of a bridge-originated update.
+pub struct RequestResult(Result<()>);
+
+#[query]
+#[candid_method(query)]
+/// Returns the open commands addressed to the calling operator, oldest first.
+fn poll_bridge_commands() -> Result<Vec<(CommandId, BridgeCommand)>> {
+    let caller = ic_cdk::api::msg_caller();
+    read_state(|state| {
+        state.require_scope(&caller, Scope::ConsumeQueue)?;
+        Ok(state.bridge.pending_for(&caller))
+    })
+}
+
+#[update]
+#[candid_method(update)]
+/// Acknowledges a command addressed to the calling operator, so that it is
+/// not handed to another operator.
+fn ack_bridge_command(id: CommandId) -> Result<()> {
+    audit::logged("ack_bridge_command", audit::args_hash((id,)), || {
+        let caller = ic_cdk::api::msg_caller();
+        mutate_state(|state| {
+            state.require_scope(&caller, Scope::ConsumeQueue)?;
+            state.bridge.ack(id, caller, blocktime())
+        })
+    })
+}
+
+#[query]
+#[candid_method(query)]
+fn query_bridge_command(id: CommandId) -> Option<QueuedCommand> {
+    read_state(|s| s.bridge.commands.get(&id).cloned())
+}
+
+/// Hands commands whose operator missed the acknowledgement deadline to the
+/// next operator.
+pub fn check_bridge() {
+    mutate_state(|s| s.check_bridge(blocktime()));
+}
+
+/// Loads the results of processed bridge-originated updates, or starts an
+/// empty record.
+pub fn processed_requests() -> ProcessedRequests {
+    StableBTreeMap::init(memory::get(BRIDGE_REQUESTS))
+}
+
+impl Storable for RequestResult {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(&self.0).expect("encoding request result"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Self(Decode!(&bytes, Result<()>).expect("decoding request result"))
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl BridgeCommand {
+    /// The direction and amount an operator must serve for the command.
+    fn requirement(&self) -> (Direction, &Amount) {
+        match self {
+            BridgeCommand::IssueInvoice { amount, .. } => (Direction::FromLightning, amount),
+        }
+    }
+}
+
+impl QueuedCommand {
+    /// The operator the command is currently addressed to. Commands are
+    /// enqueued with an attempt, so the anonymous principal is never returned.
+    pub fn operator(&self) -> Principal {
+        self.attempts
+            .last()
+            .map_or(Principal::anonymous(), |a| a.operator)
+    }
+
+    fn open(&self) -> bool {
+        matches!(self.status, CommandStatus::Pending | CommandStatus::Acked)
+    }
+}
+
+impl BridgeQueue {
+    pub fn enqueue(
+        &mut self,
+        operator: Principal,
+        command: BridgeCommand,
+        now: Timestamp,
+    ) -> CommandId {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.commands.insert(
+            id,
+            QueuedCommand {
+                command,
+                status: CommandStatus::Pending,
+                attempts: vec![CommandAttempt {
+                    operator,
+                    sent_at: now,
+                    acked_at: None,
+                }],
+            },
+        );
+        id
+    }
+
+    pub fn pending_for(&self, operator: &Principal) -> Vec<(CommandId, BridgeCommand)> {
+        self.commands
+            .iter()
+            .filter(|(_, c)| c.open() && c.operator() == *operator)
+            .map(|(id, c)| (*id, c.command.clone()))
+            .collect()
+    }
+
+    pub fn ack(&mut self, id: CommandId, operator: Principal, now: Timestamp) -> Result<()> {
+        let cmd = self.commands.get_mut(&id).ok_or(ErrorCode::NotFound)?;
+        require!(cmd.open(), AlreadyConcluded);
+        require!(cmd.operator() == operator, Unauthorized);
+        let Some(attempt) = cmd.attempts.last_mut() else {
+            return Err(ErrorCode::NotFound.into());
+        };
+        require!(now < attempt.sent_at + COMMAND_ACK_WINDOW, Expired);
+        attempt.acked_at.get_or_insert(now);
+        cmd.status = CommandStatus::Acked;
+        Ok(())
+    }
+
+    /// Counts the commands whose status matches.
+    pub fn count(&self, status: impl Fn(CommandStatus) -> bool) -> u64 {
+        self.commands.values().filter(|c| status(c.status)).count() as u64
+    }
+
+    /// Closes an open command with a final status.
+    pub fn finish(&mut self, id: CommandId, status: CommandStatus) {
+        if let Some(cmd) = self.commands.get_mut(&id).filter(|c| c.open()) {
+            cmd.status = status;
+        }
+    }
+
+    /// Lists unacknowledged commands whose deadline passed.
+    fn overdue(&self, now: Timestamp) -> Vec<CommandId> {
+        self.commands
+            .iter()
+            .filter(|(_, c)| {
+                c.status == CommandStatus::Pending
+                    && c.attempts
+                        .last()
+                        .is_some_and(|a| now >= a.sent_at + COMMAND_ACK_WINDOW)
+            })
+            .map(|(id, _)| *id)
+            .collect()
+    }
+}
+
+impl<Q: TXQuerier> CanisterState<Q> {
+    /// Runs a bridge-originated update once per submitter and request id,
+    /// and returns the recorded result for repeated submissions. Transient
+    /// failures are not recorded, so the update runs again when retried.
+    pub fn once(
+        &mut self,
+        submitter: Principal,
+        request_id: BridgeRequestId,
+        update: impl FnOnce(&mut Self) -> Result<()>,
+    ) -> Result<()> {
+        if let Some(result) = self.processed.get(&(submitter, request_id)) {
+            return result.0;
+        }
+        let result = update(self);
+        if !result.as_ref().is_err_and(|e| e.code.is_transient()) {
+            self.processed
+                .insert((submitter, request_id), RequestResult(result.clone()));
+        }
+        result
+    }
+
+    /// Sends overdue commands to the best operator not tried yet that serves
+    /// them for at most the fee agreed with the requester. Commands without
+    /// such an operator, or that used up their retry budget, fail along with
+    /// their flow. Commands whose flow is gone are dropped.
+    pub fn check_bridge(&mut self, now: Timestamp) {
+        for id in self.bridge.overdue(now) {
+            let Some(cmd) = self.bridge.commands.get(&id) else {
+                continue;
+            };
+            let tried: Vec<Principal> = cmd.attempts.iter().map(|a| a.operator).collect();
+            let operator = cmd.operator();
+
+            let BridgeCommand::IssueInvoice { request, .. } = cmd.command;
+            let (direction, amount) = cmd.command.requirement();
+            let Some(max_fee) = self.invoices.get(&request).map(|r| r.fee.clone()) else {
+                self.bridge.commands.remove(&id);
+                continue;
+            };
+            let next = match tried.len() < MAX_COMMAND_ATTEMPTS {
+                true => self
+                    .operator_candidates(direction, amount, now, &tried)
+                    .into_iter()
+                    .map(|(op, ad)| (op, ad.fee(amount)))
+                    .find(|(_, fee)| *fee <= max_fee),
+                false => None,
+            };
+
+            self.record_missed(operator, now);
+            let (Some(cmd), Some(req)) = (
+                self.bridge.commands.get_mut(&id),
+                self.invoices.get_mut(&request),
+            ) else {
+                continue;
+            };
+            match next {
+                Some((operator, fee)) => {
+                    cmd.attempts.push(CommandAttempt {
+                        operator,
+                        sent_at: now,
+                        acked_at: None,
+                    });
+                    req.operator = operator;
+                    req.fee = fee;
+                }
+                None => {
+                    cmd.status = CommandStatus::Failed;
+                    req.status = InvoiceStatus::Expired;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+
+    #[test]
+    fn test_unacked_command_moves_to_next_operator() {
+        let mut s = new_state();
+        let ops: Vec<Principal> = (10..14).map(|n| operator(&mut s, n, n)).collect();
+        for op in &ops {
+            advertise(&mut s, *op, Direction::FromLightning, 20_000);
+        }
+        let inv = s
+            .request_invoice(Principal::anonymous(), Amount::from(50u32), "".into(), 0)
+            .unwrap();
+        let InvoiceStatus::Requested { command } = s.invoices[&inv].status else {
+            panic!("invoice not requested");
+        };
+
+        s.check_bridge(COMMAND_ACK_WINDOW);
+        assert_eq!(s.invoices[&inv].operator, ops[1]);
+        assert!(s.bridge.pending_for(&ops[0]).is_empty());
+        assert!(matches!(
+            s.bridge.ack(command, ops[0], COMMAND_ACK_WINDOW),
+            Err(Error {
+                code: ErrorCode::Unauthorized,
+                ..
+            })
+        ));
+
+        // The retry budget is used up after the third operator.
+        s.check_bridge(2 * COMMAND_ACK_WINDOW);
+        s.check_bridge(3 * COMMAND_ACK_WINDOW);
+        let cmd = &s.bridge.commands[&command];
+        assert_eq!(cmd.status, CommandStatus::Failed);
+        assert_eq!(cmd.attempts.len(), MAX_COMMAND_ATTEMPTS);
+        assert_eq!(s.invoices[&inv].status, InvoiceStatus::Expired);
+    }
+
+    #[test]
+    fn test_repeated_request_returns_original_result() {
+        let mut s = new_state();
+        let op = Principal::from_slice(&[10]);
+        let mut runs = 0;
+        let mut run = |s: &mut CanisterState<_>, id| {
+            s.once(op, id, |_| {
+                runs += 1;
+                Err(ErrorCode::Expired.into())
+            })
+        };
+        assert_eq!(run(&mut s, 1), Err(ErrorCode::Expired.into()));
+        assert_eq!(run(&mut s, 1), Err(ErrorCode::Expired.into()));
+        assert_eq!(run(&mut s, 2), Err(ErrorCode::Expired.into()));
+        assert_eq!(runs, 2);
+
+        // Results are kept in stable memory, across upgrades.
+        assert_eq!(
+            new_state().once(op, 1, |_| Ok(())),
+            Err(ErrorCode::Expired.into())
+        );
+
+        // Transient failures are not recorded.
+        assert_eq!(
+            s.once(op, 3, |_| Err(ErrorCode::Paused.into())),
+            Err(ErrorCode::Paused.into())
+        );
+        assert_eq!(s.once(op, 3, |_| Ok(())), Ok(()));
+        assert_eq!(s.once(op, 3, |_| Err(ErrorCode::Paused.into())), Ok(()));
+    }
+
+    #[test]
+    fn test_orphaned_command_is_dropped() {
+        let mut s = new_state();
+        let op = Principal::from_slice(&[10]);
+        let cmd = s.bridge.enqueue(
+            op,
+            BridgeCommand::IssueInvoice {
+                request: 7,
+                amount: Amount::from(1u32),
+                memo: "".into(),
+            },
+            0,
+        );
+        s.check_bridge(COMMAND_ACK_WINDOW);
+        assert!(!s.bridge.commands.contains_key(&cmd));
+        assert!(s.bridge.pending_for(&op).is_empty());
+    }
+
+    #[test]
+    fn test_acked_command_is_not_retried() {
+        let mut s = new_state();
+        let op = Principal::from_slice(&[10]);
+        let cmd = s.bridge.enqueue(
+            op,
+            BridgeCommand::IssueInvoice {
+                request: 0,
+                amount: Amount::from(1u32),
+                memo: "".into(),
+            },
+            0,
+        );
+        s.bridge.ack(cmd, op, 1).unwrap();
+        assert!(s.bridge.overdue(COMMAND_ACK_WINDOW).is_empty());
+        assert_eq!(s.bridge.pending_for(&op).len(), 1);
+    }
+}
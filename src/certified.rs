@@ -0,0 +1,487 @@
+//  Copyright 2025 PolyCrypt GmbH
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+
+//! Certified query responses. The holdings and the registered states are
+//! kept in Merkle trees in the IC's hash tree format, whose root is set as
+//! the canister's certified data after every update changing them:
+//!
+//! ```text
+//! fork(labeled("holdings", holdings), labeled("states", states))
+//! ```
+//!
+//! The holdings are labeled by the funding's stable encoding (channel id
+//! followed by the compressed participant key), the states by channel id.
+//! Each leaf is the Candid encoding of the value. A certified query returns
+//! the value along with the certificate and a witness, the CBOR-encoded hash
+//! tree with everything but the path to the value pruned, so that a client
+//! can check the value against the certificate without an update call.
+
+use crate::memory::Memory;
+use crate::receiver::TXQuerier;
+use crate::store::HoldingsMap;
+use crate::types::*;
+use crate::{CanisterState, read_state};
+use candid::{CandidType, Encode, candid_method};
+use ic_cdk::query;
+use ic_stable_structures::StableBTreeMap;
+use k256::sha2::{Digest, Sha256};
+use serde_bytes::ByteBuf;
+use std::collections::BTreeMap;
+
+pub const HOLDINGS_LABEL: &[u8] = b"holdings";
+pub const STATES_LABEL: &[u8] = b"states";
+
+pub type NodeHash = [u8; 32];
+
+#[derive(Clone, Deserialize, CandidType)]
+/// A value along with the proof that it is certified.
+pub struct Certified<T> {
+    pub value: T,
+    /// The system certificate of the canister's certified data. Missing if
+    /// the query was not called as a query.
+    pub certificate: Option<ByteBuf>,
+    /// The CBOR-encoded hash tree whose root is the certified data.
+    pub witness: ByteBuf,
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+/// A hash tree as specified by the IC interface specification.
+pub enum HashTree {
+    Empty,
+    Fork(Box<HashTree>, Box<HashTree>),
+    Labeled(Vec<u8>, Box<HashTree>),
+    Leaf(Vec<u8>),
+    Pruned(NodeHash),
+}
+
+/// Labeled leaves, split into buckets by the first byte of their label. A
+/// change only rehashes its bucket, which keeps updates cheap since labels
+/// are uniformly distributed.
+pub struct MerkleMap {
+    /// The hash of each entry's leaf, by label.
+    buckets: Vec<BTreeMap<Vec<u8>, NodeHash>>,
+    bucket_hashes: Vec<NodeHash>,
+    changed: bool,
+}
+
+#[query]
+#[candid_method(query)]
+/// Returns the funds deposited for a channel's participant as in
+/// `query_holdings`, certified under the `holdings` label.
+fn query_holdings_certified(funding: Funding) -> Certified<Option<Amount>> {
+    let caller = ic_cdk::api::msg_caller();
+    read_state(|s| {
+        if !s.may_read_funding(&caller, &funding) {
+            return Certified {
+                value: None,
+                certificate: None,
+                witness: ByteBuf::new(),
+            };
+        }
+        let value = s.user_holdings.get(&funding);
+        let leaf = value.as_ref().map(leaf_bytes);
+        let witness = s.certified_witness(HOLDINGS_LABEL, &funding.key(), leaf);
+        Certified {
+            value,
+            certificate: ic_cdk::api::data_certificate().map(ByteBuf::from),
+            witness: ByteBuf::from(witness.to_cbor()),
+        }
+    })
+}
+
+#[query]
+#[candid_method(query)]
+/// Returns the latest registered state of a channel as in `query_state`,
+/// certified under the `states` label.
+fn query_state_certified(id: ChannelId) -> Certified<Option<RegisteredState>> {
+    read_state(|s| {
+        let value = s.channels.get(&id);
+        let leaf = value.as_ref().map(leaf_bytes);
+        let witness = s.certified_witness(STATES_LABEL, &id.0, leaf);
+        Certified {
+            value,
+            certificate: ic_cdk::api::data_certificate().map(ByteBuf::from),
+            witness: ByteBuf::from(witness.to_cbor()),
+        }
+    })
+}
+
+/// The leaf of a certified value: its Candid encoding.
+pub fn leaf_bytes<T: CandidType>(value: &T) -> Vec<u8> {
+    Encode!(value).expect("encoding certified value")
+}
+
+fn domain_hash(domain: &str, parts: &[&[u8]]) -> NodeHash {
+    let mut h = Sha256::new();
+    h.update([domain.len() as u8]);
+    h.update(domain.as_bytes());
+    for part in parts {
+        h.update(part);
+    }
+    h.finalize().into()
+}
+
+pub fn empty_hash() -> NodeHash {
+    domain_hash("ic-hashtree-empty", &[])
+}
+
+pub fn fork_hash(left: &NodeHash, right: &NodeHash) -> NodeHash {
+    domain_hash("ic-hashtree-fork", &[left, right])
+}
+
+pub fn labeled_hash(label: &[u8], subtree: &NodeHash) -> NodeHash {
+    domain_hash("ic-hashtree-labeled", &[label, subtree])
+}
+
+pub fn leaf_hash(value: &[u8]) -> NodeHash {
+    domain_hash("ic-hashtree-leaf", &[value])
+}
+
+/// The hash of a tree of nodes, split in halves until single nodes remain.
+fn fork_tree(nodes: &[NodeHash]) -> NodeHash {
+    match nodes {
+        [] => empty_hash(),
+        [node] => *node,
+        _ => {
+            let (left, right) = nodes.split_at(nodes.len() / 2);
+            fork_hash(&fork_tree(left), &fork_tree(right))
+        }
+    }
+}
+
+/// The tree of nodes shaped as in `fork_tree`, with the nodes that are not
+/// revealed pruned.
+fn witness_tree(nodes: &[(NodeHash, Option<HashTree>)]) -> HashTree {
+    if nodes.iter().all(|(_, revealed)| revealed.is_none()) {
+        return match nodes {
+            [] => HashTree::Empty,
+            _ => HashTree::Pruned(fork_tree(
+                &nodes.iter().map(|(h, _)| *h).collect::<Vec<_>>(),
+            )),
+        };
+    }
+    match nodes {
+        [(_, Some(revealed))] => revealed.clone(),
+        _ => {
+            let (left, right) = nodes.split_at(nodes.len() / 2);
+            HashTree::Fork(Box::new(witness_tree(left)), Box::new(witness_tree(right)))
+        }
+    }
+}
+
+impl HashTree {
+    /// The root hash of the tree.
+    pub fn digest(&self) -> NodeHash {
+        match self {
+            Self::Empty => empty_hash(),
+            Self::Fork(l, r) => fork_hash(&l.digest(), &r.digest()),
+            Self::Labeled(label, t) => labeled_hash(label, &t.digest()),
+            Self::Leaf(value) => leaf_hash(value),
+            Self::Pruned(hash) => *hash,
+        }
+    }
+
+    /// The tree's CBOR encoding, prefixed with the self-describing tag.
+    pub fn to_cbor(&self) -> Vec<u8> {
+        let mut out = vec![0xd9, 0xd9, 0xf7];
+        self.write_cbor(&mut out);
+        out
+    }
+
+    fn write_cbor(&self, out: &mut Vec<u8>) {
+        const ARRAY: u8 = 4;
+        const BYTES: u8 = 2;
+        match self {
+            Self::Empty => {
+                cbor_head(out, ARRAY, 1);
+                out.push(0);
+            }
+            Self::Fork(l, r) => {
+                cbor_head(out, ARRAY, 3);
+                out.push(1);
+                l.write_cbor(out);
+                r.write_cbor(out);
+            }
+            Self::Labeled(label, t) => {
+                cbor_head(out, ARRAY, 3);
+                out.push(2);
+                cbor_head(out, BYTES, label.len() as u64);
+                out.extend_from_slice(label);
+                t.write_cbor(out);
+            }
+            Self::Leaf(value) => {
+                cbor_head(out, ARRAY, 2);
+                out.push(3);
+                cbor_head(out, BYTES, value.len() as u64);
+                out.extend_from_slice(value);
+            }
+            Self::Pruned(hash) => {
+                cbor_head(out, ARRAY, 2);
+                out.push(4);
+                cbor_head(out, BYTES, hash.len() as u64);
+                out.extend_from_slice(hash);
+            }
+        }
+    }
+}
+
+/// Appends a CBOR data item head of the given major type.
+fn cbor_head(out: &mut Vec<u8>, major: u8, n: u64) {
+    let major = major << 5;
+    match n {
+        0..24 => out.push(major | n as u8),
+        24..0x100 => out.extend_from_slice(&[major | 24, n as u8]),
+        0x100..0x1_0000 => {
+            out.push(major | 25);
+            out.extend_from_slice(&(n as u16).to_be_bytes());
+        }
+        0x1_0000..0x1_0000_0000 => {
+            out.push(major | 26);
+            out.extend_from_slice(&(n as u32).to_be_bytes());
+        }
+        _ => {
+            out.push(major | 27);
+            out.extend_from_slice(&n.to_be_bytes());
+        }
+    }
+}
+
+impl Default for MerkleMap {
+    fn default() -> Self {
+        Self {
+            buckets: vec![BTreeMap::new(); 256],
+            bucket_hashes: vec![empty_hash(); 256],
+            changed: true,
+        }
+    }
+}
+
+impl MerkleMap {
+    /// Sets the leaf labeled `label`. Labels must not be empty.
+    pub fn insert(&mut self, label: Vec<u8>, leaf: &[u8]) {
+        let b = label[0] as usize;
+        self.buckets[b].insert(label, leaf_hash(leaf));
+        self.rehash(b);
+    }
+
+    pub fn remove(&mut self, label: &[u8]) {
+        let b = label[0] as usize;
+        if self.buckets[b].remove(label).is_some() {
+            self.rehash(b);
+        }
+    }
+
+    fn rehash(&mut self, b: usize) {
+        let nodes: Vec<_> = self.buckets[b]
+            .iter()
+            .map(|(label, leaf)| labeled_hash(label, leaf))
+            .collect();
+        self.bucket_hashes[b] = fork_tree(&nodes);
+        self.changed = true;
+    }
+
+    pub fn root_hash(&self) -> NodeHash {
+        fork_tree(&self.bucket_hashes)
+    }
+
+    /// Whether the map changed since this was last called.
+    pub fn take_changed(&mut self) -> bool {
+        std::mem::take(&mut self.changed)
+    }
+
+    /// The tree revealing the leaf labeled `label`, or, if there is none,
+    /// the labels next to it to prove its absence.
+    pub fn witness(&self, label: &[u8], leaf: Option<Vec<u8>>) -> HashTree {
+        let below = self.buckets[..=label[0] as usize]
+            .iter()
+            .rev()
+            .find_map(|b| b.range(..label.to_vec()).next_back());
+        let above = self.buckets[label[0] as usize..].iter().find_map(|b| {
+            b.range(label.to_vec()..)
+                .find(|(l, _)| l.as_slice() != label)
+        });
+        let mut revealed = BTreeMap::new();
+        for (l, leaf) in below.into_iter().chain(above) {
+            revealed.insert(l.clone(), HashTree::Pruned(*leaf));
+        }
+        if let Some(leaf) = leaf {
+            revealed.insert(label.to_vec(), HashTree::Leaf(leaf));
+        }
+        let buckets: Vec<_> = self
+            .buckets
+            .iter()
+            .zip(&self.bucket_hashes)
+            .map(|(bucket, hash)| {
+                let nodes: Vec<_> = bucket
+                    .iter()
+                    .map(|(l, leaf)| {
+                        let node = revealed
+                            .get(l)
+                            .map(|t| HashTree::Labeled(l.clone(), Box::new(t.clone())));
+                        (labeled_hash(l, leaf), node)
+                    })
+                    .collect();
+                let revealed = nodes.iter().any(|(_, n)| n.is_some());
+                (*hash, revealed.then(|| witness_tree(&nodes)))
+            })
+            .collect();
+        witness_tree(&buckets)
+    }
+}
+
+impl<Q: TXQuerier> CanisterState<Q> {
+    /// The root hash of the certified trees.
+    pub fn certified_root(&self) -> NodeHash {
+        fork_hash(
+            &labeled_hash(HOLDINGS_LABEL, &self.user_holdings.tree().root_hash()),
+            &labeled_hash(STATES_LABEL, &self.certified_states.root_hash()),
+        )
+    }
+
+    /// The witness for a leaf of one of the certified trees.
+    pub fn certified_witness(&self, tree: &[u8], label: &[u8], leaf: Option<Vec<u8>>) -> HashTree {
+        let (holdings, states) = (self.user_holdings.tree(), &self.certified_states);
+        let labeled = |name: &[u8], t| HashTree::Labeled(name.to_vec(), Box::new(t));
+        let (left, right) = if tree == HOLDINGS_LABEL {
+            (
+                labeled(HOLDINGS_LABEL, holdings.witness(label, leaf)),
+                HashTree::Pruned(labeled_hash(STATES_LABEL, &states.root_hash())),
+            )
+        } else {
+            (
+                HashTree::Pruned(labeled_hash(HOLDINGS_LABEL, &holdings.root_hash())),
+                labeled(STATES_LABEL, states.witness(label, leaf)),
+            )
+        };
+        HashTree::Fork(Box::new(left), Box::new(right))
+    }
+
+    /// Sets the certified data if the certified trees changed.
+    pub fn certify(&mut self) {
+        let holdings = self.user_holdings.tree_mut().take_changed();
+        if self.certified_states.take_changed() | holdings {
+            ic_cdk::api::certified_data_set(self.certified_root());
+        }
+    }
+}
+
+/// Builds the certified tree over the latest registered states.
+pub fn states_tree(channels: &StableBTreeMap<ChannelId, RegisteredState, Memory>) -> MerkleMap {
+    let mut tree = MerkleMap::default();
+    for (id, state) in channels.iter() {
+        tree.insert(id.0.to_vec(), &leaf_bytes(&state));
+    }
+    tree
+}
+
+impl HoldingsMap {
+    /// Builds the certified tree over the holdings.
+    pub fn certified_tree(&self) -> MerkleMap {
+        let mut tree = MerkleMap::default();
+        for (funding, amount) in self.iter().chain(self.iter_assets()) {
+            tree.insert(funding.key(), &leaf_bytes(&amount));
+        }
+        tree
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+
+    fn labeled(label: &str, t: HashTree) -> HashTree {
+        HashTree::Labeled(label.as_bytes().to_vec(), Box::new(t))
+    }
+
+    fn fork(l: HashTree, r: HashTree) -> HashTree {
+        HashTree::Fork(Box::new(l), Box::new(r))
+    }
+
+    fn leaf(value: &str) -> HashTree {
+        HashTree::Leaf(value.as_bytes().to_vec())
+    }
+
+    #[test]
+    fn test_hash_tree_matches_specification_example() {
+        let tree = fork(
+            fork(
+                labeled(
+                    "a",
+                    fork(
+                        fork(labeled("x", leaf("hello")), HashTree::Empty),
+                        labeled("y", leaf("world")),
+                    ),
+                ),
+                labeled("b", leaf("good")),
+            ),
+            fork(labeled("c", HashTree::Empty), labeled("d", leaf("morning"))),
+        );
+        assert_eq!(
+            hex::encode(tree.digest()),
+            "eb5c5b2195e62d996b84c9bcc8259d19a83786a2f59e0878cec84c811f669aa0"
+        );
+        assert_eq!(HashTree::Empty.to_cbor(), [0xd9, 0xd9, 0xf7, 0x81, 0x00]);
+    }
+
+    #[test]
+    fn test_witnesses_match_root() {
+        let mut map = MerkleMap::default();
+        let labels: Vec<Vec<u8>> = [[1u8, 5], [1, 9], [7, 0], [200, 3]]
+            .iter()
+            .map(|l| l.to_vec())
+            .collect();
+        for l in &labels {
+            map.insert(l.clone(), l);
+        }
+        let root = map.root_hash();
+        for l in &labels {
+            let witness = map.witness(l, Some(l.clone()));
+            assert_eq!(witness.digest(), root);
+        }
+        // Absent labels are proven by their neighbors.
+        assert_eq!(map.witness(&[1, 7], None).digest(), root);
+        assert_eq!(map.witness(&[0, 1], None).digest(), root);
+        assert_eq!(map.witness(&[255], None).digest(), root);
+
+        map.remove(&labels[0]);
+        assert_ne!(map.root_hash(), root);
+        assert!(map.take_changed());
+        assert!(!map.take_changed());
+    }
+
+    #[test]
+    fn test_state_certifies_holdings_and_states() {
+        let mut s = new_state();
+        let id = concluded(&mut s, 1, 1, 2);
+        let root = s.certified_root();
+
+        let funding = Funding::new(id.clone(), account(1));
+        let amount = s.user_holdings.get(&funding).unwrap();
+        let witness =
+            s.certified_witness(HOLDINGS_LABEL, &funding.key(), Some(leaf_bytes(&amount)));
+        assert_eq!(witness.digest(), root);
+
+        let state = s.channels.get(&id).unwrap();
+        let witness = s.certified_witness(STATES_LABEL, &id.0, Some(leaf_bytes(&state)));
+        assert_eq!(witness.digest(), root);
+
+        s.credit(
+            funding,
+            Amount::from(1u32),
+            crate::holdings::ChangeCause::Deposit,
+        );
+        assert_ne!(s.certified_root(), root);
+    }
+}
@@ -0,0 +1,110 @@
+//  Copyright 2025 PolyCrypt GmbH
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+
+//! Challenge durations scaled by the value at stake. Without a policy,
+//! disputes time out after the channel's `challenge_duration`. With a
+//! policy, channels holding more funds get proportionally longer to react,
+//! so that griefing a large channel's participants into missing the timeout
+//! requires keeping them offline for longer. The channel's own challenge
+//! duration always remains the minimum.
+
+use crate::audit;
+use crate::error::*;
+use crate::receiver::TXQuerier;
+use crate::types::*;
+use crate::{CanisterState, mutate_state, read_state, require};
+use candid::candid_method;
+use ic_cdk::{query, update};
+
+#[derive(Clone, Deserialize, CandidType, PartialEq, Debug)]
+pub struct ChallengePolicy {
+    /// The channel value per which `extension` is granted.
+    pub step: Amount,
+    /// The reaction time granted per full `step` of channel value.
+    pub extension: Duration,
+    /// Upper bound of the scaled duration.
+    pub max_duration: Duration,
+}
+
+#[update]
+#[candid_method(update)]
+/// Sets the policy scaling challenge durations by channel value, or removes
+/// it if `policy` is empty. Admin only.
+fn set_challenge_policy(policy: Option<ChallengePolicy>) -> Result<()> {
+    let hash = audit::args_hash((&policy,));
+    audit::logged("set_challenge_policy", hash, || {
+        crate::admin::require_admin()?;
+        if let Some(p) = &policy {
+            require!(p.step > Amount::default(), InvalidInput);
+        }
+        mutate_state(|s| s.challenge_policy = policy);
+        Ok(())
+    })
+}
+
+#[query]
+#[candid_method(query)]
+fn query_challenge_policy() -> Option<ChallengePolicy> {
+    read_state(|s| s.challenge_policy.clone())
+}
+
+#[query]
+#[candid_method(query)]
+/// Returns how long a dispute of the channel would currently be open.
+fn query_challenge_duration(params: Params) -> Duration {
+    read_state(|s| s.challenge_duration(&params))
+}
+
+impl ChallengePolicy {
+    /// The scaled duration for a channel holding `value`, at least `min`.
+    pub fn duration(&self, value: &Amount, min: Duration) -> Duration {
+        let steps = (value.clone() / self.step.clone()).0;
+        let steps = u64::try_from(steps).unwrap_or(u64::MAX);
+        let scaled = steps.saturating_mul(self.extension).min(self.max_duration);
+        scaled.max(min)
+    }
+}
+
+impl<Q: TXQuerier> CanisterState<Q> {
+    /// The effective challenge duration of a channel under the current
+    /// policy.
+    pub fn challenge_duration(&self, params: &Params) -> Duration {
+        match &self.challenge_policy {
+            Some(policy) => {
+                policy.duration(&self.holdings_total(params), params.challenge_duration)
+            }
+            None => params.challenge_duration,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_duration_scales_with_value() {
+        let policy = ChallengePolicy {
+            step: Amount::from(100u32),
+            extension: 10,
+            max_duration: 50,
+        };
+        let duration = |value: u32, min| policy.duration(&Amount::from(value), min);
+        assert_eq!(duration(99, 5), 5);
+        assert_eq!(duration(250, 5), 20);
+        assert_eq!(duration(250, 30), 30);
+        assert_eq!(duration(100_000, 5), 50);
+        assert_eq!(duration(100_000, 70), 70);
+    }
+}
@@ -0,0 +1,143 @@
+//  Copyright 2025 PolyCrypt GmbH
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+
+//! Deployment configuration passed as init and upgrade argument, so that the
+//! same WASM can be installed on a local replica, testnet and mainnet.
+//! Canisters installed without a configuration use the local devnet ledger.
+
+use crate::audit;
+use crate::error::Result;
+use crate::receiver::{DEFAULT_CKBTC_FEE, DEVNET_CKBTC_LEDGER, TXQuerier};
+use crate::types::*;
+use crate::{CanisterState, read_state};
+use candid::{CandidType, Principal, candid_method};
+use ic_cdk::{query, update};
+
+#[derive(Clone, Copy, Deserialize, CandidType, PartialEq, Eq, Debug)]
+pub enum Network {
+    Local,
+    Testnet,
+    Mainnet,
+}
+
+#[derive(Clone, Deserialize, CandidType, PartialEq, Debug)]
+pub struct CanisterConfig {
+    /// The ckBTC ledger holding the canister's funds.
+    pub ledger: Principal,
+    /// The fee of a ledger transfer, in base units.
+    pub fee: Nat,
+    pub network: Network,
+    /// The append-only canister the audit log's running hash is anchored
+    /// to. No anchoring takes place without one.
+    pub audit_anchor: Option<Principal>,
+    /// The threshold ECDSA key attestations are signed with. Defaults to the
+    /// network's key, see `ecdsa_key_name`.
+    pub ecdsa_key: Option<String>,
+}
+
+#[query]
+#[candid_method(query)]
+fn query_config() -> CanisterConfig {
+    current()
+}
+
+#[update]
+#[candid_method(update)]
+/// Switches to another configuration, such as another ledger or fee. It
+/// applies until an upgrade installs its own. Admin only.
+fn set_config(config: CanisterConfig) -> Result<()> {
+    audit::logged("set_config", audit::args_hash((&config,)), || {
+        crate::admin::require_admin()?;
+        crate::configure(config);
+        Ok(())
+    })
+}
+
+impl Default for CanisterConfig {
+    fn default() -> Self {
+        Self {
+            ledger: DEVNET_CKBTC_LEDGER,
+            fee: Nat::from(DEFAULT_CKBTC_FEE),
+            network: Network::Local,
+            audit_anchor: None,
+            ecdsa_key: None,
+        }
+    }
+}
+
+impl CanisterConfig {
+    /// The name of the threshold ECDSA key to sign with: the configured one,
+    /// or else the network's test or production key.
+    pub fn ecdsa_key_name(&self) -> &str {
+        match (&self.ecdsa_key, self.network) {
+            (Some(name), _) => name,
+            (None, Network::Local) => "dfx_test_key",
+            (None, Network::Testnet) => "test_key_1",
+            (None, Network::Mainnet) => "key_1",
+        }
+    }
+}
+
+/// The configuration in effect.
+pub fn current() -> CanisterConfig {
+    read_state(|s| s.config.clone())
+}
+
+impl<Q: TXQuerier> CanisterState<Q> {
+    /// Switches to another configuration. Assets registered for the previous
+    /// ledger, and its liquidity pool, move to the new one. Unspent deposits
+    /// stay with the ledger they were received on.
+    pub fn configure(&mut self, config: CanisterConfig) {
+        if let Some(info) = self.assets.remove(&self.config.ledger) {
+            self.assets.insert(config.ledger, info);
+        }
+        if let Some(pool) = self.liq_pools.remove(&self.config.ledger) {
+            self.liq_pools.insert(config.ledger, pool);
+        }
+        self.icrc_receiver.set_ledger(config.ledger);
+        self.config = config;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+
+    #[test]
+    fn test_configure_moves_ckbtc_asset() {
+        let mut s = new_state();
+        let ledger = Principal::from_slice(&[3]);
+        s.configure(CanisterConfig {
+            ledger,
+            fee: Nat::from(10u32),
+            network: Network::Mainnet,
+            audit_anchor: None,
+            ecdsa_key: None,
+        });
+        assert_eq!(s.ckbtc(), ledger);
+        assert_eq!(s.asset(&ledger).unwrap().symbol, "ckBTC");
+        assert!(s.asset(&CanisterConfig::default().ledger).is_err());
+    }
+
+    #[test]
+    fn test_ecdsa_key_follows_network_unless_configured() {
+        let mut config = CanisterConfig::default();
+        assert_eq!(config.ecdsa_key_name(), "dfx_test_key");
+        config.network = Network::Mainnet;
+        assert_eq!(config.ecdsa_key_name(), "key_1");
+        config.ecdsa_key = Some("custom".into());
+        assert_eq!(config.ecdsa_key_name(), "custom");
+    }
+}
@@ -4,7 +4,7 @@ use std::cell::RefCell;
 use std::collections::VecDeque;
 
 thread_local! {
-    static MESSAGE_QUEUE: RefCell<VecDeque<String>> = RefCell::new(VecDeque::new());
+    static MESSAGE_QUEUE: RefCell<VecDeque<String>> = const { RefCell::new(VecDeque::new()) };
 }
 
 // Add a message to the queue
@@ -37,6 +37,7 @@ fn clear() {
     MESSAGE_QUEUE.with(|queue| queue.borrow_mut().clear());
 }
 
+#[derive(Default)]
 pub struct Deq {
     queue: VecDeque<String>,
 }
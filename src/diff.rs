@@ -0,0 +1,148 @@
+//  Copyright 2025 PolyCrypt GmbH
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+
+//! Diffs of the registered states and holdings for light synchronization.
+//! Every modification of a channel's registered state or of a funding's
+//! holdings is stamped with the event sequence number current at the time,
+//! i.e. the sequence number of the next event. A hub polling the canister
+//! passes the `next_seq` of its previous diff and only receives the entries
+//! modified since, instead of downloading all channels again.
+
+use crate::receiver::TXQuerier;
+use crate::types::*;
+use crate::{CanisterState, read_state};
+use candid::{CandidType, candid_method};
+use ic_cdk::query;
+use std::collections::{BTreeMap, BTreeSet};
+
+#[derive(Clone, Deserialize, CandidType)]
+pub struct StateDiff {
+    /// The registered states modified since the requested sequence number.
+    pub channels: Vec<RegisteredState>,
+    /// The holdings modified since the requested sequence number. Emptied
+    /// holdings are included with an amount of zero.
+    pub holdings: Vec<(Funding, Amount)>,
+    /// The sequence number to request the next diff with.
+    pub next_seq: u64,
+}
+
+/// The event sequence numbers at which keys were last modified.
+struct Stamps<K> {
+    by_key: BTreeMap<K, u64>,
+    by_seq: BTreeMap<u64, BTreeSet<K>>,
+}
+
+#[derive(Default)]
+pub struct Modifications {
+    channels: Stamps<ChannelId>,
+    holdings: Stamps<Funding>,
+}
+
+#[query]
+#[candid_method(query)]
+/// Returns the registered states and holdings modified since the event
+/// sequence number `since_event_seq`. Pass 0 to receive all of them, and
+/// the returned `next_seq` with the next call. Entries modified around the
+/// previous call may be returned again.
+fn state_diff(since_event_seq: u64) -> StateDiff {
+    read_state(|s| s.state_diff(since_event_seq))
+}
+
+impl<K> Default for Stamps<K> {
+    fn default() -> Self {
+        Self {
+            by_key: BTreeMap::new(),
+            by_seq: BTreeMap::new(),
+        }
+    }
+}
+
+impl<K: Ord + Clone> Stamps<K> {
+    fn stamp(&mut self, key: &K, seq: u64) {
+        if let Some(old) = self.by_key.insert(key.clone(), seq)
+            && let Some(keys) = self.by_seq.get_mut(&old)
+        {
+            keys.remove(key);
+            if keys.is_empty() {
+                self.by_seq.remove(&old);
+            }
+        }
+        self.by_seq.entry(seq).or_default().insert(key.clone());
+    }
+
+    /// The keys modified at or after `seq`, in modification order.
+    fn since(&self, seq: u64) -> impl Iterator<Item = &K> {
+        self.by_seq.range(seq..).flat_map(|(_, keys)| keys)
+    }
+}
+
+impl<Q: TXQuerier> CanisterState<Q> {
+    /// Stamps a channel's registered state as modified.
+    pub(crate) fn touch_channel(&mut self, channel: &ChannelId) {
+        let seq = self.events.next_seq();
+        self.modifications.channels.stamp(channel, seq);
+    }
+
+    /// Stamps a funding's holdings as modified.
+    pub(crate) fn touch_holdings(&mut self, funding: &Funding) {
+        let seq = self.events.next_seq();
+        self.modifications.holdings.stamp(funding, seq);
+    }
+
+    pub fn state_diff(&self, since_event_seq: u64) -> StateDiff {
+        let m = &self.modifications;
+        StateDiff {
+            channels: m
+                .channels
+                .since(since_event_seq)
+                .filter_map(|ch| self.channels.get(ch))
+                .collect(),
+            holdings: m
+                .holdings
+                .since(since_event_seq)
+                .map(|f| (f.clone(), self.user_holdings.get(f).unwrap_or_default()))
+                .collect(),
+            next_seq: self.events.next_seq(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::holdings::ChangeCause;
+    use crate::testing::*;
+
+    #[test]
+    fn test_diff_holds_modified_entries() {
+        let mut s = new_state();
+        let old = concluded(&mut s, 1, 1, 2);
+        let since = s.state_diff(0).next_seq;
+        assert!(s.state_diff(since).channels.is_empty());
+
+        let ch = concluded(&mut s, 2, 3, 4);
+        let diff = s.state_diff(since);
+        assert_eq!(diff.channels.len(), 1);
+        assert!(diff.channels[0].state.channel == ch);
+        assert_eq!(diff.holdings.len(), 2);
+        assert!(diff.holdings.iter().all(|(f, _)| f.channel == ch));
+
+        let funding = Funding::new(old, account(1));
+        s.debit(&funding, &Amount::from(100u32), ChangeCause::Withdrawal);
+        let diff = s.state_diff(diff.next_seq);
+        assert!(diff.channels.is_empty());
+        assert!(diff.holdings[0].0 == funding);
+        assert_eq!(diff.holdings[0].1, Amount::default());
+    }
+}
@@ -0,0 +1,300 @@
+//  Copyright 2025 PolyCrypt GmbH
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+
+//! Sweeping of dust: holdings in settled channels too small to be worth a
+//! withdrawal. Under a dust policy, holdings that stayed below its threshold
+//! for its retention period are swept either into the treasury or into a
+//! credit that is paid out with the participant's next withdrawal. Every
+//! sweep is recorded as a `DustSwept` event of the channel. Participants can
+//! opt out of sweeping.
+
+use crate::audit;
+use crate::error::*;
+use crate::events::Event;
+use crate::guard::GuardKey;
+use crate::holdings::ChangeCause;
+use crate::receiver::TXQuerier;
+use crate::types::*;
+use crate::{CanisterState, mutate_state, read_state, require};
+use candid::candid_method;
+use ic_cdk::api::time as blocktime;
+use ic_cdk::{query, update};
+use k256::elliptic_curve::sec1::ToEncodedPoint;
+use std::collections::{BTreeMap, HashMap};
+use std::time::Duration as StdDuration;
+
+/// How often dust is swept (one hour).
+pub const DUST_SWEEP_INTERVAL: StdDuration = StdDuration::from_secs(3600);
+
+#[derive(Clone, Copy, Deserialize, CandidType, PartialEq, Eq, Debug)]
+pub enum DustDestination {
+    /// The dust becomes canister revenue.
+    Treasury,
+    /// The dust is paid out with the participant's next withdrawal.
+    NextWithdrawal,
+}
+
+#[derive(Clone, Deserialize, CandidType, PartialEq, Eq, Debug)]
+pub struct DustPolicy {
+    /// Holdings below this amount are dust.
+    pub threshold: Amount,
+    /// How long holdings must stay dust before they are swept.
+    pub retention: Duration,
+    pub destination: DustDestination,
+}
+
+#[derive(Clone, Copy, Deserialize, CandidType, PartialEq, Eq, Debug)]
+/// A participant's choice whether their dust may be swept.
+pub struct DustOptOut {
+    pub opt_out: bool,
+    /// Increases with each choice so that old ones cannot be replayed.
+    pub seq: u64,
+}
+
+#[derive(Default)]
+pub struct Dust {
+    policy: Option<DustPolicy>,
+    /// When each funding's holdings were first seen as dust.
+    seen: HashMap<Funding, Timestamp>,
+    opt_outs: BTreeMap<L2Account, DustOptOut>,
+    /// Swept dust kept as canister revenue.
+    treasury: Amount,
+    /// Swept dust owed to participants with their next withdrawal.
+    credits: BTreeMap<L2Account, Amount>,
+}
+
+#[update]
+#[candid_method(update)]
+/// Sets the dust policy, or stops sweeping dust. Admin only.
+fn set_dust_policy(policy: Option<DustPolicy>) -> Result<()> {
+    audit::logged("set_dust_policy", audit::args_hash((&policy,)), || {
+        crate::admin::require_admin()?;
+        mutate_state(|s| s.dust.policy = policy);
+        Ok(())
+    })
+}
+
+#[query]
+#[candid_method(query)]
+fn query_dust_policy() -> Option<DustPolicy> {
+    read_state(|s| s.dust.policy.clone())
+}
+
+#[update]
+#[candid_method(update)]
+/// Opts a participant out of or back into dust sweeping. The signature is by
+/// the participant over `DustOptOut::signing_bytes`.
+fn set_dust_opt_out(participant: L2Account, choice: DustOptOut, sig: Vec<u8>) -> Result<()> {
+    mutate_state(|s| s.set_dust_opt_out(participant, choice, &sig))
+}
+
+#[query]
+#[candid_method(query)]
+/// The swept dust paid out with the participant's next withdrawal.
+fn query_dust_credit(participant: L2Account) -> Amount {
+    read_state(|s| {
+        s.dust
+            .credits
+            .get(&participant)
+            .cloned()
+            .unwrap_or_default()
+    })
+}
+
+#[query]
+#[candid_method(query)]
+/// The swept dust kept as canister revenue.
+fn query_treasury() -> Amount {
+    read_state(|s| s.dust.treasury.clone())
+}
+
+/// Sweeps the dust that is due, unless the canister is halted.
+pub fn sweep_dust() {
+    if read_state(|s| s.require_running()).is_err() {
+        return;
+    }
+    mutate_state(|s| s.sweep_dust(blocktime()));
+}
+
+impl DustOptOut {
+    /// The bytes the participant signs to make the choice.
+    pub fn signing_bytes(&self, participant: &L2Account) -> Vec<u8> {
+        let mut data = b"ckLightning dust opt-out".to_vec();
+        data.extend_from_slice(participant.0.to_encoded_point(true).as_bytes());
+        data.push(self.opt_out as u8);
+        data.extend_from_slice(&self.seq.to_le_bytes());
+        data
+    }
+}
+
+impl Dust {
+    pub fn policy(&self) -> Option<&DustPolicy> {
+        self.policy.as_ref()
+    }
+
+    /// Removes and returns the participant's dust credit.
+    pub fn take_credit(&mut self, participant: &L2Account) -> Amount {
+        self.credits.remove(participant).unwrap_or_default()
+    }
+
+    pub fn add_credit(&mut self, participant: L2Account, amount: Amount) {
+        if amount > Amount::default() {
+            *self.credits.entry(participant).or_default() += amount;
+        }
+    }
+
+    /// The swept dust the canister holds: the treasury and all credits.
+    pub fn total(&self) -> Amount {
+        self.credits
+            .values()
+            .fold(self.treasury.clone(), |acc, a| acc + a.clone())
+    }
+}
+
+impl<Q: TXQuerier> CanisterState<Q> {
+    pub fn set_dust_opt_out(
+        &mut self,
+        participant: L2Account,
+        choice: DustOptOut,
+        sig: &[u8],
+    ) -> Result<()> {
+        let msg = choice.signing_bytes(&participant);
+        require!(
+            participant.verify(&msg, sig),
+            Authentication {
+                participant_index: None
+            }
+        );
+        if let Some(old) = self.dust.opt_outs.get(&participant) {
+            require!(
+                choice.seq > old.seq,
+                OutdatedState {
+                    registered_version: old.seq,
+                    submitted_version: choice.seq,
+                }
+            );
+        }
+        self.dust.opt_outs.insert(participant, choice);
+        Ok(())
+    }
+
+    /// Whether a funding's holdings are dust that may be swept: they are
+    /// below the threshold, their channel is settled, and they are neither
+    /// claimed, reserved nor of a participant who opted out.
+    fn is_sweepable(
+        &self,
+        funding: &Funding,
+        held: &Amount,
+        policy: &DustPolicy,
+        now: Timestamp,
+    ) -> bool {
+        *held < policy.threshold
+            && self
+                .channels
+                .get(&funding.channel)
+                .is_some_and(|s| s.settled(now))
+            && !self.locked.contains(&funding.channel)
+            && !self.guards.is_claimed(&GuardKey::Funding(funding.clone()))
+            && self.unreserved(funding, held.clone(), now) == *held
+            && !self
+                .dust
+                .opt_outs
+                .get(&funding.participant)
+                .is_some_and(|c| c.opt_out)
+    }
+
+    /// Sweeps the holdings that were dust for the policy's retention period
+    /// and returns them.
+    pub fn sweep_dust(&mut self, now: Timestamp) -> Vec<(Funding, Amount)> {
+        let Some(policy) = self.dust.policy.clone() else {
+            self.dust.seen.clear();
+            return vec![];
+        };
+        let dust: Vec<(Funding, Amount)> = self
+            .user_holdings
+            .iter()
+            .filter(|(f, held)| self.is_sweepable(f, held, &policy, now))
+            .collect();
+
+        let mut seen = HashMap::new();
+        let mut swept = vec![];
+        for (funding, amount) in dust {
+            let since = self.dust.seen.get(&funding).copied().unwrap_or(now);
+            if now.saturating_sub(since) < policy.retention {
+                seen.insert(funding, since);
+                continue;
+            }
+            self.debit(&funding, &amount, ChangeCause::DustSwept);
+            match policy.destination {
+                DustDestination::Treasury => self.dust.treasury += amount.clone(),
+                DustDestination::NextWithdrawal => {
+                    let participant = funding.participant.clone();
+                    self.dust.add_credit(participant, amount.clone());
+                }
+            }
+            let event = Event::DustSwept {
+                who: funding.participant.clone(),
+                amount: amount.clone(),
+                destination: policy.destination,
+                timestamp: now,
+            };
+            self.events.push(now, funding.channel.clone(), event);
+            swept.push((funding, amount));
+        }
+        self.dust.seen = seen;
+        swept
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+
+    #[test]
+    fn test_dust_is_swept_after_retention() {
+        let mut s = new_state();
+        let ch = concluded(&mut s, 51, 1, 2);
+        let (a, b) = (
+            Funding::new(ch.clone(), account(1)),
+            Funding::new(ch.clone(), account(2)),
+        );
+        s.debit(&a, &Amount::from(95u32), ChangeCause::Withdrawal);
+        s.debit(&b, &Amount::from(97u32), ChangeCause::Withdrawal);
+        s.dust.policy = Some(DustPolicy {
+            threshold: Amount::from(10u32),
+            retention: 100,
+            destination: DustDestination::NextWithdrawal,
+        });
+        let choice = DustOptOut {
+            opt_out: true,
+            seq: 1,
+        };
+        let sig = sign(2, &choice.signing_bytes(&account(2)));
+        s.set_dust_opt_out(account(2), choice, &sig).unwrap();
+
+        assert!(s.sweep_dust(1).is_empty());
+        assert!(s.sweep_dust(100).is_empty());
+        let swept: Vec<_> = s
+            .sweep_dust(101)
+            .into_iter()
+            .filter(|(f, _)| f.channel == ch)
+            .collect();
+        assert_eq!(swept.len(), 1);
+        assert!(swept[0].0 == a);
+        assert_eq!(holdings(&s, &ch, 1), Amount::default());
+        assert_eq!(holdings(&s, &ch, 2), Amount::from(3u32));
+        assert_eq!(s.dust.take_credit(&account(1)), Amount::from(5u32));
+    }
+}
@@ -17,26 +17,47 @@ pub use candid::{
     CandidType, Deserialize, Int, Nat,
     types::{Serializer, Type},
 };
+use ic_cdk::call::Error as CallError;
+use icrc_ledger_types::icrc1::transfer::TransferError;
+use icrc_ledger_types::icrc2::transfer_from::TransferFromError;
 #[macro_export]
 macro_rules! require {
     ($cond:expr, $err:ident) => {
         if !($cond) {
-            return Err(Error::$err);
+            return Err(Error::from(ErrorCode::$err).with("requirement", stringify!($cond)));
+        }
+    };
+    ($cond:expr, $err:ident { $($field:ident: $value:expr),* $(,)? }) => {
+        if !($cond) {
+            let code = ErrorCode::$err { $($field: $value),* };
+            return Err(Error::from(code).with("requirement", stringify!($cond)));
         }
     };
     ($cond:expr, $err:expr) => {
         if !($cond) {
-            return Err($err);
+            return Err($err.into());
         }
     };
 }
 
-#[derive(PartialEq, Eq, CandidType, Deserialize, Debug)]
-/// Contains all errors that can occur during an operation on the Perun
-/// canister.
-pub enum Error {
-    /// Any kind of signature mismatch.
-    Authentication,
+#[derive(Clone, CandidType, Deserialize, Debug)]
+/// An error of an operation on the Perun canister. Besides its machine-readable
+/// code, it carries a message and the offending fields and their values.
+pub struct Error {
+    pub code: ErrorCode,
+    pub message: String,
+    /// Names and values of the fields that caused the error.
+    pub context: Vec<(String, String)>,
+}
+
+#[derive(Clone, PartialEq, Eq, CandidType, Deserialize, Debug)]
+/// Contains all kinds of errors that can occur during an operation on the
+/// Perun canister.
+pub enum ErrorCode {
+    /// Any kind of signature or hash mismatch. `participant_index` is the
+    /// index of the channel participant whose signature did not verify, if
+    /// the signature was one of a channel's participants.
+    Authentication { participant_index: Option<u32> },
     /// A non-finalized state was registered when a finalized state was
     /// expected.
     NotFinalized,
@@ -45,23 +66,257 @@ pub enum Error {
     /// In some way, the input was invalid.
     InvalidInput,
     /// When trying get more funds out of a pool than have been put into it.
-    InsufficientFunding,
+    InsufficientFunding { required: Nat, available: Nat },
     /// When there is not enough liquidity in the pool to perform a withdrawal of ckBTC
     InsufficientLiquidity,
+    /// When a pool withdrawal exceeds the depositor's pool holdings and could
+    /// only be served from channel participants' funds.
+    ChannelFundsProtected,
     /// When a state that is registered for dispute is older than the previously
-    /// registered state.
-    OutdatedState,
-    /// Error while interaction with the ledger.
-    LedgerError,
+    /// registered state. For signed records that carry a sequence number
+    /// instead of a version, the versions are their sequence numbers.
+    OutdatedState {
+        registered_version: u64,
+        submitted_version: u64,
+    },
+    /// A call to a ledger failed or the ledger rejected it for a reason
+    /// without a code of its own. `code` is the reject code of a rejected
+    /// call, -1 if the call could not be made or its response not be decoded,
+    /// and 0 if the ledger answered with `transfer_error`.
+    Ledger {
+        code: i32,
+        message: String,
+        transfer_error: Option<Box<TransferError>>,
+    },
     /// Error receiving ICP tokens.
     ReceiverError(crate::receiver::ICPReceiverError),
     /// Error confirming tx
     ConfirmationError,
+    /// The requested channel, state or record is not known to the canister.
+    NotFound,
+    /// A deadline of the operation has passed.
+    Expired,
+    /// The operation is only possible after a deadline that has not passed
+    /// yet.
+    NotExpired,
+    /// The caller is not permitted to perform the operation.
+    Unauthorized,
+    /// Error while obtaining a threshold signature from the management
+    /// canister.
+    SigningError,
+    /// The canister is draining before an upgrade and accepts no new
+    /// fund-moving requests.
+    Draining,
+    /// The canister is halted by an admin and accepts no fund-moving
+    /// requests until resumed.
+    Paused,
+    /// The flow of the asset is paused.
+    AssetPaused,
+    /// An argument violates a limit of the validation layer.
+    Invalid(crate::validation::Violation),
+    /// The ledger block was already credited.
+    AlreadyProcessed,
+    /// A signed request with this nonce was already used for the same funds.
+    NonceReused { nonce: u64 },
+    /// Another call settling the same channel or funding is in progress, or
+    /// the canister is shedding load. The call can be retried later.
+    Busy,
+    /// The creator or the operator already has as many open swaps and
+    /// invoice requests as allowed.
+    TooManyOpenSwaps { limit: u32 },
+    /// The funds are reserved for a scheduled payment whose window has not
+    /// ended yet.
+    CapacityReserved,
+    /// The ledger expects another transfer fee.
+    BadFee { expected_fee: Nat },
+    /// The ledger rejected a burn below its minimum.
+    BadBurn { min_burn_amount: Nat },
+    /// The debited ledger account holds less than transferred.
+    InsufficientLedgerFunds { balance: Nat },
+    /// The canister's allowance on the debited ledger account is less than
+    /// transferred.
+    InsufficientAllowance { allowance: Nat },
+    /// The transfer's creation time is too far in the past.
+    TransferTooOld,
+    /// The transfer's creation time is ahead of the ledger's time.
+    CreatedInFuture { ledger_time: u64 },
+    /// The ledger cannot process transfers at the moment.
+    LedgerUnavailable,
+    /// The transfer was already executed in the given block.
+    DuplicateTransfer { duplicate_of: Nat },
+}
+
+impl Error {
+    /// Adds an offending field and its value to the context.
+    pub fn with(mut self, field: &str, value: impl std::fmt::Display) -> Self {
+        self.context.push((field.into(), value.to_string()));
+        self
+    }
+}
+
+impl From<ErrorCode> for Error {
+    fn from(code: ErrorCode) -> Self {
+        Self {
+            message: format!("{:?}", code),
+            code,
+            context: vec![],
+        }
+    }
+}
+
+impl From<TransferError> for Error {
+    fn from(e: TransferError) -> Self {
+        ErrorCode::from(e).into()
+    }
+}
+
+/// Errors are equal if their codes are, whatever their message and context.
+impl PartialEq for Error {
+    fn eq(&self, other: &Self) -> bool {
+        self.code == other.code
+    }
+}
+
+impl Eq for Error {}
+
+impl From<TransferError> for ErrorCode {
+    fn from(e: TransferError) -> Self {
+        match e {
+            TransferError::BadFee { expected_fee } => Self::BadFee { expected_fee },
+            TransferError::BadBurn { min_burn_amount } => Self::BadBurn { min_burn_amount },
+            TransferError::InsufficientFunds { balance } => {
+                Self::InsufficientLedgerFunds { balance }
+            }
+            TransferError::TooOld => Self::TransferTooOld,
+            TransferError::CreatedInFuture { ledger_time } => Self::CreatedInFuture { ledger_time },
+            TransferError::TemporarilyUnavailable => Self::LedgerUnavailable,
+            TransferError::Duplicate { duplicate_of } => Self::DuplicateTransfer { duplicate_of },
+            e @ TransferError::GenericError { .. } => Self::rejected(e),
+        }
+    }
 }
+
+impl ErrorCode {
+    /// Whether the error may clear up when the request is retried: the
+    /// canister, an asset or a ledger is unavailable for the moment, or a
+    /// call could not be completed.
+    pub fn is_transient(&self) -> bool {
+        matches!(
+            self,
+            Self::LedgerUnavailable
+                | Self::Busy
+                | Self::Draining
+                | Self::Paused
+                | Self::AssetPaused
+                | Self::SigningError
+                | Self::Ledger {
+                    transfer_error: None,
+                    ..
+                }
+        )
+    }
+
+    /// A rejection by the ledger that answered the call.
+    fn rejected(e: TransferError) -> Self {
+        let message = match &e {
+            TransferError::GenericError { message, .. } => message.clone(),
+            e => format!("{:?}", e),
+        };
+        Self::Ledger {
+            code: 0,
+            message,
+            transfer_error: Some(Box::new(e)),
+        }
+    }
+}
+
+impl From<CallError> for Error {
+    fn from(e: CallError) -> Self {
+        let code = match &e {
+            CallError::CallRejected(rejected) => rejected.raw_reject_code() as i32,
+            _ => -1,
+        };
+        let message = match &e {
+            CallError::CallRejected(rejected) => rejected.reject_message().to_string(),
+            e => e.to_string(),
+        };
+        ErrorCode::Ledger {
+            code,
+            message,
+            transfer_error: None,
+        }
+        .into()
+    }
+}
+
+impl From<TransferFromError> for Error {
+    fn from(e: TransferFromError) -> Self {
+        let code = match e {
+            TransferFromError::BadFee { expected_fee } => ErrorCode::BadFee { expected_fee },
+            TransferFromError::BadBurn { min_burn_amount } => {
+                ErrorCode::BadBurn { min_burn_amount }
+            }
+            TransferFromError::InsufficientFunds { balance } => {
+                ErrorCode::InsufficientLedgerFunds { balance }
+            }
+            TransferFromError::InsufficientAllowance { allowance } => {
+                ErrorCode::InsufficientAllowance { allowance }
+            }
+            TransferFromError::TooOld => ErrorCode::TransferTooOld,
+            TransferFromError::CreatedInFuture { ledger_time } => {
+                ErrorCode::CreatedInFuture { ledger_time }
+            }
+            TransferFromError::TemporarilyUnavailable => ErrorCode::LedgerUnavailable,
+            TransferFromError::Duplicate { duplicate_of } => {
+                ErrorCode::DuplicateTransfer { duplicate_of }
+            }
+            TransferFromError::GenericError {
+                error_code,
+                message,
+            } => ErrorCode::rejected(TransferError::GenericError {
+                error_code,
+                message,
+            }),
+        };
+        code.into()
+    }
+}
+
 impl std::fmt::Display for Error {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        std::fmt::Debug::fmt(self, f)
+        write!(f, "{}", self.message)?;
+        for (field, value) in &self.context {
+            write!(f, ", {}: {}", field, value)?;
+        }
+        Ok(())
     }
 }
 /// Canister operation result type.
 pub type Result<T> = core::result::Result<T, Error>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ledger_rejection_keeps_transfer_error() {
+        let rejection = TransferError::GenericError {
+            error_code: Nat::from(7u32),
+            message: "frozen".into(),
+        };
+        let e = Error::from(rejection.clone());
+        assert_eq!(
+            e.code,
+            ErrorCode::Ledger {
+                code: 0,
+                message: "frozen".into(),
+                transfer_error: Some(Box::new(rejection)),
+            }
+        );
+        let e = Error::from(TransferFromError::GenericError {
+            error_code: Nat::from(7u32),
+            message: "frozen".into(),
+        });
+        assert!(matches!(e.code, ErrorCode::Ledger { code: 0, .. }));
+    }
+}
@@ -12,45 +12,132 @@
 //  See the License for the specific language governing permissions and
 //  limitations under the License.
 
+use crate::audit;
+use crate::dust::DustDestination;
+use crate::error::Result;
+use crate::memory::Memory;
+use crate::page::*;
+use crate::receiver::TXQuerier;
 use crate::types::*;
+use crate::{mutate_state, read_state};
 use async_trait::async_trait;
 use candid::CandidType;
 use candid::{Principal, candid_method};
+use ic_cdk::call::Call;
 use ic_cdk::query;
 use ic_cdk::update;
-use lazy_static::lazy_static;
+use ic_stable_structures::Log;
+use k256::elliptic_curve::sec1::ToEncodedPoint;
+use serde_bytes::ByteBuf;
 use std::collections::BTreeMap;
 use std::fmt;
-use std::sync::RwLock;
-lazy_static! {
-    pub static ref STATE: RwLock<LocalEventRegisterer> = RwLock::new(LocalEventRegisterer::new());
+
+/// Most bytes of encoded events returned per replay (1.5 MiB), below the
+/// response size limit.
+pub const MAX_REPLAY_BYTES: u32 = 3 << 19;
+
+/// How often events older than the retention window are archived (one hour).
+pub const EVENT_ARCHIVE_INTERVAL: std::time::Duration = std::time::Duration::from_secs(3_600);
+
+#[query]
+#[candid_method(query)]
+/// Returns a page of a channel's events registered at or after the given time,
+/// in registration order. In privacy mode, only callers entitled to read the
+/// channel are served.
+fn query_events(
+    et: ChannelTime,
+    cursor: Option<Cursor>,
+    limit: u32,
+) -> crate::error::Result<Page<Event>> {
+    let caller = ic_cdk::api::msg_caller();
+    read_state(|s| {
+        if !s.may_read_channel(&caller, &et.chanid) {
+            return Err(crate::error::ErrorCode::Unauthorized.into());
+        }
+        s.events.events_page(&et.chanid, et.time, cursor, limit)
+    })
 }
 
-#[update]
-#[candid_method(update)]
-async fn register_event(ch: ChannelId, time: Timestamp, e: Event) {
-    STATE.write().unwrap().register_event(time, ch, e).await;
+#[query]
+#[candid_method(query)]
+/// Returns up to `MAX_PAGE_LIMIT` events of a channel registered at or after
+/// `since`, in registration order, so that clients can react to the
+/// channel's progress. Use `query_events` to page through more events. In
+/// privacy mode, callers not entitled to read the channel get no events.
+fn query_channel_events(channel: ChannelId, since: Timestamp) -> Vec<RegEvent> {
+    let caller = ic_cdk::api::msg_caller();
+    read_state(|s| match s.may_read_channel(&caller, &channel) {
+        true => s.events.events_since(&channel, since, MAX_PAGE_LIMIT),
+        false => vec![],
+    })
+}
+
+#[query]
+#[candid_method(query)]
+/// Returns the events of all channels from sequence number `from_seq` on, in
+/// registration order, as one compact blob of at most `max_bytes`, see
+/// `Event::encode`. A replay returns at least one event if there is any, so
+/// an indexer rebuilding from scratch continues with `next_seq` until the
+/// batch is empty. In privacy mode, only controllers get events.
+fn replay_events(from_seq: u64, max_bytes: u32) -> ReplayBatch {
+    if !may_replay() {
+        return ReplayBatch::empty(from_seq);
+    }
+    read_state(|s| s.events.replay(from_seq, max_bytes))
+}
+
+#[query]
+#[candid_method(query)]
+/// Returns archived events from position `from` in the archive on, oldest
+/// first, encoded as by `replay_events`. The batch's sequence numbers are
+/// positions in the archive, which unlike event sequence numbers are never
+/// reused, also not after upgrades. In privacy mode, only controllers get
+/// events.
+fn replay_archived_events(from: u64, max_bytes: u32) -> ReplayBatch {
+    if !may_replay() {
+        return ReplayBatch::empty(from);
+    }
+    read_state(|s| s.event_archive.replay(from, max_bytes))
+}
+
+/// Whether the caller may replay the events of all channels.
+fn may_replay() -> bool {
+    !read_state(|s| s.privacy.enabled()) || ic_cdk::api::is_controller(&ic_cdk::api::msg_caller())
 }
 
 #[update]
 #[candid_method(update)]
-async fn register_event_isolated(regev: RegEvent) {
-    // test event handling using this method
-    let time = regev.time;
-    let ch = regev.chanid;
-    let e = regev.event;
-    STATE.write().unwrap().register_event(time, ch, e).await;
+/// Sets for how long events are kept in the queryable event log before they
+/// are moved into the archive, or disables archiving if `window` is empty.
+/// Admin only.
+fn set_event_retention(window: Option<Duration>) -> Result<()> {
+    audit::logged("set_event_retention", audit::args_hash((window,)), || {
+        crate::admin::require_admin()?;
+        mutate_state(|s| s.event_retention = window);
+        Ok(())
+    })
 }
 
 #[query]
 #[candid_method(query)]
-fn query_events(et: ChannelTime) -> String {
-    STATE.read().unwrap().events_after_str(&et.chanid, et.time)
+fn query_event_retention() -> Option<Duration> {
+    read_state(|s| s.event_retention)
+}
+
+/// Moves the events that left the retention window into the archive.
+pub fn archive_events() {
+    mutate_state(|s| s.archive_events(ic_cdk::api::time()));
 }
 
 #[derive(Clone, CandidType, Deserialize)]
 
 pub enum Event {
+    /// The channel was announced with the deposits its participants owe.
+    Registered {
+        params: Params,
+        allocation: Vec<Amount>,
+        timestamp: Timestamp,
+    },
     /// A participant supplied funds into the channel.
     Funded {
         who: L2Account,
@@ -67,6 +154,33 @@ pub enum Event {
         state: RegisteredState,
         timestamp: Timestamp,
     },
+    /// The given percentage of a disputed channel's challenge window elapsed.
+    DisputeReminder {
+        state: RegisteredState,
+        elapsed_percent: u32,
+        timestamp: Timestamp,
+    },
+    /// Funds of a participant were paid out to the ledger.
+    Withdrawn {
+        who: L2Account,
+        amount: Amount,
+        timestamp: Timestamp,
+    },
+    /// Dust of a participant was swept out of the channel.
+    DustSwept {
+        who: L2Account,
+        amount: Amount,
+        destination: DustDestination,
+        timestamp: Timestamp,
+    },
+    /// The canister goes into maintenance, draining from `start` on for
+    /// `duration`.
+    MaintenanceScheduled {
+        start: Timestamp,
+        duration: Duration,
+        reason: String,
+        timestamp: Timestamp,
+    },
 }
 
 #[derive(PartialEq, Clone, Deserialize, Eq, Hash, CandidType)]
@@ -89,6 +203,30 @@ pub struct RegEvent {
     event: Event,
 }
 
+#[derive(Clone, CandidType, Deserialize)]
+/// Encoded events with consecutive sequence numbers.
+pub struct ReplayBatch {
+    /// The sequence number of the first event in `data`. Exceeds the
+    /// requested one if older events are no longer retained.
+    pub first_seq: u64,
+    /// The sequence number to continue the replay with.
+    pub next_seq: u64,
+    /// The events, each framed by its length as u32 LE and encoded as by
+    /// `Event::encode` after its timestamp (u64 LE) and channel id.
+    pub data: ByteBuf,
+}
+
+impl ReplayBatch {
+    /// A batch without events that continues where it started.
+    pub fn empty(seq: u64) -> Self {
+        Self {
+            first_seq: seq,
+            next_seq: seq,
+            data: ByteBuf::new(),
+        }
+    }
+}
+
 #[async_trait]
 pub trait EventRegisterer {
     async fn register_event(&mut self, time: Timestamp, ch: ChannelId, e: Event);
@@ -101,29 +239,40 @@ pub struct RPCEventRegisterer {
 #[async_trait]
 impl EventRegisterer for RPCEventRegisterer {
     async fn register_event(&mut self, time: Timestamp, ch: ChannelId, e: Event) {
-        let () = ic_cdk::call(self.event_canister, &"register_event", (ch, time, e))
+        if let Err(e) = Call::unbounded_wait(self.event_canister, "register_event")
+            .with_args(&(ch, time, e))
             .await
-            .unwrap();
+        {
+            ic_cdk::println!("registering event failed: {:?}", e);
+        }
     }
 }
 
 /// The event canister's state. Contains
-
 pub struct CanisterState {
     perun_canister: Principal,
     imple: LocalEventRegisterer,
 }
 
+/// Events moved out of the event log, in stable memory. Each entry is an
+/// event framed as in a `ReplayBatch`, without its length.
+pub struct EventArchive {
+    log: Log<Vec<u8>, Memory, Memory>,
+}
+
+#[derive(Default)]
 pub struct LocalEventRegisterer {
     /// All currently stored events.
     events: BTreeMap<ChannelId, BTreeMap<Timestamp, Vec<Event>>>,
+    /// Where each stored event is, by sequence number.
+    sequence: BTreeMap<u64, (ChannelId, Timestamp, usize)>,
+    next_seq: u64,
 }
 
 #[async_trait]
 impl EventRegisterer for LocalEventRegisterer {
     async fn register_event(&mut self, time: Timestamp, ch: ChannelId, e: Event) {
-        let events = self.events.entry(ch).or_insert(Default::default());
-        events.entry(time).or_insert(Default::default()).push(e);
+        self.push(time, ch, e);
     }
 }
 
@@ -155,6 +304,24 @@ impl fmt::Display for ChannelId {
 impl fmt::Display for Event {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
+            Event::Registered {
+                params,
+                allocation,
+                timestamp,
+            } => {
+                let alloc_string = allocation
+                    .iter()
+                    .map(|nat| format!("{}", nat))
+                    .collect::<Vec<String>>()
+                    .join(", ");
+                write!(
+                    f,
+                    "Registered event: Registered_channel=ChannelIDStart{}ChannelIDEnd, Registered_alloc=AllocStart{}AllocEnd, Registered_timestamp=TimestampStart{}TimestampEnd",
+                    params.id(),
+                    alloc_string,
+                    timestamp
+                )
+            }
             Event::Funded {
                 who,
                 total,
@@ -206,14 +373,185 @@ impl fmt::Display for Event {
                     timestamp
                 )
             }
+            Event::DisputeReminder {
+                state,
+                elapsed_percent,
+                timestamp,
+            } => {
+                write!(
+                    f,
+                    "DisputeReminder event: Reminder_state=ChannelIDStart{}ChannelIDEnd, Reminder_state=VersionStart{}VersionEnd, Reminder_elapsed=ElapsedStart{}ElapsedEnd, Reminder_timeout=TimeoutStart{}TimeoutEnd, Reminder_timestamp=TimestampStart{}TimestampEnd",
+                    state.state.channel,
+                    state.state.version,
+                    elapsed_percent,
+                    state.timeout,
+                    timestamp
+                )
+            }
+            Event::Withdrawn {
+                who,
+                amount,
+                timestamp,
+            } => {
+                write!(
+                    f,
+                    "Withdrawn event: Withdrawn_who={}, Withdrawn_amount=AmountStart{}AmountEnd, Withdrawn_timestamp=TimestampStart{}TimestampEnd",
+                    who, amount, timestamp
+                )
+            }
+            Event::DustSwept {
+                who,
+                amount,
+                destination,
+                timestamp,
+            } => {
+                write!(
+                    f,
+                    "DustSwept event: DustSwept_who={}, DustSwept_amount=AmountStart{}AmountEnd, DustSwept_destination={:?}, DustSwept_timestamp=TimestampStart{}TimestampEnd",
+                    who, amount, destination, timestamp
+                )
+            }
+            Event::MaintenanceScheduled {
+                start,
+                duration,
+                reason,
+                timestamp,
+            } => {
+                write!(
+                    f,
+                    "MaintenanceScheduled event: MaintenanceScheduled_start=TimestampStart{}TimestampEnd, MaintenanceScheduled_duration={}, MaintenanceScheduled_reason={:?}, MaintenanceScheduled_timestamp=TimestampStart{}TimestampEnd",
+                    start, duration, reason, timestamp
+                )
+            }
         }
     }
 }
 
+impl Event {
+    /// Appends the compact replay encoding: a kind byte (0 `Funded`,
+    /// 1 `Disputed`, 2 `Concluded`, 3 `DisputeReminder`, 4 `Registered`,
+    /// 5 `Withdrawn`, 6 `DustSwept`, 7 `MaintenanceScheduled`), followed by
+    /// the fields in declaration order. Integers are LE, amounts
+    /// length-prefixed LE bytes, accounts compressed SEC1 keys and registered
+    /// states their `State::signing_bytes` followed by the timeout. Lists and
+    /// UTF-8 strings are prefixed by their length as u32, and parameters are
+    /// encoded as their nonce, participants, challenge duration and expiry,
+    /// the latter as a presence byte followed by the timestamp if present.
+    pub fn encode(&self, out: &mut Vec<u8>) {
+        match self {
+            Event::Funded {
+                who,
+                total,
+                timestamp,
+            } => {
+                out.push(0);
+                out.extend_from_slice(who.0.to_encoded_point(true).as_bytes());
+                encode_amount(total, out);
+                out.extend_from_slice(&timestamp.to_le_bytes());
+            }
+            Event::Disputed { state, timestamp } | Event::Concluded { state, timestamp } => {
+                out.push(if matches!(self, Event::Disputed { .. }) {
+                    1
+                } else {
+                    2
+                });
+                encode_registered(state, out);
+                out.extend_from_slice(&timestamp.to_le_bytes());
+            }
+            Event::DisputeReminder {
+                state,
+                elapsed_percent,
+                timestamp,
+            } => {
+                out.push(3);
+                encode_registered(state, out);
+                out.extend_from_slice(&elapsed_percent.to_le_bytes());
+                out.extend_from_slice(&timestamp.to_le_bytes());
+            }
+            Event::Registered {
+                params,
+                allocation,
+                timestamp,
+            } => {
+                out.push(4);
+                out.extend_from_slice(&params.nonce.0);
+                out.extend_from_slice(&(params.participants.len() as u32).to_le_bytes());
+                for p in &params.participants {
+                    out.extend_from_slice(p.0.to_encoded_point(true).as_bytes());
+                }
+                out.extend_from_slice(&params.challenge_duration.to_le_bytes());
+                match params.expiry {
+                    Some(expiry) => {
+                        out.push(1);
+                        out.extend_from_slice(&expiry.to_le_bytes());
+                    }
+                    None => out.push(0),
+                }
+                out.extend_from_slice(&(allocation.len() as u32).to_le_bytes());
+                for amount in allocation {
+                    encode_amount(amount, out);
+                }
+                out.extend_from_slice(&timestamp.to_le_bytes());
+            }
+            Event::Withdrawn {
+                who,
+                amount,
+                timestamp,
+            } => {
+                out.push(5);
+                out.extend_from_slice(who.0.to_encoded_point(true).as_bytes());
+                encode_amount(amount, out);
+                out.extend_from_slice(&timestamp.to_le_bytes());
+            }
+            Event::DustSwept {
+                who,
+                amount,
+                destination,
+                timestamp,
+            } => {
+                out.push(6);
+                out.extend_from_slice(who.0.to_encoded_point(true).as_bytes());
+                encode_amount(amount, out);
+                out.push(match destination {
+                    DustDestination::Treasury => 0,
+                    DustDestination::NextWithdrawal => 1,
+                });
+                out.extend_from_slice(&timestamp.to_le_bytes());
+            }
+            Event::MaintenanceScheduled {
+                start,
+                duration,
+                reason,
+                timestamp,
+            } => {
+                out.push(7);
+                out.extend_from_slice(&start.to_le_bytes());
+                out.extend_from_slice(&duration.to_le_bytes());
+                out.extend_from_slice(&(reason.len() as u32).to_le_bytes());
+                out.extend_from_slice(reason.as_bytes());
+                out.extend_from_slice(&timestamp.to_le_bytes());
+            }
+        }
+    }
+}
+
+fn encode_amount(amount: &Amount, out: &mut Vec<u8>) {
+    let bytes = amount.0.to_bytes_le();
+    out.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+    out.extend_from_slice(&bytes);
+}
+
+fn encode_registered(state: &RegisteredState, out: &mut Vec<u8>) {
+    let bytes = state.state.signing_bytes();
+    out.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+    out.extend_from_slice(&bytes);
+    out.extend_from_slice(&state.timeout.to_le_bytes());
+}
+
 #[async_trait]
 impl EventRegisterer for CanisterState {
     async fn register_event(&mut self, time: Timestamp, ch: ChannelId, e: Event) {
-        if ic_cdk::api::caller() != self.perun_canister {
+        if ic_cdk::api::msg_caller() != self.perun_canister {
             return;
         }
         self.imple.register_event(time, ch, e).await;
@@ -221,6 +559,66 @@ impl EventRegisterer for CanisterState {
 }
 
 impl LocalEventRegisterer {
+    /// Stores an event for a channel at the given time.
+    pub fn push(&mut self, time: Timestamp, ch: ChannelId, e: Event) {
+        let events = self.events.entry(ch.clone()).or_default();
+        let at_time = events.entry(time).or_default();
+        self.sequence
+            .insert(self.next_seq, (ch, time, at_time.len()));
+        at_time.push(e);
+        self.next_seq += 1;
+    }
+
+    /// The sequence number of the next event.
+    pub fn next_seq(&self) -> u64 {
+        self.next_seq
+    }
+
+    /// Encodes events from a sequence number on, up to `max_bytes` but at
+    /// least one.
+    pub fn replay(&self, from_seq: u64, max_bytes: u32) -> ReplayBatch {
+        let max_bytes = max_bytes.min(MAX_REPLAY_BYTES) as usize;
+        let mut data = vec![];
+        let mut first_seq = None;
+        let mut next_seq = from_seq.max(self.sequence.keys().next().copied().unwrap_or(0));
+        for (seq, (ch, time, i)) in self.sequence.range(from_seq..) {
+            let record = self.record(ch, *time, *i);
+            if first_seq.is_some() && data.len() + 4 + record.len() > max_bytes {
+                break;
+            }
+            data.extend_from_slice(&(record.len() as u32).to_le_bytes());
+            data.extend_from_slice(&record);
+            first_seq.get_or_insert(*seq);
+            next_seq = seq + 1;
+        }
+        ReplayBatch {
+            first_seq: first_seq.unwrap_or(next_seq),
+            next_seq,
+            data: ByteBuf::from(data),
+        }
+    }
+
+    /// An event encoded after its timestamp (u64 LE) and channel id.
+    fn record(&self, ch: &ChannelId, time: Timestamp, i: usize) -> Vec<u8> {
+        let mut record = time.to_le_bytes().to_vec();
+        record.extend_from_slice(&ch.0);
+        self.events[ch][&time][i].encode(&mut record);
+        record
+    }
+
+    /// Removes the events registered before `min_time` and returns them
+    /// encoded as by `record`, in sequence order.
+    pub fn take_before(&mut self, min_time: Timestamp) -> Vec<Vec<u8>> {
+        let records = self
+            .sequence
+            .values()
+            .filter(|(_, t, _)| *t < min_time)
+            .map(|(ch, t, i)| self.record(ch, *t, *i))
+            .collect();
+        self.gc(min_time);
+        records
+    }
+
     pub fn events_after(&self, ch: &ChannelId, time: Timestamp) -> Vec<Event> {
         self.events.get(ch).map_or(vec![], |events| {
             let mut ret = vec![];
@@ -231,6 +629,44 @@ impl LocalEventRegisterer {
         })
     }
 
+    /// Returns up to `limit` of a channel's events at or after the given
+    /// time, in registration order.
+    pub fn events_since(&self, ch: &ChannelId, time: Timestamp, limit: u32) -> Vec<RegEvent> {
+        self.events
+            .get(ch)
+            .into_iter()
+            .flat_map(|events| events.range(time..))
+            .flat_map(|(t, es)| {
+                es.iter().map(|e| RegEvent {
+                    chanid: ch.clone(),
+                    time: *t,
+                    event: e.clone(),
+                })
+            })
+            .take(limit as usize)
+            .collect()
+    }
+
+    /// Returns a page of a channel's events at or after the given time. The
+    /// cursor key is the event's timestamp and its index within that
+    /// timestamp.
+    pub fn events_page(
+        &self,
+        ch: &ChannelId,
+        time: Timestamp,
+        cursor: Option<Cursor>,
+        limit: u32,
+    ) -> crate::error::Result<Page<Event>> {
+        let entries = self.events.get(ch).into_iter().flat_map(|events| {
+            events.range(time..).flat_map(|(t, es)| {
+                es.iter()
+                    .enumerate()
+                    .map(move |(i, e)| ((*t, i as u64), e.clone()))
+            })
+        });
+        paginate(entries, cursor, limit)
+    }
+
     pub fn events_after_str(&self, ch: &ChannelId, time: Timestamp) -> String {
         self.events
             .get(ch)
@@ -249,20 +685,68 @@ impl LocalEventRegisterer {
         for (_, ch_events) in self.events.iter_mut() {
             ch_events.retain(|&t, _| t >= min_time);
         }
-        self.events.retain(|_, events| !events.is_empty())
+        self.events.retain(|_, events| !events.is_empty());
+        self.sequence.retain(|_, (_, t, _)| *t >= min_time);
     }
 
     pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl EventArchive {
+    /// Loads the archive kept in the given memories, or starts an empty one.
+    pub fn init(index: Memory, data: Memory) -> Self {
         Self {
-            events: Default::default(),
+            log: Log::init(index, data).expect("initializing event archive"),
+        }
+    }
+
+    pub fn append(&mut self, record: &Vec<u8>) {
+        self.log.append(record).expect("appending to event archive");
+    }
+
+    /// Frames archived events from a position on, up to `max_bytes` but at
+    /// least one.
+    pub fn replay(&self, from: u64, max_bytes: u32) -> ReplayBatch {
+        let max_bytes = max_bytes.min(MAX_REPLAY_BYTES) as usize;
+        let mut data = vec![];
+        let mut next = from;
+        while let Some(record) = self.log.get(next) {
+            if next > from && data.len() + 4 + record.len() > max_bytes {
+                break;
+            }
+            data.extend_from_slice(&(record.len() as u32).to_le_bytes());
+            data.extend_from_slice(&record);
+            next += 1;
+        }
+        ReplayBatch {
+            first_seq: from,
+            next_seq: next,
+            data: ByteBuf::from(data),
+        }
+    }
+}
+
+impl<Q: TXQuerier> crate::CanisterState<Q> {
+    /// Moves the events registered before the retention window into the
+    /// archive. Returns how many events were archived.
+    pub fn archive_events(&mut self, now: Timestamp) -> usize {
+        let Some(window) = self.event_retention else {
+            return 0;
+        };
+        let records = self.events.take_before(now.saturating_sub(window));
+        for record in &records {
+            self.event_archive.append(record);
         }
+        records.len()
     }
 }
 
 impl CanisterState {
     pub fn new(perun_canister: Principal) -> Self {
         Self {
-            perun_canister: perun_canister,
+            perun_canister,
             imple: LocalEventRegisterer::new(),
         }
     }
@@ -271,3 +755,143 @@ impl CanisterState {
         self.imple.events_after(ch, time)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+
+    fn funded(timestamp: Timestamp) -> Event {
+        Event::Funded {
+            who: account(1),
+            total: Amount::from(5u32),
+            timestamp,
+        }
+    }
+
+    #[test]
+    fn test_replay_in_bounded_batches() {
+        let mut log = LocalEventRegisterer::new();
+        for t in 0..5 {
+            log.push(t, ChannelId([t as u8 % 2; 32]), funded(t));
+        }
+        let mut record = vec![];
+        funded(0).encode(&mut record);
+        let frame = 4 + 8 + 32 + record.len();
+
+        let batch = log.replay(0, 2 * frame as u32 + 1);
+        assert_eq!((batch.first_seq, batch.next_seq), (0, 2));
+        assert_eq!(batch.data.len(), 2 * frame);
+        let batch = log.replay(4, 1);
+        assert_eq!((batch.first_seq, batch.next_seq), (4, 5));
+        assert!(log.replay(5, 1000).data.is_empty());
+
+        log.gc(3);
+        let batch = log.replay(0, 1000);
+        assert_eq!((batch.first_seq, batch.next_seq), (3, 5));
+        // The first record's timestamp follows its length.
+        assert_eq!(batch.data[4..12], 3u64.to_le_bytes());
+    }
+
+    #[test]
+    fn test_channel_progress_is_recorded() {
+        let mut s = new_state();
+        let params = Params {
+            nonce: Nonce([4; 32]),
+            participants: vec![account(1), account(2)],
+            challenge_duration: 10,
+            expiry: None,
+        };
+        let id = params.id();
+        let allocation = vec![Amount::from(5u32), Amount::from(5u32)];
+        let intent = FundingIntent {
+            params: params.clone(),
+            allocation: allocation.clone(),
+            callback: None,
+        };
+        s.register_funding_intent(intent, 1).unwrap();
+        for p in 1..=2 {
+            let funding = Funding::new(id.clone(), account(p));
+            s.credit_deposit(funding, Amount::from(5u32), 2);
+        }
+        let state = |version, finalized| RegisteredState {
+            state: State {
+                channel: id.clone(),
+                version,
+                allocation: allocation.clone(),
+                finalized,
+                assets: vec![],
+            },
+            timeout: 20,
+        };
+        s.register_channel(&params, state(1, false), 3).unwrap();
+        s.register_channel(&params, state(2, true), 4).unwrap();
+        let req = WithdrawalReq {
+            channel: id.clone(),
+            participant: account(1),
+            amount: Amount::from(5u32),
+            receiver: Principal::anonymous(),
+            nonce: 0,
+            expiry: None,
+            asset: None,
+        };
+        s.record_withdrawal(&req, 5);
+
+        let events = s.events.events_since(&id, 0, 10);
+        assert_eq!(events.len(), 6);
+        assert!(matches!(
+            events[0].event,
+            Event::Registered { timestamp: 1, .. }
+        ));
+        assert!(matches!(
+            events[2].event,
+            Event::Funded { timestamp: 2, .. }
+        ));
+        assert!(matches!(
+            events[3].event,
+            Event::Disputed { timestamp: 3, .. }
+        ));
+        assert!(matches!(
+            events[4].event,
+            Event::Concluded { timestamp: 4, .. }
+        ));
+        assert!(matches!(
+            events[5].event,
+            Event::Withdrawn { timestamp: 5, .. }
+        ));
+
+        let since = s.events.events_since(&id, 3, 2);
+        assert_eq!(since.len(), 2);
+        assert_eq!(since[0].time, 3);
+        assert!(s.events.events_since(&id, 6, 10).is_empty());
+    }
+
+    #[test]
+    fn test_old_events_are_archived() {
+        let mut s = new_state();
+        for t in 0..4 {
+            s.events.push(t, ChannelId([1; 32]), funded(t));
+        }
+        assert_eq!(s.archive_events(10), 0);
+
+        s.event_retention = Some(8);
+        assert_eq!(s.archive_events(10), 2);
+        assert_eq!(s.events.events_since(&ChannelId([1; 32]), 0, 10).len(), 2);
+        assert_eq!(s.archive_events(10), 0);
+
+        let mut record = vec![];
+        funded(0).encode(&mut record);
+        let frame = 4 + 8 + 32 + record.len();
+        let batch = s.event_archive.replay(0, 1000);
+        assert_eq!((batch.first_seq, batch.next_seq), (0, 2));
+        assert_eq!(batch.data.len(), 2 * frame);
+        assert_eq!(batch.data[frame + 4..frame + 12], 1u64.to_le_bytes());
+
+        // Archived events keep their positions as more are archived.
+        assert_eq!(s.archive_events(20), 2);
+        let batch = s.event_archive.replay(2, 1);
+        assert_eq!((batch.first_seq, batch.next_seq), (2, 3));
+        assert_eq!(batch.data[4..12], 2u64.to_le_bytes());
+        assert!(s.event_archive.replay(4, 1000).data.is_empty());
+    }
+}
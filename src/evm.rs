@@ -0,0 +1,193 @@
+//  Copyright 2025 PolyCrypt GmbH
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+
+//! Attestations of registered outcomes for channels that are also anchored
+//! on an EVM Perun adjudicator. The statement is ABI-encoded as
+//!
+//! ```solidity
+//! abi.encode(
+//!     bytes32 domain,      // keccak256("ckLightning EVM attestation")
+//!     bytes32 canister,    // keccak256 of the attesting canister's principal
+//!     bytes32 channelId,
+//!     uint64 version,
+//!     uint256[] balances,  // in the order of the channel's participants
+//!     bool isFinal,
+//!     uint64 timeout,      // nanoseconds since the epoch
+//!     uint64 registeredAt  // nanoseconds since the epoch
+//! )
+//! ```
+//!
+//! and signed over its keccak256 digest, so that a Solidity verifier can check
+//! it with `ecrecover` against the canister's Ethereum address.
+
+use crate::attestation::Signer;
+use crate::error::*;
+use crate::types::*;
+use crate::{read_state, require};
+use candid::{Principal, candid_method};
+use ic_cdk::update;
+use k256::ecdsa::{RecoveryId, Signature, VerifyingKey};
+use tiny_keccak::{Hasher, Keccak};
+
+/// The statement's domain separator, hashed into its first word.
+pub const EVM_ATTESTATION_DOMAIN: &[u8] = b"ckLightning EVM attestation";
+
+#[derive(Clone, Deserialize, CandidType)]
+/// A registered outcome, signed for verification on an EVM chain.
+pub struct EvmAttestation {
+    /// The ABI-encoded statement.
+    pub statement: Vec<u8>,
+    /// The keccak256 digest of the statement, which was signed.
+    pub digest: Vec<u8>,
+    /// The 65-byte signature `r || s || v` with `v` in {27, 28}.
+    pub signature: Vec<u8>,
+    /// The Ethereum address of the canister's key.
+    pub signer: Vec<u8>,
+}
+
+#[update]
+#[candid_method(update)]
+/// Returns the latest registered state of a channel as a statement that a
+/// Solidity verifier can check, signed by the canister's threshold ECDSA key.
+async fn export_evm_attestation(channel_id: ChannelId) -> Result<EvmAttestation> {
+    let (record, signer) = read_state(|state| {
+        let record = state
+            .state_history(&channel_id)
+            .pop()
+            .ok_or(ErrorCode::NotFound)?;
+        Ok::<_, Error>((record, state.signer.clone()))
+    })?;
+    attest(&record, &ic_cdk::api::canister_self(), signer.as_ref()).await
+}
+
+/// Encodes and signs the statement for a registered state.
+pub async fn attest(
+    record: &StateRecord,
+    canister: &Principal,
+    signer: &dyn Signer,
+) -> Result<EvmAttestation> {
+    let statement = statement(record, canister)?;
+    let digest = keccak256(&statement);
+    let signature = signer.sign(digest).await?;
+    let public_key = signer.public_key().await?;
+    let key = VerifyingKey::from_sec1_bytes(&public_key).map_err(|_| ErrorCode::SigningError)?;
+    Ok(EvmAttestation {
+        signature: recoverable(&key, &digest, &signature)?,
+        signer: address(&key).to_vec(),
+        statement,
+        digest: digest.to_vec(),
+    })
+}
+
+/// ABI-encodes a registered state as described in the module documentation.
+pub fn statement(record: &StateRecord, canister: &Principal) -> Result<Vec<u8>> {
+    let state = &record.state.state;
+    let mut head = vec![
+        keccak256(EVM_ATTESTATION_DOMAIN),
+        keccak256(canister.as_slice()),
+        state.channel.0,
+        uint(state.version),
+        uint(8 * 32), // Offset of the balances, right after the head.
+        uint(state.finalized as u64),
+        uint(record.state.timeout),
+        uint(record.registered_at),
+    ];
+    head.push(uint(state.allocation.len() as u64));
+    for amount in &state.allocation {
+        head.push(uint256(amount)?);
+    }
+    Ok(head.concat())
+}
+
+pub fn keccak256(data: &[u8]) -> [u8; 32] {
+    let mut out = [0; 32];
+    let mut k = Keccak::v256();
+    k.update(data);
+    k.finalize(&mut out);
+    out
+}
+
+/// The Ethereum address of a public key.
+pub fn address(key: &VerifyingKey) -> [u8; 20] {
+    let point = key.to_encoded_point(false);
+    let hash = keccak256(&point.as_bytes()[1..]);
+    let mut address = [0; 20];
+    address.copy_from_slice(&hash[12..]);
+    address
+}
+
+/// Turns a 64-byte `r || s` signature into the low-s `r || s || v` form
+/// expected by `ecrecover`.
+fn recoverable(key: &VerifyingKey, digest: &[u8; 32], sig: &[u8]) -> Result<Vec<u8>> {
+    let sig = Signature::from_slice(sig).map_err(|_| ErrorCode::SigningError)?;
+    let sig = sig.normalize_s().unwrap_or(sig);
+    let id = (0..2)
+        .filter_map(RecoveryId::from_byte)
+        .find(|id| VerifyingKey::recover_from_prehash(digest, &sig, *id).is_ok_and(|k| k == *key))
+        .ok_or(ErrorCode::SigningError)?;
+    let mut out = sig.to_bytes().to_vec();
+    out.push(27 + id.to_byte());
+    Ok(out)
+}
+
+fn uint(x: u64) -> [u8; 32] {
+    let mut word = [0; 32];
+    word[24..].copy_from_slice(&x.to_be_bytes());
+    word
+}
+
+fn uint256(x: &Amount) -> Result<[u8; 32]> {
+    let bytes = x.0.to_bytes_be();
+    require!(bytes.len() <= 32, InvalidInput);
+    let mut word = [0; 32];
+    word[32 - bytes.len()..].copy_from_slice(&bytes);
+    Ok(word)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::attestation::SoftwareSigner;
+    use crate::testing::*;
+
+    #[test]
+    fn test_attestation_recovers_to_signer() {
+        let record = StateRecord {
+            state: RegisteredState {
+                state: State {
+                    channel: ChannelId([1; 32]),
+                    version: 4,
+                    allocation: vec![Amount::from(10u32), Amount::from(300u32)],
+                    finalized: true,
+                    assets: vec![],
+                },
+                timeout: 7,
+            },
+            registered_at: 5,
+        };
+        let canister = Principal::anonymous();
+        let att = block_on(attest(&record, &canister, &SoftwareSigner::new(key(3)))).unwrap();
+
+        assert_eq!(att.statement.len(), 11 * 32);
+        assert_eq!(att.statement[3 * 32 + 31], 4);
+        assert_eq!(att.statement[8 * 32 + 31], 2);
+        assert_eq!(&att.statement[10 * 32 + 30..], &[1, 44]);
+
+        let sig = Signature::from_slice(&att.signature[..64]).unwrap();
+        let id = RecoveryId::from_byte(att.signature[64] - 27).unwrap();
+        let recovered = VerifyingKey::recover_from_prehash(&att.digest, &sig, id).unwrap();
+        assert_eq!(address(&recovered).to_vec(), att.signer);
+        assert_eq!(att.signer, address(&VerifyingKey::from(&key(3))).to_vec());
+    }
+}
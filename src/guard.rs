@@ -0,0 +1,128 @@
+//  Copyright 2025 PolyCrypt GmbH
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+
+//! Reentrancy guards for settlement calls. Withdrawals deduct funds, await a
+//! ledger transfer and restore the funds if it fails. While one such call
+//! awaits its transfer, another call for the same channel or funding is
+//! rejected, so that no two calls act on the same funds at once.
+
+use crate::error::*;
+use crate::mutate_state;
+use crate::require;
+use crate::types::*;
+
+#[derive(Clone, PartialEq, Eq)]
+/// What a settlement call claims.
+pub enum GuardKey {
+    /// All funds of a channel.
+    Channel(ChannelId),
+    /// The funds of one participant of a channel.
+    Funding(Funding),
+}
+
+#[derive(Default)]
+/// The keys claimed by settlement calls in flight.
+pub struct Guards {
+    claimed: Vec<GuardKey>,
+}
+
+/// Holds claimed keys for the duration of a settlement call. Dropping it
+/// releases the keys, also when the call fails or traps after awaiting.
+/// It must not be dropped inside `mutate_state` or `read_state`.
+pub struct SettlementGuard {
+    keys: Vec<GuardKey>,
+}
+
+impl GuardKey {
+    fn channel(&self) -> &ChannelId {
+        match self {
+            Self::Channel(id) => id,
+            Self::Funding(f) => &f.channel,
+        }
+    }
+
+    /// Whether both keys claim some of the same funds.
+    fn overlaps(&self, other: &GuardKey) -> bool {
+        match (self, other) {
+            (Self::Funding(a), Self::Funding(b)) => a == b,
+            _ => self.channel() == other.channel(),
+        }
+    }
+}
+
+impl Guards {
+    pub fn is_claimed(&self, key: &GuardKey) -> bool {
+        self.claimed.iter().any(|c| c.overlaps(key))
+    }
+
+    /// Fails if any of the keys overlaps a claimed one.
+    pub fn require_free(&self, keys: &[GuardKey]) -> Result<()> {
+        require!(!keys.iter().any(|k| self.is_claimed(k)), Busy);
+        Ok(())
+    }
+
+    /// Claims keys checked with `require_free`. They are released by the
+    /// `SettlementGuard` created for them.
+    pub fn claim(&mut self, keys: &[GuardKey]) {
+        self.claimed.extend_from_slice(keys);
+    }
+
+    /// Releases claimed keys, for claims that outlive the settlement call.
+    pub fn release(&mut self, keys: &[GuardKey]) {
+        for key in keys {
+            if let Some(i) = self.claimed.iter().position(|c| c == key) {
+                self.claimed.swap_remove(i);
+            }
+        }
+    }
+}
+
+impl SettlementGuard {
+    /// Takes over keys claimed in the canister state.
+    pub fn new(keys: Vec<GuardKey>) -> Self {
+        Self { keys }
+    }
+}
+
+impl Drop for SettlementGuard {
+    fn drop(&mut self) {
+        mutate_state(|s| s.guards.release(&self.keys));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+
+    #[test]
+    fn test_overlapping_claims_are_rejected() {
+        let (a, b) = (ChannelId([1; 32]), ChannelId([2; 32]));
+        let alice = [GuardKey::Funding(Funding::new(a.clone(), account(1)))];
+        let bob = GuardKey::Funding(Funding::new(a.clone(), account(2)));
+        let mut guards = Guards::default();
+        guards.require_free(&alice).unwrap();
+        guards.claim(&alice);
+
+        assert_eq!(guards.require_free(&alice), Err(ErrorCode::Busy.into()));
+        assert_eq!(
+            guards.require_free(&[GuardKey::Channel(a)]),
+            Err(ErrorCode::Busy.into())
+        );
+        guards.require_free(&[bob, GuardKey::Channel(b)]).unwrap();
+
+        guards.release(&alice);
+        guards.require_free(&alice).unwrap();
+    }
+}
@@ -0,0 +1,224 @@
+//  Copyright 2025 PolyCrypt GmbH
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+
+//! The handshake of the bridge with a connecting LNP node daemon. The node
+//! announces its protocol versions and capabilities with `Hello`, and the
+//! canister answers with the negotiated version and everything the node needs
+//! to configure its integration: the supported assets, the fee schedule and
+//! what it takes to serve as a bridge operator. `GetInfo` returns the same
+//! information without negotiating.
+
+use crate::asset::{AssetId, AssetInfo};
+use crate::bridge::{COMMAND_ACK_WINDOW, MAX_COMMAND_ATTEMPTS};
+use crate::dust::DustPolicy;
+use crate::error::*;
+use crate::operator::OperatorInfo;
+use crate::permission::Scope;
+use crate::receiver::TXQuerier;
+use crate::types::*;
+use crate::{CanisterState, read_state};
+use candid::{Principal, candid_method};
+use ic_cdk::query;
+
+/// The bridge protocol versions the canister speaks, oldest first.
+pub const PROTOCOL_VERSIONS: [u32; 1] = [1];
+
+#[derive(Clone, Copy, Deserialize, CandidType, PartialEq, Eq, PartialOrd, Ord, Debug)]
+/// A part of the bridge protocol a node or the canister supports.
+pub enum Capability {
+    /// Paying Lightning invoices for canister funds.
+    Swaps,
+    /// Issuing Lightning invoices whose payment credits canister funds.
+    Invoices,
+    /// Forwards through canister channels locked under a payment hash.
+    Forwards,
+}
+
+/// The capabilities the canister supports.
+pub const CAPABILITIES: [Capability; 3] = [
+    Capability::Swaps,
+    Capability::Invoices,
+    Capability::Forwards,
+];
+
+#[derive(Clone, Deserialize, CandidType, PartialEq, Eq, Debug)]
+/// The opening message of a connecting node.
+pub struct Hello {
+    /// The protocol versions the node speaks.
+    pub protocol_versions: Vec<u32>,
+    pub capabilities: Vec<Capability>,
+}
+
+#[derive(Clone, Deserialize, CandidType)]
+/// An asset the canister holds channels in.
+pub struct SupportedAsset {
+    pub id: AssetId,
+    pub info: AssetInfo,
+    /// The fee of a ledger transfer, in base units.
+    pub transfer_fee: Amount,
+    /// The fee charged on withdrawals from the asset's pool, in basis
+    /// points.
+    pub pool_withdrawal_fee_bps: u32,
+}
+
+#[derive(Clone, Deserialize, CandidType)]
+/// The fees charged by the canister itself. Operators advertise their own
+/// fees with their liquidity.
+pub struct FeeSchedule {
+    /// The ledger fee deducted from withdrawals, in base units.
+    pub withdrawal_fee: Amount,
+    pub dust_policy: Option<DustPolicy>,
+}
+
+#[derive(Clone, Deserialize, CandidType)]
+/// What the canister expects of a bridge operator.
+pub struct OperatorRequirements {
+    /// The scopes an operator must be granted.
+    pub scopes: Vec<Scope>,
+    /// How long an operator has to acknowledge a command.
+    pub ack_window: Duration,
+    /// How many operators a command is sent to before it fails.
+    pub max_command_attempts: u32,
+    /// The caller's registration as an operator, if any.
+    pub registration: Option<OperatorInfo>,
+    /// The required scopes the caller has not been granted yet.
+    pub missing_scopes: Vec<Scope>,
+}
+
+#[derive(Clone, Deserialize, CandidType)]
+pub struct NodeInfo {
+    /// The version spoken from now on: the newest both sides support.
+    pub protocol_version: u32,
+    /// The capabilities both sides support.
+    pub capabilities: Vec<Capability>,
+    pub assets: Vec<SupportedAsset>,
+    pub fees: FeeSchedule,
+    pub operator: OperatorRequirements,
+}
+
+#[query]
+#[candid_method(query)]
+/// Answers a connecting node's `Hello` with the negotiated protocol version
+/// and the information to configure its integration.
+fn hello(hello: Hello) -> Result<NodeInfo> {
+    let caller = ic_cdk::api::msg_caller();
+    read_state(|s| s.hello(&caller, &hello))
+}
+
+#[query]
+#[candid_method(query)]
+/// The information to configure a node's integration, for the newest
+/// protocol version and all capabilities of the canister.
+fn get_info() -> NodeInfo {
+    let caller = ic_cdk::api::msg_caller();
+    read_state(|s| s.node_info(&caller))
+}
+
+impl<Q: TXQuerier> CanisterState<Q> {
+    /// Negotiates the newest common protocol version and the common
+    /// capabilities with a node.
+    pub fn hello(&self, caller: &Principal, hello: &Hello) -> Result<NodeInfo> {
+        let version = PROTOCOL_VERSIONS
+            .iter()
+            .rev()
+            .find(|v| hello.protocol_versions.contains(v))
+            .ok_or_else(|| {
+                Error::from(ErrorCode::InvalidInput)
+                    .with("supported", format!("{:?}", PROTOCOL_VERSIONS))
+            })?;
+        let mut info = self.node_info(caller);
+        info.protocol_version = *version;
+        info.capabilities.retain(|c| hello.capabilities.contains(c));
+        Ok(info)
+    }
+
+    pub fn node_info(&self, caller: &Principal) -> NodeInfo {
+        let assets = self
+            .assets
+            .iter()
+            .map(|(id, info)| SupportedAsset {
+                id: *id,
+                info: info.clone(),
+                transfer_fee: self
+                    .ledger_metadata
+                    .get(id)
+                    .map_or(self.config.fee.clone(), |m| m.fee.clone()),
+                pool_withdrawal_fee_bps: self.pool(id).map_or(0, |p| p.fee_bps()),
+            })
+            .collect();
+        let scopes = vec![
+            Scope::ConsumeQueue,
+            Scope::SubmitProofs,
+            Scope::ManageLiquidity,
+        ];
+        let missing_scopes = scopes
+            .iter()
+            .filter(|s| self.require_scope(caller, **s).is_err())
+            .copied()
+            .collect();
+        NodeInfo {
+            protocol_version: PROTOCOL_VERSIONS[PROTOCOL_VERSIONS.len() - 1],
+            capabilities: CAPABILITIES.to_vec(),
+            assets,
+            fees: FeeSchedule {
+                withdrawal_fee: self.config.fee.clone(),
+                dust_policy: self.dust.policy().cloned(),
+            },
+            operator: OperatorRequirements {
+                scopes,
+                ack_window: COMMAND_ACK_WINDOW,
+                max_command_attempts: MAX_COMMAND_ATTEMPTS as u32,
+                registration: self.operators.get(caller).cloned(),
+                missing_scopes,
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+
+    #[test]
+    fn test_hello_negotiates_version_and_capabilities() {
+        let mut s = new_state();
+        let op = operator(&mut s, 10, 10);
+        let hello = Hello {
+            protocol_versions: vec![0, 1, 2],
+            capabilities: vec![Capability::Invoices, Capability::Swaps],
+        };
+        let info = s.hello(&op, &hello).unwrap();
+        assert_eq!(info.protocol_version, 1);
+        assert_eq!(
+            info.capabilities,
+            vec![Capability::Swaps, Capability::Invoices]
+        );
+        assert!(info.operator.registration.is_some());
+        assert_eq!(info.assets.len(), 1);
+
+        let stranger = s.node_info(&Principal::anonymous());
+        assert!(stranger.operator.registration.is_none());
+        assert_eq!(stranger.operator.missing_scopes.len(), 3);
+
+        let old = Hello {
+            protocol_versions: vec![0],
+            capabilities: vec![],
+        };
+        assert_eq!(
+            s.hello(&op, &old).map(|_| ()),
+            Err(ErrorCode::InvalidInput.into())
+        );
+    }
+}
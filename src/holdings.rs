@@ -0,0 +1,184 @@
+//  Copyright 2025 PolyCrypt GmbH
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+
+//! Stream of changes to the channel holdings. Every change is recorded with
+//! a sequence number that increases by one per change, so that indexers can
+//! mirror the holdings exactly by applying the changes in order.
+
+use crate::error::*;
+use crate::receiver::TXQuerier;
+use crate::types::*;
+use crate::{CanisterState, read_state, require};
+use candid::candid_method;
+use ic_cdk::query;
+use std::collections::VecDeque;
+
+/// How many changes are retained.
+pub const HOLDINGS_LOG_LEN: usize = 50_000;
+
+/// Most changes returned per query.
+pub const MAX_CHANGES_PER_QUERY: u32 = 1_000;
+
+#[derive(Clone, Copy, Deserialize, CandidType, PartialEq, Eq, Debug)]
+pub enum ChangeCause {
+    /// A ledger deposit was credited.
+    Deposit,
+    /// A settled outcome of a remote channel was credited.
+    RemoteFunding,
+    /// A registered state reallocated the channel's funds.
+    StateRegistered,
+    /// Funds were withdrawn to the ledger.
+    Withdrawal,
+    /// Funds were locked in or returned from a swap.
+    Swap,
+    /// Funds were locked in, paid out of or refunded from a forward.
+    Forward,
+    /// Funds were moved into quarantine.
+    Quarantined,
+    /// Quarantined funds were released or reassigned.
+    QuarantineResolved,
+    /// Dust was swept out of the holdings.
+    DustSwept,
+}
+
+#[derive(Clone, Deserialize, CandidType)]
+pub struct HoldingsChange {
+    pub seq: u64,
+    pub funding: Funding,
+    /// The signed change of the funding's holdings.
+    pub delta: Int,
+    pub cause: ChangeCause,
+}
+
+#[derive(Default)]
+pub struct HoldingsLog {
+    changes: VecDeque<HoldingsChange>,
+    next_seq: u64,
+}
+
+#[query]
+#[candid_method(query)]
+/// Returns up to `limit` holdings changes starting at sequence number
+/// `since_seq`, in order. Fails with `Expired` if changes from `since_seq` on
+/// are no longer retained, in which case the mirror must be rebuilt.
+fn holdings_changes(since_seq: u64, limit: u32) -> Result<Vec<HoldingsChange>> {
+    read_state(|s| s.holdings_log.since(since_seq, limit))
+}
+
+impl HoldingsLog {
+    fn push(&mut self, funding: Funding, delta: Int, cause: ChangeCause) {
+        if self.changes.len() == HOLDINGS_LOG_LEN {
+            self.changes.pop_front();
+        }
+        self.changes.push_back(HoldingsChange {
+            seq: self.next_seq,
+            funding,
+            delta,
+            cause,
+        });
+        self.next_seq += 1;
+    }
+
+    /// The sequence number of the latest change.
+    pub fn last_seq(&self) -> u64 {
+        self.next_seq.saturating_sub(1)
+    }
+
+    pub fn since(&self, seq: u64, limit: u32) -> Result<Vec<HoldingsChange>> {
+        let first = self.changes.front().map_or(self.next_seq, |c| c.seq);
+        require!(seq >= first, Expired);
+        let limit = limit.min(MAX_CHANGES_PER_QUERY) as usize;
+        Ok(self
+            .changes
+            .iter()
+            .skip((seq - first) as usize)
+            .take(limit)
+            .cloned()
+            .collect())
+    }
+}
+
+impl<Q: TXQuerier> CanisterState<Q> {
+    /// Adds funds to a funding's holdings.
+    pub(crate) fn credit(&mut self, funding: Funding, amount: Amount, cause: ChangeCause) {
+        if amount == Amount::default() {
+            return;
+        }
+        let held = self.user_holdings.get(&funding).unwrap_or_default();
+        self.user_holdings
+            .insert(funding.clone(), held + amount.clone());
+        self.touch_holdings(&funding);
+        let delta = Int::from(amount);
+        self.holdings_log
+            .push(funding.clone(), delta.clone(), cause);
+        self.shadow_change(&funding, &delta, cause);
+    }
+
+    /// Deducts funds from a funding's holdings, removing emptied holdings.
+    /// The caller must ensure that the holdings suffice.
+    pub(crate) fn debit(&mut self, funding: &Funding, amount: &Amount, cause: ChangeCause) {
+        if *amount == Amount::default() {
+            return;
+        }
+        if let Some(held) = self.user_holdings.get(funding) {
+            let held = held - amount.clone();
+            if held == Amount::default() {
+                self.user_holdings.remove(funding);
+            } else {
+                self.user_holdings.insert(funding.clone(), held);
+            }
+            self.touch_holdings(funding);
+            let delta = Int::default() - Int::from(amount.clone());
+            self.holdings_log
+                .push(funding.clone(), delta.clone(), cause);
+            self.shadow_change(funding, &delta, cause);
+        }
+    }
+
+    /// Sets a funding's holdings to the given amount.
+    pub(crate) fn set_holdings(&mut self, funding: Funding, amount: Amount, cause: ChangeCause) {
+        let held = self.user_holdings.get(&funding).unwrap_or_default();
+        if amount > held {
+            self.credit(funding, amount - held, cause);
+        } else {
+            self.debit(&funding, &(held - amount), cause);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+
+    #[test]
+    fn test_changes_mirror_holdings() {
+        let mut s = new_state();
+        let ch = concluded(&mut s, 1, 1, 2);
+        let f = Funding::new(ch.clone(), account(1));
+        s.debit(&f, &Amount::from(30u32), ChangeCause::Withdrawal);
+
+        let changes = s.holdings_log.since(0, 100).unwrap();
+        let seqs: Vec<u64> = changes.iter().map(|c| c.seq).collect();
+        assert_eq!(seqs, vec![0, 1, 2]);
+        let mirror = changes
+            .iter()
+            .filter(|c| c.funding == f)
+            .fold(Int::default(), |acc, c| acc + c.delta.clone());
+        assert_eq!(mirror, Int::from(holdings(&s, &ch, 1)));
+        assert_eq!(changes[2].cause, ChangeCause::Withdrawal);
+        assert_eq!(s.holdings_log.since(2, 100).unwrap().len(), 1);
+        assert!(s.holdings_log.since(3, 100).unwrap().is_empty());
+    }
+}
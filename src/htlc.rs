@@ -0,0 +1,542 @@
+//  Copyright 2025 PolyCrypt GmbH
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+
+//! Hash-locked forwarding of funds between two concluded canister channels.
+//! A forward locks the payer's funds in the incoming channel and the hub's
+//! funds in the outgoing channel under the same payment hash. Revealing the
+//! preimage before the expiry settles both legs at once, otherwise both legs
+//! are refunded after the expiry.
+//!
+//! A hub charges for forwarding according to the fee policy it set on the
+//! outgoing channel: the incoming leg carries the forwarded amount plus the
+//! fee, so that settling the forward leaves the fee in the hub's holdings.
+
+use crate::error::*;
+use crate::holdings::ChangeCause;
+use crate::pause::Flow;
+use crate::receiver::TXQuerier;
+use crate::types::*;
+use crate::{CanisterState, mutate_state, read_state, require};
+use candid::candid_method;
+use ic_cdk::api::time as blocktime;
+use ic_cdk::{query, update};
+use k256::elliptic_curve::sec1::ToEncodedPoint;
+
+#[derive(Clone, Deserialize, CandidType)]
+/// One hop of a forward: ckBTC moves from `from` to `to` within `channel`.
+pub struct Leg {
+    pub channel: ChannelId,
+    pub from: L2Account,
+    pub to: L2Account,
+}
+
+#[derive(Clone, Deserialize, CandidType)]
+/// The terms of a forward, signed by the paying party of each leg.
+pub struct ForwardTerms {
+    /// The payment hash both legs are locked under.
+    pub hash: PaymentHash,
+    /// The amount delivered to the payee.
+    pub amount: Amount,
+    /// The highest forwarding fee the payer agrees to pay to the hub.
+    pub max_fee: Amount,
+    /// After this time, the forward can no longer be settled but refunded.
+    pub expiry: Timestamp,
+    /// Payer to hub.
+    pub incoming: Leg,
+    /// Hub to payee.
+    pub outgoing: Leg,
+}
+
+#[derive(Clone, Deserialize, CandidType, PartialEq, Eq, Debug)]
+pub enum ForwardStatus {
+    /// Both legs' funds are locked.
+    Locked,
+    /// The preimage was revealed and both legs paid out.
+    Settled { preimage: Vec<u8> },
+    /// The forward expired and both legs were refunded.
+    Refunded,
+}
+
+#[derive(Clone, Deserialize, CandidType)]
+pub struct Forward {
+    pub terms: ForwardTerms,
+    /// The fee charged by the hub, locked on top of the incoming leg.
+    pub fee: Amount,
+    pub status: ForwardStatus,
+}
+
+#[derive(Clone, Deserialize, CandidType, Default, PartialEq, Eq, Debug)]
+/// A hub's fee for forwarding payments out of one of its channels, computed
+/// like in Lightning as `base_fee + amount * fee_ppm / 1_000_000`.
+pub struct FeePolicy {
+    pub base_fee: Amount,
+    /// Proportional fee, in millionths of the forwarded amount.
+    pub fee_ppm: u32,
+    /// Increases with each update so that old policies cannot be replayed.
+    pub seq: u64,
+}
+
+#[update]
+#[candid_method(update)]
+/// Sets the hub's fee policy for forwards out of `channel`. The signature is
+/// by `hub`, which must be a participant of the channel.
+fn set_fee_policy(
+    channel: ChannelId,
+    hub: L2Account,
+    policy: FeePolicy,
+    sig: Vec<u8>,
+) -> Result<()> {
+    mutate_state(|s| s.set_fee_policy(Funding::new(channel, hub), policy, &sig))
+}
+
+#[query]
+#[candid_method(query)]
+/// The hub's fee policy for forwards out of `channel`, if it set one.
+fn query_fee_policy(channel: ChannelId, hub: L2Account) -> Option<FeePolicy> {
+    read_state(|s| s.fee_policies.get(&Funding::new(channel, hub)).cloned())
+}
+
+#[update]
+#[candid_method(update)]
+/// Locks a forward. `payer_sig` and `hub_sig` are the signatures of the
+/// incoming and outgoing leg's paying participant over the terms.
+fn lock_forward(terms: ForwardTerms, payer_sig: Vec<u8>, hub_sig: Vec<u8>) -> Result<()> {
+    crate::unpaused!();
+    mutate_state(|s| s.lock_forward(terms, &payer_sig, &hub_sig, blocktime()))
+}
+
+#[update]
+#[candid_method(update)]
+/// Settles the forward locked under the preimage's hash.
+fn settle_forward(preimage: Vec<u8>) -> Result<()> {
+    crate::unpaused!();
+    mutate_state(|s| s.settle_forward(preimage, blocktime()))
+}
+
+#[update]
+#[candid_method(update)]
+/// Refunds an expired forward.
+fn refund_forward(hash: PaymentHash) -> Result<()> {
+    crate::unpaused!();
+    mutate_state(|s| s.refund_forward(&hash, blocktime()))
+}
+
+#[query]
+#[candid_method(query)]
+fn query_forward(hash: PaymentHash) -> Option<Forward> {
+    read_state(|s| s.forwards.get(&hash).cloned())
+}
+
+impl ForwardTerms {
+    /// The bytes signed by both paying participants.
+    pub fn signing_bytes(&self) -> Vec<u8> {
+        let mut data = b"ckLightning forward".to_vec();
+        data.extend_from_slice(&self.hash);
+        let amount = self.amount.0.to_bytes_le();
+        data.extend_from_slice(&(amount.len() as u32).to_le_bytes());
+        data.extend_from_slice(&amount);
+        let max_fee = self.max_fee.0.to_bytes_le();
+        data.extend_from_slice(&(max_fee.len() as u32).to_le_bytes());
+        data.extend_from_slice(&max_fee);
+        data.extend_from_slice(&self.expiry.to_le_bytes());
+        for leg in [&self.incoming, &self.outgoing] {
+            data.extend_from_slice(&leg.channel.0);
+            data.extend_from_slice(leg.from.0.to_encoded_point(true).as_bytes());
+            data.extend_from_slice(leg.to.0.to_encoded_point(true).as_bytes());
+        }
+        data
+    }
+}
+
+impl FeePolicy {
+    /// The bytes the hub signs to set the policy for its funding.
+    pub fn signing_bytes(&self, funding: &Funding) -> Vec<u8> {
+        let mut data = b"ckLightning fee policy".to_vec();
+        data.extend_from_slice(&funding.channel.0);
+        data.extend_from_slice(funding.participant.0.to_encoded_point(true).as_bytes());
+        let base = self.base_fee.0.to_bytes_le();
+        data.extend_from_slice(&(base.len() as u32).to_le_bytes());
+        data.extend_from_slice(&base);
+        data.extend_from_slice(&self.fee_ppm.to_le_bytes());
+        data.extend_from_slice(&self.seq.to_le_bytes());
+        data
+    }
+
+    /// The fee for forwarding `amount`.
+    pub fn fee(&self, amount: &Amount) -> Amount {
+        let ppm = Amount::from(amount.0.clone() * self.fee_ppm / 1_000_000u32);
+        self.base_fee.clone() + ppm
+    }
+}
+
+impl Leg {
+    fn payer(&self) -> Funding {
+        Funding::new(self.channel.clone(), self.from.clone())
+    }
+
+    fn payee(&self) -> Funding {
+        Funding::new(self.channel.clone(), self.to.clone())
+    }
+}
+
+impl<Q: TXQuerier> CanisterState<Q> {
+    pub fn set_fee_policy(&mut self, hub: Funding, policy: FeePolicy, sig: &[u8]) -> Result<()> {
+        let params = self
+            .channel_params(&hub.channel)
+            .ok_or_else(|| Error::from(ErrorCode::NotFound).with("channel", &hub.channel))?;
+        require!(params.participants.contains(&hub.participant), InvalidInput);
+        let msg = policy.signing_bytes(&hub);
+        require!(
+            hub.participant.verify(&msg, sig),
+            Authentication {
+                participant_index: None
+            }
+        );
+        if let Some(old) = self.fee_policies.get(&hub) {
+            require!(
+                policy.seq > old.seq,
+                OutdatedState {
+                    registered_version: old.seq,
+                    submitted_version: policy.seq,
+                }
+            );
+        }
+        self.fee_policies.insert(hub, policy);
+        Ok(())
+    }
+
+    /// The fee the hub charges for forwarding `amount` out of the leg's
+    /// channel, zero if it has not set a policy.
+    pub fn forward_fee(&self, outgoing: &Leg, amount: &Amount) -> Amount {
+        self.fee_policies
+            .get(&outgoing.payer())
+            .map(|p| p.fee(amount))
+            .unwrap_or_default()
+    }
+
+    /// Checks the forward's signatures, that the same hub receives the
+    /// incoming and pays the outgoing leg, that each leg is between
+    /// participants of its channel and covered by the unreserved holdings of a
+    /// concluded channel, then locks both legs' funds.
+    pub fn lock_forward(
+        &mut self,
+        terms: ForwardTerms,
+        payer_sig: &[u8],
+        hub_sig: &[u8],
+        now: Timestamp,
+    ) -> Result<()> {
+        self.accepting()?;
+        self.require_unpaused(&self.ckbtc(), Flow::Withdrawal)?;
+        require!(!self.forwards.contains_key(&terms.hash), InvalidInput);
+        require!(terms.expiry > now, Expired);
+        require!(terms.amount > Amount::default(), InvalidInput);
+        require!(
+            terms.incoming.to == terms.outgoing.from,
+            Error::from(ErrorCode::InvalidInput).with("hub", &terms.outgoing.from)
+        );
+        for leg in [&terms.incoming, &terms.outgoing] {
+            let params = self
+                .channel_params(&leg.channel)
+                .ok_or_else(|| Error::from(ErrorCode::NotFound).with("channel", &leg.channel))?;
+            require!(
+                leg.from != leg.to
+                    && params.participants.contains(&leg.from)
+                    && params.participants.contains(&leg.to),
+                Error::from(ErrorCode::InvalidInput).with("channel", &leg.channel)
+            );
+        }
+        let msg = terms.signing_bytes();
+        require!(
+            terms.incoming.from.verify(&msg, payer_sig),
+            Authentication {
+                participant_index: None
+            }
+        );
+        require!(
+            terms.outgoing.from.verify(&msg, hub_sig),
+            Authentication {
+                participant_index: None
+            }
+        );
+        let fee = self.forward_fee(&terms.outgoing, &terms.amount);
+        require!(
+            fee <= terms.max_fee,
+            Error::from(ErrorCode::InvalidInput).with("fee", &fee)
+        );
+        let incoming = terms.amount.clone() + fee.clone();
+        for (leg, amount) in [
+            (&terms.incoming, &incoming),
+            (&terms.outgoing, &terms.amount),
+        ] {
+            require!(
+                self.state(&leg.channel).is_some_and(|s| s.settled(now)),
+                NotFinalized
+            );
+            let held = self.query_holdings(leg.payer()).unwrap_or_default();
+            require!(
+                held >= *amount,
+                InsufficientFunding {
+                    required: amount.clone(),
+                    available: held.clone(),
+                }
+            );
+            self.require_unreserved(&leg.payer(), &held, amount, now)?;
+        }
+
+        self.debit(&terms.incoming.payer(), &incoming, ChangeCause::Forward);
+        self.debit(&terms.outgoing.payer(), &terms.amount, ChangeCause::Forward);
+        self.forwards.insert(
+            terms.hash,
+            Forward {
+                terms,
+                fee,
+                status: ForwardStatus::Locked,
+            },
+        );
+        Ok(())
+    }
+
+    /// Pays out both legs of the forward locked under the preimage's hash,
+    /// and releases the swaps and invoices sharing the hash.
+    pub fn settle_forward(&mut self, preimage: Vec<u8>, now: Timestamp) -> Result<()> {
+        let hash = payment_hash(&preimage);
+        let fwd = self.forwards.get(&hash).ok_or(ErrorCode::NotFound)?;
+        require!(fwd.status == ForwardStatus::Locked, AlreadyConcluded);
+        require!(now < fwd.terms.expiry, Expired);
+        self.release(preimage, now);
+        Ok(())
+    }
+
+    /// Whether the forward under `hash` can be settled at `now`.
+    pub(crate) fn forward_settleable(&self, hash: &PaymentHash, now: Timestamp) -> bool {
+        self.forwards
+            .get(hash)
+            .is_some_and(|f| f.status == ForwardStatus::Locked && now < f.terms.expiry)
+    }
+
+    /// Credits both legs' payees of a locked forward.
+    pub(crate) fn pay_out_forward(&mut self, hash: &PaymentHash, preimage: Vec<u8>) {
+        let Some(fwd) = self.forwards.get(hash) else {
+            return;
+        };
+        let terms = fwd.terms.clone();
+        let incoming = terms.amount.clone() + fwd.fee.clone();
+        self.credit(terms.incoming.payee(), incoming, ChangeCause::Forward);
+        self.credit(terms.outgoing.payee(), terms.amount, ChangeCause::Forward);
+        if let Some(fwd) = self.forwards.get_mut(hash) {
+            fwd.status = ForwardStatus::Settled { preimage };
+        }
+    }
+
+    /// Returns both legs' locked funds to their payers after the expiry.
+    pub fn refund_forward(&mut self, hash: &PaymentHash, now: Timestamp) -> Result<()> {
+        let fwd = self.forwards.get(hash).ok_or(ErrorCode::NotFound)?;
+        require!(fwd.status == ForwardStatus::Locked, AlreadyConcluded);
+        require!(now >= fwd.terms.expiry, NotExpired);
+
+        let terms = fwd.terms.clone();
+        let incoming = terms.amount.clone() + fwd.fee.clone();
+        self.credit(terms.incoming.payer(), incoming, ChangeCause::Forward);
+        self.credit(terms.outgoing.payer(), terms.amount, ChangeCause::Forward);
+        if let Some(fwd) = self.forwards.get_mut(hash) {
+            fwd.status = ForwardStatus::Refunded;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::receiver::MockTXQuerier;
+    use crate::testing::*;
+
+    fn setup() -> (CanisterState<MockTXQuerier>, ForwardTerms) {
+        let mut s = new_state();
+        let (payer, hub, payee) = (1, 2, 3);
+        let a = concluded(&mut s, 1, payer, hub);
+        let b = concluded(&mut s, 2, hub, payee);
+        let terms = ForwardTerms {
+            hash: payment_hash(b"secret"),
+            amount: Amount::from(30u32),
+            max_fee: Amount::default(),
+            expiry: 10,
+            incoming: Leg {
+                channel: a,
+                from: account(payer),
+                to: account(hub),
+            },
+            outgoing: Leg {
+                channel: b,
+                from: account(hub),
+                to: account(payee),
+            },
+        };
+        let msg = terms.signing_bytes();
+        s.lock_forward(terms.clone(), &sign(payer, &msg), &sign(hub, &msg), 1)
+            .unwrap();
+        (s, terms)
+    }
+
+    #[test]
+    fn test_forward_settles_both_legs() {
+        let (mut s, t) = setup();
+        let (a, b) = (&t.incoming.channel, &t.outgoing.channel);
+        assert_eq!(holdings(&s, a, 1), Amount::from(70u32));
+        assert_eq!(holdings(&s, b, 2), Amount::from(70u32));
+
+        assert!(matches!(
+            s.settle_forward(b"wrong".to_vec(), 2),
+            Err(Error {
+                code: ErrorCode::NotFound,
+                ..
+            })
+        ));
+        s.settle_forward(b"secret".to_vec(), 2).unwrap();
+        assert_eq!(holdings(&s, a, 2), Amount::from(130u32));
+        assert_eq!(holdings(&s, b, 3), Amount::from(130u32));
+        assert!(matches!(
+            s.refund_forward(&t.hash, 10),
+            Err(Error {
+                code: ErrorCode::AlreadyConcluded,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn test_forward_pays_hub_fee() {
+        let mut s = new_state();
+        let (payer, hub, payee) = (1, 2, 3);
+        let a = concluded(&mut s, 11, payer, hub);
+        let b = concluded(&mut s, 12, hub, payee);
+        let policy = FeePolicy {
+            base_fee: Amount::from(2u32),
+            fee_ppm: 100_000,
+            seq: 1,
+        };
+        let funding = Funding::new(b.clone(), account(hub));
+        let sig = sign(hub, &policy.signing_bytes(&funding));
+        s.set_fee_policy(funding.clone(), policy.clone(), &sig)
+            .unwrap();
+        assert_eq!(
+            s.set_fee_policy(funding, policy, &sig),
+            Err(Error::from(ErrorCode::OutdatedState {
+                registered_version: 1,
+                submitted_version: 1
+            })
+            .with("seq", 1u64))
+        );
+
+        let mut terms = ForwardTerms {
+            hash: payment_hash(b"fee"),
+            amount: Amount::from(30u32),
+            max_fee: Amount::from(4u32),
+            expiry: 10,
+            incoming: Leg {
+                channel: a.clone(),
+                from: account(payer),
+                to: account(hub),
+            },
+            outgoing: Leg {
+                channel: b.clone(),
+                from: account(hub),
+                to: account(payee),
+            },
+        };
+        let msg = terms.signing_bytes();
+        assert_eq!(
+            s.lock_forward(terms.clone(), &sign(payer, &msg), &sign(hub, &msg), 1),
+            Err(Error::from(ErrorCode::InvalidInput).with("fee", Amount::from(5u32)))
+        );
+        terms.max_fee = Amount::from(5u32);
+        let msg = terms.signing_bytes();
+        s.lock_forward(terms, &sign(payer, &msg), &sign(hub, &msg), 1)
+            .unwrap();
+        assert_eq!(holdings(&s, &a, payer), Amount::from(65u32));
+        s.settle_forward(b"fee".to_vec(), 2).unwrap();
+        assert_eq!(holdings(&s, &a, hub), Amount::from(135u32));
+        assert_eq!(holdings(&s, &b, hub), Amount::from(70u32));
+        assert_eq!(holdings(&s, &b, payee), Amount::from(130u32));
+    }
+
+    #[test]
+    fn test_forward_legs_meet_at_a_participating_hub() {
+        let mut s = new_state();
+        let a = concluded(&mut s, 11, 1, 2);
+        let b = concluded(&mut s, 12, 2, 3);
+        let c = concluded(&mut s, 13, 4, 3);
+        let leg = |channel: &ChannelId, from, to| Leg {
+            channel: channel.clone(),
+            from: account(from),
+            to: account(to),
+        };
+        // Locks a forward from `payer` to 3, with the outgoing leg paid by
+        // `hub`.
+        let lock = |s: &mut CanisterState<MockTXQuerier>, payer, incoming, hub, outgoing| {
+            let terms = ForwardTerms {
+                hash: payment_hash(b"hub"),
+                amount: Amount::from(30u32),
+                max_fee: Amount::default(),
+                expiry: 10,
+                incoming,
+                outgoing,
+            };
+            let msg = terms.signing_bytes();
+            s.lock_forward(terms, &sign(payer, &msg), &sign(hub, &msg), 1)
+        };
+
+        // The outgoing leg is paid by another party than the incoming leg's
+        // payee.
+        assert!(matches!(
+            lock(&mut s, 1, leg(&a, 1, 2), 4, leg(&c, 4, 3)),
+            Err(Error {
+                code: ErrorCode::InvalidInput,
+                ..
+            })
+        ));
+        // The hub pays out of a channel it is no participant of.
+        assert!(matches!(
+            lock(&mut s, 1, leg(&a, 1, 2), 2, leg(&c, 2, 3)),
+            Err(Error {
+                code: ErrorCode::InvalidInput,
+                ..
+            })
+        ));
+        assert_eq!(holdings(&s, &a, 1), Amount::from(100u32));
+        lock(&mut s, 1, leg(&a, 1, 2), 2, leg(&b, 2, 3)).unwrap();
+    }
+
+    #[test]
+    fn test_forward_refunds_after_expiry() {
+        let (mut s, t) = setup();
+        assert!(matches!(
+            s.refund_forward(&t.hash, 9),
+            Err(Error {
+                code: ErrorCode::NotExpired,
+                ..
+            })
+        ));
+        assert!(matches!(
+            s.settle_forward(b"secret".to_vec(), 10),
+            Err(Error {
+                code: ErrorCode::Expired,
+                ..
+            })
+        ));
+        s.refund_forward(&t.hash, 10).unwrap();
+        assert_eq!(holdings(&s, &t.incoming.channel, 1), Amount::from(100u32));
+        assert_eq!(holdings(&s, &t.outgoing.channel, 2), Amount::from(100u32));
+    }
+}
@@ -0,0 +1,366 @@
+//  Copyright 2025 PolyCrypt GmbH
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+
+//! Receiving from Lightning via the bridge. A user requests an invoice, which
+//! is queued as a command to a selected operator. The operator issues the
+//! invoice and locks the amount to be credited, minus its fee, from its
+//! balance. Revealing the invoice's preimage credits the locked funds to the
+//! requester's balance; if the invoice expires unpaid, they return to the
+//! operator.
+
+use crate::audit;
+use crate::bridge::{BridgeCommand, BridgeRequestId, CommandId, CommandStatus};
+use crate::error::*;
+use crate::load::Priority;
+use crate::operator::Direction;
+use crate::permission::Scope;
+use crate::receiver::TXQuerier;
+use crate::types::*;
+use crate::validation;
+use crate::{CanisterState, mutate_state, read_state, require};
+use candid::{Principal, candid_method};
+use ic_cdk::api::time as blocktime;
+use ic_cdk::{query, update};
+
+/// Identifies an invoice request.
+pub type InvoiceId = u64;
+
+/// How long operators have to issue a requested invoice, including retries
+/// with alternative operators (five minutes).
+pub const INVOICE_ISSUE_WINDOW: Duration = 300_000_000_000;
+
+/// How often invoice requests are checked for missed deadlines (one minute).
+pub const INVOICE_CHECK_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60);
+
+/// Longest accepted invoice memo, the BOLT11 description limit (bytes).
+pub const MAX_INVOICE_MEMO_LEN: usize = 639;
+
+#[derive(Clone, Deserialize, CandidType, PartialEq, Eq, Debug)]
+pub enum InvoiceStatus {
+    /// Waiting for the operator to issue the invoice.
+    Requested { command: CommandId },
+    /// The invoice was issued and the amount to credit is locked.
+    Issued {
+        invoice: String,
+        hash: PaymentHash,
+        expiry: Timestamp,
+    },
+    /// The invoice was paid and the requester was credited.
+    Settled { preimage: Vec<u8> },
+    /// The invoice was not issued or not paid in time.
+    Expired,
+}
+
+#[derive(Clone, Deserialize, CandidType)]
+pub struct InvoiceRequest {
+    /// Who is credited once the invoice is paid.
+    pub requester: Principal,
+    /// The operator issuing the invoice.
+    pub operator: Principal,
+    /// The invoice amount.
+    pub amount: Amount,
+    pub memo: String,
+    /// The operator's fee, deducted from the credited amount.
+    pub fee: Amount,
+    pub status: InvoiceStatus,
+    pub created_at: Timestamp,
+}
+
+#[update]
+#[candid_method(update)]
+/// Requests a Lightning invoice over `amount` whose payment credits the
+/// caller's balance, less the selected operator's fee.
+fn request_invoice(amount: Amount, memo: String) -> Result<InvoiceId> {
+    crate::load::admit(Priority::Low)?;
+    mutate_state(|s| s.request_invoice(ic_cdk::api::msg_caller(), amount, memo, blocktime()))
+}
+
+#[update]
+#[candid_method(update)]
+/// Hands in the invoice for a request assigned to the calling operator.
+fn submit_invoice(
+    request_id: BridgeRequestId,
+    id: InvoiceId,
+    invoice: String,
+    hash: PaymentHash,
+    expiry: Timestamp,
+) -> Result<()> {
+    let caller = ic_cdk::api::msg_caller();
+    let args = audit::args_hash((request_id, id, &invoice, hash, expiry));
+    audit::logged("submit_invoice", args, || {
+        mutate_state(|s| {
+            s.once(caller, request_id, |s| {
+                s.submit_invoice(caller, id, invoice, hash, expiry, blocktime())
+            })
+        })
+    })
+}
+
+#[update]
+#[candid_method(update)]
+/// Credits the requester of a paid invoice by revealing its preimage. Since
+/// paying the invoice reveals the preimage to the payer, the payer can settle
+/// without the operator's cooperation.
+fn settle_invoice(request_id: BridgeRequestId, id: InvoiceId, preimage: Vec<u8>) -> Result<()> {
+    let caller = ic_cdk::api::msg_caller();
+    mutate_state(|s| {
+        s.once(caller, request_id, |s| {
+            s.settle_invoice(id, preimage, blocktime())
+        })
+    })
+}
+
+#[query]
+#[candid_method(query)]
+fn query_invoice_request(id: InvoiceId) -> Option<InvoiceRequest> {
+    read_state(|s| s.invoices.get(&id).cloned())
+}
+
+/// Expires invoice requests that were not issued or not paid in time.
+pub fn check_invoices() {
+    mutate_state(|s| s.check_invoices(blocktime()));
+}
+
+impl InvoiceRequest {
+    /// The amount credited to the requester.
+    pub fn credit(&self) -> Amount {
+        self.amount.clone() - self.fee.clone()
+    }
+
+    /// Whether the invoice is still awaiting issuance or payment.
+    pub fn is_open(&self) -> bool {
+        matches!(
+            self.status,
+            InvoiceStatus::Requested { .. } | InvoiceStatus::Issued { .. }
+        )
+    }
+}
+
+impl<Q: TXQuerier> CanisterState<Q> {
+    pub fn request_invoice(
+        &mut self,
+        requester: Principal,
+        amount: Amount,
+        memo: String,
+        now: Timestamp,
+    ) -> Result<InvoiceId> {
+        self.accepting()?;
+        require!(amount > Amount::default(), InvalidInput);
+        require!(memo.len() <= MAX_INVOICE_MEMO_LEN, InvalidInput);
+        self.require_below_user_limit(requester)?;
+        let candidates = self.operator_candidates(Direction::FromLightning, &amount, now, &[]);
+        let first = candidates
+            .first()
+            .cloned()
+            .ok_or(ErrorCode::InsufficientLiquidity)?;
+        let (operator, ad) = candidates
+            .into_iter()
+            .find(|(o, _)| self.below_operator_limit(o))
+            .unwrap_or(first);
+        self.require_below_operator_limit(operator)?;
+
+        let id = self.next_invoice_id;
+        self.next_invoice_id += 1;
+        let command = self.bridge.enqueue(
+            operator,
+            BridgeCommand::IssueInvoice {
+                request: id,
+                amount: amount.clone(),
+                memo: memo.clone(),
+            },
+            now,
+        );
+        self.invoices.insert(
+            id,
+            InvoiceRequest {
+                requester,
+                operator,
+                fee: ad.fee(&amount),
+                amount,
+                memo,
+                status: InvoiceStatus::Requested { command },
+                created_at: now,
+            },
+        );
+        Ok(id)
+    }
+
+    /// Records the issued invoice and locks the amount to be credited from
+    /// the operator's balance.
+    pub fn submit_invoice(
+        &mut self,
+        caller: Principal,
+        id: InvoiceId,
+        invoice: String,
+        hash: PaymentHash,
+        expiry: Timestamp,
+        now: Timestamp,
+    ) -> Result<()> {
+        self.require_scope(&caller, Scope::ConsumeQueue)?;
+        validation::data(invoice.as_bytes())?;
+        let req = self.invoices.get(&id).ok_or(ErrorCode::NotFound)?;
+        let InvoiceStatus::Requested { command } = req.status else {
+            return Err(ErrorCode::AlreadyConcluded.into());
+        };
+        require!(req.operator == caller, Unauthorized);
+        require!(now < req.created_at + INVOICE_ISSUE_WINDOW, Expired);
+        require!(expiry > now, InvalidInput);
+
+        let credit = req.credit();
+        self.debit_balance(&caller, &credit)?;
+        self.bridge.finish(command, CommandStatus::Done);
+        if let Some(req) = self.invoices.get_mut(&id) {
+            req.status = InvoiceStatus::Issued {
+                invoice,
+                hash,
+                expiry,
+            };
+        }
+        self.payment_hashes.add_invoice(hash, id);
+        Ok(())
+    }
+
+    pub fn settle_invoice(
+        &mut self,
+        id: InvoiceId,
+        preimage: Vec<u8>,
+        now: Timestamp,
+    ) -> Result<()> {
+        let req = self.invoices.get(&id).ok_or(ErrorCode::NotFound)?;
+        let InvoiceStatus::Issued { hash, expiry, .. } = &req.status else {
+            return Err(ErrorCode::AlreadyConcluded.into());
+        };
+        require!(now < *expiry, Expired);
+        require!(
+            payment_hash(&preimage) == *hash,
+            Authentication {
+                participant_index: None
+            }
+        );
+        self.release(preimage, now);
+        Ok(())
+    }
+
+    /// Whether the invoice was issued and can still be settled at `now`.
+    pub(crate) fn invoice_settleable(&self, id: InvoiceId, now: Timestamp) -> bool {
+        self.invoices.get(&id).is_some_and(
+            |req| matches!(req.status, InvoiceStatus::Issued { expiry, .. } if now < expiry),
+        )
+    }
+
+    /// Credits the requester of an issued invoice.
+    pub(crate) fn pay_out_invoice(&mut self, id: InvoiceId, preimage: Vec<u8>, now: Timestamp) {
+        let Some(req) = self.invoices.get(&id) else {
+            return;
+        };
+        let (requester, operator, credit) = (req.requester, req.operator, req.credit());
+        let (amount, fee) = (req.amount.clone(), req.fee.clone());
+        *self.balances.entry(requester).or_default() += credit;
+        self.record_served(operator, Direction::FromLightning, amount, fee, now);
+        if let Some(req) = self.invoices.get_mut(&id) {
+            req.status = InvoiceStatus::Settled { preimage };
+        }
+    }
+
+    /// Expires requests whose operator did not issue the invoice in time, and
+    /// returns the locked funds of unpaid expired invoices to their operator.
+    pub fn check_invoices(&mut self, now: Timestamp) {
+        let (mut unlocked, mut missed, mut expired) = (vec![], vec![], vec![]);
+        for (id, req) in self.invoices.iter_mut() {
+            match &req.status {
+                InvoiceStatus::Requested { command }
+                    if now >= req.created_at + INVOICE_ISSUE_WINDOW =>
+                {
+                    self.bridge.finish(*command, CommandStatus::Failed);
+                    missed.push(req.operator);
+                }
+                InvoiceStatus::Issued { hash, expiry, .. } if now >= *expiry => {
+                    unlocked.push((req.operator, req.credit()));
+                    expired.push((*hash, *id));
+                }
+                _ => continue,
+            }
+            req.status = InvoiceStatus::Expired;
+        }
+        for (operator, amount) in unlocked {
+            *self.balances.entry(operator).or_default() += amount;
+        }
+        for operator in missed {
+            self.record_missed(operator, now);
+        }
+        for (hash, id) in expired {
+            self.payment_hashes.remove_invoice(&hash, id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::receiver::MockTXQuerier;
+    use crate::testing::*;
+
+    fn setup() -> (CanisterState<MockTXQuerier>, Principal, InvoiceId) {
+        let mut s = new_state();
+        let op = operator(&mut s, 10, 10);
+        advertise(&mut s, op, Direction::FromLightning, 20_000);
+        s.balances.insert(op, Amount::from(100u32));
+        let id = s
+            .request_invoice(
+                Principal::anonymous(),
+                Amount::from(50u32),
+                "coffee".into(),
+                0,
+            )
+            .unwrap();
+        (s, op, id)
+    }
+
+    #[test]
+    fn test_paid_invoice_credits_requester() {
+        let (mut s, op, id) = setup();
+        let cmds = s.bridge.pending_for(&op);
+        assert_eq!(cmds.len(), 1);
+        assert!(matches!(
+            &cmds[0].1,
+            BridgeCommand::IssueInvoice { request, .. } if *request == id
+        ));
+
+        let hash = payment_hash(b"secret");
+        s.submit_invoice(op, id, "lnbc1".into(), hash, 100, 1)
+            .unwrap();
+        assert!(s.bridge.pending_for(&op).is_empty());
+        assert_eq!(s.balances[&op], Amount::from(51u32));
+
+        s.settle_invoice(id, b"secret".to_vec(), 2).unwrap();
+        assert_eq!(s.balances[&Principal::anonymous()], Amount::from(49u32));
+        assert!(matches!(
+            s.settle_invoice(id, b"secret".to_vec(), 2),
+            Err(Error {
+                code: ErrorCode::AlreadyConcluded,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn test_unpaid_invoice_unlocks_operator_funds() {
+        let (mut s, op, id) = setup();
+        s.submit_invoice(op, id, "lnbc1".into(), [0; 32], 100, 1)
+            .unwrap();
+        s.check_invoices(100);
+        assert_eq!(s.invoices[&id].status, InvoiceStatus::Expired);
+        assert_eq!(s.balances[&op], Amount::from(100u32));
+    }
+}
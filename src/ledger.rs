@@ -0,0 +1,186 @@
+//  Copyright 2025 PolyCrypt GmbH
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+
+//! Cached metadata of registered ledgers. Withdrawals take the transfer fee
+//! from the cache and only query the ledger once the cached metadata is older
+//! than `METADATA_TTL`, so that a changed fee is picked up within the TTL, or
+//! right away through `refresh_ledger_metadata`. The metadata of all
+//! registered ledgers is fetched when the canister starts and refreshed every
+//! `FEE_REFRESH_INTERVAL`, so that the cached fee rarely falls back to the
+//! configured default.
+
+use crate::asset::AssetId;
+use crate::audit;
+use crate::error::*;
+use crate::receiver::TXQuerier;
+use crate::types::*;
+use crate::{CanisterState, LedgerCall, mutate_state, read_state};
+use candid::candid_method;
+use ic_cdk::api::time as blocktime;
+use ic_cdk::call::{Call, CallResult};
+use ic_cdk::{query, update};
+use icrc_ledger_types::icrc1::account::Account;
+use std::time::Duration as StdDuration;
+
+/// How long fetched metadata is used before it is fetched again.
+pub const METADATA_TTL: Duration = 3_600_000_000_000;
+
+/// How often the metadata of all registered ledgers is refreshed, well within
+/// the TTL so that withdrawals do not have to wait for a fetch.
+pub const FEE_REFRESH_INTERVAL: StdDuration = StdDuration::from_nanos(METADATA_TTL / 2);
+
+#[derive(Clone, Deserialize, CandidType, PartialEq, Debug)]
+pub struct LedgerMetadata {
+    /// The fee of a transfer, in base units.
+    pub fee: Nat,
+    pub decimals: u8,
+    /// The account minting and burning the ledger's tokens, if any.
+    pub minter: Option<Account>,
+    pub fetched_at: Timestamp,
+}
+
+#[update]
+#[candid_method(update)]
+/// Fetches the metadata of a registered asset's ledger, replacing the cached
+/// metadata. Admin only.
+async fn refresh_ledger_metadata(asset: AssetId) -> Result<LedgerMetadata> {
+    let caller = ic_cdk::api::msg_caller();
+    let hash = audit::args_hash((asset,));
+    let outcome = refresh(asset).await;
+    audit::record("refresh_ledger_metadata", caller, hash, &outcome);
+    outcome
+}
+
+#[query]
+#[candid_method(query)]
+/// Returns the cached metadata of an asset's ledger, whether fresh or not.
+fn query_ledger_metadata(asset: AssetId) -> Option<LedgerMetadata> {
+    read_state(|s| s.ledger_metadata.get(&asset).cloned())
+}
+
+async fn refresh(asset: AssetId) -> Result<LedgerMetadata> {
+    crate::admin::require_admin()?;
+    read_state(|s| s.asset(&asset).map(|_| ()))?;
+    let metadata = fetch(asset, blocktime()).await.map_err(|e| {
+        ic_cdk::println!("fetching ledger metadata failed: {:?}", e);
+        Error::from(e)
+    })?;
+    mutate_state(|s| s.cache_metadata(asset, metadata.clone()));
+    Ok(metadata)
+}
+
+async fn fetch(ledger: AssetId, now: Timestamp) -> CallResult<LedgerMetadata> {
+    let _call = LedgerCall::start();
+    let fee = Call::unbounded_wait(ledger, "icrc1_fee").await?.candid()?;
+    let decimals = Call::unbounded_wait(ledger, "icrc1_decimals")
+        .await?
+        .candid()?;
+    let minter = Call::unbounded_wait(ledger, "icrc1_minting_account")
+        .await?
+        .candid()?;
+    Ok(LedgerMetadata {
+        fee,
+        decimals,
+        minter,
+        fetched_at: now,
+    })
+}
+
+/// Refreshes the metadata of all registered ledgers.
+pub fn refresh_fees() {
+    ic_cdk::futures::spawn(refresh_all());
+}
+
+async fn refresh_all() {
+    let ledgers: Vec<AssetId> = read_state(|s| s.assets.keys().copied().collect());
+    for ledger in ledgers {
+        match fetch(ledger, blocktime()).await {
+            Ok(metadata) => mutate_state(|s| s.cache_metadata(ledger, metadata)),
+            Err(e) => ic_cdk::println!("fetching metadata of {} failed: {:?}", ledger, e),
+        }
+    }
+}
+
+/// The transfer fee of a ledger. Expired metadata is fetched again; if that
+/// fails, the last known fee is used.
+pub async fn fee(ledger: AssetId) -> Nat {
+    let now = blocktime();
+    if let Some(fee) = read_state(|s| s.fresh_metadata(&ledger, now).map(|m| m.fee.clone())) {
+        return fee;
+    }
+    refetch_fee(ledger).await
+}
+
+/// The transfer fee of a ledger, fetched regardless of the cached metadata.
+/// If fetching fails, the last known fee is used.
+pub async fn refetch_fee(ledger: AssetId) -> Nat {
+    match fetch(ledger, blocktime()).await {
+        Ok(metadata) => {
+            let fee = metadata.fee.clone();
+            mutate_state(|s| s.cache_metadata(ledger, metadata));
+            fee
+        }
+        Err(e) => {
+            ic_cdk::println!("fetching ledger metadata failed: {:?}", e);
+            read_state(|s| s.fee(&ledger))
+        }
+    }
+}
+
+impl<Q: TXQuerier> CanisterState<Q> {
+    /// The cached metadata of a ledger, unless it expired.
+    pub fn fresh_metadata(&self, ledger: &AssetId, now: Timestamp) -> Option<&LedgerMetadata> {
+        self.ledger_metadata
+            .get(ledger)
+            .filter(|m| now < m.fetched_at.saturating_add(METADATA_TTL))
+    }
+
+    pub fn cache_metadata(&mut self, ledger: AssetId, metadata: LedgerMetadata) {
+        self.ledger_metadata.insert(ledger, metadata);
+    }
+
+    /// The last known transfer fee of a ledger, or the configured fee if its
+    /// metadata was never fetched.
+    pub fn fee(&self, ledger: &AssetId) -> Nat {
+        match self.ledger_metadata.get(ledger) {
+            Some(metadata) => metadata.fee.clone(),
+            None => self.config.fee.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+
+    #[test]
+    fn test_metadata_expires_after_ttl() {
+        let mut s = new_state();
+        let ledger = s.ckbtc();
+        assert_eq!(s.fee(&ledger), s.config.fee);
+        s.cache_metadata(
+            ledger,
+            LedgerMetadata {
+                fee: Nat::from(20u32),
+                decimals: 8,
+                minter: None,
+                fetched_at: 5,
+            },
+        );
+        assert!(s.fresh_metadata(&ledger, 4 + METADATA_TTL).is_some());
+        assert!(s.fresh_metadata(&ledger, 5 + METADATA_TTL).is_none());
+        assert_eq!(s.fee(&ledger), Nat::from(20u32));
+    }
+}
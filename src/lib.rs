@@ -12,78 +12,441 @@
 //  See the License for the specific language governing permissions and
 //  limitations under the License.
 
-use crate::receiver::DEFAULT_CKBTC_FEE;
+use crate::receiver::{BlockHeight, Memo, TXQuerier};
+use crate::statement::{Period, Statement};
 use icrc_ledger_types::icrc1::account::Account;
-use icrc_ledger_types::icrc1::transfer::TransferArg;
+use icrc_ledger_types::icrc1::transfer::{TransferArg, TransferError};
+use icrc_ledger_types::icrc2::transfer_from::{TransferFromArgs, TransferFromError};
+pub mod ack;
+pub mod admin;
+pub mod anchor;
+pub mod asset;
+pub mod attestation;
+pub mod audit;
+pub mod bridge;
+pub mod certified;
+pub mod challenge;
+pub mod config;
 pub mod deq;
+pub mod diff;
+pub mod dust;
 pub mod error;
 pub mod events;
+pub mod evm;
+pub mod guard;
+pub mod handshake;
+pub mod holdings;
+pub mod htlc;
+pub mod invoice;
+pub mod ledger;
+pub mod load;
+pub mod maintenance;
+pub mod memo;
+pub mod memory;
 pub mod msg;
+pub mod observer;
+pub mod operator;
+pub mod page;
+pub mod pause;
+pub mod payout;
+pub mod permission;
+pub mod pool;
+pub mod preimage;
+pub mod privacy;
+pub mod processed;
+pub mod quarantine;
+pub mod reminder;
+pub mod remote;
+pub mod reservation;
+pub mod rewards;
+pub mod routing;
+pub mod scanner;
+pub mod settlement;
+pub mod shadow;
+pub mod statement;
+pub mod store;
+pub mod subaccount;
+pub mod swap;
+#[cfg(test)]
+mod testing;
+pub mod transfer;
+use crate::anchor::Anchor;
+use crate::asset::{AssetId, AssetInfo};
+use crate::audit::{AuditEntry, AuditHead};
+use crate::bridge::{BridgeCommand, BridgeQueue, BridgeRequestId, CommandId, QueuedCommand};
+use crate::certified::{Certified, MerkleMap};
+use crate::challenge::ChallengePolicy;
+use crate::config::CanisterConfig;
+use crate::diff::{Modifications, StateDiff};
+use crate::dust::{Dust, DustOptOut, DustPolicy};
 use crate::events::ChannelTime;
 use crate::events::Event;
+use crate::events::EventArchive;
+use crate::events::LocalEventRegisterer;
 use crate::events::RegEvent;
+use crate::events::ReplayBatch;
+use crate::evm::EvmAttestation;
+use crate::guard::Guards;
+use crate::handshake::{Hello, NodeInfo};
+use crate::holdings::{ChangeCause, HoldingsChange, HoldingsLog};
+use crate::htlc::{FeePolicy, Forward, ForwardTerms, Leg};
+use crate::invoice::{InvoiceId, InvoiceRequest};
+use crate::ledger::LedgerMetadata;
+use crate::load::{Load, LoadPolicy, LoadStatus, Priority};
+use crate::maintenance::{Maintenance, MaintenanceWindow};
+use crate::memo::MemoRegistry;
+use crate::memory::Memory;
+use crate::observer::{ObservedChannel, ObserverChange, Observers};
+use crate::operator::{Direction, LiquidityAd, OperatorInfo, Reputation, RouteQuote};
+use crate::pause::{AssetPause, Flow, Health};
+use crate::permission::Scope;
+use crate::pool::{LiquidityPool, PoolShare, PoolStats};
+use crate::preimage::{PaymentHashes, Released};
+use crate::privacy::{PrincipalLink, Privacy};
+use crate::reconcile::ReconciliationReport;
+use crate::remote::RemoteFunding;
+use crate::reservation::{Reservation, ReservationId, Reservations, Window};
+use crate::rewards::{Accrual, Rewards, RewardsProgram};
+use crate::shadow::{Divergence, ShadowStatus};
+use crate::store::{HoldingsMap, NonceSet};
+use crate::swap::{Swap, SwapId, SwapLimits, SwapRequest};
+use crate::transfer::{WithdrawalQueue, WithdrawalStatus};
+use crate::unattributed::Unattributed;
+use crate::upgrade::{DrainStatus, UpgradeVerdict};
+use crate::upload::{BlobHash, UploadId, Uploads};
+use crate::validation::Validate;
+use crate::view::ChannelView;
+use crate::watermark::{MemoryAlert, MemoryStatus, MemoryThresholds, Watermarks};
 use candid::{Principal, candid_method};
-use ic_cdk::api::call::CallResult;
+use ic_cdk::call::{Call, CallResult};
 use ic_cdk::query;
 use ic_cdk::update;
+use ic_cdk::{init, post_upgrade, pre_upgrade};
 pub mod receiver;
+pub mod reconcile;
 pub mod types;
+pub mod unattributed;
+pub mod upgrade;
+pub mod upload;
+pub mod validation;
+pub mod view;
+pub mod watermark;
 use candid::export_service;
 use error::*;
 use ic_cdk::api::time as blocktime;
 
-use ic_ledger_types::{AccountIdentifier, DEFAULT_SUBACCOUNT, Tokens};
-
 use receiver::DEVNET_CKBTC_LEDGER;
 
-use lazy_static::lazy_static;
-use std::collections::HashMap;
-use std::sync::RwLock;
+use ic_stable_structures::StableBTreeMap;
+use page::*;
+use quarantine::*;
+use reminder::*;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU32, Ordering};
 use types::*;
 
+/// How long an announced channel may stay underfunded before it is reported
+/// by `query_underfunded_channels` (one hour).
+pub const FUNDING_GRACE_PERIOD: Duration = 3_600_000_000_000;
+
+/// How many registered states are kept per channel.
+pub const STATE_HISTORY_LEN: usize = 16;
+
 #[query(name = "__get_candid_interface_tmp_hack")]
 fn export_candid() -> String {
     export_service!();
     __export_service()
 }
 
-lazy_static! {
-    static ref STATE: RwLock<CanisterState<receiver::CanisterTXQuerier>> =
-        RwLock::new(CanisterState::new(
+thread_local! {
+    static STATE: RefCell<CanisterState<receiver::CanisterTXQuerier>> =
+        RefCell::new(CanisterState::new(
             receiver::CanisterTXQuerier::new(
-                Principal::from_text(DEVNET_CKBTC_LEDGER).expect("parsing principal") // //bkyz2-fmaaa-aaaaa-qaaaq-cai
+                DEVNET_CKBTC_LEDGER
             ),
-            ic_cdk::id(),
+            ic_cdk::api::canister_self(),
+            Arc::new(attestation::ManagementCanisterSigner::new(
+                CanisterConfig::default().ecdsa_key_name()
+            )),
         ));
 }
 
+/// Reads the canister state. The state is only ever borrowed synchronously:
+/// updates that call other canisters read what the call needs, make the call
+/// without holding the state, and then commit its result with
+/// `mutate_state`, re-checking anything the call's interleaving may have
+/// changed.
+fn read_state<R>(f: impl FnOnce(&CanisterState<receiver::CanisterTXQuerier>) -> R) -> R {
+    STATE.with_borrow(f)
+}
+
+/// Changes the canister state. See `read_state`.
+fn mutate_state<R>(f: impl FnOnce(&mut CanisterState<receiver::CanisterTXQuerier>) -> R) -> R {
+    STATE.with_borrow_mut(|s| {
+        let r = f(s);
+        s.certify();
+        r
+    })
+}
+
 /// The canister's state. Contains all currently registered channels, as well as
 /// all deposits and withdrawable balances.
 pub struct CanisterState<Q: receiver::TXQuerier> {
     icrc_receiver: receiver::Receiver<Q>,
+    /// The receivers of deposits in the registered assets other than ckBTC.
+    asset_receivers: BTreeMap<AssetId, receiver::Receiver<Q>>,
+    /// Performs operations with the canister's keys.
+    signer: Arc<dyn attestation::Signer>,
     /// Tracks all deposits for unregistered channels. For registered channels,
     /// tracks withdrawable balances instead.
-    user_holdings: HashMap<Funding, Amount>,
+    user_holdings: HoldingsMap,
     /// Tracks all registered channels.
-    channels: HashMap<ChannelId, RegisteredState>,
-    // ckBTC liquidity pools can be operated, in principle, by multiple key holders
-    liq_pool_holdings: HashMap<L1Account, Amount>,
+    channels: StableBTreeMap<ChannelId, RegisteredState, Memory>,
+    /// The certified tree over the latest registered states.
+    certified_states: MerkleMap,
+    /// The parameters of all registered channels.
+    params: HashMap<ChannelId, Params>,
+    /// The registered channels of each participant.
+    channels_of: BTreeMap<L2Account, BTreeSet<ChannelId>>,
+    /// Announced channels and their funding progress.
+    funding: HashMap<ChannelId, ChannelFunding>,
+    /// Contested funds, excluded from the holdings until resolved.
+    quarantine: Quarantine,
+    /// Hash-locked forwards between channels, by payment hash.
+    forwards: BTreeMap<PaymentHash, Forward>,
+    /// Hubs' forwarding fee policies, by outgoing channel and hub.
+    fee_policies: HashMap<Funding, FeePolicy>,
+    /// Swaps and invoices by payment hash, and revealed preimages.
+    payment_hashes: PaymentHashes,
+    /// Withdrawals awaiting another transfer attempt, and their statuses.
+    withdrawal_queue: WithdrawalQueue,
+    /// The dust policy, swept dust and participants' opt-outs.
+    dust: Dust,
+    /// The watch-only observers of channels.
+    observers: Observers,
+    /// Whether privacy mode is on, and the principals linked to participants.
+    privacy: Privacy,
+    /// The scheduled maintenance window.
+    maintenance: Maintenance,
+    /// Registered assets.
+    assets: BTreeMap<AssetId, AssetInfo>,
+    /// The permission scopes held by privileged principals.
+    scopes: BTreeMap<Principal, BTreeSet<Scope>>,
+    /// Registered bridge operators.
+    operators: BTreeMap<Principal, OperatorInfo>,
+    /// Liquidity advertised by bridge operators, per operator and direction.
+    liquidity_ads: BTreeMap<(Principal, Direction), LiquidityAd>,
+    /// Swaps paying Lightning invoices, by id.
+    swaps: BTreeMap<SwapId, Swap>,
+    next_swap_id: SwapId,
+    /// Caps on open swaps and invoice requests per principal.
+    swap_limits: SwapLimits,
+    /// How reliably each operator served its swaps.
+    reputation: BTreeMap<Principal, Reputation>,
+    /// Settlement statements of operators, by operator and period.
+    statements: BTreeMap<(Principal, statement::Period), statement::Statement>,
+    /// Invoice requests for receiving from Lightning, by id.
+    invoices: BTreeMap<InvoiceId, InvoiceRequest>,
+    next_invoice_id: InvoiceId,
+    /// Commands to bridge operators.
+    bridge: BridgeQueue,
+    /// The results of processed bridge-originated updates, by submitter and
+    /// request id.
+    processed: bridge::ProcessedRequests,
+    /// Withdrawable balances of principals, e.g. operators' swap proceeds.
+    balances: BTreeMap<Principal, Amount>,
+    /// Scanned deposits no funding claims, by block.
+    unattributed: BTreeMap<u64, Unattributed>,
+    /// Whether new fund-moving requests are rejected ahead of an upgrade.
+    draining: bool,
+    /// Whether an admin halted all fund-moving requests.
+    paused: bool,
+    /// Canisters funding intents may name as their callback.
+    callback_canisters: BTreeSet<Principal>,
+    /// Trusted remote Perun canisters and their attestation public keys.
+    remote_canisters: BTreeMap<Principal, Vec<u8>>,
+    /// Remote outcomes already used for funding, by attesting canister.
+    remote_fundings: HashSet<(Principal, Funding)>,
+    /// Chunked uploads and committed blobs.
+    uploads: Uploads,
+    /// Sequenced changes of `user_holdings`.
+    holdings_log: HoldingsLog,
+    /// Argument size limits overriding the defaults, by method.
+    call_size_limits: BTreeMap<String, u64>,
+    /// Memos reserved for transfers to the canister's default account.
+    memo_registry: MemoRegistry,
+    /// The nonces of the signed withdrawal requests paid out, by funding.
+    withdrawal_nonces: NonceSet,
+    /// The nonces of the signed swap requests created, by funding.
+    swap_nonces: NonceSet,
+    /// Channels under an active dispute. Their funds cannot be withdrawn
+    /// until the dispute settles.
+    locked: HashSet<ChannelId>,
+    /// Channels and fundings claimed by withdrawals awaiting their transfer.
+    guards: Guards,
+    /// Thresholds for shedding low-priority updates, if enabled.
+    load_policy: Option<LoadPolicy>,
+    /// The updates admitted in the current round.
+    load: Load,
+    /// Scales dispute timeouts by channel value, if set.
+    challenge_policy: Option<ChallengePolicy>,
+    /// Cached metadata of ledgers, by asset.
+    ledger_metadata: BTreeMap<AssetId, LedgerMetadata>,
+    /// The deployment's ledger and network.
+    config: CanisterConfig,
+    /// The audit log head last accepted by the anchor canister.
+    last_anchor: Option<Anchor>,
+    /// Saved payout destinations of principals, by name.
+    payout_aliases: BTreeMap<Principal, BTreeMap<String, Account>>,
+    /// The candidate accounting engine run in shadow mode.
+    shadow: shadow::Shadow,
+    /// Reminders for channels under dispute.
+    reminders: ReminderSchedule,
+    /// The last `STATE_HISTORY_LEN` registered states per channel, oldest
+    /// first.
+    history: HashMap<ChannelId, VecDeque<StateRecord>>,
+    /// The events of all channels, by channel and time.
+    events: LocalEventRegisterer,
+    /// How long events stay in `events` before they are archived, if they
+    /// are archived at all.
+    event_retention: Option<Duration>,
+    /// Events moved out of `events`.
+    event_archive: EventArchive,
+    // Liquidity pools can be operated, in principle, by multiple key holders
+    /// The liquidity pool of each registered asset.
+    liq_pools: BTreeMap<AssetId, LiquidityPool>,
+    /// The rewards program for pool depositors and their accrued rewards.
+    rewards: Rewards,
+    /// Holdings reserved for scheduled payments.
+    reservations: Reservations,
+    /// When channels and holdings were last modified, for `state_diff`.
+    modifications: Modifications,
+    /// Assets with paused deposits or withdrawals.
+    asset_pauses: BTreeMap<AssetId, AssetPause>,
+    /// The newest state version each participant of a channel acknowledged,
+    /// in the order of the channel's participants.
+    acks: HashMap<ChannelId, Vec<Version>>,
+    /// Memory sizes above which alerts are recorded, if any.
+    memory_thresholds: Option<MemoryThresholds>,
+    /// Memory high-water marks and alerts.
+    watermarks: Watermarks,
+}
+
+#[init]
+#[candid_method(init)]
+fn init(config: Option<CanisterConfig>) {
+    admin::add(ic_cdk::api::msg_caller());
+    configure(config.unwrap_or_default());
+    start_timers();
+}
+
+#[pre_upgrade]
+fn pre_upgrade() {
+    if let Err(e) = read_state(|s| s.require_upgradable()) {
+        ic_cdk::trap(format!("upgrade refused, withdrawals are queued: {e}"));
+    }
+}
+
+#[post_upgrade]
+fn post_upgrade(config: Option<CanisterConfig>) {
+    configure(config.unwrap_or_default());
+    mutate_state(|s| s.draining = false);
+    start_timers();
+}
+
+fn configure(config: CanisterConfig) {
+    mutate_state(|state| {
+        state.icrc_receiver.querier().set_ledger(config.ledger);
+        state.signer = Arc::new(attestation::ManagementCanisterSigner::new(
+            config.ecdsa_key_name(),
+        ));
+        state.configure(config);
+    })
+}
+
+fn start_timers() {
+    ic_cdk_timers::set_timer_interval(REMINDER_CHECK_INTERVAL, send_reminders);
+    ic_cdk_timers::set_timer_interval(swap::SWAP_CHECK_INTERVAL, swap::check_swaps);
+    ic_cdk_timers::set_timer_interval(invoice::INVOICE_CHECK_INTERVAL, invoice::check_invoices);
+    ic_cdk_timers::set_timer_interval(bridge::BRIDGE_CHECK_INTERVAL, bridge::check_bridge);
+    ic_cdk_timers::set_timer_interval(scanner::LEDGER_SCAN_INTERVAL, scanner::scan_ledger);
+    ic_cdk_timers::set_timer_interval(anchor::ANCHOR_INTERVAL, anchor::anchor_audit_log);
+    ic_cdk_timers::set_timer_interval(events::EVENT_ARCHIVE_INTERVAL, events::archive_events);
+    ic_cdk_timers::set_timer_interval(watermark::MEMORY_CHECK_INTERVAL, watermark::check_memory);
+    ic_cdk_timers::set_timer(std::time::Duration::ZERO, ledger::refresh_fees);
+    ic_cdk_timers::set_timer_interval(ledger::FEE_REFRESH_INTERVAL, ledger::refresh_fees);
+    ic_cdk_timers::set_timer_interval(
+        transfer::WITHDRAWAL_QUEUE_INTERVAL,
+        transfer::drain_withdrawals,
+    );
+    ic_cdk_timers::set_timer_interval(dust::DUST_SWEEP_INTERVAL, dust::sweep_dust);
+    ic_cdk_timers::set_timer_interval(
+        maintenance::MAINTENANCE_CHECK_INTERVAL,
+        maintenance::check_maintenance,
+    );
+    settlement::schedule_open_disputes();
+}
+
+/// Emits due dispute reminders as events and notifies their subscribers.
+fn send_reminders() {
+    let now = blocktime();
+    let due = mutate_state(|s| s.due_reminders(now));
+    for r in due {
+        let ch = r.state.state.channel.clone();
+        for cb in &r.subscribers {
+            notify(cb, &ch);
+        }
+        let event = Event::DisputeReminder {
+            state: r.state,
+            elapsed_percent: r.elapsed_percent,
+            timestamp: now,
+        };
+        mutate_state(|s| s.events.push(now, ch, event));
+    }
 }
 
 #[update]
 #[candid_method(update)]
+/// Subscribes or unsubscribes a callback that is called with the channel id
+/// whenever a reminder about the channel's running challenge window is due.
+/// The signature is by one of the channel's participants over
+/// `SubscriptionChange::signing_bytes`. Callbacks must name a registered
+/// callback canister.
+fn set_reminder_subscriber(id: ChannelId, change: SubscriptionChange, sig: Vec<u8>) -> Result<()> {
+    mutate_state(|s| s.set_reminder_subscriber(&id, change, &sig))
+}
 
-/// The user needs to call this with his transaction.
-async fn transaction_notification(notify_args: NotifyArgs) -> Option<Amount> {
-    STATE
-        .write()
-        .unwrap()
-        .process_icrc_tx(
-            notify_args.block_height,
-            notify_args.amount,
-            notify_args.funding,
-        )
-        .await
+#[update]
+#[candid_method(update)]
+/// Sets at which percentages of an elapsed challenge window reminders are
+/// sent. Admin only.
+fn set_reminder_percentages(percentages: Vec<u32>) -> Result<()> {
+    let hash = audit::args_hash((&percentages,));
+    audit::logged("set_reminder_percentages", hash, || {
+        admin::require_admin()?;
+        validation::batch(&percentages)?;
+        mutate_state(|s| s.reminders.set_percentages(percentages))
+    })
+}
+
+#[query]
+#[candid_method(query)]
+fn query_reminder_percentages() -> Vec<u32> {
+    read_state(|s| s.reminders.percentages())
+}
+
+#[update]
+#[candid_method(update)]
+/// The user needs to call this with his transaction, which must have been
+/// sent from one of the caller's accounts. Returns the credited transfer, or
+/// why it was not credited.
+async fn transaction_notification(notify_args: NotifyArgs) -> Result<CreditedDeposit> {
+    unpaused!();
+    let asset = read_state(|s| s.asset_or_ckbtc(notify_args.funding.asset));
+    let tx = query_callers_transfer(asset, notify_args.block_height).await?;
+    mutate_state(|s| s.process_icrc_tx(&tx, notify_args.amount, notify_args.funding))
 }
 
 #[query]
@@ -101,342 +464,2505 @@ fn query_funding_only(funding: Funding) -> Option<Funding> {
 /// Returns the funds deposited for a channel's specified participant, if any.
 /// this function should be used to check whether all participants have
 /// deposited their owed funds into a channel to ensure it is fully funded.
+/// In privacy mode, only callers entitled to read the funding see them.
 fn query_holdings(funding: Funding) -> Option<Amount> {
-    STATE.read().unwrap().query_holdings(funding)
+    let caller = ic_cdk::api::msg_caller();
+    read_state(|s| {
+        s.may_read_funding(&caller, &funding)
+            .then(|| s.query_holdings(funding))
+            .flatten()
+    })
 }
 
 #[update]
 #[candid_method(update)]
+fn deposit(funding: Funding) -> Option<Error> {
+    unpaused!(Some);
+    if let Err(e) = read_state(|s| s.accepting()) {
+        return Some(e);
+    }
+    mutate_state(|s| s.deposit_icrc(blocktime(), funding).err())
+}
 
-async fn deposit(funding: Funding) -> Option<Error> {
-    STATE
-        .write()
-        .unwrap()
-        .deposit_icrc(blocktime(), funding)
-        .await
-        .err()
+#[update]
+#[candid_method(update)]
+/// Announces a channel and the deposits each participant owes. Once the final
+/// owed deposit is credited, the intent's callback (if any) is called with the
+/// channel id, so that orchestration services need not poll for funding. The
+/// signature is by one of the channel's participants over
+/// `FundingIntent::signing_bytes`. A participant may replace the intent until
+/// the channel is funded. Callbacks must name a registered callback canister.
+fn register_funding_intent(intent: FundingIntent, sig: Vec<u8>) -> Result<ChannelId> {
+    load::admit(Priority::Low)?;
+    mutate_state(|s| s.register_signed_funding_intent(intent, &sig, blocktime()))
+}
+
+#[update]
+#[candid_method(update)]
+/// Allows funding intents to name the canister as their callback. Controller
+/// only.
+fn register_callback_canister(canister: Principal) -> Result<()> {
+    let hash = audit::args_hash((canister,));
+    audit::logged("register_callback_canister", hash, || {
+        require_controller()?;
+        mutate_state(|s| s.callback_canisters.insert(canister));
+        Ok(())
+    })
+}
+
+#[update]
+#[candid_method(update)]
+/// Stops funding intents from naming the canister as their callback.
+/// Controller only.
+fn remove_callback_canister(canister: Principal) -> Result<()> {
+    let hash = audit::args_hash((canister,));
+    audit::logged("remove_callback_canister", hash, || {
+        require_controller()?;
+        mutate_state(|s| s.callback_canisters.remove(&canister));
+        Ok(())
+    })
+}
+
+#[query]
+#[candid_method(query)]
+fn query_callback_canisters() -> Vec<Principal> {
+    read_state(|s| s.callback_canisters.iter().copied().collect())
+}
+
+#[update]
+#[candid_method(update)]
+/// Adds funds to a funded, open channel. The participant at `my_index`
+/// deposits the increase in ledger block `block_height`, and all participants
+/// sign the channel's new state, whose allocation includes the deposit. The
+/// channel's holdings are set to the new allocation along with the deposit.
+async fn top_up(
+    params: Params,
+    state: State,
+    sigs: Vec<Vec<u8>>,
+    my_index: u32,
+    block_height: u64,
+) -> Result<ChannelFunding> {
+    unpaused!();
+    let tx = query_callers_transfer(config::current().ledger, block_height).await?;
+    mutate_state(|s| s.top_up(&params, state, &sigs, my_index, &tx, blocktime()))
+}
+
+#[update]
+#[candid_method(update)]
+/// Opens a channel in a single call. Announces the channel with the initial
+/// state's allocation as its owed deposits, unless it was announced before,
+/// and credits the deposit made in the ledger block `block_height` to the
+/// participant at `my_index`. If this completes the funding, the initial state
+/// becomes the channel's funded allocation. Returns the funding progress.
+async fn fund_and_register(
+    params: Params,
+    initial_state: State,
+    my_index: u32,
+    block_height: u64,
+) -> Result<ChannelFunding> {
+    unpaused!();
+    let tx = query_callers_transfer(config::current().ledger, block_height).await?;
+    mutate_state(|s| s.fund_and_register(params, initial_state, my_index, &tx, blocktime()))
+}
+
+#[query]
+#[candid_method(query)]
+/// Returns the funding progress of an announced channel.
+fn query_funding_status(id: ChannelId) -> Option<ChannelFunding> {
+    read_state(|s| s.funding_status(&id))
+}
+
+#[update]
+#[candid_method(update)]
+/// Queries the ledger again for a previously credited deposit. If the block no
+/// longer verifies, the credited amount is moved from the funding's holdings
+/// into quarantine and the quarantine entry's id is returned. Admin only.
+async fn reverify_deposit(funding: Funding, block_height: u64) -> Result<Option<QuarantineId>> {
+    let caller = ic_cdk::api::msg_caller();
+    let hash = audit::args_hash((&funding, block_height));
+    let outcome = match admin::require_admin() {
+        Ok(()) => reverify(funding, block_height).await,
+        Err(e) => Err(e),
+    };
+    audit::record("reverify_deposit", caller, hash, &outcome);
+    outcome
+}
+
+async fn reverify(funding: Funding, block_height: u64) -> Result<Option<QuarantineId>> {
+    let querier = read_state(|s| {
+        s.credited_deposit(&funding, block_height)?;
+        Ok::<_, Error>(s.icrc_receiver.tx_querier())
+    })?;
+    let tx = querier.query_icrc_tx(block_height).await.ok();
+    mutate_state(|s| s.reverify_deposit(funding, block_height, tx.as_ref(), blocktime()))
+}
+
+#[update]
+#[candid_method(update)]
+/// Moves up to `amount` of a funding's holdings into quarantine while a fraud
+/// proof concerning them is reviewed. Admin only.
+fn quarantine_holdings(funding: Funding, amount: Amount, evidence: String) -> Result<QuarantineId> {
+    let hash = audit::args_hash((&funding, &amount, &evidence));
+    audit::logged("quarantine_holdings", hash, || {
+        admin::require_admin()?;
+        validation::data(evidence.as_bytes())?;
+        Ok(mutate_state(|s| {
+            s.quarantine_holdings(
+                funding,
+                amount,
+                QuarantineReason::FraudProof { evidence },
+                blocktime(),
+            )
+        }))
+    })
+}
+
+#[update]
+#[candid_method(update)]
+/// Moves all holdings of a channel into quarantine. Admin only.
+fn freeze_channel(id: ChannelId) -> Result<Vec<QuarantineId>> {
+    audit::logged("freeze_channel", audit::args_hash((&id,)), || {
+        admin::require_admin()?;
+        Ok(mutate_state(|s| s.freeze_channel(&id, blocktime())))
+    })
+}
+
+#[update]
+#[candid_method(update)]
+/// Votes for a resolution of quarantined funds. The resolution is applied once
+/// enough distinct controllers voted for it. Returns whether it was applied.
+fn approve_resolution(id: QuarantineId, resolution: Resolution) -> Result<bool> {
+    let hash = audit::args_hash((id, &resolution));
+    audit::logged("approve_resolution", hash, || {
+        require_controller()?;
+        mutate_state(|s| s.approve_resolution(id, ic_cdk::api::msg_caller(), resolution))
+    })
+}
+
+#[update]
+#[candid_method(update)]
+/// Votes for how many distinct approvals a quarantine resolution needs. The
+/// threshold changes once as many distinct controllers as the current
+/// threshold voted for it. Returns whether it changed.
+fn set_quarantine_threshold(threshold: u32) -> Result<bool> {
+    audit::logged(
+        "set_quarantine_threshold",
+        audit::args_hash((threshold,)),
+        || {
+            require_controller()?;
+            let caller = ic_cdk::api::msg_caller();
+            mutate_state(|s| s.quarantine.vote_threshold(caller, threshold))
+        },
+    )
+}
+
+#[query]
+#[candid_method(query)]
+/// Lists quarantined funds along with the votes on their resolution, ordered
+/// by quarantine id.
+fn query_quarantine(
+    cursor: Option<Cursor>,
+    limit: u32,
+) -> Result<Page<(QuarantineId, QuarantineEntry)>> {
+    read_state(|s| s.quarantine.page(cursor, limit))
+}
+
+#[query]
+#[candid_method(query)]
+/// Returns the total quarantined funds taken from a funding's holdings. These
+/// are not included in `query_holdings`.
+fn query_quarantined(funding: Funding) -> Amount {
+    read_state(|s| s.quarantine.total_of(&funding))
+}
+
+#[query]
+#[candid_method(query)]
+/// Returns up to `limit` announced channels involving `operator` whose funding
+/// is still incomplete `FUNDING_GRACE_PERIOD` after their announcement, oldest
+/// first, along with the outstanding deposits.
+fn query_underfunded_channels(operator: L2Account, limit: u32) -> Vec<UnderfundedChannel> {
+    read_state(|s| s.underfunded_channels(&operator, blocktime(), limit))
 }
 
 #[query]
 #[candid_method(query)]
 /// Returns the latest registered state for a given channel and its dispute
 /// timeout. This function should be used to check for registered disputes.
+/// To follow a channel as a whole, use `get_channel`.
 fn query_state(id: ChannelId) -> Option<RegisteredState> {
-    STATE.read().unwrap().state(&id)
+    read_state(|s| s.state(&id))
 }
 
 #[update]
-#[candid::candid_method]
-async fn simple_withdraw(req: WithdrawalReq) -> Nat {
-    let receiver = req.receiver;
-    let amount_nat = req.amount;
+#[candid_method(update)]
+/// Concludes a channel with a finalized state signed by all participants, in
+/// the order of the parameters' participant list. The funds become
+/// withdrawable immediately.
+fn conclude(params: Params, state: State, sigs: Vec<Vec<u8>>) -> Result<()> {
+    load::admit(Priority::Critical)?;
+    mutate_state(|s| s.conclude(&params, state, &sigs, blocktime()))
+}
+
+#[update]
+#[candid_method(update)]
+/// Registers a non-finalized state signed by all participants to close a
+/// channel without cooperation. The state becomes final after the channel's
+/// challenge duration, unless a newer state is registered before.
+fn dispute(params: Params, state: State, sigs: Vec<Vec<u8>>) -> Result<()> {
+    load::admit(Priority::Critical)?;
+    let channel = state.channel.clone();
+    mutate_state(|s| s.dispute(&params, state, &sigs, blocktime()))?;
+    settlement::schedule_settlement(&channel);
+    Ok(())
+}
+
+#[update]
+#[candid_method(update)]
+/// Refutes a channel's open dispute with a newer state signed by all
+/// participants. The newer state replaces the disputed one and the challenge
+/// duration starts over. Fails with `AlreadyConcluded` once the dispute's
+/// timeout has passed.
+fn refute(params: Params, state: State, sigs: Vec<Vec<u8>>) -> Result<()> {
+    load::admit(Priority::Critical)?;
+    let channel = state.channel.clone();
+    mutate_state(|s| s.refute(&params, state, &sigs, blocktime()))?;
+    settlement::schedule_settlement(&channel);
+    Ok(())
+}
+
+#[update]
+#[candid_method(update)]
+/// Concludes a channel whose expiry has passed with its last registered state,
+/// or with the deposits if no state was registered. Any participant can force
+/// the conclusion by signing `Params::force_conclusion_bytes`.
+fn force_conclude(params: Params, participant: L2Account, signature: Vec<u8>) -> Result<()> {
+    load::admit(Priority::Critical)?;
+    mutate_state(|s| s.force_conclude(&params, &participant, &signature, blocktime()))
+}
+
+#[query]
+#[candid_method(query)]
+/// Lists registered channels and their latest state, ordered by channel id.
+/// In privacy mode, only the channels the caller may read are listed.
+fn list_channels(cursor: Option<Cursor>, limit: u32) -> Result<Page<(ChannelId, RegisteredState)>> {
+    let caller = ic_cdk::api::msg_caller();
+    read_state(|s| s.channels_page(&caller, cursor, limit))
+}
+
+#[query]
+#[candid_method(query)]
+/// Lists up to `limit` registered channels and their latest state, starting
+/// at the `offset`-th channel ordered by channel id. The limit is clamped as
+/// in `list_channels`, which is preferable when channels are registered
+/// between calls, as new channels shift the offsets.
+fn query_channels(offset: u64, limit: u64) -> Vec<(ChannelId, RegisteredState)> {
+    let caller = ic_cdk::api::msg_caller();
+    read_state(|s| s.channels_at(&caller, offset, limit))
+}
+
+#[query]
+#[candid_method(query)]
+/// Returns the number of registered channels.
+fn channel_count() -> u64 {
+    read_state(|s| s.channels.len())
+}
+
+#[query]
+#[candid_method(query)]
+/// Returns the ids of all registered channels a participant is part of,
+/// ordered by channel id, so that a wallet restored from its seed can
+/// rediscover its channels. In privacy mode, only the channels the caller may
+/// read are returned.
+fn query_channels_of(participant: L2Account) -> Vec<ChannelId> {
+    let caller = ic_cdk::api::msg_caller();
+    read_state(|s| {
+        s.channels_of(&participant)
+            .into_iter()
+            .filter(|id| s.may_read_channel(&caller, id))
+            .collect()
+    })
+}
+
+#[query]
+#[candid_method(query)]
+/// Returns the last registered states of a channel together with their
+/// registration times, oldest first. At most `STATE_HISTORY_LEN` states are
+/// retained per channel.
+fn query_state_history(id: ChannelId) -> Vec<StateRecord> {
+    read_state(|s| s.state_history(&id))
+}
+
+#[update]
+#[candid_method(update)]
+/// Returns the state registered for a channel at the given version, signed by
+/// the canister's threshold ECDSA key. Only versions still contained in the
+/// channel's bounded state history can be exported.
+async fn export_balance_proof(id: ChannelId, version: Version) -> Result<BalanceProof> {
+    let (record, signer) = read_state(|state| {
+        let record = state.state_at(&id, version).ok_or_else(|| {
+            Error::from(ErrorCode::NotFound)
+                .with("channel", &id)
+                .with("version", version)
+        })?;
+        Ok::<_, Error>((record, state.signer.clone()))
+    })?;
+    let canister = ic_cdk::api::canister_self();
+    let message_hash = record.attestation_hash(&canister);
+    let signature = signer.sign(message_hash).await?;
+    Ok(BalanceProof {
+        record,
+        canister,
+        message_hash: message_hash.to_vec(),
+        signature,
+    })
+}
+
+#[update]
+#[candid_method(update)]
+/// Returns the public key with which balance proofs can be verified.
+async fn attestation_public_key() -> Result<Vec<u8>> {
+    let signer = read_state(|s| s.signer.clone());
+    signer.public_key().await
+}
 
-    let transfer_arg = TransferArg {
+#[cfg(feature = "dev-endpoints")]
+#[update]
+#[candid::candid_method]
+/// Transfers the request's asset to its receiver, without accounting for it
+/// in any holdings. Rejections of the ledger are returned with their details.
+/// Only built with the `dev-endpoints` feature.
+async fn simple_withdraw(req: WithdrawalReq) -> Result<Nat> {
+    unpaused!();
+    let keys = vec![guard::GuardKey::Channel(req.channel.clone())];
+    let ledger = mutate_state(|state| {
+        state.accepting()?;
+        let ledger = state.asset_or_ckbtc(req.asset);
+        state.require_unpaused(&ledger, Flow::Withdrawal)?;
+        state.require_unlocked(&req.channel, blocktime())?;
+        state.guards.require_free(&keys)?;
+        state.guards.claim(&keys);
+        Ok::<_, Error>(ledger)
+    })?;
+    let _guard = guard::SettlementGuard::new(keys);
+    let mut transfer_arg = TransferArg {
         from_subaccount: None,
         to: Account {
-            owner: receiver,
+            owner: req.receiver,
             subaccount: None,
         },
-        amount: amount_nat.clone(),
-        fee: Some(Nat(1000u64.into())), // ckBTC fee
+        amount: req.amount,
+        fee: Some(ledger::fee(ledger).await),
         memo: None,
         created_at_time: None,
     };
+    transfer::transfer(ledger, &mut transfer_arg)
+        .await
+        .into_result()
+}
 
-    let ckbtc_ledger_id = Principal::from_text(DEVNET_CKBTC_LEDGER).expect("parsing principal");
+#[query]
+#[candid_method(query)]
+/// Returns a principal's withdrawable balance.
+fn query_balance(who: Principal) -> Amount {
+    read_state(|s| s.balances.get(&who).cloned().unwrap_or_default())
+}
 
-    let call_result: CallResult<(
-        std::result::Result<Nat, icrc_ledger_types::icrc1::transfer::TransferError>,
-    )> = ic_cdk::call(ckbtc_ledger_id, "icrc1_transfer", (transfer_arg,)).await;
+#[query]
+#[candid_method(query)]
+/// Returns a principal's withdrawable balance formatted for humans.
+fn query_balance_display(who: Principal) -> Result<String> {
+    read_state(|state| {
+        let balance = state.balances.get(&who).cloned().unwrap_or_default();
+        Ok(state.asset(&state.ckbtc())?.display(&balance))
+    })
+}
 
-    match call_result {
-        Ok((inner_result,)) => match inner_result {
-            Ok(block_height) => Nat::from(block_height),
-            Err(e) => match e {
-                icrc_ledger_types::icrc1::transfer::TransferError::BadFee { expected_fee } => {
-                    ic_cdk::println!("BadFee: expected_fee = {:?}", expected_fee);
-                    Nat::from(111u32)
-                }
-                icrc_ledger_types::icrc1::transfer::TransferError::BadBurn { min_burn_amount } => {
-                    ic_cdk::println!("BadBurn: min_burn_amount = {:?}", min_burn_amount);
-                    Nat::from(112u32)
-                }
-                icrc_ledger_types::icrc1::transfer::TransferError::InsufficientFunds {
-                    balance,
-                } => {
-                    ic_cdk::println!("InsufficientFunds: balance = {:?}", balance);
-                    Nat::from(222u32)
-                }
-                icrc_ledger_types::icrc1::transfer::TransferError::TooOld => Nat::from(333u32),
-                icrc_ledger_types::icrc1::transfer::TransferError::CreatedInFuture {
-                    ledger_time,
-                } => {
-                    ic_cdk::println!("CreatedInFuture: ledger_time = {:?}", ledger_time);
-                    Nat::from(444u32)
-                }
-                icrc_ledger_types::icrc1::transfer::TransferError::TemporarilyUnavailable => {
-                    ic_cdk::println!("TemporarilyUnavailable");
-                    Nat::from(666u32)
-                }
-                icrc_ledger_types::icrc1::transfer::TransferError::Duplicate { duplicate_of } => {
-                    ic_cdk::println!("Duplicate: duplicate_of = {:?}", duplicate_of);
-                    Nat::from(555u32)
-                }
-                icrc_ledger_types::icrc1::transfer::TransferError::GenericError {
-                    error_code,
-                    message,
-                } => {
-                    ic_cdk::println!(
-                        "GenericError: code = {:?}, message = {}",
-                        error_code,
-                        message
-                    );
-                    Nat::from(777u32)
-                }
-            },
-        },
-        Err(e) => {
-            ic_cdk::println!("CallResult error: {:?}", e);
-            Nat::from(999u32) // Generic call error
+#[update]
+#[candid_method(update)]
+/// Transfers funds from the caller's balance to the caller's payout
+/// destination saved under `to`, or to the caller's ledger account.
+async fn withdraw_balance(amount: Amount, to: Option<String>) -> Result<Nat> {
+    unpaused!();
+    let caller = ic_cdk::api::msg_caller();
+    let (ledger, to) = mutate_state(|state| {
+        state.accepting()?;
+        state.require_unpaused(&state.config.ledger, Flow::Withdrawal)?;
+        let to = state.payout_account(caller, to.as_deref())?;
+        state.debit_balance(&caller, &amount)?;
+        Ok::<_, Error>((state.config.ledger, to))
+    })?;
+    let mut arg = TransferArg {
+        from_subaccount: None,
+        to,
+        amount: amount.clone(),
+        fee: Some(ledger::fee(ledger).await),
+        memo: None,
+        created_at_time: None,
+    };
+    let outcome = transfer::transfer_retrying(ledger, &mut arg).await;
+    if outcome.failed() {
+        mutate_state(|s| *s.balances.entry(caller).or_default() += amount);
+    }
+    outcome.into_result()
+}
+
+#[update]
+#[candid_method(update)]
+/// Queues the payout of a participant's holdings in a settled channel, less
+/// the ledger fee, to the receiver of the request, authorized by the
+/// participant's signature over `WithdrawalReq::signing_bytes`. Each signed
+/// request is paid out once. The payout's progress is reported by
+/// `withdrawal_status` under the returned id.
+async fn withdraw(req: WithdrawalReq, sig: Vec<u8>) -> Result<WithdrawalId> {
+    unpaused!();
+    let fee = ledger::fee(read_state(|s| s.asset_or_ckbtc(req.asset))).await;
+    let id = mutate_state(|s| s.queue_withdrawal(req, &sig, fee, blocktime()))?;
+    transfer::schedule_drain();
+    Ok(id)
+}
+
+#[update]
+#[candid_method(update)]
+/// Queues the payout of a participant's entire withdrawable holdings in a
+/// settled channel to `receiver`, less the ledger fee, authorized by the
+/// participant's signature over `Funding::withdraw_all_bytes`. The nonce is
+/// shared with the participant's signed withdrawal requests, and each is paid
+/// out once. Holdings reserved for a scheduled payment are kept. The payout's
+/// progress is reported by `withdrawal_status` under the returned id.
+async fn withdraw_all(
+    funding: Funding,
+    receiver: L1Account,
+    nonce: u64,
+    sig: Vec<u8>,
+) -> Result<WithdrawalId> {
+    unpaused!();
+    let fee = ledger::fee(read_state(|s| s.asset_or_ckbtc(funding.asset))).await;
+    let id =
+        mutate_state(|s| s.queue_withdraw_all(&funding, &receiver, nonce, &sig, fee, blocktime()))?;
+    transfer::schedule_drain();
+    Ok(id)
+}
+
+/// Reads the transfer in a block of an asset's ledger, failing unless the
+/// block may be credited and the caller sent the transfer.
+async fn query_callers_transfer(
+    asset: AssetId,
+    block_height: u64,
+) -> Result<receiver::IcrcTransfer> {
+    let querier = read_state(|s| s.block_querier(&asset, block_height))?;
+    let tx = querier
+        .query_icrc_tx(block_height)
+        .await
+        .map_err(ErrorCode::ReceiverError)?;
+    tx.require_sender(&ic_cdk::api::msg_caller())
+        .map_err(ErrorCode::ReceiverError)?;
+    Ok(tx)
+}
+
+/// Checks that a state belongs to the channel and is signed by all of its
+/// participants, in the order of the participant list.
+fn verify_signed(params: &Params, state: &State, sigs: &[Vec<u8>]) -> Result<()> {
+    (params, state).validate()?;
+    require!(state.channel == params.id(), InvalidInput);
+    require!(
+        sigs.len() == params.participants.len(),
+        Authentication {
+            participant_index: None
         }
+    );
+    let msg = state.signing_bytes();
+    for (i, (participant, sig)) in params.participants.iter().zip(sigs).enumerate() {
+        require!(
+            participant.verify(&msg, sig),
+            Authentication {
+                participant_index: Some(i as u32)
+            }
+        );
+    }
+    Ok(())
+}
+
+/// Fails unless the caller is a controller of this canister.
+fn require_controller() -> Result<()> {
+    require!(
+        ic_cdk::api::is_controller(&ic_cdk::api::msg_caller()),
+        Unauthorized
+    );
+    Ok(())
+}
+
+/// Notifies a callback about progress on a channel. The call is one-way, so
+/// a misbehaving callee cannot block or fail the notifying operation.
+fn notify(cb: &Callback, id: &ChannelId) {
+    if let Err(e) = Call::unbounded_wait(cb.canister, &cb.method)
+        .with_arg(id)
+        .oneway()
+    {
+        ic_cdk::println!("notifying {} failed: {:?}", cb.canister, e);
+    }
+}
+
+/// Number of ledger calls awaiting their response.
+static LEDGER_CALLS_IN_FLIGHT: AtomicU32 = AtomicU32::new(0);
+
+/// Counts an outstanding ledger call for as long as it is alive.
+pub struct LedgerCall;
+
+impl LedgerCall {
+    pub fn start() -> Self {
+        LEDGER_CALLS_IN_FLIGHT.fetch_add(1, Ordering::SeqCst);
+        LedgerCall
+    }
+
+    pub fn in_flight() -> u32 {
+        LEDGER_CALLS_IN_FLIGHT.load(Ordering::SeqCst)
     }
 }
 
+impl Drop for LedgerCall {
+    fn drop(&mut self) {
+        LEDGER_CALLS_IN_FLIGHT.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Calls `icrc1_transfer` on the given ledger.
+async fn icrc1_transfer(
+    ledger: Principal,
+    arg: TransferArg,
+) -> CallResult<std::result::Result<Nat, TransferError>> {
+    let _call = LedgerCall::start();
+    Ok(Call::unbounded_wait(ledger, "icrc1_transfer")
+        .with_arg(arg)
+        .await?
+        .candid()?)
+}
+
+/// Calls `icrc2_transfer_from` on the given ledger.
+async fn icrc2_transfer_from(
+    ledger: Principal,
+    arg: TransferFromArgs,
+) -> CallResult<std::result::Result<Nat, TransferFromError>> {
+    let _call = LedgerCall::start();
+    Ok(Call::unbounded_wait(ledger, "icrc2_transfer_from")
+        .with_arg(arg)
+        .await?
+        .candid()?)
+}
+
+/// Calls `icrc1_balance_of` on the given ledger.
+async fn icrc1_balance_of(ledger: Principal, account: Account) -> CallResult<Nat> {
+    let _call = LedgerCall::start();
+    Ok(Call::unbounded_wait(ledger, "icrc1_balance_of")
+        .with_arg(account)
+        .await?
+        .candid()?)
+}
+
 #[update]
 #[candid::candid_method]
-async fn trigger_withdraw(req: WithdrawalReq) -> std::result::Result<candid::Nat, error::Error> {
-    STATE.write().unwrap().withdraw_from_liq_pool(req).await
+/// Queues a payout of pool liquidity from the pool holdings of the request's
+/// receiver. Requires the `ApproveWithdrawals` scope.
+async fn trigger_withdraw(req: WithdrawalReq) -> Result<WithdrawalId> {
+    unpaused!();
+    let caller = ic_cdk::api::msg_caller();
+    let hash = audit::args_hash((&req,));
+    let fee = ledger::fee(config::current().ledger).await;
+    let outcome = mutate_state(|s| s.queue_pool_withdrawal(caller, req, fee, blocktime()));
+    audit::record("trigger_withdraw", caller, hash, &outcome);
+    if outcome.is_ok() {
+        transfer::schedule_drain();
+    }
+    outcome
 }
 
 impl<Q> CanisterState<Q>
 where
     Q: receiver::TXQuerier,
 {
-    pub fn new(q: Q, my_principal: Principal) -> Self {
+    pub fn new(q: Q, my_principal: Principal, signer: Arc<dyn attestation::Signer>) -> Self {
+        let channels = StableBTreeMap::init(memory::get(memory::CHANNELS));
         Self {
-            icrc_receiver: receiver::Receiver::new(q, my_principal),
-            user_holdings: Default::default(),
-            channels: Default::default(),
-            liq_pool_holdings: Default::default(),
+            icrc_receiver: receiver::Receiver::new(
+                q,
+                my_principal,
+                CanisterConfig::default().ledger,
+            ),
+            asset_receivers: Default::default(),
+            signer,
+            user_holdings: HoldingsMap::init(
+                memory::get(memory::HOLDINGS),
+                memory::get(memory::ASSET_HOLDINGS),
+            ),
+            certified_states: certified::states_tree(&channels),
+            channels,
+            params: Default::default(),
+            channels_of: Default::default(),
+            history: Default::default(),
+            events: Default::default(),
+            event_retention: None,
+            event_archive: EventArchive::init(
+                memory::get(memory::EVENT_ARCHIVE_INDEX),
+                memory::get(memory::EVENT_ARCHIVE_DATA),
+            ),
+            funding: Default::default(),
+            quarantine: Default::default(),
+            reminders: Default::default(),
+            forwards: Default::default(),
+            fee_policies: Default::default(),
+            payment_hashes: Default::default(),
+            withdrawal_queue: Default::default(),
+            dust: Default::default(),
+            observers: Default::default(),
+            privacy: Default::default(),
+            maintenance: Default::default(),
+            assets: [(CanisterConfig::default().ledger, AssetInfo::ckbtc())].into(),
+            scopes: Default::default(),
+            operators: Default::default(),
+            liquidity_ads: Default::default(),
+            swaps: Default::default(),
+            swap_limits: Default::default(),
+            next_swap_id: 0,
+            reputation: Default::default(),
+            statements: Default::default(),
+            invoices: Default::default(),
+            next_invoice_id: 0,
+            bridge: Default::default(),
+            processed: bridge::processed_requests(),
+            draining: false,
+            paused: false,
+            callback_canisters: Default::default(),
+            remote_canisters: Default::default(),
+            remote_fundings: Default::default(),
+            uploads: Default::default(),
+            holdings_log: Default::default(),
+            call_size_limits: Default::default(),
+            memo_registry: Default::default(),
+            withdrawal_nonces: NonceSet::init(memory::get(memory::WITHDRAWAL_NONCES)),
+            swap_nonces: NonceSet::init(memory::get(memory::SWAP_NONCES)),
+            challenge_policy: None,
+            config: Default::default(),
+            last_anchor: None,
+            payout_aliases: Default::default(),
+            shadow: Default::default(),
+            locked: Default::default(),
+            guards: Default::default(),
+            load_policy: None,
+            load: Default::default(),
+            ledger_metadata: Default::default(),
+            balances: Default::default(),
+            unattributed: Default::default(),
+            liq_pools: [(CanisterConfig::default().ledger, Default::default())].into(),
+            rewards: Default::default(),
+            reservations: Default::default(),
+            modifications: Default::default(),
+            asset_pauses: Default::default(),
+            acks: Default::default(),
+            memory_thresholds: None,
+            watermarks: Default::default(),
         }
     }
+    /// The size limit of a method's encoded arguments.
+    pub fn call_size_limit(&self, method: &str) -> u64 {
+        self.call_size_limits
+            .get(method)
+            .copied()
+            .unwrap_or_else(|| validation::default_call_size_limit(method))
+    }
+
     pub fn deposit(&mut self, funding: Funding, amount: Amount) -> Result<()> {
-        *self
-            .user_holdings
-            .entry(funding)
-            .or_insert(Default::default()) += amount;
+        self.credit(funding, amount, ChangeCause::Deposit);
         Ok(())
     }
 
-    pub fn deposit_liq_pool(
+    /// Checks a signed withdrawal request and deducts its amount from the
+    /// participant's holdings, to be paid out by the caller.
+    pub fn authorize_withdrawal(
         &mut self,
-        funding: u64, //PoolFunding,
-        amount: Amount,
-        depositor: L1Account,
+        req: &WithdrawalReq,
+        sig: &[u8],
+        now: Timestamp,
     ) -> Result<()> {
-        *self
-            .liq_pool_holdings
-            .entry(depositor.clone())
-            .or_insert(Default::default()) += amount;
+        let msg = req.signing_bytes();
+        require!(
+            req.participant.verify(&msg, sig),
+            Authentication {
+                participant_index: None
+            }
+        );
+        self.require_withdrawable(&req.channel, req.asset, now)?;
+        require!(req.expiry.is_none_or(|e| now < e), Expired);
+        let funding = req.funding();
+        self.require_unused_nonce(&funding, req.nonce)?;
+        let held = self.user_holdings.get(&funding).unwrap_or_default();
+        require!(
+            held >= req.amount,
+            InsufficientFunding {
+                required: req.amount.clone(),
+                available: held.clone(),
+            }
+        );
+        self.require_unreserved(&funding, &held, &req.amount, now)?;
+        self.withdrawal_nonces.insert(&funding, req.nonce);
+        self.debit(&funding, &req.amount, ChangeCause::Withdrawal);
         Ok(())
     }
 
-    pub async fn deposit_icrc(&mut self, time: Timestamp, funding: Funding) -> Result<()> {
-        let memo = funding.memo();
-        let amount = self.icrc_receiver.drain(memo);
-
-        self.deposit(funding.clone(), amount)?;
-        // events::STATE
-        //     .write()
-        //     .unwrap()
-        //     .register_event(
-        //         time,
-        //         funding.channel.clone(),
-        //         Event::Funded {
-        //             who: funding.participant.clone(),
-        //             total: self.user_holdings.get(&funding).cloned().unwrap(),
-        //             timestamp: time,
-        //         },
-        //     )
-        //     .await;
+    /// Deducts a participant's entire unreserved holdings, which must exceed
+    /// the ledger fee, and returns the withdrawal request sweeping them, to
+    /// be paid out less the fee.
+    pub fn authorize_withdraw_all(
+        &mut self,
+        funding: &Funding,
+        receiver: &L1Account,
+        nonce: u64,
+        sig: &[u8],
+        fee: &Amount,
+        now: Timestamp,
+    ) -> Result<WithdrawalReq> {
+        let msg = funding.withdraw_all_bytes(receiver, nonce);
+        require!(
+            funding.participant.verify(&msg, sig),
+            Authentication {
+                participant_index: None
+            }
+        );
+        self.require_withdrawable(&funding.channel, funding.asset, now)?;
+        self.require_unused_nonce(funding, nonce)?;
+        let held = self.user_holdings.get(funding).unwrap_or_default();
+        let swept = self.unreserved(funding, held, now);
+        require!(
+            swept > *fee,
+            Error::from(ErrorCode::InsufficientFunding {
+                required: fee.clone() + Amount::from(1u32),
+                available: swept.clone(),
+            })
+            .with("fee", fee)
+        );
+        self.withdrawal_nonces.insert(funding, nonce);
+        self.debit(funding, &swept, ChangeCause::Withdrawal);
+        Ok(WithdrawalReq {
+            channel: funding.channel.clone(),
+            participant: funding.participant.clone(),
+            amount: swept,
+            receiver: receiver.0,
+            nonce,
+            expiry: None,
+            asset: funding.asset,
+        })
+    }
+
+    /// Fails if a withdrawal from the funding was paid out under the nonce,
+    /// or is underway.
+    fn require_unused_nonce(&self, funding: &Funding, nonce: u64) -> Result<()> {
+        require!(
+            !self.withdrawal_nonces.contains(funding, nonce),
+            NonceReused { nonce: nonce }
+        );
         Ok(())
     }
 
-    pub async fn process_icrc_tx(
+    /// Fails unless the funds of a channel in an asset, none for ckBTC, can
+    /// be withdrawn: the canister accepts requests, the asset's withdrawals
+    /// are not paused and the channel is settled.
+    fn require_withdrawable(
         &mut self,
-        tx: receiver::BlockHeight,
-        amount: u64,
-        funding: Funding,
-    ) -> Option<Nat> {
-        match self.icrc_receiver.verify_icrc(tx, amount, funding).await {
-            Ok(v) => Some(v),
-            Err(_e) => None,
+        channel: &ChannelId,
+        asset: Option<AssetId>,
+        now: Timestamp,
+    ) -> Result<()> {
+        self.accepting()?;
+        self.require_unpaused(&self.asset_or_ckbtc(asset), Flow::Withdrawal)?;
+        self.require_unlocked(channel, now)?;
+        let registered = self
+            .channels
+            .get(channel)
+            .ok_or_else(|| Error::from(ErrorCode::NotFound).with("channel", channel))?;
+        require!(registered.settled(now), NotFinalized);
+        Ok(())
+    }
+
+    /// Fails with `NotFinalized` while a dispute of the channel is open, and
+    /// lifts the channel's lock once the dispute has settled.
+    pub fn require_unlocked(&mut self, channel: &ChannelId, now: Timestamp) -> Result<()> {
+        if !self.locked.contains(channel) {
+            return Ok(());
         }
+        let settled = self.channels.get(channel).is_none_or(|s| s.settled(now));
+        require!(settled, NotFinalized);
+        self.settle_dispute(channel, now);
+        Ok(())
     }
 
-    pub fn query_holdings(&self, funding: Funding) -> Option<Amount> {
-        self.user_holdings.get(&funding).cloned()
+    /// Records a withdrawal whose transfer succeeded as a `Withdrawn` event.
+    pub fn record_withdrawal(&mut self, req: &WithdrawalReq, now: Timestamp) {
+        let event = Event::Withdrawn {
+            who: req.participant.clone(),
+            amount: req.amount.clone(),
+            timestamp: now,
+        };
+        self.events.push(now, req.channel.clone(), event);
     }
 
-    pub fn query_liq_holdings(&self, depositor: L1Account) -> Option<Amount> {
-        self.liq_pool_holdings.get(&depositor).cloned()
+    /// Returns the funds of a withdrawal whose transfer failed, so that the
+    /// request can be retried.
+    pub fn revert_withdrawal(&mut self, req: &WithdrawalReq) {
+        let funding = req.funding();
+        self.withdrawal_nonces.remove(&funding, req.nonce);
+        self.credit(funding, req.amount.clone(), ChangeCause::Withdrawal);
     }
 
-    /// Queries a registered state.
-    pub fn state(&self, id: &ChannelId) -> Option<RegisteredState> {
-        self.channels.get(&id).cloned()
+    pub fn debit_balance(&mut self, who: &Principal, amount: &Amount) -> Result<()> {
+        let held = self.balances.get(who).cloned();
+        require!(
+            held.as_ref().is_some_and(|held| held >= amount),
+            InsufficientFunding {
+                required: amount.clone(),
+                available: held.unwrap_or_default(),
+            }
+        );
+        let rest = held.unwrap_or_default() - amount.clone();
+        match rest == Amount::default() {
+            true => self.balances.remove(who),
+            false => self.balances.insert(*who, rest),
+        };
+        Ok(())
     }
 
-    /// Updates the holdings associated with a channel to the outcome of the
-    /// supplied state, then registers the state. If the state is the channel's
-    /// initial state, the holdings are not updated, as initial states are
-    /// allowed to be under-funded and are otherwise expected to match the
-    /// deposit distribution exactly if fully funded.
-    fn register_channel(&mut self, params: &Params, state: RegisteredState) -> Result<()> {
-        let total = &self.holdings_total(&params);
-        if total < &state.state.total() {
-            require!(state.state.may_be_underfunded(), InsufficientFunding);
-        } else {
-            self.update_holdings(&params, &state.state);
-        }
+    pub fn deposit_liq_pool(
+        &mut self,
+        asset: &AssetId,
+        amount: Amount,
+        depositor: L1Account,
+        now: Timestamp,
+    ) -> Result<Amount> {
+        self.settle_rewards(&depositor, now);
+        self.pool_mut(asset)?.mint(depositor, amount)
+    }
 
-        self.channels.insert(state.state.channel.clone(), state);
+    pub fn deposit_icrc(&mut self, time: Timestamp, funding: Funding) -> Result<()> {
+        let asset = self.asset_or_ckbtc(funding.asset);
+        self.require_unpaused(&asset, Flow::Deposit)?;
+        let memo = funding.memo();
+        let amount = self.receiver_mut(&asset)?.drain(memo);
+        self.credit_deposit(funding, amount, time);
         Ok(())
     }
 
+    /// Credits a deposit to a funding and records it as a `Funded` event. If
+    /// the deposit completes the channel's announced funding, the funding
+    /// intent's callback is notified.
+    pub(crate) fn credit_deposit(&mut self, funding: Funding, amount: Amount, now: Timestamp) {
+        if amount == Amount::default() {
+            return;
+        }
+        self.credit(funding.clone(), amount, ChangeCause::Deposit);
+        let event = Event::Funded {
+            who: funding.participant.clone(),
+            total: self.user_holdings.get(&funding).unwrap_or_default(),
+            timestamp: now,
+        };
+        self.events.push(now, funding.channel.clone(), event);
+        if let Some(cb) = self.complete_funding(&funding.channel, now) {
+            notify(&cb, &funding.channel);
+        }
+    }
+
+    /// Credits a notified transfer read from the ledger of the funding's
+    /// asset, unless its block was credited before. Returns the transfer's
+    /// amount along with the funding's new total.
+    pub fn process_icrc_tx(
+        &mut self,
+        tx: &receiver::IcrcTransfer,
+        amount: u64,
+        funding: Funding,
+    ) -> Result<CreditedDeposit> {
+        let asset = self.asset_or_ckbtc(funding.asset);
+        self.require_unpaused(&asset, Flow::Deposit)?;
+        self.require_unprocessed(&asset, tx.block)?;
+        let receiver = self.receiver_mut(&asset)?;
+        let amount = receiver
+            .verify_icrc(tx, amount, &funding)
+            .map_err(ErrorCode::ReceiverError)?;
+        let memo = funding.memo();
+        let pending = receiver.unspent(memo);
+        self.mark_processed(&asset, tx.block)?;
+        Ok(CreditedDeposit {
+            amount,
+            memo,
+            total: self.user_holdings.get(&funding).unwrap_or_default() + pending,
+        })
+    }
+
+    /// Checks that a participant signed the funding intent and that its
+    /// callback is registered, then stores it in place of an intent of the
+    /// channel that is not funded yet.
+    pub fn register_signed_funding_intent(
+        &mut self,
+        intent: FundingIntent,
+        sig: &[u8],
+        now: Timestamp,
+    ) -> Result<ChannelId> {
+        intent.validate()?;
+        let msg = intent.signing_bytes();
+        require!(
+            intent
+                .params
+                .participants
+                .iter()
+                .any(|p| p.verify(&msg, sig)),
+            Authentication {
+                participant_index: None
+            }
+        );
+        if let Some(cb) = &intent.callback {
+            require!(
+                self.callback_canisters.contains(&cb.canister),
+                Error::from(ErrorCode::Unauthorized).with("callback", cb.canister)
+            );
+        }
+        let id = intent.params.id();
+        if let Some(f) = self.funding.get(&id) {
+            require!(f.funded_at.is_none(), AlreadyConcluded);
+            self.funding.remove(&id);
+        }
+        self.register_funding_intent(intent, now)
+    }
+
+    /// Stores a funding intent for the channel described by its parameters. If
+    /// the channel is already fully funded, its callback is called right away.
+    pub fn register_funding_intent(
+        &mut self,
+        intent: FundingIntent,
+        now: Timestamp,
+    ) -> Result<ChannelId> {
+        intent.validate()?;
+        let id = intent.params.id();
+        require!(!self.funding.contains_key(&id), InvalidInput);
+        let event = Event::Registered {
+            params: intent.params.clone(),
+            allocation: intent.allocation.clone(),
+            timestamp: now,
+        };
+        self.events.push(now, id.clone(), event);
+        self.funding.insert(
+            id.clone(),
+            ChannelFunding {
+                intent,
+                created_at: now,
+                funded_at: None,
+                top_up_version: 0,
+            },
+        );
+        if let Some(cb) = self.complete_funding(&id, now) {
+            notify(&cb, &id);
+        }
+        Ok(id)
+    }
+
+    /// Announces a channel with its initial state's allocation, if it was not
+    /// announced yet, and credits a participant's deposit to it.
+    pub fn fund_and_register(
+        &mut self,
+        params: Params,
+        initial_state: State,
+        my_index: u32,
+        tx: &receiver::IcrcTransfer,
+        now: Timestamp,
+    ) -> Result<ChannelFunding> {
+        (&params, &initial_state).validate()?;
+        let id = params.id();
+        require!(
+            initial_state.channel == id && initial_state.may_be_underfunded(),
+            InvalidInput
+        );
+        // Funding intents only cover ckBTC.
+        require!(initial_state.assets.is_empty(), InvalidInput);
+        let participant = params
+            .participants
+            .get(my_index as usize)
+            .ok_or_else(|| Error::from(ErrorCode::InvalidInput).with("my_index", my_index))?
+            .clone();
+        let announced = self.funding.get(&id);
+        if let Some(f) = announced {
+            require!(
+                f.intent.allocation == initial_state.allocation,
+                InvalidInput
+            );
+        }
+        let announced = announced.is_some();
+        let funding = Funding::new(id.clone(), participant);
+        self.process_icrc_tx(tx, 0, funding.clone())?;
+        if !announced {
+            let intent = FundingIntent {
+                params,
+                allocation: initial_state.allocation,
+                callback: None,
+            };
+            self.register_funding_intent(intent, now)?;
+        }
+        self.deposit_icrc(now, funding)?;
+        self.funding_status(&id).ok_or(ErrorCode::NotFound.into())
+    }
+
+    /// Credits a top-up deposit and sets the channel's holdings to the new
+    /// state's allocation. The deposit must be exactly the state's increase
+    /// over the channel's holdings.
+    pub fn top_up(
+        &mut self,
+        params: &Params,
+        state: State,
+        sigs: &[Vec<u8>],
+        my_index: u32,
+        tx: &receiver::IcrcTransfer,
+        now: Timestamp,
+    ) -> Result<ChannelFunding> {
+        self.accepting()?;
+        verify_signed(params, &state, sigs)?;
+        require!(!state.finalized && state.assets.is_empty(), InvalidInput);
+        let id = params.id();
+        require!(self.channels.get(&id).is_none(), AlreadyConcluded);
+        let f = self
+            .funding
+            .get(&id)
+            .ok_or_else(|| Error::from(ErrorCode::NotFound).with("channel", &id))?;
+        require!(f.funded_at.is_some(), InvalidInput);
+        require!(
+            state.version > f.top_up_version && !state.may_be_underfunded(),
+            OutdatedState {
+                registered_version: f.top_up_version,
+                submitted_version: state.version,
+            }
+        );
+        let participant = params
+            .participants
+            .get(my_index as usize)
+            .ok_or_else(|| Error::from(ErrorCode::InvalidInput).with("my_index", my_index))?
+            .clone();
+        let funding = Funding::new(id.clone(), participant);
+
+        let asset = self.asset_or_ckbtc(funding.asset);
+        self.require_unpaused(&asset, Flow::Deposit)?;
+        self.require_unprocessed(&asset, tx.block)?;
+        let receiver = self.receiver(&asset)?;
+        receiver
+            .require_new(tx.block)
+            .and_then(|()| receiver.check_icrc(tx, 0, &funding))
+            .map_err(ErrorCode::ReceiverError)?;
+        // Deposits made to the funding before count towards the increase.
+        let held = self.holdings_total(params) + receiver.unspent(funding.memo());
+        let increase = state.total() - held.clone().min(state.total());
+        require!(
+            increase > Amount::default() && increase == tx.amount,
+            Error::from(ErrorCode::InvalidInput)
+                .with("increase", &increase)
+                .with("deposit", tx.amount)
+        );
+        self.process_icrc_tx(tx, 0, funding.clone())?;
+        self.deposit_icrc(now, funding)?;
+        self.update_holdings(params, &state);
+        let f = self
+            .funding
+            .get_mut(&id)
+            .ok_or_else(|| Error::from(ErrorCode::NotFound).with("channel", &id))?;
+        f.top_up_version = state.version;
+        Ok(f.clone())
+    }
+
+    pub fn funding_status(&self, id: &ChannelId) -> Option<ChannelFunding> {
+        self.funding.get(id).cloned()
+    }
+
+    /// Lists the outstanding deposits of an announced channel.
+    fn missing_deposits(&self, f: &ChannelFunding) -> Vec<(L2Account, Amount)> {
+        let id = f.intent.params.id();
+        f.intent
+            .params
+            .participants
+            .iter()
+            .zip(f.intent.allocation.iter())
+            .filter_map(|(p, owed)| {
+                let held = self
+                    .user_holdings
+                    .get(&Funding::new(id.clone(), p.clone()))
+                    .unwrap_or_default();
+                (&held < owed).then(|| (p.clone(), owed.clone() - held))
+            })
+            .collect()
+    }
+
+    /// Lists announced channels involving the given participant that are
+    /// still underfunded after `FUNDING_GRACE_PERIOD`, oldest first.
+    pub fn underfunded_channels(
+        &self,
+        participant: &L2Account,
+        now: Timestamp,
+        limit: u32,
+    ) -> Vec<UnderfundedChannel> {
+        let mut overdue: Vec<(&ChannelId, &ChannelFunding)> = self
+            .funding
+            .iter()
+            .filter(|(_, f)| {
+                f.funded_at.is_none()
+                    && now.saturating_sub(f.created_at) >= FUNDING_GRACE_PERIOD
+                    && f.intent.params.participants.contains(participant)
+            })
+            .collect();
+        overdue.sort_by_key(|(id, f)| (f.created_at, *id));
+        overdue
+            .into_iter()
+            .take(limit.min(MAX_PAGE_LIMIT) as usize)
+            .map(|(id, f)| UnderfundedChannel {
+                channel: id.clone(),
+                created_at: f.created_at,
+                missing: self.missing_deposits(f),
+            })
+            .collect()
+    }
+
+    /// Whether every participant of an announced channel has deposited at
+    /// least their owed amount.
+    fn fully_funded(&self, f: &ChannelFunding) -> bool {
+        self.missing_deposits(f).is_empty()
+    }
+
+    /// Marks an announced channel as funded if all owed deposits arrived.
+    /// Returns the callback to notify if the channel just became fully funded.
+    fn complete_funding(&mut self, id: &ChannelId, now: Timestamp) -> Option<Callback> {
+        let f = self.funding.get(id)?;
+        if f.funded_at.is_some() || !self.fully_funded(f) {
+            return None;
+        }
+        let f = self.funding.get_mut(id)?;
+        f.funded_at = Some(now);
+        f.intent.callback.clone()
+    }
+
+    /// The amount a ledger block credited to a funding.
+    pub fn credited_deposit(
+        &self,
+        funding: &Funding,
+        block_height: receiver::BlockHeight,
+    ) -> Result<u64> {
+        let (memo, amount) = self
+            .icrc_receiver
+            .credited(block_height)
+            .ok_or_else(|| Error::from(ErrorCode::NotFound).with("block_height", block_height))?;
+        require!(memo == funding.memo(), InvalidInput);
+        Ok(amount)
+    }
+
+    /// Checks a credited ledger block against its transfer as read again from
+    /// the ledger, as deposits are checked when notified, and quarantines its
+    /// amount if the transfer is missing, no longer covers it or was not sent
+    /// to the canister with the funding's memo. Funds not yet moved into the
+    /// holdings are taken from the receiver first.
+    pub fn reverify_deposit(
+        &mut self,
+        funding: Funding,
+        block_height: receiver::BlockHeight,
+        tx: Option<&receiver::IcrcTransfer>,
+        now: Timestamp,
+    ) -> Result<Option<QuarantineId>> {
+        let amount = self.credited_deposit(&funding, block_height)?;
+        let verified = tx.is_some_and(|t| {
+            t.block == block_height && self.icrc_receiver.check_icrc(t, amount, &funding).is_ok()
+        });
+        if verified {
+            return Ok(None);
+        }
+
+        let memo = funding.memo();
+        let amount = Amount::from(amount);
+        let mut taken = self.icrc_receiver.take(memo, amount.clone());
+        if let Some(held) = self.user_holdings.get(&funding) {
+            let rest = held.clone().min(amount - taken.clone());
+            self.debit(&funding, &rest, ChangeCause::Quarantined);
+            taken += rest;
+        }
+        Ok(Some(self.quarantine.park(
+            funding,
+            taken,
+            QuarantineReason::FailedReverification { block_height },
+            now,
+        )))
+    }
+
+    /// Moves up to the given amount out of a funding's holdings into
+    /// quarantine.
+    pub fn quarantine_holdings(
+        &mut self,
+        funding: Funding,
+        amount: Amount,
+        reason: QuarantineReason,
+        now: Timestamp,
+    ) -> QuarantineId {
+        let held = self.query_holdings(funding.clone()).unwrap_or_default();
+        let taken = held.min(amount);
+        self.debit(&funding, &taken, ChangeCause::Quarantined);
+        self.quarantine.park(funding, taken, reason, now)
+    }
+
+    /// Moves all holdings of a channel into quarantine. Empty holdings are
+    /// skipped.
+    pub fn freeze_channel(&mut self, id: &ChannelId, now: Timestamp) -> Vec<QuarantineId> {
+        let fundings: Vec<(Funding, Amount)> = self
+            .user_holdings
+            .iter()
+            .filter(|(f, amount)| f.channel == *id && *amount > Amount::default())
+            .collect();
+        fundings
+            .into_iter()
+            .map(|(f, amount)| {
+                self.debit(&f, &amount, ChangeCause::Quarantined);
+                self.quarantine
+                    .park(f, amount, QuarantineReason::FrozenChannel, now)
+            })
+            .collect()
+    }
+
+    /// Records a vote for resolving quarantined funds and applies the
+    /// resolution once it has enough votes.
+    pub fn approve_resolution(
+        &mut self,
+        id: QuarantineId,
+        approver: Principal,
+        resolution: Resolution,
+    ) -> Result<bool> {
+        let Some((entry, resolution)) = self.quarantine.approve(id, approver, resolution)? else {
+            return Ok(false);
+        };
+        match resolution {
+            Resolution::Release => {
+                self.credit(entry.funding, entry.amount, ChangeCause::QuarantineResolved)
+            }
+            Resolution::Reassign(to) => {
+                self.credit(to, entry.amount, ChangeCause::QuarantineResolved)
+            }
+            Resolution::Discard => {}
+        }
+        Ok(true)
+    }
+
+    pub fn query_holdings(&self, funding: Funding) -> Option<Amount> {
+        self.user_holdings.get(&funding)
+    }
+
+    pub fn query_liq_holdings(&self, depositor: L1Account) -> Option<Amount> {
+        let pool = self.pool(&self.ckbtc()).ok()?;
+        let held = pool.shares_of(&depositor) > Amount::default();
+        held.then(|| pool.value_of(&depositor))
+    }
+
+    /// Queries a registered state.
+    pub fn state(&self, id: &ChannelId) -> Option<RegisteredState> {
+        self.channels.get(id)
+    }
+
+    /// Returns a page of registered channels, ordered by channel id.
+    pub fn channels_page(
+        &self,
+        caller: &Principal,
+        cursor: Option<Cursor>,
+        limit: u32,
+    ) -> Result<Page<(ChannelId, RegisteredState)>> {
+        paginate(
+            self.channels
+                .iter()
+                .filter(|(id, _)| self.may_read_channel(caller, id))
+                .map(|(id, state)| (id.clone(), (id, state))),
+            cursor,
+            limit,
+        )
+    }
+
+    /// Returns up to `limit` registered channels the caller may read from
+    /// the `offset`-th on, ordered by channel id.
+    pub fn channels_at(
+        &self,
+        caller: &Principal,
+        offset: u64,
+        limit: u64,
+    ) -> Vec<(ChannelId, RegisteredState)> {
+        let limit = match limit {
+            0 => MAX_PAGE_LIMIT,
+            l => l.min(MAX_PAGE_LIMIT as u64) as u32,
+        };
+        self.channels
+            .iter()
+            .filter(|(id, _)| self.may_read_channel(caller, id))
+            .skip(offset.try_into().unwrap_or(usize::MAX))
+            .take(limit as usize)
+            .collect()
+    }
+
+    pub fn channels_of(&self, participant: &L2Account) -> Vec<ChannelId> {
+        self.channels_of
+            .get(participant)
+            .map(|ids| ids.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// Queries the retained registered states of a channel, oldest first.
+    pub fn state_history(&self, id: &ChannelId) -> Vec<StateRecord> {
+        self.history
+            .get(id)
+            .map_or(vec![], |h| h.iter().cloned().collect())
+    }
+
+    /// Queries the most recent registered state of a channel with the given
+    /// version, if it is still contained in the channel's history.
+    pub fn state_at(&self, id: &ChannelId, version: Version) -> Option<StateRecord> {
+        self.history
+            .get(id)?
+            .iter()
+            .rev()
+            .find(|r| r.state.state.version == version)
+            .cloned()
+    }
+
+    /// Updates the holdings associated with a channel to the outcome of the
+    /// supplied state, then registers the state. If the state is the channel's
+    /// initial state, the holdings are not updated, as initial states are
+    /// allowed to be under-funded and are otherwise expected to match the
+    /// deposit distribution exactly if fully funded.
+    fn register_channel(
+        &mut self,
+        params: &Params,
+        state: RegisteredState,
+        now: Timestamp,
+    ) -> Result<()> {
+        for a in &state.state.assets {
+            self.require_channel_asset(&a.asset)?;
+        }
+        let shortfall = state.state.allocations().find_map(|(asset, allocation)| {
+            let total = allocation
+                .iter()
+                .fold(Amount::default(), |x, y| x + y.clone());
+            let held = self.holdings_in(params, asset);
+            (held < total).then_some((total, held))
+        });
+        match shortfall {
+            Some((total, held)) => require!(
+                state.state.may_be_underfunded(),
+                InsufficientFunding {
+                    required: total,
+                    available: held,
+                }
+            ),
+            None => self.update_holdings(params, &state.state),
+        }
+
+        let history = self.history.entry(state.state.channel.clone()).or_default();
+        if history.len() == STATE_HISTORY_LEN {
+            history.pop_front();
+        }
+        history.push_back(StateRecord {
+            state: state.clone(),
+            registered_at: now,
+        });
+        self.reminders.schedule(now, &state);
+
+        if state.settled(now) {
+            self.locked.remove(&state.state.channel);
+        } else {
+            self.locked.insert(state.state.channel.clone());
+        }
+        for p in &params.participants {
+            let channels = self.channels_of.entry(p.clone()).or_default();
+            channels.insert(state.state.channel.clone());
+        }
+        let event = if state.state.finalized {
+            Event::Concluded {
+                state: state.clone(),
+                timestamp: now,
+            }
+        } else {
+            Event::Disputed {
+                state: state.clone(),
+                timestamp: now,
+            }
+        };
+        self.touch_channel(&state.state.channel);
+        self.events.push(now, state.state.channel.clone(), event);
+        self.params
+            .insert(state.state.channel.clone(), params.clone());
+        let leaf = certified::leaf_bytes(&state);
+        self.certified_states
+            .insert(state.state.channel.0.to_vec(), &leaf);
+        self.channels.insert(state.state.channel.clone(), state);
+        Ok(())
+    }
+
+    /// Registers a finalized state after checking every participant's
+    /// signature on it.
+    pub fn conclude(
+        &mut self,
+        params: &Params,
+        state: State,
+        sigs: &[Vec<u8>],
+        now: Timestamp,
+    ) -> Result<()> {
+        verify_signed(params, &state, sigs)?;
+        require!(state.finalized, NotFinalized);
+        if let Some(registered) = self.channels.get(&state.channel) {
+            require!(!registered.settled(now), AlreadyConcluded);
+            require!(!registered.state.finalized, AlreadyConcluded);
+            require!(
+                registered.state.version <= state.version,
+                OutdatedState {
+                    registered_version: registered.state.version,
+                    submitted_version: state.version,
+                }
+            );
+        }
+        self.require_reservations_kept(params, &state, now)?;
+        self.register_channel(
+            params,
+            RegisteredState {
+                state,
+                timeout: now,
+            },
+            now,
+        )
+    }
+
+    /// Registers a non-finalized state signed by all participants, which
+    /// becomes withdrawable after the channel's effective challenge duration
+    /// unless a newer state is registered in time.
+    pub fn dispute(
+        &mut self,
+        params: &Params,
+        state: State,
+        sigs: &[Vec<u8>],
+        now: Timestamp,
+    ) -> Result<()> {
+        verify_signed(params, &state, sigs)?;
+        require!(!state.finalized, InvalidInput);
+        let acked = self.acked_version(&state.channel);
+        require!(
+            state.version >= acked,
+            Error::from(ErrorCode::OutdatedState {
+                registered_version: acked,
+                submitted_version: state.version,
+            })
+            .with("acked_version", acked)
+        );
+        if let Some(registered) = self.channels.get(&state.channel) {
+            require!(!registered.settled(now), AlreadyConcluded);
+            require!(
+                registered.state.version < state.version,
+                OutdatedState {
+                    registered_version: registered.state.version,
+                    submitted_version: state.version,
+                }
+            );
+        }
+        let timeout = now + self.challenge_duration(params);
+        self.register_channel(params, RegisteredState { state, timeout }, now)
+    }
+
+    /// Replaces the state of an open dispute with a newer one, restarting the
+    /// challenge duration.
+    pub fn refute(
+        &mut self,
+        params: &Params,
+        state: State,
+        sigs: &[Vec<u8>],
+        now: Timestamp,
+    ) -> Result<()> {
+        let registered = self
+            .channels
+            .get(&state.channel)
+            .ok_or_else(|| Error::from(ErrorCode::NotFound).with("channel", &state.channel))?;
+        require!(!registered.state.finalized, AlreadyConcluded);
+        self.dispute(params, state, sigs, now)
+    }
+
+    /// Finalizes an expired channel's last registered state. Channels without
+    /// a registered state, or only their possibly underfunded initial state,
+    /// are concluded with each participant's deposits.
+    pub fn force_conclude(
+        &mut self,
+        params: &Params,
+        participant: &L2Account,
+        signature: &[u8],
+        now: Timestamp,
+    ) -> Result<()> {
+        params.validate()?;
+        let expiry = params.expiry.ok_or(ErrorCode::InvalidInput)?;
+        require!(now >= expiry, NotExpired);
+        let index = params
+            .participants
+            .iter()
+            .position(|p| p == participant)
+            .ok_or(ErrorCode::Unauthorized)?;
+        require!(
+            participant.verify(&params.force_conclusion_bytes(), signature),
+            Authentication {
+                participant_index: Some(index as u32)
+            }
+        );
+
+        let channel = params.id();
+        let registered = self.channels.get(&channel);
+        require!(
+            !registered.as_ref().is_some_and(|r| r.state.finalized),
+            AlreadyConcluded
+        );
+        let state = match registered.filter(|r| !r.state.may_be_underfunded()) {
+            Some(registered) => registered.state.clone(),
+            None => {
+                let deposits = |asset: Option<AssetId>| -> Vec<Amount> {
+                    params
+                        .participants
+                        .iter()
+                        .map(|p| {
+                            let funding = Funding::new(channel.clone(), p.clone()).in_asset(asset);
+                            self.user_holdings.get(&funding).unwrap_or_default()
+                        })
+                        .collect()
+                };
+                let assets = self
+                    .asset_receivers
+                    .keys()
+                    .map(|asset| AssetAllocation {
+                        asset: *asset,
+                        allocation: deposits(Some(*asset)),
+                    })
+                    .filter(|a| a.allocation.iter().any(|x| *x > Amount::default()))
+                    .collect();
+                State {
+                    allocation: deposits(None),
+                    assets,
+                    channel,
+                    ..Default::default()
+                }
+            }
+        };
+        let state = RegisteredState {
+            state: State {
+                finalized: true,
+                ..state
+            },
+            timeout: now,
+        };
+        self.register_channel(params, state, now)
+    }
+
     /// Pushes a state's funding allocation into the channel's holdings mapping
     /// in the canister.
     fn update_holdings(&mut self, params: &Params, state: &State) {
-        for (i, outcome) in state.allocation.iter().enumerate() {
-            self.user_holdings.insert(
-                Funding::new(
-                    state.channel.clone(),
-                    params.participants[i].clone(),
-                    // state.l1_accounts[i].clone(),
-                ),
-                outcome.clone(),
-            );
+        let allocations: Vec<_> = state
+            .allocations()
+            .map(|(asset, allocation)| (asset, allocation.clone()))
+            .collect();
+        for (asset, allocation) in allocations {
+            for (i, outcome) in allocation.into_iter().enumerate() {
+                self.set_holdings(
+                    Funding::new(
+                        state.channel.clone(),
+                        params.participants[i].clone(),
+                        // state.l1_accounts[i].clone(),
+                    )
+                    .in_asset(asset),
+                    outcome,
+                    ChangeCause::StateRegistered,
+                );
+            }
         }
     }
 
-    /// Calculates the total funds held in a channel. If the channel is unknown
+    /// Calculates the total ckBTC held in a channel. If the channel is unknown
     /// and there are no deposited funds for the channel, returns 0.
     pub fn holdings_total(&self, params: &Params) -> Amount {
+        self.holdings_in(params, None)
+    }
+
+    /// Calculates the total funds held in a channel in an asset, none for
+    /// ckBTC.
+    pub fn holdings_in(&self, params: &Params, asset: Option<AssetId>) -> Amount {
         let mut acc = Amount::default();
         for pk in params.participants.iter() {
-            let funding = Funding::new(params.id(), pk.clone());
-            acc += self
-                .user_holdings
-                .get(&funding)
-                .unwrap_or(&Amount::default())
-                .clone();
+            let funding = Funding::new(params.id(), pk.clone()).in_asset(asset);
+            acc += self.user_holdings.get(&funding).unwrap_or_default();
         }
         acc
     }
+}
 
-    pub async fn withdraw_from_liq_pool(
-        &mut self,
-        req: WithdrawalReq,
-    ) -> std::result::Result<Nat, Error> {
-        let amount = req.amount.clone();
-
-        let (total_deducted, to_deduct) = match self.calculate_required_deductions(&amount) {
-            Ok(res) => res,
-            Err(_) => {
-                return Err(Error::InsufficientLiquidity);
-            }
-        };
+#[derive(CandidType)]
+pub struct CkAccount {
+    pub owner: Principal,
+    pub subaccount: Option<Vec<u8>>,
+}
 
-        let transfer_result = self.execute_ledger_transfer(&req, total_deducted).await;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use testing::*;
 
-        match transfer_result {
-            Ok(block_height) => {
-                self.apply_deductions(to_deduct);
-                Ok(block_height)
-            }
-            Err(error_msg) => Err(error_msg),
+    fn registered(version: Version) -> RegisteredState {
+        RegisteredState {
+            state: State {
+                version,
+                ..Default::default()
+            },
+            timeout: 0,
         }
     }
 
-    fn calculate_required_deductions(
-        &self,
-        amount: &Nat,
-    ) -> std::result::Result<(u64, Vec<(Funding, Nat)>), Error> {
-        let mut needed = amount.clone();
-        let mut to_deduct = Vec::new();
-        let zero = Nat::from(0u32);
-
-        for (acc, available) in &self.user_holdings {
-            if needed == zero {
-                break;
-            }
+    fn account(seed: u8) -> L2Account {
+        L2Account(
+            k256::SecretKey::from_slice(&[seed; 32])
+                .unwrap()
+                .public_key(),
+        )
+    }
 
-            let take = available.min(&needed);
-            if *take > zero {
-                to_deduct.push((acc.clone(), take.clone()));
-                needed -= take.clone();
-            }
+    fn empty_params() -> Params {
+        Params {
+            nonce: Nonce::default(),
+            participants: vec![],
+            challenge_duration: 0,
+            expiry: None,
         }
+    }
 
-        if needed > zero {
-            return Err(Error::InsufficientLiquidity);
+    #[test]
+    fn test_state_history_is_bounded() {
+        let mut s = new_state();
+        let params = empty_params();
+        let n = STATE_HISTORY_LEN as u64 + 3;
+        for v in 0..n {
+            s.register_channel(&params, registered(v), 100 + v).unwrap();
         }
 
-        let total = amount.clone() - needed;
-        let total_u64 = total.0.to_u64_digits()[0];
-        Ok((total_u64, to_deduct))
+        let id = ChannelId::default();
+        assert!(s.state_at(&id, 2).is_none());
+        let record = s.state_at(&id, 3).unwrap();
+        assert_eq!(record.state.state.version, 3);
+        assert_eq!(record.registered_at, 103);
+        assert_eq!(s.state(&id).unwrap().state.version, n - 1);
+
+        let history = s.state_history(&id);
+        assert_eq!(history.len(), STATE_HISTORY_LEN);
+        assert_eq!(history.first().unwrap().state.state.version, 3);
+        assert_eq!(history.last().unwrap().state.state.version, n - 1);
     }
 
-    async fn execute_ledger_transfer(
-        &self,
-        req: &WithdrawalReq,
-        amount_u64: u64,
-    ) -> std::result::Result<Nat, Error> {
-        let receiver = req.receiver;
+    #[test]
+    fn test_channels_listed_by_offset() {
+        let mut s = new_state();
+        let mut ids: Vec<ChannelId> = (1..=3).map(|n| concluded(&mut s, n, 1, 2)).collect();
+        ids.sort();
+        assert_eq!(s.channels.len(), 3);
+
+        let listed: Vec<ChannelId> = s
+            .channels_at(&Principal::anonymous(), 1, 5)
+            .into_iter()
+            .map(|(id, _)| id)
+            .collect();
+        assert!(listed == ids[1..]);
+        assert!(s.channels_at(&Principal::anonymous(), 0, 1)[0].0 == ids[0]);
+        assert!(s.channels_at(&Principal::anonymous(), 3, 5).is_empty());
+    }
+
+    #[test]
+    fn test_channels_found_by_participant() {
+        let mut s = new_state();
+        let first = concluded(&mut s, 1, 1, 2);
+        let second = concluded(&mut s, 2, 2, 3);
+        assert!(s.channels_of(&account(1)) == vec![first.clone()]);
+        assert!(s.channels_of(&account(3)) == vec![second.clone()]);
+        let mut both = vec![first, second];
+        both.sort();
+        assert!(s.channels_of(&account(2)) == both);
+        assert!(s.channels_of(&account(4)).is_empty());
+    }
+
+    #[test]
+    fn test_funding_completes_with_last_deposit() {
+        let mut s = new_state();
+        let params = Params {
+            participants: vec![account(1), account(2)],
+            ..empty_params()
+        };
+        let intent = FundingIntent {
+            params: params.clone(),
+            allocation: vec![Nat::from(10u32), Nat::from(20u32)],
+            callback: None,
+        };
+        let id = s.register_funding_intent(intent, 1).unwrap();
+
+        s.deposit(Funding::new(id.clone(), account(1)), Nat::from(10u32))
+            .unwrap();
+        s.complete_funding(&id, 2);
+        assert!(s.funding_status(&id).unwrap().funded_at.is_none());
+
+        s.deposit(Funding::new(id.clone(), account(2)), Nat::from(20u32))
+            .unwrap();
+        s.complete_funding(&id, 3);
+        assert_eq!(s.funding_status(&id).unwrap().funded_at, Some(3));
+    }
+
+    #[test]
+    fn test_underfunded_channels_after_grace_period() {
+        let mut s = new_state();
+        let intent = FundingIntent {
+            params: Params {
+                participants: vec![account(1), account(2)],
+                ..empty_params()
+            },
+            allocation: vec![Nat::from(10u32), Nat::from(20u32)],
+            callback: None,
+        };
+        let id = s.register_funding_intent(intent, 0).unwrap();
+        s.deposit(Funding::new(id.clone(), account(1)), Nat::from(10u32))
+            .unwrap();
+
+        assert!(
+            s.underfunded_channels(&account(1), FUNDING_GRACE_PERIOD - 1, 10)
+                .is_empty()
+        );
+        let overdue = s.underfunded_channels(&account(1), FUNDING_GRACE_PERIOD, 10);
+        assert_eq!(overdue.len(), 1);
+        assert_eq!(overdue[0].missing, vec![(account(2), Nat::from(20u32))]);
+        assert!(
+            s.underfunded_channels(&account(3), FUNDING_GRACE_PERIOD, 10)
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn test_funding_intents_are_signed_by_participants() {
+        let mut s = new_state();
+        let intent = |owed: u32, callback| FundingIntent {
+            params: Params {
+                participants: vec![account(1), account(2)],
+                ..empty_params()
+            },
+            allocation: vec![Nat::from(owed), Nat::from(owed)],
+            callback,
+        };
+        let bogus = intent(1, None);
+        let sig = sign(3, &bogus.signing_bytes());
+        assert_eq!(
+            s.register_signed_funding_intent(bogus, &sig, 0).err(),
+            Some(
+                ErrorCode::Authentication {
+                    participant_index: None
+                }
+                .into()
+            )
+        );
+        let cb = Callback {
+            canister: Principal::from_slice(&[9]),
+            method: "funded".into(),
+        };
+        let called = intent(10, Some(cb.clone()));
+        let sig = sign(1, &called.signing_bytes());
+        assert_eq!(
+            s.register_signed_funding_intent(called.clone(), &sig, 0)
+                .err(),
+            Some(ErrorCode::Unauthorized.into())
+        );
+        s.callback_canisters.insert(cb.canister);
+        let id = s.register_signed_funding_intent(called, &sig, 0).unwrap();
+
+        // A participant replaces the intent until the channel is funded.
+        let replaced = intent(20, None);
+        let sig = sign(2, &replaced.signing_bytes());
+        s.register_signed_funding_intent(replaced.clone(), &sig, 1)
+            .unwrap();
+        assert_eq!(
+            s.funding_status(&id).unwrap().intent.allocation,
+            replaced.allocation
+        );
+        for p in [1, 2] {
+            s.deposit(Funding::new(id.clone(), account(p)), Nat::from(20u32))
+                .unwrap();
+        }
+        s.complete_funding(&id, 2);
+        assert_eq!(
+            s.register_signed_funding_intent(replaced, &sig, 3).err(),
+            Some(ErrorCode::AlreadyConcluded.into())
+        );
+    }
 
-        let transfer_arg = TransferArg {
-            from_subaccount: None,
+    #[test]
+    fn test_freezing_skips_empty_holdings() {
+        let mut s = new_state();
+        let ch = concluded(&mut s, 1, 1, 2);
+        s.user_holdings
+            .insert(Funding::new(ch.clone(), account(3)), Amount::default());
+        assert_eq!(s.freeze_channel(&ch, 0).len(), 2);
+        assert_eq!(s.quarantine.total(), Amount::from(200u32));
+        assert_eq!(holdings(&s, &ch, 1), Amount::default());
+    }
+
+    #[test]
+    fn test_reverified_deposits_must_match_funding() {
+        let mut s = new_state();
+        let f = Funding::new(ChannelId([1; 32]), account(1));
+        let tx = receiver::IcrcTransfer {
+            block: 0,
+            from: None,
+            to: s.icrc_receiver.icrc_account(),
+            amount: 50,
+            memo: Some(f.memo().to_be_bytes().to_vec()),
+        };
+        s.process_icrc_tx(&tx, 50, f.clone()).unwrap();
+        s.deposit_icrc(0, f.clone()).unwrap();
+        assert_eq!(s.reverify_deposit(f.clone(), 0, Some(&tx), 1), Ok(None));
+
+        // The same amount sent elsewhere no longer verifies the deposit.
+        let elsewhere = receiver::IcrcTransfer {
             to: Account {
-                owner: receiver,
+                owner: Principal::from_slice(&[9]),
                 subaccount: None,
             },
-            amount: Nat(amount_u64.into()),
-            fee: Some(Nat(DEFAULT_CKBTC_FEE.into())),
-            memo: None,
-            created_at_time: None,
+            ..tx
+        };
+        assert!(
+            s.reverify_deposit(f.clone(), 0, Some(&elsewhere), 1)
+                .unwrap()
+                .is_some()
+        );
+        assert_eq!(s.quarantine.total_of(&f), Amount::from(50u32));
+        assert_eq!(s.query_holdings(f), None);
+    }
+
+    #[test]
+    fn test_notified_transfers_must_match_funding() {
+        let mut s = new_state();
+        let f = Funding::new(ChannelId([1; 32]), account(1));
+        let other = Funding::new(ChannelId([1; 32]), account(2));
+        let to = s.icrc_receiver.icrc_account();
+        let transfer = |block, to, memo: u64| receiver::IcrcTransfer {
+            block,
+            from: None,
+            to,
+            amount: 50,
+            memo: Some(memo.to_be_bytes().to_vec()),
+        };
+        let elsewhere = Account {
+            owner: Principal::from_slice(&[9]),
+            subaccount: None,
         };
+        s.icrc_receiver
+            .querier()
+            .register_icrc_transfer(transfer(0, to, f.memo()));
+        let paid = block_on(s.icrc_receiver.tx_querier().query_icrc_tx(0)).unwrap();
 
-        let ckbtc_ledger_id = Principal::from_text(DEVNET_CKBTC_LEDGER).expect("parsing principal");
+        let rejected = |e| Err(ErrorCode::ReceiverError(e).into());
+        assert_eq!(
+            s.process_icrc_tx(&transfer(1, elsewhere, f.memo()), 50, f.clone()),
+            rejected(receiver::ICPReceiverError::Recipient)
+        );
+        assert_eq!(
+            s.process_icrc_tx(&paid, 50, other),
+            rejected(receiver::ICPReceiverError::Memo)
+        );
+        assert_eq!(
+            s.process_icrc_tx(&paid, 51, f.clone()),
+            rejected(receiver::ICPReceiverError::Amount)
+        );
+        let credited = |amount: u32, total: u32| CreditedDeposit {
+            amount: Amount::from(amount),
+            memo: f.memo(),
+            total: Amount::from(total),
+        };
+        assert_eq!(
+            s.process_icrc_tx(&paid, 40, f.clone()),
+            Ok(credited(50, 50))
+        );
+        assert_eq!(
+            s.process_icrc_tx(&paid, 40, f.clone()),
+            Err(ErrorCode::AlreadyProcessed.into())
+        );
 
-        let call_result: CallResult<(
-            std::result::Result<Nat, icrc_ledger_types::icrc1::transfer::TransferError>,
-        )> = ic_cdk::call(ckbtc_ledger_id, "icrc1_transfer", (transfer_arg,)).await;
+        // Deposited funds count towards the total.
+        s.deposit_icrc(0, f.clone()).unwrap();
+        assert_eq!(
+            s.process_icrc_tx(&transfer(2, to, f.memo()), 50, f.clone()),
+            Ok(credited(50, 100))
+        );
+    }
+
+    #[test]
+    fn test_notified_funds_survive_upgrades() {
+        let mut s = new_state();
+        let f = Funding::new(ChannelId([1; 32]), account(1));
+        let paid = receiver::IcrcTransfer {
+            block: 0,
+            from: None,
+            to: s.icrc_receiver.icrc_account(),
+            amount: 50,
+            memo: Some(f.memo().to_be_bytes().to_vec()),
+        };
+        s.process_icrc_tx(&paid, 50, f.clone()).unwrap();
 
-        match call_result {
-            Ok((inner_result,)) => match inner_result {
-                Ok(block_height) => Ok(block_height),
-                Err(_e) => Err(Error::LedgerError),
+        let mut s = new_state();
+        assert_eq!(s.icrc_receiver.unspent(f.memo()), Amount::from(50u32));
+        assert_eq!(
+            s.icrc_receiver.take(f.memo(), Amount::from(20u32)),
+            Amount::from(20u32)
+        );
+        assert_eq!(
+            new_state().icrc_receiver.drain(f.memo()),
+            Amount::from(30u32)
+        );
+        assert_eq!(
+            new_state().icrc_receiver.unspent(f.memo()),
+            Amount::default()
+        );
+    }
+
+    #[test]
+    fn test_notified_transfers_must_be_sent_by_caller() {
+        let depositor = Principal::from_slice(&[42]);
+        let mut tx = receiver::IcrcTransfer {
+            block: 0,
+            from: None,
+            to: Account {
+                owner: Principal::anonymous(),
+                subaccount: None,
             },
-            Err((_code, _msg)) => Err(Error::LedgerError),
+            amount: 50,
+            memo: None,
+        };
+        assert_eq!(
+            tx.require_sender(&depositor),
+            Err(receiver::ICPReceiverError::Sender)
+        );
+        tx.from = Some(Account {
+            owner: depositor,
+            subaccount: Some([1; 32]),
+        });
+        tx.require_sender(&depositor).unwrap();
+        assert_eq!(
+            tx.require_sender(&Principal::anonymous()),
+            Err(receiver::ICPReceiverError::Sender)
+        );
+    }
+
+    #[test]
+    fn test_channel_funded_and_registered_in_one_call() {
+        let mut s = new_state();
+        let params = Params {
+            participants: vec![account(1), account(2)],
+            ..empty_params()
+        };
+        let id = params.id();
+        let initial = State {
+            channel: id.clone(),
+            allocation: vec![Nat::from(30u32), Nat::from(20u32)],
+            ..Default::default()
+        };
+        let to = s.icrc_receiver.icrc_account();
+        let transfer = |block, i: u8, amount| {
+            let memo = Funding::new(id.clone(), account(i)).memo();
+            receiver::IcrcTransfer {
+                block,
+                from: None,
+                to,
+                amount,
+                memo: Some(memo.to_be_bytes().to_vec()),
+            }
+        };
+
+        assert_eq!(
+            s.fund_and_register(params.clone(), initial.clone(), 2, &transfer(0, 1, 30), 1)
+                .err(),
+            Some(ErrorCode::InvalidInput.into())
+        );
+        let status = s
+            .fund_and_register(params.clone(), initial.clone(), 0, &transfer(0, 1, 30), 1)
+            .unwrap();
+        assert_eq!(status.created_at, 1);
+        assert!(status.funded_at.is_none());
+        assert_eq!(
+            s.query_holdings(Funding::new(id.clone(), account(1))),
+            Some(Nat::from(30u32))
+        );
+
+        let other = State {
+            allocation: vec![Nat::from(30u32), Nat::from(25u32)],
+            ..initial.clone()
+        };
+        assert_eq!(
+            s.fund_and_register(params.clone(), other, 1, &transfer(1, 2, 20), 2)
+                .err(),
+            Some(ErrorCode::InvalidInput.into())
+        );
+        let status = s
+            .fund_and_register(params.clone(), initial, 1, &transfer(1, 2, 20), 2)
+            .unwrap();
+        assert_eq!(status.created_at, 1);
+        assert_eq!(status.funded_at, Some(2));
+
+        // Bob tops up 10 after paying Alice 5 off-chain.
+        let topped_up = State {
+            channel: id.clone(),
+            version: 3,
+            allocation: vec![Nat::from(35u32), Nat::from(25u32)],
+            finalized: false,
+            assets: vec![],
+        };
+        let sigs: Vec<_> = [1, 2].map(|p| sign(p, &topped_up.signing_bytes())).into();
+        // 4 of the 10 were notified before, but not credited yet.
+        s.process_icrc_tx(&transfer(5, 2, 4), 0, Funding::new(id.clone(), account(2)))
+            .unwrap();
+        let seq = s.holdings_log.last_seq();
+        assert_eq!(
+            s.top_up(&params, topped_up.clone(), &sigs, 1, &transfer(2, 2, 10), 3)
+                .err(),
+            Some(ErrorCode::InvalidInput.into())
+        );
+        assert_eq!(
+            s.top_up(&params, topped_up.clone(), &sigs, 1, &transfer(2, 1, 6), 3)
+                .err(),
+            Some(ErrorCode::ReceiverError(receiver::ICPReceiverError::Memo).into())
+        );
+        // Rejected top-ups leave the holdings untouched.
+        assert_eq!(s.holdings_log.last_seq(), seq);
+        let status = s
+            .top_up(&params, topped_up.clone(), &sigs, 1, &transfer(2, 2, 6), 3)
+            .unwrap();
+        assert_eq!(status.top_up_version, 3);
+        assert_eq!(holdings(&s, &id, 1), Nat::from(35u32));
+        assert_eq!(holdings(&s, &id, 2), Nat::from(25u32));
+        assert_eq!(
+            s.top_up(&params, topped_up, &sigs, 1, &transfer(3, 2, 10), 4)
+                .err(),
+            Some(
+                ErrorCode::OutdatedState {
+                    registered_version: 3,
+                    submitted_version: 3
+                }
+                .into()
+            )
+        );
+    }
+
+    #[test]
+    fn test_signed_withdrawal_pays_out_once() {
+        let mut s = new_state();
+        let ch = concluded(&mut s, 1, 1, 2);
+        let req = WithdrawalReq {
+            channel: ch.clone(),
+            participant: account(1),
+            amount: Nat::from(60u32),
+            receiver: Principal::from_slice(&[7]),
+            nonce: 0,
+            expiry: Some(10),
+            asset: None,
+        };
+        let sig = sign(1, &req.signing_bytes());
+        assert_eq!(
+            s.authorize_withdrawal(&req, &sign(2, &req.signing_bytes()), 0),
+            Err(ErrorCode::Authentication {
+                participant_index: None
+            }
+            .into())
+        );
+        s.authorize_withdrawal(&req, &sig, 0).unwrap();
+        assert_eq!(holdings(&s, &ch, 1), Nat::from(40u32));
+        assert_eq!(
+            s.authorize_withdrawal(&req, &sig, 0),
+            Err(ErrorCode::NonceReused { nonce: 0 }.into())
+        );
+        // Used nonces are kept in stable memory, across upgrades.
+        assert!(new_state().withdrawal_nonces.contains(&req.funding(), 0));
+
+        // A failed transfer returns the funds and allows a retry.
+        s.revert_withdrawal(&req);
+        assert_eq!(holdings(&s, &ch, 1), Nat::from(100u32));
+        s.authorize_withdrawal(&req, &sig, 0).unwrap();
+
+        let all = WithdrawalReq {
+            amount: Nat::from(41u32),
+            nonce: 1,
+            ..req
+        };
+        let sig = sign(1, &all.signing_bytes());
+        assert_eq!(
+            s.authorize_withdrawal(&all, &sig, 0),
+            Err(ErrorCode::InsufficientFunding {
+                required: 41u32.into(),
+                available: 40u32.into()
+            }
+            .into())
+        );
+
+        // The remainder is withdrawable with another nonce until the expiry.
+        let rest = WithdrawalReq {
+            amount: Nat::from(40u32),
+            ..all
+        };
+        let sig = sign(1, &rest.signing_bytes());
+        assert_eq!(
+            s.authorize_withdrawal(&rest, &sig, 10),
+            Err(ErrorCode::Expired.into())
+        );
+        s.authorize_withdrawal(&rest, &sig, 9).unwrap();
+        assert_eq!(holdings(&s, &ch, 1), Nat::default());
+    }
+
+    #[test]
+    fn test_withdraw_all_sweeps_holdings() {
+        let mut s = new_state();
+        let ch = concluded(&mut s, 1, 1, 2);
+        let funding = Funding::new(ch.clone(), account(2));
+        let receiver = L1Account(Principal::from_slice(&[7]));
+        let sig = sign(2, &funding.withdraw_all_bytes(&receiver, 1));
+        let fee = Nat::from(10u32);
+        let stranger = L1Account(Principal::anonymous());
+        assert_eq!(
+            s.authorize_withdraw_all(&funding, &stranger, 1, &sig, &fee, 0)
+                .err(),
+            Some(
+                ErrorCode::Authentication {
+                    participant_index: None
+                }
+                .into()
+            )
+        );
+        assert_eq!(
+            s.authorize_withdraw_all(&funding, &receiver, 2, &sig, &fee, 0)
+                .err(),
+            Some(
+                ErrorCode::Authentication {
+                    participant_index: None
+                }
+                .into()
+            )
+        );
+        let req = s
+            .authorize_withdraw_all(&funding, &receiver, 1, &sig, &fee, 0)
+            .unwrap();
+        assert_eq!(req.amount, Nat::from(100u32));
+        assert!(req.funding() == funding);
+        assert_eq!(holdings(&s, &ch, 2), Nat::default());
+
+        // The signature does not sweep funds credited later.
+        s.deposit(funding.clone(), Nat::from(50u32)).unwrap();
+        assert_eq!(
+            s.authorize_withdraw_all(&funding, &receiver, 1, &sig, &fee, 0)
+                .err(),
+            Some(ErrorCode::NonceReused { nonce: 1 }.into())
+        );
+        let sig = sign(2, &funding.withdraw_all_bytes(&receiver, 2));
+        s.authorize_withdraw_all(&funding, &receiver, 2, &sig, &fee, 0)
+            .unwrap();
+        let sig = sign(2, &funding.withdraw_all_bytes(&receiver, 3));
+        assert_eq!(
+            s.authorize_withdraw_all(&funding, &receiver, 3, &sig, &fee, 0)
+                .err(),
+            Some(
+                ErrorCode::InsufficientFunding {
+                    required: 11u32.into(),
+                    available: 0u32.into()
+                }
+                .into()
+            )
+        );
+    }
+
+    #[test]
+    fn test_conclude_requires_all_signatures() {
+        let mut s = new_state();
+        let params = Params {
+            participants: vec![account(1), account(2)],
+            ..empty_params()
+        };
+        let id = params.id();
+        for p in [1, 2] {
+            s.deposit(Funding::new(id.clone(), account(p)), Nat::from(100u32))
+                .unwrap();
+        }
+        let mut state = State {
+            channel: id.clone(),
+            version: 5,
+            allocation: vec![Nat::from(150u32), Nat::from(50u32)],
+            finalized: false,
+            assets: vec![],
+        };
+        let sigs = |state: &State| {
+            vec![
+                sign(1, &state.signing_bytes()),
+                sign(2, &state.signing_bytes()),
+            ]
+        };
+
+        assert_eq!(
+            s.conclude(&params, state.clone(), &sigs(&state), 1),
+            Err(ErrorCode::NotFinalized.into())
+        );
+        state.finalized = true;
+        let mut forged = sigs(&state);
+        forged[1] = sign(1, &state.signing_bytes());
+        assert_eq!(
+            s.conclude(&params, state.clone(), &forged, 1),
+            Err(ErrorCode::Authentication {
+                participant_index: Some(1)
+            }
+            .into())
+        );
+        s.conclude(&params, state.clone(), &sigs(&state), 1)
+            .unwrap();
+        assert_eq!(holdings(&s, &id, 1), Nat::from(150u32));
+        assert_eq!(holdings(&s, &id, 2), Nat::from(50u32));
+        assert!(s.state(&id).unwrap().settled(1));
+        assert_eq!(
+            s.conclude(&params, state.clone(), &sigs(&state), 2),
+            Err(ErrorCode::AlreadyConcluded.into())
+        );
+    }
+
+    #[test]
+    fn test_dispute_rejects_outdated_states() {
+        let mut s = new_state();
+        let params = Params {
+            participants: vec![account(1), account(2)],
+            challenge_duration: 10,
+            ..empty_params()
+        };
+        let id = params.id();
+        for p in [1, 2] {
+            s.deposit(Funding::new(id.clone(), account(p)), Nat::from(100u32))
+                .unwrap();
+        }
+        let state = |version| State {
+            channel: id.clone(),
+            version,
+            allocation: vec![Nat::from(120u32), Nat::from(80u32)],
+            finalized: false,
+            assets: vec![],
+        };
+        let sigs = |state: &State| {
+            vec![
+                sign(1, &state.signing_bytes()),
+                sign(2, &state.signing_bytes()),
+            ]
+        };
+
+        s.dispute(&params, state(3), &sigs(&state(3)), 5).unwrap();
+        let registered = s.state(&id).unwrap();
+        assert_eq!(registered.timeout, 15);
+        assert!(!registered.settled(14));
+        assert_eq!(
+            s.dispute(&params, state(2), &sigs(&state(2)), 6),
+            Err(ErrorCode::OutdatedState {
+                registered_version: 3,
+                submitted_version: 2
+            }
+            .into())
+        );
+        s.dispute(&params, state(4), &sigs(&state(4)), 7).unwrap();
+        assert_eq!(s.state(&id).unwrap().state.version, 4);
+        assert_eq!(
+            s.require_unlocked(&id, 16),
+            Err(ErrorCode::NotFinalized.into())
+        );
+        assert_eq!(
+            s.dispute(&params, state(5), &sigs(&state(5)), 17),
+            Err(ErrorCode::AlreadyConcluded.into())
+        );
+        assert_eq!(holdings(&s, &id, 1), Nat::from(120u32));
+        s.require_unlocked(&id, 17).unwrap();
+        assert!(!s.locked.contains(&id));
+    }
+
+    #[test]
+    fn test_conclude_rejects_settled_disputes() {
+        let mut s = new_state();
+        let params = Params {
+            participants: vec![account(1), account(2)],
+            challenge_duration: 10,
+            ..empty_params()
+        };
+        let id = params.id();
+        for p in [1, 2] {
+            s.deposit(Funding::new(id.clone(), account(p)), Nat::from(100u32))
+                .unwrap();
+        }
+        let state = |version, finalized| State {
+            channel: id.clone(),
+            version,
+            allocation: vec![
+                Nat::from(120u32 - version as u32),
+                Nat::from(80u32 + version as u32),
+            ],
+            finalized,
+            assets: vec![],
+        };
+        let sigs = |state: &State| {
+            vec![
+                sign(1, &state.signing_bytes()),
+                sign(2, &state.signing_bytes()),
+            ]
+        };
+
+        let disputed = state(3, false);
+        s.dispute(&params, disputed.clone(), &sigs(&disputed), 5)
+            .unwrap();
+        let concluding = state(4, true);
+        assert_eq!(
+            s.conclude(&params, concluding.clone(), &sigs(&concluding), 15),
+            Err(ErrorCode::AlreadyConcluded.into())
+        );
+        assert_eq!(s.state(&id).unwrap().state.version, 3);
+        assert_eq!(holdings(&s, &id, 1), Nat::from(117u32));
+    }
+
+    #[test]
+    fn test_refute_replaces_disputed_state() {
+        let mut s = new_state();
+        let params = Params {
+            nonce: Nonce([5; 32]),
+            participants: vec![account(1), account(2)],
+            challenge_duration: 10,
+            ..empty_params()
+        };
+        let id = params.id();
+        for p in [1, 2] {
+            s.deposit(Funding::new(id.clone(), account(p)), Nat::from(100u32))
+                .unwrap();
         }
+        let state = |version| State {
+            channel: id.clone(),
+            version,
+            allocation: vec![
+                Nat::from(90u32 + version as u32),
+                Nat::from(110u32 - version as u32),
+            ],
+            finalized: false,
+            assets: vec![],
+        };
+        let sigs = |state: &State| {
+            vec![
+                sign(1, &state.signing_bytes()),
+                sign(2, &state.signing_bytes()),
+            ]
+        };
+
+        assert_eq!(
+            s.refute(&params, state(1), &sigs(&state(1)), 0),
+            Err(ErrorCode::NotFound.into())
+        );
+        s.dispute(&params, state(1), &sigs(&state(1)), 0).unwrap();
+        assert_eq!(
+            s.refute(&params, state(1), &sigs(&state(1)), 5),
+            Err(ErrorCode::OutdatedState {
+                registered_version: 1,
+                submitted_version: 1
+            }
+            .into())
+        );
+        s.refute(&params, state(2), &sigs(&state(2)), 5).unwrap();
+        let registered = s.state(&id).unwrap();
+        assert_eq!(registered.state.version, 2);
+        assert_eq!(registered.timeout, 15);
+        assert_eq!(holdings(&s, &id, 1), Nat::from(92u32));
+        assert_eq!(
+            s.refute(&params, state(3), &sigs(&state(3)), 15),
+            Err(ErrorCode::AlreadyConcluded.into())
+        );
     }
 
-    fn apply_deductions(&mut self, to_deduct: Vec<(Funding, Nat)>) {
-        let zero = Nat(0u64.into());
+    #[test]
+    fn test_expired_channel_concludes_with_deposits() {
+        let mut s = new_state();
+        let params = Params {
+            participants: vec![account(1), account(2)],
+            expiry: Some(100),
+            ..empty_params()
+        };
+        let id = params.id();
+        s.deposit(Funding::new(id.clone(), account(1)), Nat::from(10u32))
+            .unwrap();
+        let msg = params.force_conclusion_bytes();
+
+        assert_eq!(
+            s.force_conclude(&params, &account(2), &sign(2, &msg), 99),
+            Err(ErrorCode::NotExpired.into())
+        );
+        assert_eq!(
+            s.force_conclude(&params, &account(3), &sign(3, &msg), 100),
+            Err(ErrorCode::Unauthorized.into())
+        );
+        assert_eq!(
+            s.force_conclude(&params, &account(2), &sign(1, &msg), 100),
+            Err(ErrorCode::Authentication {
+                participant_index: Some(1)
+            }
+            .into())
+        );
+        s.force_conclude(&params, &account(2), &sign(2, &msg), 100)
+            .unwrap();
+
+        let state = s.state(&id).unwrap();
+        assert!(state.settled(100));
+        assert_eq!(
+            state.state.allocation,
+            vec![Nat::from(10u32), Nat::from(0u32)]
+        );
+        assert_eq!(holdings(&s, &id, 1), Nat::from(10u32));
+        assert_eq!(
+            s.force_conclude(&params, &account(2), &sign(2, &msg), 101),
+            Err(ErrorCode::AlreadyConcluded.into())
+        );
+    }
 
-        for (acc, take) in to_deduct {
-            if let Some(entry) = self.user_holdings.get_mut(&acc) {
-                *entry -= take;
-                if *entry == zero {
-                    self.user_holdings.remove(&acc);
+    /// Request paths must not trap midway through a state mutation. Outside
+    /// of tests, only failures of stable memory, which cannot be recovered
+    /// from, may trap, through an `expect` naming the failed step.
+    #[test]
+    fn test_request_paths_avoid_panicking_calls() {
+        // Only calls are checked; indexing and arithmetic can still panic.
+        const BANNED: [&str; 6] = [
+            ".unwrap()",
+            "panic!(",
+            "unreachable!(",
+            "todo!(",
+            "unimplemented!(",
+            "_digits()[",
+        ];
+        const TRAPPING_STEPS: [&str; 5] = [
+            "encoding",
+            "decoding",
+            "initializing",
+            "appending",
+            "storing",
+        ];
+        let dir = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("src");
+        for entry in std::fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            if path.file_name().unwrap() == "testing.rs" {
+                continue;
+            }
+            let source = std::fs::read_to_string(&path).unwrap();
+            let code = source.split("#[cfg(test)]\nmod tests").next().unwrap();
+            for (i, line) in code.lines().enumerate() {
+                let at = format!("{}:{}", path.display(), i + 1);
+                assert!(!BANNED.iter().any(|b| line.contains(b)), "{at}: {line}");
+                if let Some((_, msg)) = line.split_once(".expect(\"") {
+                    assert!(
+                        TRAPPING_STEPS.iter().any(|s| msg.starts_with(s)),
+                        "{at}: {line}"
+                    );
                 }
             }
         }
     }
-}
 
-#[derive(CandidType)]
-pub struct ckAccount {
-    pub owner: Principal,
-    pub subaccount: Option<Vec<u8>>,
+    #[test]
+    fn test_ledger_principals() {
+        assert_eq!(
+            receiver::MAINNET_ICP_LEDGER.to_text(),
+            "bkyz2-fmaaa-aaaaa-qaaaq-cai"
+        );
+        assert_eq!(DEVNET_CKBTC_LEDGER.to_text(), "bd3sg-teaaa-aaaaa-qaaba-cai");
+    }
 }
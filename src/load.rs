@@ -0,0 +1,155 @@
+//  Copyright 2025 PolyCrypt GmbH
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+
+//! Load shedding under heavy update traffic. Load is measured as the number
+//! of updates admitted in the current round, i.e. at the current block time,
+//! and as the size of the heap. Once a threshold of the load policy is
+//! exceeded, low-priority updates such as queue enqueues and metadata writes
+//! are rejected with `Busy`. Critical updates, such as dispute registrations,
+//! are always admitted, so that participants can protect their funds even
+//! while the canister is flooded.
+
+use crate::audit;
+use crate::error::*;
+use crate::receiver::TXQuerier;
+use crate::types::*;
+use crate::{CanisterState, mutate_state, read_state};
+use candid::candid_method;
+use ic_cdk::api::time as blocktime;
+use ic_cdk::{query, update};
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Priority {
+    /// Shed while the canister is overloaded.
+    Low,
+    /// Always admitted.
+    Critical,
+}
+
+#[derive(Clone, Copy, Deserialize, CandidType, PartialEq, Eq, Debug)]
+/// Thresholds above which low-priority updates are shed.
+pub struct LoadPolicy {
+    /// Most updates admitted per round before shedding.
+    pub max_updates_per_round: u32,
+    /// Heap size in bytes above which updates are shed.
+    pub max_heap_bytes: u64,
+}
+
+#[derive(Clone, Deserialize, CandidType, PartialEq, Eq, Debug)]
+pub struct LoadStatus {
+    /// Updates admitted in the current round.
+    pub updates_in_round: u32,
+    pub heap_bytes: u64,
+    /// Updates shed since the canister was installed or upgraded.
+    pub shed: u64,
+}
+
+#[derive(Default)]
+pub struct Load {
+    round: Timestamp,
+    updates: u32,
+    shed: u64,
+}
+
+#[update]
+#[candid_method(update)]
+/// Sets the thresholds for shedding low-priority updates, or disables load
+/// shedding if `policy` is empty. Admin only.
+fn set_load_policy(policy: Option<LoadPolicy>) -> Result<()> {
+    audit::logged("set_load_policy", audit::args_hash((policy,)), || {
+        crate::admin::require_admin()?;
+        mutate_state(|s| s.load_policy = policy);
+        Ok(())
+    })
+}
+
+#[query]
+#[candid_method(query)]
+fn query_load_policy() -> Option<LoadPolicy> {
+    read_state(|s| s.load_policy)
+}
+
+#[query]
+#[candid_method(query)]
+fn query_load() -> LoadStatus {
+    read_state(|s| LoadStatus {
+        updates_in_round: s.load.updates_at(blocktime()),
+        heap_bytes: heap_bytes(),
+        shed: s.load.shed,
+    })
+}
+
+/// Admits the calling update, or rejects it with `Busy` if it has low
+/// priority and the canister is overloaded.
+pub fn admit(priority: Priority) -> Result<()> {
+    mutate_state(|s| s.admit(priority, heap_bytes(), blocktime()))
+}
+
+/// The size of the canister's heap.
+pub fn heap_bytes() -> u64 {
+    #[cfg(target_arch = "wasm32")]
+    return core::arch::wasm32::memory_size(0) as u64 * 65_536;
+    #[cfg(not(target_arch = "wasm32"))]
+    return 0;
+}
+
+impl Load {
+    fn updates_at(&self, now: Timestamp) -> u32 {
+        if self.round == now { self.updates } else { 0 }
+    }
+}
+
+impl<Q: TXQuerier> CanisterState<Q> {
+    pub fn admit(&mut self, priority: Priority, heap_bytes: u64, now: Timestamp) -> Result<()> {
+        let updates = self.load.updates_at(now);
+        let overloaded = self
+            .load_policy
+            .is_some_and(|p| updates >= p.max_updates_per_round || heap_bytes >= p.max_heap_bytes);
+        if overloaded && priority == Priority::Low {
+            self.load.shed += 1;
+            return Err(ErrorCode::Busy.into());
+        }
+        self.load.round = now;
+        self.load.updates = updates + 1;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+
+    #[test]
+    fn test_low_priority_updates_are_shed() {
+        let mut s = new_state();
+        s.load_policy = Some(LoadPolicy {
+            max_updates_per_round: 2,
+            max_heap_bytes: 1_000,
+        });
+        s.admit(Priority::Low, 0, 1).unwrap();
+        s.admit(Priority::Critical, 0, 1).unwrap();
+        assert_eq!(s.admit(Priority::Low, 0, 1), Err(ErrorCode::Busy.into()));
+        s.admit(Priority::Critical, 0, 1).unwrap();
+
+        // The next round starts afresh, unless the heap is full.
+        s.admit(Priority::Low, 0, 2).unwrap();
+        assert_eq!(
+            s.admit(Priority::Low, 1_000, 3),
+            Err(ErrorCode::Busy.into())
+        );
+        s.admit(Priority::Critical, 1_000, 3).unwrap();
+        assert_eq!(s.load.shed, 2);
+    }
+}
@@ -0,0 +1,189 @@
+//  Copyright 2025 PolyCrypt GmbH
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+
+//! Scheduled maintenance windows. Admins announce downtime ahead of time, so
+//! that integrators can plan for it: the window is shown by `health` with a
+//! countdown, every open channel receives a `MaintenanceScheduled` event
+//! `MAINTENANCE_NOTICE` before the start, and the canister drains for the
+//! duration of the window. Draining ends with the window unless it was
+//! started by hand.
+
+use crate::audit;
+use crate::error::*;
+use crate::events::Event;
+use crate::receiver::TXQuerier;
+use crate::types::*;
+use crate::{CanisterState, mutate_state, read_state, require};
+use candid::candid_method;
+use ic_cdk::api::time as blocktime;
+use ic_cdk::{query, update};
+use std::collections::BTreeSet;
+
+/// How long before a maintenance window open channels are warned (one hour).
+pub const MAINTENANCE_NOTICE: Duration = 3_600_000_000_000;
+
+/// How often the maintenance window is checked (one minute).
+pub const MAINTENANCE_CHECK_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60);
+
+#[derive(Clone, Deserialize, CandidType, PartialEq, Eq, Debug)]
+pub struct MaintenanceWindow {
+    pub start: Timestamp,
+    pub duration: Duration,
+    /// Why the canister goes into maintenance, shown to integrators.
+    pub reason: String,
+}
+
+#[derive(Default)]
+pub struct Maintenance {
+    window: Option<MaintenanceWindow>,
+    /// Whether open channels were warned of the window.
+    warned: bool,
+    /// Whether the window started the draining in effect.
+    draining: bool,
+}
+
+#[update]
+#[candid_method(update)]
+/// Schedules a maintenance window, replacing the scheduled one, or cancels
+/// it if `window` is empty. Admin only.
+fn schedule_maintenance(window: Option<MaintenanceWindow>) -> Result<()> {
+    audit::logged("schedule_maintenance", audit::args_hash((&window,)), || {
+        crate::admin::require_admin()?;
+        mutate_state(|s| s.schedule_maintenance(window, blocktime()))
+    })
+}
+
+#[query]
+#[candid_method(query)]
+fn query_maintenance() -> Option<MaintenanceWindow> {
+    read_state(|s| s.maintenance.window.clone())
+}
+
+/// Warns of, starts and ends the scheduled maintenance window as due.
+pub fn check_maintenance() {
+    mutate_state(|s| s.check_maintenance(blocktime()));
+}
+
+impl MaintenanceWindow {
+    pub fn end(&self) -> Timestamp {
+        self.start.saturating_add(self.duration)
+    }
+}
+
+impl Maintenance {
+    pub fn window(&self) -> Option<&MaintenanceWindow> {
+        self.window.as_ref()
+    }
+}
+
+impl<Q: TXQuerier> CanisterState<Q> {
+    pub fn schedule_maintenance(
+        &mut self,
+        window: Option<MaintenanceWindow>,
+        now: Timestamp,
+    ) -> Result<()> {
+        if let Some(w) = &window {
+            require!(
+                w.start > now,
+                Error::from(ErrorCode::InvalidInput).with("start", w.start)
+            );
+            require!(w.duration > 0, InvalidInput);
+        }
+        if self.maintenance.draining {
+            self.draining = false;
+        }
+        self.maintenance = Maintenance {
+            window,
+            ..Default::default()
+        };
+        self.check_maintenance(now);
+        Ok(())
+    }
+
+    pub fn check_maintenance(&mut self, now: Timestamp) {
+        let Some(window) = self.maintenance.window.clone() else {
+            return;
+        };
+        if now >= window.end() {
+            if self.maintenance.draining {
+                self.draining = false;
+            }
+            self.maintenance = Maintenance::default();
+            return;
+        }
+        if !self.maintenance.warned && now >= window.start.saturating_sub(MAINTENANCE_NOTICE) {
+            for channel in self.open_channels(now) {
+                let event = Event::MaintenanceScheduled {
+                    start: window.start,
+                    duration: window.duration,
+                    reason: window.reason.clone(),
+                    timestamp: now,
+                };
+                self.events.push(now, channel, event);
+            }
+            self.maintenance.warned = true;
+        }
+        if now >= window.start && !self.maintenance.draining && !self.draining {
+            self.draining = true;
+            self.maintenance.draining = true;
+        }
+    }
+
+    /// The channels that are announced or registered and not settled yet.
+    fn open_channels(&self, now: Timestamp) -> BTreeSet<ChannelId> {
+        let registered = self.channels.iter().filter(|(_, s)| !s.settled(now));
+        let announced = self
+            .funding
+            .keys()
+            .filter(|id| !self.channels.contains_key(id));
+        registered
+            .map(|(id, _)| id)
+            .chain(announced.cloned())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+
+    #[test]
+    fn test_maintenance_window_warns_and_drains() {
+        let mut s = new_state();
+        let start = 2 * MAINTENANCE_NOTICE;
+        let window = MaintenanceWindow {
+            start,
+            duration: 100,
+            reason: "ledger migration".into(),
+        };
+        assert_eq!(
+            s.schedule_maintenance(Some(window.clone()), start),
+            Err(ErrorCode::InvalidInput.into())
+        );
+        s.schedule_maintenance(Some(window), 0).unwrap();
+        assert!(!s.maintenance.warned);
+
+        s.check_maintenance(start - MAINTENANCE_NOTICE);
+        assert!(s.maintenance.warned);
+        assert!(s.accepting().is_ok());
+        assert_eq!(s.health(start - 10).maintenance_in, Some(10));
+
+        s.check_maintenance(start);
+        assert_eq!(s.accepting(), Err(ErrorCode::Draining.into()));
+        s.check_maintenance(start + 100);
+        assert!(s.accepting().is_ok());
+        assert!(s.maintenance.window().is_none());
+    }
+}
@@ -0,0 +1,172 @@
+//  Copyright 2025 PolyCrypt GmbH
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+
+//! Funding through the canister's default account. A depositor reserves a
+//! memo for a funding and transfers to the canister's default account with
+//! that memo. Scanning the transfer's block credits the funding. Reserved
+//! memos are unique, unlike the hash-derived `Funding::memo`, which two
+//! fundings may share.
+
+use crate::error::*;
+use crate::pause::Flow;
+use crate::receiver::{BlockHeight, Memo, TXQuerier, TransactionNotification};
+use crate::subaccount::deposit_subaccount;
+use crate::types::*;
+use crate::{CanisterState, mutate_state, read_state};
+use candid::candid_method;
+use ic_cdk::api::time as blocktime;
+use ic_cdk::{query, update};
+use std::collections::{BTreeMap, HashMap};
+
+#[derive(Default)]
+pub struct MemoRegistry {
+    fundings: BTreeMap<Memo, Funding>,
+    memos: HashMap<Funding, Memo>,
+    /// Registered fundings by their deposit subaccount.
+    subaccounts: BTreeMap<[u8; 32], Funding>,
+}
+
+#[update]
+#[candid_method(update)]
+/// Reserves a memo for transfers to the canister's default account that fund
+/// `funding`. Registering a funding again returns its memo. Registered
+/// fundings are credited automatically by the ledger scan, for transfers
+/// with the memo as well as into the funding's deposit account.
+fn register_memo(funding: Funding) -> Memo {
+    mutate_state(|s| s.memo_registry.register(funding))
+}
+
+#[query]
+#[candid_method(query)]
+fn query_memo_funding(memo: Memo) -> Option<Funding> {
+    read_state(|s| s.memo_registry.fundings.get(&memo).cloned())
+}
+
+#[update]
+#[candid_method(update)]
+/// Scans a ledger block for a transfer to the canister's default account and
+/// credits it to the funding its memo is reserved for. Returns the credited
+/// amount.
+async fn scan_block(block_height: BlockHeight) -> Result<Amount> {
+    crate::unpaused!();
+    let querier = read_state(|s| s.block_querier(&s.ckbtc(), block_height))?;
+    let tx = querier
+        .query_tx(block_height)
+        .await
+        .map_err(ErrorCode::ReceiverError)?;
+    mutate_state(|s| s.scan_block(block_height, &tx, blocktime()))
+}
+
+impl MemoRegistry {
+    /// Reserves the funding's hash-derived memo, or the next free one after
+    /// it if another funding holds it. Transfers to the canister's accounts
+    /// are scanned on the ckBTC ledger, so the memo funds the participant's
+    /// ckBTC.
+    pub fn register(&mut self, funding: Funding) -> Memo {
+        let funding = funding.in_asset(None);
+        if let Some(memo) = self.memos.get(&funding) {
+            return *memo;
+        }
+        let mut memo = funding.memo();
+        while self.fundings.contains_key(&memo) {
+            memo = memo.wrapping_add(1);
+        }
+        self.fundings.insert(memo, funding.clone());
+        self.subaccounts
+            .insert(deposit_subaccount(&funding), funding.clone());
+        self.memos.insert(funding, memo);
+        memo
+    }
+
+    pub fn funding(&self, memo: Memo) -> Option<&Funding> {
+        self.fundings.get(&memo)
+    }
+
+    /// The registered funding whose deposit subaccount this is.
+    pub fn subaccount_funding(&self, subaccount: &[u8; 32]) -> Option<&Funding> {
+        self.subaccounts.get(subaccount)
+    }
+}
+
+impl<Q: TXQuerier> CanisterState<Q> {
+    /// Verifies the transfer read from a block and credits it to the funding
+    /// reserved for its memo. Transfers with unreserved memos stay with the
+    /// receiver.
+    pub fn scan_block(
+        &mut self,
+        block_height: BlockHeight,
+        tx: &TransactionNotification,
+        now: Timestamp,
+    ) -> Result<Amount> {
+        self.require_unpaused(&self.config.ledger, Flow::Deposit)?;
+        self.require_unprocessed(&self.ckbtc(), block_height)?;
+        let amount = self
+            .icrc_receiver
+            .verify(block_height, tx)
+            .map_err(ErrorCode::ReceiverError)?;
+        let (memo, _) = self
+            .icrc_receiver
+            .credited(block_height)
+            .ok_or_else(|| Error::from(ErrorCode::NotFound).with("block_height", block_height))?;
+        let funding = self
+            .memo_registry
+            .funding(memo)
+            .cloned()
+            .ok_or(ErrorCode::NotFound)?;
+        self.mark_processed(&self.ckbtc(), block_height)?;
+        let amount = self.icrc_receiver.take(memo, amount);
+        self.credit_deposit(funding.clone(), amount.clone(), now);
+        Ok(amount)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::receiver::TransactionNotification;
+    use crate::testing::*;
+
+    #[test]
+    fn test_reserved_memos_are_unique() {
+        let mut r = MemoRegistry::default();
+        let a = Funding::new(ChannelId([1; 32]), account(1));
+        let b = Funding::new(ChannelId([2; 32]), account(1));
+        let memo = r.register(a.clone());
+        assert_eq!(r.register(a.clone()), memo);
+
+        // Another funding whose hash-derived memo collides moves on.
+        r.fundings.insert(b.memo(), a.clone());
+        assert_ne!(r.register(b.clone()), b.memo());
+    }
+
+    #[test]
+    fn test_scanned_transfer_credits_reserved_funding() {
+        let mut s = new_state();
+        let f = Funding::new(ChannelId([1; 32]), account(1));
+        let memo = s.memo_registry.register(f.clone());
+        let to = s.icrc_receiver.account();
+        let tx = |memo| TransactionNotification {
+            to,
+            amount: 40,
+            memo,
+        };
+        assert_eq!(s.scan_block(1, &tx(memo), 0), Ok(Amount::from(40u32)));
+        assert_eq!(holdings(&s, &f.channel, 1), Amount::from(40u32));
+        assert_eq!(
+            s.scan_block(2, &tx(memo + 1), 0),
+            Err(ErrorCode::NotFound.into())
+        );
+        assert!(s.scan_block(1, &tx(memo), 0).is_err());
+    }
+}
@@ -0,0 +1,60 @@
+//  Copyright 2025 PolyCrypt GmbH
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+
+//! Layout of the canister's stable memory. Every stable structure gets its
+//! own virtual memory; ids must never be reused for a different structure.
+
+use ic_stable_structures::DefaultMemoryImpl;
+use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
+use std::cell::RefCell;
+
+pub type Memory = VirtualMemory<DefaultMemoryImpl>;
+
+/// Index of the audit log.
+pub const AUDIT_LOG_INDEX: MemoryId = MemoryId::new(0);
+/// Entries of the audit log.
+pub const AUDIT_LOG_DATA: MemoryId = MemoryId::new(1);
+/// Credited ledger blocks.
+pub const PROCESSED_BLOCKS: MemoryId = MemoryId::new(2);
+/// Running hash of the audit log.
+pub const AUDIT_HEAD: MemoryId = MemoryId::new(3);
+/// Holdings of fundings.
+pub const HOLDINGS: MemoryId = MemoryId::new(4);
+/// Latest registered states of channels.
+pub const CHANNELS: MemoryId = MemoryId::new(5);
+/// Index of the archived events.
+pub const EVENT_ARCHIVE_INDEX: MemoryId = MemoryId::new(6);
+/// Archived events.
+pub const EVENT_ARCHIVE_DATA: MemoryId = MemoryId::new(7);
+/// Holdings of fundings in assets other than ckBTC.
+pub const ASSET_HOLDINGS: MemoryId = MemoryId::new(8);
+/// Admin principals.
+pub const ADMINS: MemoryId = MemoryId::new(9);
+/// Nonces of the signed withdrawal requests used.
+pub const WITHDRAWAL_NONCES: MemoryId = MemoryId::new(10);
+/// Nonces of the signed swap requests used.
+pub const SWAP_NONCES: MemoryId = MemoryId::new(11);
+/// Results of processed bridge-originated updates.
+pub const BRIDGE_REQUESTS: MemoryId = MemoryId::new(12);
+/// Funds received per memo and not spent yet.
+pub const UNSPENT_FUNDS: MemoryId = MemoryId::new(13);
+
+thread_local! {
+    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> =
+        RefCell::new(MemoryManager::init(DefaultMemoryImpl::default()));
+}
+
+pub fn get(id: MemoryId) -> Memory {
+    MEMORY_MANAGER.with(|m| m.borrow().get(id))
+}
@@ -0,0 +1,214 @@
+//  Copyright 2025 PolyCrypt GmbH
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+
+//! Watch-only observers of channels, such as auditors and escrow agents. A
+//! participant of a channel can register principals that may read the
+//! channel's detailed state, events and holdings through `observe_channel`,
+//! whatever restrictions apply to public queries. Observers gain no write
+//! capability.
+
+use crate::error::*;
+use crate::events::RegEvent;
+use crate::page::MAX_PAGE_LIMIT;
+use crate::receiver::TXQuerier;
+use crate::types::*;
+use crate::view::ChannelView;
+use crate::{CanisterState, mutate_state, read_state, require};
+use candid::{Principal, candid_method};
+use ic_cdk::api::time as blocktime;
+use ic_cdk::{query, update};
+use std::collections::{BTreeMap, BTreeSet};
+
+#[derive(Clone, Copy, Deserialize, CandidType, PartialEq, Eq, Debug)]
+/// A participant's registration or removal of an observer of their channel.
+pub struct ObserverChange {
+    pub observer: Principal,
+    /// Whether the observer is added or removed.
+    pub add: bool,
+    /// Increases with each change of the channel's observers so that old
+    /// changes cannot be replayed.
+    pub seq: u64,
+}
+
+#[derive(Default)]
+pub struct Observers {
+    observers: BTreeMap<ChannelId, BTreeSet<Principal>>,
+    /// The sequence number of each channel's latest change.
+    seqs: BTreeMap<ChannelId, u64>,
+}
+
+#[derive(Clone, Deserialize, CandidType)]
+/// What an observer reads of a channel.
+pub struct ObservedChannel {
+    pub view: ChannelView,
+    /// Up to `MAX_PAGE_LIMIT` events at or after the requested time.
+    pub events: Vec<RegEvent>,
+}
+
+#[update]
+#[candid_method(update)]
+/// Adds or removes an observer of a channel. The signature is by one of the
+/// channel's participants over `ObserverChange::signing_bytes`.
+fn set_channel_observer(channel: ChannelId, change: ObserverChange, sig: Vec<u8>) -> Result<()> {
+    mutate_state(|s| s.set_channel_observer(&channel, change, &sig))
+}
+
+#[query]
+#[candid_method(query)]
+fn query_channel_observers(channel: ChannelId) -> Vec<Principal> {
+    read_state(|s| s.observers.of(&channel))
+}
+
+#[query]
+#[candid_method(query)]
+/// Returns a channel's view and its events since `since` to one of its
+/// observers.
+fn observe_channel(channel: ChannelId, since: Timestamp) -> Result<ObservedChannel> {
+    let caller = ic_cdk::api::msg_caller();
+    read_state(|s| s.observe_channel(&caller, &channel, since, blocktime()))
+}
+
+impl ObserverChange {
+    /// The bytes a participant signs to make the change.
+    pub fn signing_bytes(&self, channel: &ChannelId) -> Vec<u8> {
+        let mut data = b"ckLightning observer".to_vec();
+        data.extend_from_slice(&channel.0);
+        data.extend_from_slice(self.observer.as_slice());
+        data.push(self.add as u8);
+        data.extend_from_slice(&self.seq.to_le_bytes());
+        data
+    }
+}
+
+impl Observers {
+    pub fn of(&self, channel: &ChannelId) -> Vec<Principal> {
+        self.observers
+            .get(channel)
+            .map(|o| o.iter().copied().collect())
+            .unwrap_or_default()
+    }
+
+    pub fn is_observer(&self, channel: &ChannelId, principal: &Principal) -> bool {
+        self.observers
+            .get(channel)
+            .is_some_and(|o| o.contains(principal))
+    }
+}
+
+impl<Q: TXQuerier> CanisterState<Q> {
+    pub fn set_channel_observer(
+        &mut self,
+        channel: &ChannelId,
+        change: ObserverChange,
+        sig: &[u8],
+    ) -> Result<()> {
+        let params = self
+            .channel_params(channel)
+            .ok_or_else(|| Error::from(ErrorCode::NotFound).with("channel", channel))?;
+        let msg = change.signing_bytes(channel);
+        require!(
+            params.participants.iter().any(|p| p.verify(&msg, sig)),
+            Authentication {
+                participant_index: None
+            }
+        );
+        if let Some(seq) = self.observers.seqs.get(channel) {
+            require!(
+                change.seq > *seq,
+                OutdatedState {
+                    registered_version: *seq,
+                    submitted_version: change.seq,
+                }
+            );
+        }
+        self.observers.seqs.insert(channel.clone(), change.seq);
+        let observers = self.observers.observers.entry(channel.clone()).or_default();
+        match change.add {
+            true => observers.insert(change.observer),
+            false => observers.remove(&change.observer),
+        };
+        if observers.is_empty() {
+            self.observers.observers.remove(channel);
+        }
+        Ok(())
+    }
+
+    pub fn observe_channel(
+        &self,
+        caller: &Principal,
+        channel: &ChannelId,
+        since: Timestamp,
+        now: Timestamp,
+    ) -> Result<ObservedChannel> {
+        require!(self.observers.is_observer(channel, caller), Unauthorized);
+        let view = self
+            .channel_view(channel, now)
+            .ok_or_else(|| Error::from(ErrorCode::NotFound).with("channel", channel))?;
+        Ok(ObservedChannel {
+            view,
+            events: self.events.events_since(channel, since, MAX_PAGE_LIMIT),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+
+    #[test]
+    fn test_observer_registered_by_participant() {
+        let mut s = new_state();
+        let ch = concluded(&mut s, 71, 1, 2);
+        let auditor = Principal::from_slice(&[9]);
+        let add = ObserverChange {
+            observer: auditor,
+            add: true,
+            seq: 1,
+        };
+        let msg = add.signing_bytes(&ch);
+
+        assert_eq!(
+            s.observe_channel(&auditor, &ch, 0, 1).map(|_| ()),
+            Err(ErrorCode::Unauthorized.into())
+        );
+        assert_eq!(
+            s.set_channel_observer(&ch, add, &sign(3, &msg)),
+            Err(ErrorCode::Authentication {
+                participant_index: None
+            }
+            .into())
+        );
+        s.set_channel_observer(&ch, add, &sign(2, &msg)).unwrap();
+        let observed = s.observe_channel(&auditor, &ch, 0, 1).unwrap();
+        assert!(observed.view.id == ch);
+        assert_eq!(
+            s.set_channel_observer(&ch, add, &sign(2, &msg)),
+            Err(ErrorCode::OutdatedState {
+                registered_version: 1,
+                submitted_version: 1
+            }
+            .into())
+        );
+
+        let remove = ObserverChange {
+            observer: auditor,
+            add: false,
+            seq: 2,
+        };
+        let sig = sign(1, &remove.signing_bytes(&ch));
+        s.set_channel_observer(&ch, remove, &sig).unwrap();
+        assert!(s.observers.of(&ch).is_empty());
+    }
+}
@@ -0,0 +1,405 @@
+//  Copyright 2025 PolyCrypt GmbH
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+
+//! Bridge operators connect the canister's channel network to the Lightning
+//! Network. Operators are registered by the controllers and advertise, per
+//! direction, up to which amount they serve swaps and at what price. This lets
+//! payers pick an operator and the canister quote end-to-end routes.
+
+use crate::audit;
+use crate::error::*;
+use crate::htlc::Leg;
+use crate::page::{Cursor, Page, paginate};
+use crate::permission::Scope;
+use crate::receiver::TXQuerier;
+use crate::require;
+use crate::types::*;
+use crate::{CanisterState, mutate_state, read_state};
+use candid::{CandidType, Principal, candid_method};
+use ic_cdk::api::time as blocktime;
+use ic_cdk::{query, update};
+
+/// Estimated time for one hop within the canister, which settles within a
+/// single update call (seconds).
+pub const CANISTER_HOP_LATENCY_SECS: u64 = 2;
+
+/// Denominator of fee rates given in parts per million.
+pub const PPM: u64 = 1_000_000;
+
+#[derive(Clone, Copy, Deserialize, CandidType, PartialEq, Eq, PartialOrd, Ord, Debug)]
+/// The direction in which an operator moves funds across the bridge.
+pub enum Direction {
+    /// From the canister into Lightning: the operator pays Lightning invoices
+    /// and is paid in ckBTC.
+    ToLightning,
+    /// From Lightning into the canister: the operator is paid on Lightning and
+    /// pays out ckBTC.
+    FromLightning,
+}
+
+#[derive(Clone, Deserialize, CandidType)]
+/// A bridge operator registered with the canister.
+pub struct OperatorInfo {
+    /// The operator's identity in canister channels, which sends and receives
+    /// the canister side of bridged payments.
+    pub hub: L2Account,
+    /// The operator's estimate of how long its Lightning hop takes (seconds).
+    pub latency_secs: u64,
+}
+
+#[derive(Clone, Deserialize, CandidType)]
+/// An operator's offer to serve bridged payments in one direction.
+pub struct LiquidityAd {
+    pub direction: Direction,
+    /// The largest payment the operator serves.
+    pub max_amount: Amount,
+    /// The operator's fee in parts per million of the payment.
+    pub fee_ppm: u32,
+    /// When the advertisement lapses.
+    pub expiry: Timestamp,
+}
+
+#[derive(Clone, Default, Deserialize, CandidType)]
+/// How reliably an operator served the swaps and invoices assigned to it.
+pub struct Reputation {
+    /// Assignments the operator completed.
+    pub completed: u64,
+    /// Assignments the operator failed to serve in time.
+    pub missed: u64,
+}
+
+#[derive(Clone, Deserialize, CandidType)]
+/// A candidate end-to-end route: canister hops to a bridge operator's hub,
+/// followed by one Lightning hop via the operator.
+pub struct RouteQuote {
+    /// The bridging operator.
+    pub operator: Principal,
+    /// The canister hops from the payer to the operator's hub.
+    pub canister_hops: Vec<Leg>,
+    /// The operator's fee for the Lightning hop plus the fees of the hubs
+    /// forwarding the canister hops.
+    pub fee: Amount,
+    /// The estimated end-to-end latency (seconds).
+    pub latency_secs: u64,
+}
+
+#[update]
+#[candid_method(update)]
+/// Registers or updates a bridge operator. Controller only.
+fn add_operator(operator: Principal, info: OperatorInfo) -> Result<()> {
+    let hash = audit::args_hash((operator, &info));
+    audit::logged("add_operator", hash, || {
+        crate::require_controller()?;
+        mutate_state(|s| s.operators.insert(operator, info));
+        Ok(())
+    })
+}
+
+#[update]
+#[candid_method(update)]
+/// Removes a bridge operator along with its advertisements. Controller only.
+fn remove_operator(operator: Principal) -> Result<()> {
+    audit::logged("remove_operator", audit::args_hash((operator,)), || {
+        crate::require_controller()?;
+        mutate_state(|s| s.remove_operator(&operator));
+        Ok(())
+    })
+}
+
+#[query]
+#[candid_method(query)]
+fn query_operator(operator: Principal) -> Option<OperatorInfo> {
+    read_state(|s| s.operators.get(&operator).cloned())
+}
+
+#[query]
+#[candid_method(query)]
+fn query_reputation(operator: Principal) -> Reputation {
+    read_state(|s| s.reputation.get(&operator).cloned().unwrap_or_default())
+}
+
+#[update]
+#[candid_method(update)]
+/// Publishes or replaces the calling operator's advertisement for a
+/// direction.
+fn advertise_liquidity(
+    direction: Direction,
+    max_amount: Amount,
+    fee_ppm: u32,
+    expiry: Timestamp,
+) -> Result<()> {
+    let ad = LiquidityAd {
+        direction,
+        max_amount,
+        fee_ppm,
+        expiry,
+    };
+    audit::logged("advertise_liquidity", audit::args_hash((&ad,)), || {
+        mutate_state(|s| s.advertise_liquidity(ic_cdk::api::msg_caller(), ad, blocktime()))
+    })
+}
+
+#[query]
+#[candid_method(query)]
+/// Lists the live advertisements that can serve a payment of the given size
+/// in a direction, cheapest first.
+fn query_liquidity_ads(
+    direction: Direction,
+    amount: Amount,
+    cursor: Option<Cursor>,
+    limit: u32,
+) -> Result<Page<(Principal, LiquidityAd)>> {
+    read_state(|s| s.liquidity_ad_page(direction, &amount, cursor, limit, blocktime()))
+}
+
+#[query]
+#[candid_method(query)]
+/// Quotes routes paying `amount` from `from` into Lightning, cheapest first,
+/// ties broken by latency.
+fn quote_routes(from: L2Account, amount: Amount) -> Vec<RouteQuote> {
+    read_state(|s| s.quote_routes(&from, &amount, blocktime()))
+}
+
+impl LiquidityAd {
+    /// The operator's fee for serving the amount.
+    pub fn fee(&self, amount: &Amount) -> Amount {
+        amount.clone() * self.fee_ppm / PPM
+    }
+}
+
+impl<Q: TXQuerier> CanisterState<Q> {
+    pub fn remove_operator(&mut self, operator: &Principal) {
+        self.operators.remove(operator);
+        self.liquidity_ads.retain(|(o, _), _| o != operator);
+    }
+
+    pub fn advertise_liquidity(
+        &mut self,
+        operator: Principal,
+        ad: LiquidityAd,
+        now: Timestamp,
+    ) -> Result<()> {
+        require!(self.operators.contains_key(&operator), Unauthorized);
+        self.require_scope(&operator, Scope::ManageLiquidity)?;
+        require!(ad.max_amount > Amount::default(), InvalidInput);
+        require!(ad.expiry > now, Expired);
+        self.liquidity_ads.insert((operator, ad.direction), ad);
+        Ok(())
+    }
+
+    /// Lists live advertisements serving at least the amount in a direction,
+    /// ordered by fee rate.
+    pub fn liquidity_ads(
+        &self,
+        direction: Direction,
+        amount: &Amount,
+        now: Timestamp,
+    ) -> Vec<(Principal, LiquidityAd)> {
+        let mut ads: Vec<(Principal, LiquidityAd)> = self
+            .liquidity_ads
+            .iter()
+            .filter(|((_, d), ad)| *d == direction && ad.expiry > now && &ad.max_amount >= amount)
+            .map(|((o, _), ad)| (*o, ad.clone()))
+            .collect();
+        ads.sort_by_key(|(o, ad)| (ad.fee_ppm, *o));
+        ads
+    }
+
+    pub fn liquidity_ad_page(
+        &self,
+        direction: Direction,
+        amount: &Amount,
+        cursor: Option<Cursor>,
+        limit: u32,
+        now: Timestamp,
+    ) -> Result<Page<(Principal, LiquidityAd)>> {
+        let ads = self.liquidity_ads(direction, amount, now);
+        paginate(
+            ads.into_iter().map(|(o, ad)| ((ad.fee_ppm, o), (o, ad))),
+            cursor,
+            limit,
+        )
+    }
+
+    /// Lists the operators that can serve a payment of the amount in a
+    /// direction, best first: by fee, then by fewest missed assignments, then
+    /// by most completed ones.
+    pub fn operator_candidates(
+        &self,
+        direction: Direction,
+        amount: &Amount,
+        now: Timestamp,
+        exclude: &[Principal],
+    ) -> Vec<(Principal, LiquidityAd)> {
+        let mut candidates: Vec<(Principal, LiquidityAd)> = self
+            .liquidity_ads(direction, amount, now)
+            .into_iter()
+            .filter(|(op, _)| !exclude.contains(op))
+            .collect();
+        candidates.sort_by_key(|(op, ad)| {
+            let rep = self.reputation.get(op).cloned().unwrap_or_default();
+            (ad.fee_ppm, rep.missed, std::cmp::Reverse(rep.completed))
+        });
+        candidates
+    }
+
+    /// Combines the canister's routing graph with the operators' Lightning
+    /// advertisements. The payer pays the amount plus the operator's fee along
+    /// the canister hops.
+    pub fn quote_routes(
+        &self,
+        from: &L2Account,
+        amount: &Amount,
+        now: Timestamp,
+    ) -> Vec<RouteQuote> {
+        let mut quotes: Vec<RouteQuote> = self
+            .liquidity_ads(Direction::ToLightning, amount, now)
+            .into_iter()
+            .filter_map(|(operator, ad)| {
+                let info = self.operators.get(&operator)?;
+                let gross = amount.clone() + ad.fee(amount);
+                let hops = self.find_route(from, &info.hub, &gross, now)?;
+                // Each hub on the way charges for what it forwards downstream.
+                let mut carried = gross;
+                for leg in hops.iter().skip(1).rev() {
+                    carried += self.forward_fee(leg, &carried);
+                }
+                let canister_latency = hops.len() as u64 * CANISTER_HOP_LATENCY_SECS;
+                Some(RouteQuote {
+                    operator,
+                    canister_hops: hops,
+                    fee: carried - amount.clone(),
+                    latency_secs: info.latency_secs + canister_latency,
+                })
+            })
+            .collect();
+        quotes.sort_by(|a, b| (&a.fee, a.latency_secs).cmp(&(&b.fee, b.latency_secs)));
+        quotes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::htlc::FeePolicy;
+    use crate::testing::*;
+
+    fn ad(direction: Direction, fee_ppm: u32) -> LiquidityAd {
+        LiquidityAd {
+            direction,
+            max_amount: Amount::from(1000u32),
+            fee_ppm,
+            expiry: 100,
+        }
+    }
+
+    #[test]
+    fn test_ads_filtered_and_sorted() {
+        let mut s = new_state();
+        let (a, b) = (operator(&mut s, 1, 1), operator(&mut s, 2, 2));
+        let stranger = Principal::from_slice(&[3]);
+        let unscoped = operator(&mut s, 4, 4);
+        s.scopes.remove(&unscoped);
+        s.advertise_liquidity(a, ad(Direction::ToLightning, 500), 0)
+            .unwrap();
+        s.advertise_liquidity(b, ad(Direction::ToLightning, 100), 0)
+            .unwrap();
+        s.advertise_liquidity(b, ad(Direction::FromLightning, 100), 0)
+            .unwrap();
+        assert!(matches!(
+            s.advertise_liquidity(stranger, ad(Direction::ToLightning, 1), 0),
+            Err(Error {
+                code: ErrorCode::Unauthorized,
+                ..
+            })
+        ));
+        assert!(matches!(
+            s.advertise_liquidity(unscoped, ad(Direction::ToLightning, 1), 0),
+            Err(Error {
+                code: ErrorCode::Unauthorized,
+                ..
+            })
+        ));
+
+        let ads = s.liquidity_ads(Direction::ToLightning, &Amount::from(10u32), 0);
+        let ops: Vec<Principal> = ads.iter().map(|(o, _)| *o).collect();
+        assert_eq!(ops, vec![b, a]);
+        let amount = Amount::from(10u32);
+        let page = s
+            .liquidity_ad_page(Direction::ToLightning, &amount, None, 1, 0)
+            .unwrap();
+        assert_eq!(page.items[0].0, b);
+        let page = s
+            .liquidity_ad_page(Direction::ToLightning, &amount, page.next, 1, 0)
+            .unwrap();
+        assert_eq!(page.items[0].0, a);
+        assert!(!page.has_more);
+        assert!(
+            s.liquidity_ads(Direction::ToLightning, &Amount::from(1001u32), 0)
+                .is_empty()
+        );
+        assert!(
+            s.liquidity_ads(Direction::ToLightning, &Amount::from(10u32), 100)
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn test_quotes_sorted_by_fee() {
+        let mut s = new_state();
+        concluded(&mut s, 1, 1, 2);
+        concluded(&mut s, 2, 2, 3);
+        let (near, far) = (operator(&mut s, 1, 2), operator(&mut s, 2, 3));
+        s.advertise_liquidity(near, ad(Direction::ToLightning, 20_000), 0)
+            .unwrap();
+        s.advertise_liquidity(far, ad(Direction::ToLightning, 10_000), 0)
+            .unwrap();
+
+        let quotes = s.quote_routes(&account(1), &Amount::from(50u32), 0);
+        assert_eq!(quotes.len(), 2);
+        assert_eq!(quotes[0].operator, far);
+        assert_eq!(quotes[0].canister_hops.len(), 2);
+        assert_eq!(quotes[0].fee, Amount::from(0u32));
+        assert_eq!(quotes[1].operator, near);
+        assert_eq!(quotes[1].fee, Amount::from(1u32));
+        assert_eq!(quotes[1].latency_secs, 10 + CANISTER_HOP_LATENCY_SECS);
+    }
+
+    #[test]
+    fn test_quotes_include_hub_fees() {
+        let mut s = new_state();
+        concluded(&mut s, 1, 1, 2);
+        let ch = concluded(&mut s, 2, 2, 3);
+        let (near, far) = (operator(&mut s, 1, 2), operator(&mut s, 2, 3));
+        s.advertise_liquidity(near, ad(Direction::ToLightning, 20_000), 0)
+            .unwrap();
+        s.advertise_liquidity(far, ad(Direction::ToLightning, 10_000), 0)
+            .unwrap();
+        let policy = FeePolicy {
+            base_fee: Amount::from(2u32),
+            fee_ppm: 100_000,
+            seq: 1,
+        };
+        let hub = Funding::new(ch, account(2));
+        let sig = sign(2, &policy.signing_bytes(&hub));
+        s.set_fee_policy(hub, policy, &sig).unwrap();
+
+        // Hub 2 charges 2 + 10% of the 50 it forwards to the far operator.
+        let quotes = s.quote_routes(&account(1), &Amount::from(50u32), 0);
+        assert_eq!(quotes[0].operator, near);
+        assert_eq!(quotes[0].fee, Amount::from(1u32));
+        assert_eq!(quotes[1].operator, far);
+        assert_eq!(quotes[1].fee, Amount::from(7u32));
+    }
+}
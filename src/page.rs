@@ -0,0 +1,168 @@
+//  Copyright 2025 PolyCrypt GmbH
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+
+use crate::error::*;
+use crate::types::*;
+use candid::Principal;
+
+/// Largest number of entries returned in one page.
+pub const MAX_PAGE_LIMIT: u32 = 100;
+
+/// Opaque position within a list. Clients must only pass back cursors they
+/// received from the same endpoint.
+pub type Cursor = Vec<u8>;
+
+#[derive(Clone, Deserialize, CandidType)]
+/// One page of a list query. Entries are returned in a stable order, and a
+/// cursor marks the last returned entry, so that entries added or pruned
+/// between calls neither shift nor repeat later pages.
+pub struct Page<T> {
+    /// The entries of this page.
+    pub items: Vec<T>,
+    /// Pass this to the next call to continue after the last entry.
+    pub next: Option<Cursor>,
+    /// Whether there are entries after this page.
+    pub has_more: bool,
+}
+
+/// A list key that can be encoded into a cursor.
+pub trait CursorKey: Sized + Ord {
+    fn encode(&self) -> Cursor;
+    fn decode(bytes: &[u8]) -> Option<Self>;
+}
+
+impl CursorKey for u64 {
+    fn encode(&self) -> Cursor {
+        self.to_be_bytes().to_vec()
+    }
+
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        Some(u64::from_be_bytes(bytes.try_into().ok()?))
+    }
+}
+
+impl CursorKey for ChannelId {
+    fn encode(&self) -> Cursor {
+        self.0.to_vec()
+    }
+
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        Some(ChannelId(bytes.try_into().ok()?))
+    }
+}
+
+impl CursorKey for [u8; 32] {
+    fn encode(&self) -> Cursor {
+        self.to_vec()
+    }
+
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        bytes.try_into().ok()
+    }
+}
+
+impl CursorKey for (u64, u64) {
+    fn encode(&self) -> Cursor {
+        let mut c = self.0.encode();
+        c.extend(self.1.encode());
+        c
+    }
+
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() != 16 {
+            return None;
+        }
+        Some((u64::decode(&bytes[..8])?, u64::decode(&bytes[8..])?))
+    }
+}
+
+impl CursorKey for (u32, Principal) {
+    fn encode(&self) -> Cursor {
+        let mut c = self.0.to_be_bytes().to_vec();
+        c.extend(self.1.as_slice());
+        c
+    }
+
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        let (n, principal) = bytes.split_at_checked(4)?;
+        let n = u32::from_be_bytes(n.try_into().ok()?);
+        Some((n, Principal::try_from_slice(principal).ok()?))
+    }
+}
+
+/// Returns the page of entries following the cursor. The entries must be
+/// sorted by ascending key. A limit of 0 or above `MAX_PAGE_LIMIT` is clamped
+/// to `MAX_PAGE_LIMIT`.
+pub fn paginate<K: CursorKey, T>(
+    entries: impl Iterator<Item = (K, T)>,
+    cursor: Option<Cursor>,
+    limit: u32,
+) -> Result<Page<T>> {
+    let after = match cursor {
+        Some(c) => Some(K::decode(&c).ok_or(ErrorCode::InvalidInput)?),
+        None => None,
+    };
+    let limit = match limit {
+        0 => MAX_PAGE_LIMIT,
+        l => l.min(MAX_PAGE_LIMIT),
+    } as usize;
+
+    let mut rest = entries
+        .skip_while(|(k, _)| after.as_ref().is_some_and(|a| k <= a))
+        .peekable();
+    let mut items = Vec::new();
+    let mut last = None;
+    while items.len() < limit {
+        let Some((k, v)) = rest.next() else { break };
+        items.push(v);
+        last = Some(k);
+    }
+    let has_more = rest.peek().is_some();
+    Ok(Page {
+        items,
+        next: last.map(|k| k.encode()),
+        has_more,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cursor_survives_pruning() {
+        let mut entries: Vec<(u64, u64)> = (0..5).map(|i| (i, i * 10)).collect();
+        let page = paginate(entries.clone().into_iter(), None, 2).unwrap();
+        assert_eq!(page.items, vec![0, 10]);
+        assert!(page.has_more);
+
+        // Pruning already returned entries must not shift the next page.
+        entries.retain(|(k, _)| *k > 1);
+        let page = paginate(entries.into_iter(), page.next, 2).unwrap();
+        assert_eq!(page.items, vec![20, 30]);
+        assert!(page.has_more);
+    }
+
+    #[test]
+    fn test_invalid_cursor_is_rejected() {
+        let entries = (0..3u64).map(|i| (i, i));
+        assert!(matches!(
+            paginate(entries, Some(vec![1, 2, 3]), 10),
+            Err(Error {
+                code: ErrorCode::InvalidInput,
+                ..
+            })
+        ));
+    }
+}
@@ -0,0 +1,206 @@
+//  Copyright 2025 PolyCrypt GmbH
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+
+//! Circuit breakers. Draining stops all fund-moving requests before an
+//! upgrade. Halting stops them too, and with them the payout of queued
+//! withdrawals, swap refunds, dust sweeps and the ledger scan, so that an
+//! incident can be contained without an upgrade; fund-moving endpoints check
+//! it with `unpaused!`. Pausing an asset
+//! stops only its deposits, its withdrawals or both, e.g. during an incident
+//! of its ledger, while channels of other assets keep operating. Deposits to
+//! a paused asset are not lost: the ledger scan halts and resumes where it
+//! stopped once deposits reopen. Disputes and conclusions move no funds and
+//! are never paused.
+
+use crate::asset::AssetId;
+use crate::audit;
+use crate::error::*;
+use crate::maintenance::MaintenanceWindow;
+use crate::receiver::TXQuerier;
+use crate::require;
+use crate::types::{Duration, Timestamp};
+use crate::{CanisterState, mutate_state, read_state};
+use candid::candid_method;
+use ic_cdk::api::time as blocktime;
+use ic_cdk::{query, update};
+
+#[derive(Clone, Copy, Default, Deserialize, CandidType, PartialEq, Eq, Debug)]
+/// Which flows of an asset are paused.
+pub struct AssetPause {
+    pub deposits: bool,
+    pub withdrawals: bool,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Flow {
+    Deposit,
+    Withdrawal,
+}
+
+#[derive(Clone, Deserialize, CandidType, PartialEq, Eq, Debug)]
+pub struct Health {
+    /// Whether an admin halted all fund-moving requests.
+    pub paused: bool,
+    /// Whether fund-moving requests are accepted, i.e. the canister is not
+    /// draining.
+    pub accepting: bool,
+    /// The assets with paused flows.
+    pub paused_assets: Vec<(AssetId, AssetPause)>,
+    /// The scheduled or ongoing maintenance window, if any.
+    pub maintenance: Option<MaintenanceWindow>,
+    /// The time left until the maintenance window starts, zero once it has.
+    pub maintenance_in: Option<Duration>,
+}
+
+/// Returns early from a fund-moving endpoint with `Paused` while the canister
+/// is halted. Endpoints not returning a `Result` pass how to wrap the error.
+#[macro_export]
+macro_rules! unpaused {
+    () => {
+        $crate::read_state(|s| s.require_running())?
+    };
+    ($on_paused:expr) => {
+        if let Err(e) = $crate::read_state(|s| s.require_running()) {
+            return ($on_paused)(e);
+        }
+    };
+}
+
+#[update]
+#[candid_method(update)]
+/// Halts or resumes all fund-moving requests. Admin only.
+fn set_paused(paused: bool) -> Result<()> {
+    audit::logged("set_paused", audit::args_hash((paused,)), || {
+        crate::admin::require_admin()?;
+        mutate_state(|s| s.paused = paused);
+        Ok(())
+    })
+}
+
+#[update]
+#[candid_method(update)]
+/// Pauses or resumes an asset's deposits and withdrawals. Admin only.
+fn set_asset_pause(asset: AssetId, pause: AssetPause) -> Result<()> {
+    audit::logged("set_asset_pause", audit::args_hash((asset, pause)), || {
+        crate::admin::require_admin()?;
+        mutate_state(|s| s.set_asset_pause(asset, pause));
+        Ok(())
+    })
+}
+
+#[query]
+#[candid_method(query)]
+fn health() -> Health {
+    read_state(|s| s.health(blocktime()))
+}
+
+impl<Q: TXQuerier> CanisterState<Q> {
+    /// Fails with `Paused` while the canister is halted.
+    pub fn require_running(&self) -> Result<()> {
+        require!(!self.paused, Paused);
+        Ok(())
+    }
+
+    pub fn set_asset_pause(&mut self, asset: AssetId, pause: AssetPause) {
+        if pause == AssetPause::default() {
+            self.asset_pauses.remove(&asset);
+        } else {
+            self.asset_pauses.insert(asset, pause);
+        }
+    }
+
+    /// Fails with `AssetPaused` if the flow of the asset is paused.
+    pub fn require_unpaused(&self, asset: &AssetId, flow: Flow) -> Result<()> {
+        let pause = self.asset_pauses.get(asset).copied().unwrap_or_default();
+        let paused = match flow {
+            Flow::Deposit => pause.deposits,
+            Flow::Withdrawal => pause.withdrawals,
+        };
+        if paused {
+            return Err(Error::from(ErrorCode::AssetPaused).with("asset", asset));
+        }
+        Ok(())
+    }
+
+    pub fn health(&self, now: Timestamp) -> Health {
+        let maintenance = self.maintenance.window().cloned();
+        Health {
+            maintenance_in: maintenance.as_ref().map(|w| w.start.saturating_sub(now)),
+            maintenance,
+            accepting: !self.draining,
+            paused: self.paused,
+            paused_assets: self
+                .asset_pauses
+                .iter()
+                .map(|(asset, pause)| (*asset, *pause))
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+    use crate::types::*;
+
+    #[test]
+    fn test_paused_asset_stops_only_its_flows() {
+        let mut s = new_state();
+        let ch = concluded(&mut s, 1, 1, 2);
+        let (ckbtc, cketh) = (s.config.ledger, candid::Principal::from_slice(&[9]));
+        let pause = AssetPause {
+            deposits: false,
+            withdrawals: true,
+        };
+        s.set_asset_pause(cketh, pause);
+        s.require_unpaused(&ckbtc, Flow::Withdrawal).unwrap();
+        s.require_unpaused(&cketh, Flow::Deposit).unwrap();
+        assert_eq!(
+            s.require_unpaused(&cketh, Flow::Withdrawal),
+            Err(ErrorCode::AssetPaused.into())
+        );
+        assert_eq!(s.health(0).paused_assets, vec![(cketh, pause)]);
+
+        s.set_asset_pause(ckbtc, pause);
+        let req = WithdrawalReq {
+            channel: ch,
+            participant: account(1),
+            amount: Amount::from(10u32),
+            receiver: ckbtc,
+            nonce: 0,
+            expiry: None,
+            asset: None,
+        };
+        assert_eq!(
+            s.authorize_withdrawal(&req, &sign(1, &req.signing_bytes()), 1),
+            Err(ErrorCode::AssetPaused.into())
+        );
+        s.set_asset_pause(ckbtc, AssetPause::default());
+        s.authorize_withdrawal(&req, &sign(1, &req.signing_bytes()), 1)
+            .unwrap();
+        assert_eq!(s.health(0).paused_assets.len(), 1);
+    }
+
+    #[test]
+    fn test_halted_canister_rejects_fund_moving_requests() {
+        let mut s = new_state();
+        s.require_running().unwrap();
+        s.paused = true;
+        assert_eq!(s.require_running(), Err(ErrorCode::Paused.into()));
+        assert!(s.health(0).paused);
+        s.paused = false;
+        s.require_running().unwrap();
+    }
+}
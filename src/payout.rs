@@ -0,0 +1,136 @@
+//  Copyright 2025 PolyCrypt GmbH
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+
+//! Address books of payout destinations. Principals save ledger accounts
+//! under names and withdraw to them by name, instead of pasting an account
+//! into every withdrawal.
+
+use crate::error::*;
+use crate::load::Priority;
+use crate::receiver::TXQuerier;
+use crate::validation::{self, Violation};
+use crate::{CanisterState, mutate_state, read_state, require};
+use candid::{Principal, candid_method};
+use ic_cdk::{query, update};
+use icrc_ledger_types::icrc1::account::Account;
+
+/// Most payout destinations a principal can save.
+pub const MAX_PAYOUT_ALIASES: usize = 32;
+
+#[update]
+#[candid_method(update)]
+/// Saves a payout destination of the caller under `name`, replacing any
+/// destination saved under it before.
+fn add_payout_alias(name: String, account: Account) -> Result<()> {
+    crate::load::admit(Priority::Low)?;
+    let caller = ic_cdk::api::msg_caller();
+    mutate_state(|s| s.add_payout_alias(caller, name, account))
+}
+
+#[update]
+#[candid_method(update)]
+fn remove_payout_alias(name: String) -> Result<()> {
+    crate::load::admit(Priority::Low)?;
+    let caller = ic_cdk::api::msg_caller();
+    mutate_state(|state| {
+        let book = state
+            .payout_aliases
+            .get_mut(&caller)
+            .ok_or(ErrorCode::NotFound)?;
+        book.remove(&name).ok_or(ErrorCode::NotFound)?;
+        if book.is_empty() {
+            state.payout_aliases.remove(&caller);
+        }
+        Ok(())
+    })
+}
+
+#[query]
+#[candid_method(query)]
+/// Returns the caller's saved payout destinations.
+fn query_payout_aliases() -> Vec<(String, Account)> {
+    let caller = ic_cdk::api::msg_caller();
+    read_state(|s| {
+        s.payout_aliases
+            .get(&caller)
+            .map(|book| book.iter().map(|(n, a)| (n.clone(), *a)).collect())
+            .unwrap_or_default()
+    })
+}
+
+impl<Q: TXQuerier> CanisterState<Q> {
+    pub fn add_payout_alias(
+        &mut self,
+        owner: Principal,
+        name: String,
+        account: Account,
+    ) -> Result<()> {
+        validation::name(&name)?;
+        let book = self.payout_aliases.entry(owner).or_default();
+        require!(
+            book.contains_key(&name) || book.len() < MAX_PAYOUT_ALIASES,
+            ErrorCode::Invalid(Violation::BatchTooLong)
+        );
+        book.insert(name, account);
+        Ok(())
+    }
+
+    /// The account a principal withdraws to: the destination saved under
+    /// `alias`, or the principal's default account.
+    pub fn payout_account(&self, owner: Principal, alias: Option<&str>) -> Result<Account> {
+        let Some(alias) = alias else {
+            return Ok(Account {
+                owner,
+                subaccount: None,
+            });
+        };
+        self.payout_aliases
+            .get(&owner)
+            .and_then(|book| book.get(alias))
+            .copied()
+            .ok_or_else(|| Error::from(ErrorCode::NotFound).with("alias", alias))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+
+    #[test]
+    fn test_aliases_resolve_per_owner() {
+        let mut s = new_state();
+        let (alice, bob) = (Principal::from_slice(&[1]), Principal::from_slice(&[2]));
+        let cold = Account {
+            owner: Principal::from_slice(&[3]),
+            subaccount: Some([1; 32]),
+        };
+        s.add_payout_alias(alice, "cold".into(), cold).unwrap();
+        assert_eq!(s.payout_account(alice, Some("cold")), Ok(cold));
+        assert_eq!(s.payout_account(alice, None).unwrap().owner, alice);
+        assert_eq!(
+            s.payout_account(bob, Some("cold")),
+            Err(ErrorCode::NotFound.into())
+        );
+
+        for i in 1..MAX_PAYOUT_ALIASES {
+            s.add_payout_alias(alice, i.to_string(), cold).unwrap();
+        }
+        assert_eq!(
+            s.add_payout_alias(alice, "full".into(), cold),
+            Err(ErrorCode::Invalid(Violation::BatchTooLong).into())
+        );
+        s.add_payout_alias(alice, "cold".into(), cold).unwrap();
+    }
+}
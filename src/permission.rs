@@ -0,0 +1,90 @@
+//  Copyright 2025 PolyCrypt GmbH
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+
+//! Permission scopes of privileged principals. Each privileged handler
+//! requires one scope, so that keys used for different duties can be kept
+//! apart: a bridge key consuming the command queue cannot, for instance, also
+//! approve pool withdrawals.
+
+use crate::audit;
+use crate::error::*;
+use crate::receiver::TXQuerier;
+use crate::{CanisterState, mutate_state, read_state, require};
+use candid::{Principal, candid_method};
+use ic_cdk::{query, update};
+
+#[derive(Clone, Copy, Deserialize, CandidType, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum Scope {
+    /// Poll, acknowledge and serve bridge commands and swap assignments.
+    ConsumeQueue,
+    /// Prove Lightning payments for swaps.
+    SubmitProofs,
+    /// Approve withdrawals from the liquidity pool.
+    ApproveWithdrawals,
+    /// Advertise bridge liquidity.
+    ManageLiquidity,
+}
+
+#[update]
+#[candid_method(update)]
+/// Grants a scope to a principal. Controller only.
+fn grant_scope(principal: Principal, scope: Scope) -> Result<()> {
+    audit::logged("grant_scope", audit::args_hash((principal, scope)), || {
+        crate::require_controller()?;
+        mutate_state(|s| s.scopes.entry(principal).or_default().insert(scope));
+        Ok(())
+    })
+}
+
+#[update]
+#[candid_method(update)]
+/// Revokes a scope from a principal. Controller only.
+fn revoke_scope(principal: Principal, scope: Scope) -> Result<()> {
+    audit::logged("revoke_scope", audit::args_hash((principal, scope)), || {
+        crate::require_controller()?;
+        mutate_state(|state| {
+            if let Some(scopes) = state.scopes.get_mut(&principal) {
+                scopes.remove(&scope);
+                if scopes.is_empty() {
+                    state.scopes.remove(&principal);
+                }
+            }
+            Ok(())
+        })
+    })
+}
+
+#[query]
+#[candid_method(query)]
+fn query_scopes(principal: Principal) -> Vec<Scope> {
+    read_state(|s| {
+        s.scopes
+            .get(&principal)
+            .map(|scopes| scopes.iter().copied().collect())
+            .unwrap_or_default()
+    })
+}
+
+impl<Q: TXQuerier> CanisterState<Q> {
+    /// Fails unless the principal holds the scope.
+    pub fn require_scope(&self, principal: &Principal, scope: Scope) -> Result<()> {
+        require!(
+            self.scopes
+                .get(principal)
+                .is_some_and(|s| s.contains(&scope)),
+            Unauthorized
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,962 @@
+//  Copyright 2025 PolyCrypt GmbH
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+
+//! Administration of the liquidity pools, one per registered asset, so that
+//! the liquidity of different assets never mixes. A fresh deployment can be
+//! bootstrapped from ckBTC already held by the canister, e.g. migrated from a
+//! previous deployment, by attributing it to ckBTC pool depositors instead of having
+//! every depositor redeposit through the receiver. Only funds the canister
+//! does not owe anyone can be attributed, and each attribution is recorded in
+//! the audit log.
+//!
+//! Liquidity providers fund the pool by approving the canister on the ledger
+//! and calling `lp_deposit`, which pulls the approved amount with an ICRC-2
+//! `transfer_from`.
+//!
+//! The pool is accounted separately from the channels: depositors own it in
+//! proportion to their shares, fees earned by the pool accrue to all of them
+//! alike, and pool withdrawals only ever burn the withdrawing depositor's
+//! shares, never touching the holdings of channel participants. Each pool
+//! withdrawal is charged a fee in basis points, which stays in the pool.
+//!
+//! Optionally, pool positions are tokenized: with an LP token ledger set, of
+//! which the canister is the minting account, `lp_deposit` mints one LP token
+//! per share to the depositor and `lp_withdraw` burns them. The shares behind
+//! LP tokens are held by the pool on behalf of whoever holds the tokens, so
+//! positions can be transferred and used by other canisters. Rewards programs
+//! do not pay out on tokenized shares. If minting fails, or the payout of a
+//! withdrawal, the depositor keeps the shares untokenized.
+
+use crate::asset::AssetId;
+use crate::audit::{self, AuditEntry};
+use crate::config;
+use crate::error::*;
+use crate::pause::Flow;
+use crate::receiver::TXQuerier;
+use crate::transfer;
+use crate::types::*;
+use crate::{
+    CanisterState, icrc1_balance_of, icrc2_transfer_from, mutate_state, read_state, require,
+};
+use candid::{Principal, candid_method};
+use ic_cdk::api::time as blocktime;
+use ic_cdk::{query, update};
+
+use icrc_ledger_types::icrc1::account::Account;
+use icrc_ledger_types::icrc1::transfer::TransferArg;
+use icrc_ledger_types::icrc2::transfer_from::TransferFromArgs;
+use std::collections::HashMap;
+
+/// Denominator of fee rates given in basis points.
+pub const BPS: u64 = 10_000;
+
+#[derive(Default)]
+/// The pool's liquidity, owned by depositors in proportion to their shares.
+/// Deposits mint shares at the current value per share and withdrawals burn
+/// them, so fees credited to the pool raise the value of every share.
+pub struct LiquidityPool {
+    shares: HashMap<L1Account, Amount>,
+    total_shares: Amount,
+    /// Deposits and fees, less withdrawals.
+    value: Amount,
+    /// The fee charged on pool withdrawals, in basis points.
+    fee_bps: u32,
+    /// The fees credited to the pool so far.
+    accrued_fees: Amount,
+    /// The ledger of the LP token, if positions are tokenized.
+    lp_token: Option<Principal>,
+    /// The shares held on behalf of LP token holders.
+    tokenized: Amount,
+}
+
+#[derive(Clone, Deserialize, CandidType, PartialEq, Eq, Debug)]
+/// A depositor's part of the pool.
+pub struct PoolShare {
+    pub shares: Amount,
+    /// What the shares are worth.
+    pub value: Amount,
+}
+
+#[derive(Clone, Deserialize, CandidType, PartialEq, Eq, Debug)]
+pub struct PoolStats {
+    pub total_shares: Amount,
+    /// Deposits and fees, less withdrawals.
+    pub value: Amount,
+    pub depositors: u64,
+    /// The shares held on behalf of LP token holders.
+    pub tokenized_shares: Amount,
+}
+
+#[update]
+#[candid_method(update)]
+/// Attributes `amount` of the canister's unowed ledger balance to pool
+/// depositors, as given by `attribution`. Controller only.
+async fn bootstrap_pool(amount: Amount, attribution: Vec<(Principal, Amount)>) -> Result<()> {
+    let caller = ic_cdk::api::msg_caller();
+    let hash = audit::args_hash((&amount, &attribution));
+    let outcome = bootstrap(caller, amount, attribution).await;
+    audit::record("bootstrap_pool", caller, hash, &outcome);
+    outcome
+}
+
+#[update]
+#[candid_method(update)]
+/// Pulls `amount` from the caller's ledger account, which must have approved
+/// the canister for it and the ledger fee, into the caller's pool holdings.
+/// If positions are tokenized, the caller receives LP tokens for the minted
+/// shares. Returns the block height of the transfer.
+async fn lp_deposit(asset: AssetId, amount: Amount) -> Result<Nat> {
+    crate::unpaused!();
+    let caller = ic_cdk::api::msg_caller();
+    read_state(|s| s.check_lp_deposit(&asset, &amount))?;
+    let arg = TransferFromArgs {
+        spender_subaccount: None,
+        from: Account {
+            owner: caller,
+            subaccount: None,
+        },
+        to: Account {
+            owner: ic_cdk::api::canister_self(),
+            subaccount: None,
+        },
+        amount: amount.clone(),
+        fee: None,
+        memo: None,
+        created_at_time: None,
+    };
+    let block_height = match icrc2_transfer_from(asset, arg).await {
+        Ok(Ok(block_height)) => block_height,
+        Ok(Err(e)) => return Err(e.into()),
+        Err(e) => {
+            ic_cdk::println!("CallResult error: {:?}", e);
+            return Err(e.into());
+        }
+    };
+    let depositor = L1Account(caller);
+    let shares =
+        mutate_state(|s| s.deposit_liq_pool(&asset, amount, depositor.clone(), blocktime()))?;
+    if let Some(token) = read_state(|s| s.pool(&asset).ok().and_then(|p| p.lp_token)) {
+        mutate_state(|s| s.tokenize(&asset, &depositor, &shares, blocktime()))?;
+        let mut arg = TransferArg {
+            from_subaccount: None,
+            to: Account {
+                owner: caller,
+                subaccount: None,
+            },
+            amount: shares.clone(),
+            fee: None,
+            memo: None,
+            created_at_time: None,
+        };
+        let outcome = transfer::transfer_retrying(token, &mut arg).await;
+        if outcome.failed() {
+            ic_cdk::println!("minting LP tokens failed: {:?}", outcome.into_result());
+            mutate_state(|s| s.untokenize(&asset, depositor, shares, blocktime()));
+        }
+    }
+    Ok(block_height)
+}
+
+#[update]
+#[candid_method(update)]
+/// Redeems `shares` of the caller's pool position for their value, less the
+/// pool fee, paid out to the caller. Untokenized shares are redeemed first;
+/// the remainder is burnt from the caller's LP tokens, which must be approved
+/// for the canister. Returns the block height of the payout.
+async fn lp_withdraw(asset: AssetId, shares: Amount) -> Result<Nat> {
+    crate::unpaused!();
+    let caller = ic_cdk::api::msg_caller();
+    let depositor = L1Account(caller);
+    let fee = crate::ledger::fee(asset).await;
+    let (token, tokens) = read_state(|s| s.check_lp_withdrawal(&asset, &depositor, &shares))?;
+    if let Some(token) = token.filter(|_| tokens > Amount::default()) {
+        let arg = TransferFromArgs {
+            spender_subaccount: None,
+            from: Account {
+                owner: caller,
+                subaccount: None,
+            },
+            to: Account {
+                owner: ic_cdk::api::canister_self(),
+                subaccount: None,
+            },
+            amount: tokens.clone(),
+            fee: None,
+            memo: None,
+            created_at_time: None,
+        };
+        match icrc2_transfer_from(token, arg).await {
+            Ok(Ok(_)) => {}
+            Ok(Err(e)) => return Err(e.into()),
+            Err(e) => {
+                ic_cdk::println!("CallResult error: {:?}", e);
+                return Err(e.into());
+            }
+        }
+        mutate_state(|s| s.untokenize(&asset, depositor.clone(), tokens, blocktime()));
+    }
+    let (value, pool_fee) =
+        mutate_state(|s| s.redeem_lp_shares(&asset, &depositor, &shares, blocktime()))?;
+    let mut arg = TransferArg {
+        from_subaccount: None,
+        to: Account {
+            owner: caller,
+            subaccount: None,
+        },
+        amount: value.clone() - pool_fee.clone(),
+        fee: Some(fee),
+        memo: None,
+        created_at_time: None,
+    };
+    let outcome = transfer::transfer_retrying(asset, &mut arg).await;
+    if outcome.failed() {
+        mutate_state(|s| {
+            s.revert_lp_redemption(&asset, depositor, shares, value, &pool_fee, blocktime())
+        });
+    }
+    outcome.into_result()
+}
+
+#[query]
+#[candid_method(query)]
+/// What the depositor's shares of an asset's pool are worth.
+fn lp_holdings(asset: AssetId, depositor: Principal) -> Result<Amount> {
+    read_state(|s| Ok(s.pool(&asset)?.value_of(&L1Account(depositor))))
+}
+
+#[query]
+#[candid_method(query)]
+/// The liquidity of an asset's whole pool.
+fn lp_total(asset: AssetId) -> Result<Amount> {
+    read_state(|s| Ok(s.pool(&asset)?.value.clone()))
+}
+
+#[query]
+#[candid_method(query)]
+/// The fees credited to an asset's pool by its withdrawals so far.
+fn lp_accrued_fees(asset: AssetId) -> Result<Amount> {
+    read_state(|s| Ok(s.pool(&asset)?.accrued_fees.clone()))
+}
+
+#[update]
+#[candid_method(update)]
+/// Sets the fee charged on withdrawals from an asset's pool, in basis
+/// points. Admin only.
+fn set_pool_fee(asset: AssetId, fee_bps: u32) -> Result<()> {
+    audit::logged("set_pool_fee", audit::args_hash((asset, fee_bps)), || {
+        crate::admin::require_admin()?;
+        mutate_state(|s| s.pool_mut(&asset)?.set_fee(fee_bps))
+    })
+}
+
+#[query]
+#[candid_method(query)]
+fn query_pool_fee(asset: AssetId) -> Result<u32> {
+    read_state(|s| Ok(s.pool(&asset)?.fee_bps))
+}
+
+#[update]
+#[candid_method(update)]
+/// Sets the ledger of the LP token of an asset's pool, of which the canister
+/// must be the minting account, or stops tokenizing its positions. Only
+/// possible while none of its shares are tokenized. Admin only.
+fn set_lp_token(asset: AssetId, ledger: Option<Principal>) -> Result<()> {
+    audit::logged("set_lp_token", audit::args_hash((asset, ledger)), || {
+        crate::admin::require_admin()?;
+        mutate_state(|s| s.pool_mut(&asset)?.set_lp_token(ledger))
+    })
+}
+
+#[query]
+#[candid_method(query)]
+fn query_lp_token(asset: AssetId) -> Result<Option<Principal>> {
+    read_state(|s| Ok(s.pool(&asset)?.lp_token))
+}
+
+#[query]
+#[candid_method(query)]
+fn lp_share_of(asset: AssetId, depositor: Principal) -> Result<PoolShare> {
+    read_state(|s| Ok(s.pool(&asset)?.share_of(&L1Account(depositor))))
+}
+
+#[query]
+#[candid_method(query)]
+fn lp_pool_stats(asset: AssetId) -> Result<PoolStats> {
+    read_state(|s| Ok(s.pool(&asset)?.stats()))
+}
+
+impl LiquidityPool {
+    /// Deposits and fees, less withdrawals.
+    pub fn value(&self) -> &Amount {
+        &self.value
+    }
+
+    pub fn total_shares(&self) -> &Amount {
+        &self.total_shares
+    }
+
+    pub fn shares_of(&self, depositor: &L1Account) -> Amount {
+        self.shares.get(depositor).cloned().unwrap_or_default()
+    }
+
+    /// What the depositor's shares are worth, rounded down.
+    pub fn value_of(&self, depositor: &L1Account) -> Amount {
+        if self.total_shares == Amount::default() {
+            return Amount::default();
+        }
+        self.shares_of(depositor) * self.value.clone() / self.total_shares.clone()
+    }
+
+    pub fn share_of(&self, depositor: &L1Account) -> PoolShare {
+        PoolShare {
+            shares: self.shares_of(depositor),
+            value: self.value_of(depositor),
+        }
+    }
+
+    pub fn stats(&self) -> PoolStats {
+        PoolStats {
+            total_shares: self.total_shares.clone(),
+            value: self.value.clone(),
+            depositors: self.shares.len() as u64,
+            tokenized_shares: self.tokenized.clone(),
+        }
+    }
+
+    pub fn set_lp_token(&mut self, ledger: Option<Principal>) -> Result<()> {
+        require!(
+            self.tokenized == Amount::default(),
+            Error::from(ErrorCode::InvalidInput).with("tokenized", &self.tokenized)
+        );
+        self.lp_token = ledger;
+        Ok(())
+    }
+
+    /// Moves a depositor's shares to those held for LP token holders.
+    pub fn tokenize(&mut self, depositor: &L1Account, shares: &Amount) -> Result<()> {
+        let held = self.shares_of(depositor);
+        require!(
+            held >= *shares,
+            InsufficientFunding {
+                required: shares.clone(),
+                available: held.clone(),
+            }
+        );
+        self.take_shares(depositor, held, shares);
+        self.tokenized += shares.clone();
+        Ok(())
+    }
+
+    /// Moves shares held for LP token holders, whose tokens were burnt or
+    /// never minted, to a depositor.
+    pub fn untokenize(&mut self, depositor: L1Account, shares: Amount) {
+        self.tokenized -= shares.clone();
+        *self.shares.entry(depositor).or_default() += shares;
+    }
+
+    /// Burns a depositor's shares and returns their value, rounded down.
+    pub fn redeem(&mut self, depositor: &L1Account, shares: &Amount) -> Result<Amount> {
+        let held = self.shares_of(depositor);
+        require!(
+            *shares > Amount::default() && held >= *shares,
+            InsufficientFunding {
+                required: shares.clone(),
+                available: held.clone(),
+            }
+        );
+        let value = shares.clone() * self.value.clone() / self.total_shares.clone();
+        self.take_shares(depositor, held, shares);
+        self.total_shares -= shares.clone();
+        self.value -= value.clone();
+        Ok(value)
+    }
+
+    fn take_shares(&mut self, depositor: &L1Account, held: Amount, shares: &Amount) {
+        match held == *shares {
+            true => self.shares.remove(depositor),
+            false => self.shares.insert(depositor.clone(), held - shares.clone()),
+        };
+    }
+
+    /// Mints shares worth `amount`, rounded down, to a depositor and returns
+    /// them. A deposit into a pool without shares or value mints one share
+    /// per unit and takes over whatever value is left.
+    pub fn mint(&mut self, depositor: L1Account, amount: Amount) -> Result<Amount> {
+        let zero = Amount::default();
+        let shares = match self.total_shares == zero || self.value == zero {
+            true => amount.clone(),
+            false => amount.clone() * self.total_shares.clone() / self.value.clone(),
+        };
+        require!(
+            shares > zero,
+            Error::from(ErrorCode::InvalidInput).with("amount", &amount)
+        );
+        self.restore(depositor, shares.clone(), amount);
+        Ok(shares)
+    }
+
+    /// Burns a depositor's shares worth `amount`, rounded up, and returns
+    /// them. Withdrawing more than the shares are worth fails instead of
+    /// being served from channel holdings.
+    pub fn burn(&mut self, depositor: &L1Account, amount: &Amount) -> Result<Amount> {
+        let zero = Amount::default();
+        let held = self.shares_of(depositor);
+        require!(
+            held > zero,
+            Error::from(ErrorCode::NotFound).with("depositor", depositor.0)
+        );
+        let available = self.value_of(depositor);
+        require!(
+            available >= *amount,
+            Error::from(ErrorCode::ChannelFundsProtected).with("available", &available)
+        );
+        if *amount == zero {
+            return Ok(zero);
+        }
+        let shares = (amount.clone() * self.total_shares.clone() + self.value.clone()
+            - Amount::from(1u32))
+            / self.value.clone();
+        self.total_shares -= shares.clone();
+        self.value -= amount.clone();
+        self.take_shares(depositor, held, &shares);
+        Ok(shares)
+    }
+
+    /// Gives a depositor shares together with the value backing them, e.g.
+    /// those burnt for a withdrawal that failed.
+    pub fn restore(&mut self, depositor: L1Account, shares: Amount, amount: Amount) {
+        self.total_shares += shares.clone();
+        self.value += amount;
+        *self.shares.entry(depositor).or_default() += shares;
+    }
+
+    /// Credits fees earned by the pool to all share holders.
+    pub fn accrue_fee(&mut self, amount: Amount) {
+        self.accrued_fees += amount.clone();
+        self.value += amount;
+    }
+
+    /// Takes back the fee of a withdrawal that failed.
+    pub fn refund_fee(&mut self, amount: &Amount) {
+        self.accrued_fees -= amount.clone();
+        self.value -= amount.clone();
+    }
+
+    pub fn fee_bps(&self) -> u32 {
+        self.fee_bps
+    }
+
+    pub fn set_fee(&mut self, fee_bps: u32) -> Result<()> {
+        require!(
+            fee_bps as u64 <= BPS,
+            Error::from(ErrorCode::InvalidInput).with("fee_bps", fee_bps)
+        );
+        self.fee_bps = fee_bps;
+        Ok(())
+    }
+
+    /// The fee charged on a withdrawal of `amount`, rounded down.
+    pub fn fee(&self, amount: &Amount) -> Amount {
+        amount.clone() * Amount::from(self.fee_bps) / Amount::from(BPS)
+    }
+}
+
+async fn bootstrap(
+    caller: Principal,
+    amount: Amount,
+    attribution: Vec<(Principal, Amount)>,
+) -> Result<()> {
+    crate::require_controller()?;
+    let account = Account {
+        owner: ic_cdk::api::canister_self(),
+        subaccount: None,
+    };
+    let balance = icrc1_balance_of(config::current().ledger, account)
+        .await
+        .map_err(|e| {
+            ic_cdk::println!("querying the ledger balance failed: {:?}", e);
+            Error::from(e)
+        })?;
+    mutate_state(|s| s.bootstrap_pool(caller, amount, attribution, balance, blocktime()))
+}
+
+impl<Q: TXQuerier> CanisterState<Q> {
+    /// The liquidity pool of a registered asset.
+    pub fn pool(&self, asset: &AssetId) -> Result<&LiquidityPool> {
+        self.liq_pools
+            .get(asset)
+            .ok_or_else(|| Error::from(ErrorCode::NotFound).with("asset", asset))
+    }
+
+    pub fn pool_mut(&mut self, asset: &AssetId) -> Result<&mut LiquidityPool> {
+        self.liq_pools
+            .get_mut(asset)
+            .ok_or_else(|| Error::from(ErrorCode::NotFound).with("asset", asset))
+    }
+
+    /// Credits the attributed ckBTC pool deposits, which must add up to `amount`
+    /// and be covered by the part of `ledger_balance` that exceeds the
+    /// canister's liabilities.
+    pub fn bootstrap_pool(
+        &mut self,
+        caller: Principal,
+        amount: Amount,
+        attribution: Vec<(Principal, Amount)>,
+        ledger_balance: Amount,
+        now: Timestamp,
+    ) -> Result<()> {
+        self.accepting()?;
+        let zero = Amount::default();
+        require!(amount > zero, InvalidInput);
+        require!(attribution.iter().all(|(_, a)| *a > zero), InvalidInput);
+        let attributed = attribution
+            .iter()
+            .fold(Amount::default(), |sum, (_, a)| sum + a.clone());
+        require!(
+            attributed == amount,
+            Error::from(ErrorCode::InvalidInput).with("attributed", &attributed)
+        );
+        let liabilities = self.liabilities();
+        let unowed = match ledger_balance > liabilities {
+            true => ledger_balance - liabilities,
+            false => Amount::default(),
+        };
+        require!(
+            amount <= unowed,
+            InsufficientFunding {
+                required: amount.clone(),
+                available: unowed.clone(),
+            }
+        );
+
+        for (depositor, amount) in attribution {
+            audit::append(&AuditEntry {
+                method: "bootstrap_pool_credit".into(),
+                caller,
+                args_hash: audit::args_hash((depositor, &amount)),
+                timestamp: now,
+                outcome: Ok(()),
+            });
+            self.deposit_liq_pool(&self.ckbtc(), amount, L1Account(depositor), now)?;
+        }
+        Ok(())
+    }
+
+    /// Checks that a deposit into an asset's pool would be accepted before
+    /// its funds are pulled.
+    pub fn check_lp_deposit(&self, asset: &AssetId, amount: &Amount) -> Result<()> {
+        self.accepting()?;
+        self.pool(asset)?;
+        self.require_unpaused(asset, Flow::Deposit)?;
+        require!(*amount > Amount::default(), InvalidInput);
+        Ok(())
+    }
+
+    /// Checks that a depositor can redeem `shares` and returns the LP token
+    /// ledger and how many of the shares must be burnt as LP tokens.
+    pub fn check_lp_withdrawal(
+        &self,
+        asset: &AssetId,
+        depositor: &L1Account,
+        shares: &Amount,
+    ) -> Result<(Option<Principal>, Amount)> {
+        self.accepting()?;
+        let pool = self.pool(asset)?;
+        self.require_unpaused(asset, Flow::Withdrawal)?;
+        require!(*shares > Amount::default(), InvalidInput);
+        let held = pool.shares_of(depositor);
+        let token = pool.lp_token;
+        let tokens = match *shares > held {
+            true => shares.clone() - held.clone(),
+            false => Amount::default(),
+        };
+        require!(
+            token.is_some() || tokens == Amount::default(),
+            InsufficientFunding {
+                required: shares.clone(),
+                available: held.clone(),
+            }
+        );
+        require!(
+            tokens <= pool.tokenized,
+            InsufficientFunding {
+                required: tokens.clone(),
+                available: pool.tokenized.clone(),
+            }
+        );
+        Ok((token, tokens))
+    }
+
+    pub fn tokenize(
+        &mut self,
+        asset: &AssetId,
+        depositor: &L1Account,
+        shares: &Amount,
+        now: Timestamp,
+    ) -> Result<()> {
+        self.settle_rewards(depositor, now);
+        self.pool_mut(asset)?.tokenize(depositor, shares)
+    }
+
+    pub fn untokenize(
+        &mut self,
+        asset: &AssetId,
+        depositor: L1Account,
+        shares: Amount,
+        now: Timestamp,
+    ) {
+        self.settle_rewards(&depositor, now);
+        if let Ok(pool) = self.pool_mut(asset) {
+            pool.untokenize(depositor, shares);
+        }
+    }
+
+    /// Redeems a depositor's shares and credits the pool fee to the pool.
+    /// Returns the shares' value and the fee, which is withheld from the
+    /// payout.
+    pub fn redeem_lp_shares(
+        &mut self,
+        asset: &AssetId,
+        depositor: &L1Account,
+        shares: &Amount,
+        now: Timestamp,
+    ) -> Result<(Amount, Amount)> {
+        self.settle_rewards(depositor, now);
+        let pool = self.pool_mut(asset)?;
+        let value = pool.redeem(depositor, shares)?;
+        let fee = pool.fee(&value);
+        pool.accrue_fee(fee.clone());
+        Ok((value, fee))
+    }
+
+    /// Returns redeemed shares whose payout failed to the depositor,
+    /// untokenized, and takes back the pool fee.
+    pub fn revert_lp_redemption(
+        &mut self,
+        asset: &AssetId,
+        depositor: L1Account,
+        shares: Amount,
+        value: Amount,
+        fee: &Amount,
+        now: Timestamp,
+    ) {
+        self.settle_rewards(&depositor, now);
+        if let Ok(pool) = self.pool_mut(asset) {
+            pool.refund_fee(fee);
+            pool.restore(depositor, shares, value);
+        }
+    }
+
+    /// Burns the shares of the request's receiver in the pool of the
+    /// request's asset worth a pool withdrawal and credits the withdrawal's
+    /// fee to the pool. Returns the burnt shares and the fee, which is
+    /// withheld from the payout.
+    pub fn withdraw_from_liq_pool(
+        &mut self,
+        req: &WithdrawalReq,
+        now: Timestamp,
+    ) -> Result<(Amount, Amount)> {
+        let depositor = L1Account(req.receiver);
+        self.settle_rewards(&depositor, now);
+        let pool = self.pool_mut(&self.asset_or_ckbtc(req.asset))?;
+        let shares = pool.burn(&depositor, &req.amount)?;
+        let fee = pool.fee(&req.amount);
+        pool.accrue_fee(fee.clone());
+        Ok((shares, fee))
+    }
+
+    /// Returns the shares burnt for a pool withdrawal whose transfer failed
+    /// to its receiver, and takes back its fee.
+    pub fn revert_pool_withdrawal(
+        &mut self,
+        req: &WithdrawalReq,
+        shares: Amount,
+        fee: &Amount,
+        now: Timestamp,
+    ) {
+        let depositor = L1Account(req.receiver);
+        self.settle_rewards(&depositor, now);
+        if let Ok(pool) = self.pool_mut(&self.asset_or_ckbtc(req.asset)) {
+            pool.refund_fee(fee);
+            pool.restore(depositor, shares, req.amount.clone());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+    use crate::transfer::Disposition;
+
+    #[test]
+    fn test_bootstrap_attributes_unowed_balance() {
+        let mut s = new_state();
+        concluded(&mut s, 41, 1, 2);
+        let owed = s.liabilities();
+        let (a, b) = (Principal::from_slice(&[1]), Principal::from_slice(&[2]));
+        let admin = Principal::anonymous();
+        let attribution = vec![(a, Amount::from(30u32)), (b, Amount::from(20u32))];
+        let before = audit::head().length;
+
+        assert_eq!(
+            s.bootstrap_pool(
+                admin,
+                Amount::from(60u32),
+                attribution.clone(),
+                0u32.into(),
+                0
+            ),
+            Err(ErrorCode::InvalidInput.into())
+        );
+        // Only 40 of the balance exceed the funds owed.
+        let balance = owed.clone() + Amount::from(40u32);
+        assert_eq!(
+            s.bootstrap_pool(admin, Amount::from(50u32), attribution.clone(), balance, 0),
+            Err(ErrorCode::InsufficientFunding {
+                required: 50u32.into(),
+                available: 40u32.into()
+            }
+            .into())
+        );
+        let balance = owed + Amount::from(50u32);
+        s.bootstrap_pool(admin, Amount::from(50u32), attribution, balance, 0)
+            .unwrap();
+        assert_eq!(
+            s.query_liq_holdings(L1Account(a)),
+            Some(Amount::from(30u32))
+        );
+        assert_eq!(
+            s.query_liq_holdings(L1Account(b)),
+            Some(Amount::from(20u32))
+        );
+        assert_eq!(audit::head().length, before + 2);
+    }
+
+    #[test]
+    fn test_lp_deposit_is_checked_before_pull() {
+        let mut s = new_state();
+        let btc = s.ckbtc();
+        s.check_lp_deposit(&btc, &Amount::from(5u32)).unwrap();
+        assert_eq!(
+            s.check_lp_deposit(&btc, &Amount::default()),
+            Err(ErrorCode::InvalidInput.into())
+        );
+        s.draining = true;
+        assert_eq!(
+            s.check_lp_deposit(&btc, &Amount::from(5u32)),
+            Err(ErrorCode::Draining.into())
+        );
+    }
+
+    #[test]
+    fn test_asset_pools_are_segregated() {
+        let mut s = new_state();
+        let (btc, eth) = (s.ckbtc(), Principal::from_slice(&[21]));
+        let lp = L1Account(Principal::from_slice(&[1]));
+        assert_eq!(
+            s.check_lp_deposit(&eth, &Amount::from(5u32)),
+            Err(ErrorCode::NotFound.into())
+        );
+        let info = crate::asset::AssetInfo {
+            symbol: "ckETH".into(),
+            decimals: 18,
+        };
+        s.register_asset(eth, info).unwrap();
+        s.check_lp_deposit(&eth, &Amount::from(5u32)).unwrap();
+
+        s.deposit_liq_pool(&btc, Amount::from(30u32), lp.clone(), 0)
+            .unwrap();
+        s.deposit_liq_pool(&eth, Amount::from(1_000u32), lp.clone(), 0)
+            .unwrap();
+        s.pool_mut(&eth).unwrap().set_fee(100).unwrap();
+        let (value, fee) = s
+            .redeem_lp_shares(&eth, &lp, &Amount::from(500u32), 1)
+            .unwrap();
+        assert_eq!((value, fee), (Amount::from(500u32), Amount::from(5u32)));
+        assert_eq!(s.pool(&eth).unwrap().value_of(&lp), Amount::from(505u32));
+        assert_eq!(s.pool(&btc).unwrap().value_of(&lp), Amount::from(30u32));
+        assert_eq!(s.query_liq_holdings(lp), Some(Amount::from(30u32)));
+    }
+
+    #[test]
+    fn test_fees_accrue_to_share_holders() {
+        let (a, b) = (
+            L1Account(Principal::from_slice(&[1])),
+            L1Account(Principal::from_slice(&[2])),
+        );
+        let mut pool = LiquidityPool::default();
+        assert_eq!(
+            pool.mint(a.clone(), Amount::from(100u32)),
+            Ok(Amount::from(100u32))
+        );
+        pool.accrue_fee(Amount::from(50u32));
+        assert_eq!(pool.value_of(&a), Amount::from(150u32));
+        // Shares are minted at the raised value per share.
+        assert_eq!(
+            pool.mint(b.clone(), Amount::from(30u32)),
+            Ok(Amount::from(20u32))
+        );
+        pool.accrue_fee(Amount::from(12u32));
+        assert_eq!(pool.value_of(&a), Amount::from(160u32));
+        assert_eq!(pool.value_of(&b), Amount::from(32u32));
+
+        assert_eq!(
+            pool.burn(&b, &Amount::from(33u32)),
+            Err(ErrorCode::ChannelFundsProtected.into())
+        );
+        assert_eq!(pool.burn(&b, &Amount::from(32u32)), Ok(Amount::from(20u32)));
+        assert_eq!(
+            pool.stats(),
+            PoolStats {
+                total_shares: Amount::from(100u32),
+                value: Amount::from(160u32),
+                depositors: 1,
+                tokenized_shares: Amount::default(),
+            }
+        );
+    }
+
+    #[test]
+    fn test_tokenized_shares_are_redeemed_by_token_holders() {
+        let mut s = new_state();
+        let btc = s.ckbtc();
+        let (a, b) = (
+            L1Account(Principal::from_slice(&[1])),
+            L1Account(Principal::from_slice(&[2])),
+        );
+        let token = Principal::from_slice(&[9]);
+        s.pool_mut(&btc).unwrap().set_lp_token(Some(token)).unwrap();
+        let shares = s
+            .deposit_liq_pool(&s.ckbtc(), Amount::from(100u32), a.clone(), 0)
+            .unwrap();
+        s.tokenize(&btc, &a, &shares, 0).unwrap();
+        assert_eq!(s.pool(&btc).unwrap().shares_of(&a), Amount::default());
+        assert_eq!(
+            s.pool_mut(&btc).unwrap().set_lp_token(None),
+            Err(ErrorCode::InvalidInput.into())
+        );
+
+        // Bob received 40 of Alice's LP tokens and redeems them.
+        assert_eq!(
+            s.check_lp_withdrawal(&btc, &b, &Amount::from(40u32)),
+            Ok((Some(token), Amount::from(40u32)))
+        );
+        assert_eq!(
+            s.check_lp_withdrawal(&btc, &b, &Amount::from(101u32)),
+            Err(ErrorCode::InsufficientFunding {
+                required: 101u32.into(),
+                available: 100u32.into()
+            }
+            .into())
+        );
+        s.untokenize(&btc, b.clone(), Amount::from(40u32), 1);
+        let (value, fee) = s
+            .redeem_lp_shares(&btc, &b, &Amount::from(40u32), 1)
+            .unwrap();
+        assert_eq!((value, fee), (Amount::from(40u32), Amount::default()));
+        assert_eq!(
+            s.pool(&btc).unwrap().stats().tokenized_shares,
+            Amount::from(60u32)
+        );
+        assert_eq!(s.pool(&btc).unwrap().value(), &Amount::from(60u32));
+    }
+
+    #[test]
+    fn test_pool_withdrawal_fee_stays_in_pool() {
+        let mut s = new_state();
+        let btc = s.ckbtc();
+        let op = operator(&mut s, 10, 10);
+        let ch = concluded(&mut s, 81, 1, 2);
+        let (a, b) = (Principal::from_slice(&[4]), Principal::from_slice(&[5]));
+        for lp in [a, b] {
+            s.deposit_liq_pool(&s.ckbtc(), Amount::from(1_000u32), L1Account(lp), 0)
+                .unwrap();
+        }
+        assert_eq!(
+            s.pool_mut(&btc).unwrap().set_fee(10_001),
+            Err(ErrorCode::InvalidInput.into())
+        );
+        s.pool_mut(&btc).unwrap().set_fee(100).unwrap();
+        let req = WithdrawalReq {
+            channel: ch,
+            participant: account(1),
+            amount: Amount::from(500u32),
+            receiver: a,
+            nonce: 0,
+            expiry: None,
+            asset: None,
+        };
+
+        s.queue_pool_withdrawal(op, req, 0u32.into(), 1).unwrap();
+        let w = s.take_queued_withdrawals().remove(0);
+        assert_eq!(w.arg.amount, Amount::from(495u32));
+        assert_eq!(s.pool(&btc).unwrap().accrued_fees, Amount::from(5u32));
+        assert_eq!(
+            s.pool(&btc).unwrap().value_of(&L1Account(a)),
+            Amount::from(501u32)
+        );
+        assert_eq!(
+            s.pool(&btc).unwrap().value_of(&L1Account(b)),
+            Amount::from(1_003u32)
+        );
+
+        let error = ErrorCode::TransferTooOld.into();
+        s.finish_withdrawal(w, Disposition::Failed(error), 2);
+        assert_eq!(s.pool(&btc).unwrap().accrued_fees, Amount::default());
+        assert_eq!(
+            s.pool(&btc).unwrap().value_of(&L1Account(a)),
+            Amount::from(1_000u32)
+        );
+    }
+
+    #[test]
+    fn test_pool_withdrawal_leaves_channel_funds() {
+        let mut s = new_state();
+        let btc = s.ckbtc();
+        let ch = concluded(&mut s, 61, 1, 2);
+        let lp = Principal::from_slice(&[3]);
+        s.deposit_liq_pool(&s.ckbtc(), Amount::from(30u32), L1Account(lp), 0)
+            .unwrap();
+        let mut req = WithdrawalReq {
+            channel: ch.clone(),
+            participant: account(1),
+            amount: Amount::from(40u32),
+            receiver: lp,
+            nonce: 0,
+            expiry: None,
+            asset: None,
+        };
+
+        assert_eq!(
+            s.withdraw_from_liq_pool(&req, 1),
+            Err(ErrorCode::ChannelFundsProtected.into())
+        );
+        req.amount = Amount::from(20u32);
+        let (shares, fee) = s.withdraw_from_liq_pool(&req, 1).unwrap();
+        assert_eq!(fee, Amount::default());
+        assert_eq!(
+            s.pool(&btc).unwrap().value_of(&L1Account(lp)),
+            Amount::from(10u32)
+        );
+        assert_eq!(holdings(&s, &ch, 1), Amount::from(100u32));
+        assert_eq!(holdings(&s, &ch, 2), Amount::from(100u32));
+
+        s.revert_pool_withdrawal(&req, shares, &fee, 2);
+        assert_eq!(
+            s.query_liq_holdings(L1Account(lp)),
+            Some(Amount::from(30u32))
+        );
+    }
+}
@@ -0,0 +1,210 @@
+//  Copyright 2025 PolyCrypt GmbH
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+
+//! The payment hash registry shared by canister-side forwards and the
+//! Lightning invoices of swaps and invoice requests. A forward, a swap and an
+//! invoice locked under the same hash are released together by the first
+//! preimage reveal, through whichever of them it happens, so that a payment
+//! routed between canister channels and Lightning settles atomically. Revealed
+//! preimages stay queryable, so that the party paid on one side can claim on
+//! the other.
+
+use crate::error::*;
+use crate::invoice::InvoiceId;
+use crate::receiver::TXQuerier;
+use crate::swap::SwapId;
+use crate::types::*;
+use crate::{CanisterState, mutate_state, read_state};
+use candid::candid_method;
+use ic_cdk::api::time as blocktime;
+use ic_cdk::{query, update};
+use std::collections::{BTreeMap, BTreeSet};
+
+#[derive(Default)]
+/// The open swaps and invoices by payment hash, and the revealed preimages.
+pub struct PaymentHashes {
+    swaps: BTreeMap<PaymentHash, BTreeSet<SwapId>>,
+    invoices: BTreeMap<PaymentHash, BTreeSet<InvoiceId>>,
+    preimages: BTreeMap<PaymentHash, Vec<u8>>,
+}
+
+#[derive(Clone, Deserialize, CandidType, Default, PartialEq, Eq, Debug)]
+/// What a preimage reveal released.
+pub struct Released {
+    pub forward: bool,
+    pub swaps: Vec<SwapId>,
+    pub invoices: Vec<InvoiceId>,
+}
+
+#[update]
+#[candid_method(update)]
+/// Reveals a payment preimage, settling the forward, claimed swaps and issued
+/// invoices locked under its hash.
+fn reveal_preimage(preimage: Vec<u8>) -> Result<Released> {
+    mutate_state(|s| s.reveal_preimage(preimage, blocktime()))
+}
+
+#[query]
+#[candid_method(query)]
+/// The revealed preimage of a payment hash, if any.
+fn query_preimage(hash: PaymentHash) -> Option<Vec<u8>> {
+    read_state(|s| s.payment_hashes.preimages.get(&hash).cloned())
+}
+
+impl PaymentHashes {
+    pub fn add_swap(&mut self, hash: PaymentHash, id: SwapId) {
+        self.swaps.entry(hash).or_default().insert(id);
+    }
+
+    pub fn add_invoice(&mut self, hash: PaymentHash, id: InvoiceId) {
+        self.invoices.entry(hash).or_default().insert(id);
+    }
+
+    pub fn remove_swap(&mut self, hash: &PaymentHash, id: SwapId) {
+        remove(&mut self.swaps, hash, id);
+    }
+
+    pub fn remove_invoice(&mut self, hash: &PaymentHash, id: InvoiceId) {
+        remove(&mut self.invoices, hash, id);
+    }
+}
+
+fn remove(ids: &mut BTreeMap<PaymentHash, BTreeSet<u64>>, hash: &PaymentHash, id: u64) {
+    if let Some(set) = ids.get_mut(hash) {
+        set.remove(&id);
+        if set.is_empty() {
+            ids.remove(hash);
+        }
+    }
+}
+
+impl<Q: TXQuerier> CanisterState<Q> {
+    pub fn reveal_preimage(&mut self, preimage: Vec<u8>, now: Timestamp) -> Result<Released> {
+        let released = self.release(preimage, now);
+        if released == Released::default() {
+            return Err(ErrorCode::NotFound.into());
+        }
+        Ok(released)
+    }
+
+    /// Records the preimage and pays out everything still locked under its
+    /// hash. Swaps that were not claimed and expired forwards and invoices
+    /// are left to their refunds.
+    pub(crate) fn release(&mut self, preimage: Vec<u8>, now: Timestamp) -> Released {
+        let hash = payment_hash(&preimage);
+        let mut released = Released::default();
+        if self.forward_settleable(&hash, now) {
+            self.pay_out_forward(&hash, preimage.clone());
+            released.forward = true;
+        }
+        let swaps = self.payment_hashes.swaps.get(&hash).cloned();
+        for id in swaps.unwrap_or_default() {
+            if self.swap_completable(id, now) {
+                self.pay_out_swap(id, preimage.clone(), now);
+                self.payment_hashes.remove_swap(&hash, id);
+                released.swaps.push(id);
+            }
+        }
+        let invoices = self.payment_hashes.invoices.get(&hash).cloned();
+        for id in invoices.unwrap_or_default() {
+            if self.invoice_settleable(id, now) {
+                self.pay_out_invoice(id, preimage.clone(), now);
+                self.payment_hashes.remove_invoice(&hash, id);
+                released.invoices.push(id);
+            }
+        }
+        if released != Released::default() {
+            self.payment_hashes.preimages.insert(hash, preimage);
+        }
+        released
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::htlc::{ForwardStatus, ForwardTerms, Leg};
+    use crate::operator::Direction;
+    use crate::swap::{SWAP_CLAIM_WINDOW, SwapRequest, SwapStatus};
+    use crate::testing::*;
+    use icrc_ledger_types::icrc1::account::Account;
+
+    #[test]
+    fn test_swap_completion_settles_forward() {
+        let mut s = new_state();
+        let (payer, hub, payee) = (1, 2, 3);
+        let a = concluded(&mut s, 21, payer, hub);
+        let b = concluded(&mut s, 22, hub, payee);
+        let hash = payment_hash(b"atomic");
+        let expiry = 10 * SWAP_CLAIM_WINDOW;
+        let terms = ForwardTerms {
+            hash,
+            amount: Amount::from(30u32),
+            max_fee: Amount::default(),
+            expiry,
+            incoming: Leg {
+                channel: a.clone(),
+                from: account(payer),
+                to: account(hub),
+            },
+            outgoing: Leg {
+                channel: b,
+                from: account(hub),
+                to: account(payee),
+            },
+        };
+        let msg = terms.signing_bytes();
+        s.lock_forward(terms, &sign(payer, &msg), &sign(hub, &msg), 0)
+            .unwrap();
+
+        let op = operator(&mut s, 10, 10);
+        advertise(&mut s, op, Direction::ToLightning, 20_000);
+        let req = SwapRequest {
+            invoice: "lnbc1".into(),
+            hash,
+            amount: Amount::from(50u32),
+            max_fee: Amount::from(1u32),
+            nonce: 0,
+            expiry,
+            funding: Funding::new(a, account(hub)),
+            operator: None,
+            refund_to: Account {
+                owner: op,
+                subaccount: None,
+            },
+        };
+        let sig = sign(hub, &req.signing_bytes());
+        let id = s.create_swap(op, req, &sig, 0).unwrap();
+        s.claim_swap(op, id, 1).unwrap();
+
+        assert_eq!(
+            s.reveal_preimage(b"other".to_vec(), 1),
+            Err(ErrorCode::NotFound.into())
+        );
+        s.complete_swap(op, id, b"atomic".to_vec(), 1).unwrap();
+        let preimage = b"atomic".to_vec();
+        assert!(matches!(s.swaps[&id].status, SwapStatus::Completed { .. }));
+        assert_eq!(
+            s.forwards[&hash].status,
+            ForwardStatus::Settled {
+                preimage: preimage.clone()
+            }
+        );
+        assert_eq!(s.payment_hashes.preimages.get(&hash), Some(&preimage));
+        assert_eq!(
+            s.reveal_preimage(preimage, 2),
+            Err(ErrorCode::NotFound.into())
+        );
+    }
+}
@@ -0,0 +1,214 @@
+//  Copyright 2025 PolyCrypt GmbH
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+
+//! Privacy mode, an optional deployment mode that keeps payment volumes from
+//! being publicly enumerable. With privacy mode on, holdings, channel
+//! listings, channel views and events are only returned to callers entitled
+//! to read the channel: principals linked to one of its participants and its
+//! observers. A participant links a principal by signing
+//! `PrincipalLink::signing_bytes` with their channel key. Other callers get
+//! nothing back, and the replay of all events is reserved to controllers.
+
+use crate::audit;
+use crate::error::*;
+use crate::receiver::TXQuerier;
+use crate::types::*;
+use crate::{CanisterState, mutate_state, read_state, require};
+use candid::{Principal, candid_method};
+use ic_cdk::{query, update};
+use k256::elliptic_curve::sec1::ToEncodedPoint;
+use std::collections::{BTreeMap, BTreeSet};
+
+#[derive(Clone, Copy, Deserialize, CandidType, PartialEq, Eq, Debug)]
+/// A participant's linking or unlinking of a principal allowed to read their
+/// channels in privacy mode.
+pub struct PrincipalLink {
+    pub principal: Principal,
+    /// Whether the principal is linked or unlinked.
+    pub linked: bool,
+    /// Increases with each link of the participant so that old links cannot
+    /// be replayed.
+    pub seq: u64,
+}
+
+#[derive(Default)]
+pub struct Privacy {
+    enabled: bool,
+    /// The principals linked to each participant.
+    links: BTreeMap<L2Account, BTreeSet<Principal>>,
+    /// The sequence number of each participant's latest link.
+    seqs: BTreeMap<L2Account, u64>,
+}
+
+#[update]
+#[candid_method(update)]
+/// Turns privacy mode on or off. Admin only.
+fn set_privacy_mode(enabled: bool) -> Result<()> {
+    audit::logged("set_privacy_mode", audit::args_hash((enabled,)), || {
+        crate::admin::require_admin()?;
+        mutate_state(|s| s.privacy.enabled = enabled);
+        Ok(())
+    })
+}
+
+#[query]
+#[candid_method(query)]
+fn query_privacy_mode() -> bool {
+    read_state(|s| s.privacy.enabled)
+}
+
+#[update]
+#[candid_method(update)]
+/// Links a principal to a participant or unlinks it. The signature is by the
+/// participant over `PrincipalLink::signing_bytes`.
+fn link_principal(participant: L2Account, link: PrincipalLink, sig: Vec<u8>) -> Result<()> {
+    mutate_state(|s| s.link_principal(participant, link, &sig))
+}
+
+#[query]
+#[candid_method(query)]
+/// The principals linked to a participant.
+fn query_linked_principals(participant: L2Account) -> Vec<Principal> {
+    read_state(|s| {
+        s.privacy
+            .links
+            .get(&participant)
+            .map(|p| p.iter().copied().collect())
+            .unwrap_or_default()
+    })
+}
+
+impl PrincipalLink {
+    /// The bytes the participant signs to make the link.
+    pub fn signing_bytes(&self, participant: &L2Account) -> Vec<u8> {
+        let mut data = b"ckLightning principal link".to_vec();
+        data.extend_from_slice(participant.0.to_encoded_point(true).as_bytes());
+        data.extend_from_slice(self.principal.as_slice());
+        data.push(self.linked as u8);
+        data.extend_from_slice(&self.seq.to_le_bytes());
+        data
+    }
+}
+
+impl Privacy {
+    pub fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    fn is_linked(&self, principal: &Principal, participant: &L2Account) -> bool {
+        self.links
+            .get(participant)
+            .is_some_and(|p| p.contains(principal))
+    }
+}
+
+impl<Q: TXQuerier> CanisterState<Q> {
+    pub fn link_principal(
+        &mut self,
+        participant: L2Account,
+        link: PrincipalLink,
+        sig: &[u8],
+    ) -> Result<()> {
+        let msg = link.signing_bytes(&participant);
+        require!(
+            participant.verify(&msg, sig),
+            Authentication {
+                participant_index: None
+            }
+        );
+        if let Some(seq) = self.privacy.seqs.get(&participant) {
+            require!(
+                link.seq > *seq,
+                OutdatedState {
+                    registered_version: *seq,
+                    submitted_version: link.seq,
+                }
+            );
+        }
+        self.privacy.seqs.insert(participant.clone(), link.seq);
+        let links = self.privacy.links.entry(participant.clone()).or_default();
+        match link.linked {
+            true => links.insert(link.principal),
+            false => links.remove(&link.principal),
+        };
+        if links.is_empty() {
+            self.privacy.links.remove(&participant);
+        }
+        Ok(())
+    }
+
+    /// Whether the caller may read a channel: always outside privacy mode,
+    /// and in privacy mode if linked to one of its participants or observing
+    /// it.
+    pub fn may_read_channel(&self, caller: &Principal, channel: &ChannelId) -> bool {
+        !self.privacy.enabled
+            || self.observers.is_observer(channel, caller)
+            || self.channel_params(channel).is_some_and(|p| {
+                p.participants
+                    .iter()
+                    .any(|pk| self.privacy.is_linked(caller, pk))
+            })
+    }
+
+    /// Whether the caller may read a participant's holdings in a channel.
+    pub fn may_read_funding(&self, caller: &Principal, funding: &Funding) -> bool {
+        !self.privacy.enabled
+            || self.privacy.is_linked(caller, &funding.participant)
+            || self.observers.is_observer(&funding.channel, caller)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+
+    #[test]
+    fn test_privacy_mode_requires_linked_principal() {
+        let mut s = new_state();
+        let ch = concluded(&mut s, 91, 1, 2);
+        let (a, b) = (
+            Funding::new(ch.clone(), account(1)),
+            Funding::new(ch.clone(), account(2)),
+        );
+        let wallet = Principal::from_slice(&[7]);
+        assert!(s.may_read_funding(&wallet, &a));
+
+        s.privacy.enabled = true;
+        assert!(!s.may_read_funding(&wallet, &a));
+        assert!(!s.may_read_channel(&wallet, &ch));
+        let listed = s.channels_at(&wallet, 0, 0);
+        assert!(listed.iter().all(|(id, _)| *id != ch));
+
+        let link = PrincipalLink {
+            principal: wallet,
+            linked: true,
+            seq: 1,
+        };
+        let msg = link.signing_bytes(&account(1));
+        assert_eq!(
+            s.link_principal(account(1), link, &sign(2, &msg)),
+            Err(ErrorCode::Authentication {
+                participant_index: None
+            }
+            .into())
+        );
+        s.link_principal(account(1), link, &sign(1, &msg)).unwrap();
+        assert!(s.may_read_funding(&wallet, &a));
+        assert!(!s.may_read_funding(&wallet, &b));
+        assert!(s.may_read_channel(&wallet, &ch));
+        let listed = s.channels_at(&wallet, 0, 0);
+        assert!(listed.iter().any(|(id, _)| *id == ch));
+    }
+}
@@ -0,0 +1,93 @@
+//  Copyright 2025 PolyCrypt GmbH
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+
+//! Ledger blocks whose transfers were credited, per ledger. The set lives in
+//! stable memory, so that a block credited before an upgrade cannot be
+//! credited again after it, whichever way it is submitted.
+
+use crate::asset::AssetId;
+use crate::error::*;
+use crate::memory::{self, Memory, PROCESSED_BLOCKS};
+use crate::receiver::{BlockHeight, TXQuerier};
+use crate::{CanisterState, require};
+use candid::Principal;
+use ic_stable_structures::StableBTreeMap;
+use std::cell::RefCell;
+
+thread_local! {
+    static PROCESSED: RefCell<StableBTreeMap<(Principal, BlockHeight), (), Memory>> =
+        RefCell::new(StableBTreeMap::init(memory::get(PROCESSED_BLOCKS)));
+}
+
+pub fn is_processed(ledger: Principal, block: BlockHeight) -> bool {
+    PROCESSED.with(|p| p.borrow().contains_key(&(ledger, block)))
+}
+
+/// Marks a block as credited. Fails with `AlreadyProcessed` if it was.
+pub fn mark(ledger: Principal, block: BlockHeight) -> Result<()> {
+    let fresh = PROCESSED.with(|p| p.borrow_mut().insert((ledger, block), ()).is_none());
+    require!(fresh, AlreadyProcessed);
+    Ok(())
+}
+
+/// Reopens a block whose funds were not moved after all.
+pub fn unmark(ledger: Principal, block: BlockHeight) {
+    PROCESSED.with(|p| p.borrow_mut().remove(&(ledger, block)));
+}
+
+impl<Q: TXQuerier> CanisterState<Q> {
+    /// Fails with `AlreadyProcessed` if a block of an asset's ledger was
+    /// credited.
+    pub fn require_unprocessed(&self, asset: &AssetId, block: BlockHeight) -> Result<()> {
+        require!(!is_processed(*asset, block), AlreadyProcessed);
+        Ok(())
+    }
+
+    /// Checks that a block of an asset's ledger may be credited and returns
+    /// the querier to read it with.
+    pub fn block_querier(&self, asset: &AssetId, block: BlockHeight) -> Result<Q> {
+        self.accepting()?;
+        self.require_unprocessed(asset, block)?;
+        let receiver = self.receiver(asset)?;
+        receiver
+            .require_new(block)
+            .map_err(ErrorCode::ReceiverError)?;
+        Ok(receiver.tx_querier())
+    }
+
+    /// Marks a block of an asset's ledger as credited. A ckBTC block is no
+    /// longer an unattributed deposit.
+    pub fn mark_processed(&mut self, asset: &AssetId, block: BlockHeight) -> Result<()> {
+        mark(*asset, block)?;
+        if *asset == self.ckbtc() {
+            self.unattributed.remove(&block);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_blocks_are_processed_once_per_ledger() {
+        let (a, b) = (Principal::from_slice(&[1]), Principal::from_slice(&[2]));
+        assert!(!is_processed(a, 7));
+        mark(a, 7).unwrap();
+        assert_eq!(mark(a, 7), Err(ErrorCode::AlreadyProcessed.into()));
+        assert!(is_processed(a, 7));
+        mark(b, 7).unwrap();
+    }
+}
@@ -0,0 +1,267 @@
+//  Copyright 2025 PolyCrypt GmbH
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+
+use crate::error::*;
+use crate::page::*;
+use crate::require;
+use crate::types::*;
+use candid::Principal;
+use std::collections::BTreeMap;
+
+/// Identifies a quarantined amount.
+pub type QuarantineId = u64;
+
+/// How many distinct approvals a resolution needs by default.
+pub const DEFAULT_APPROVAL_THRESHOLD: u32 = 2;
+
+#[derive(Clone, Deserialize, CandidType, PartialEq, Eq, Debug)]
+/// Why funds were put into quarantine.
+pub enum QuarantineReason {
+    /// The ledger block that credited the funds no longer verifies.
+    FailedReverification { block_height: u64 },
+    /// A fraud proof concerning the funds is under review.
+    FraudProof { evidence: String },
+    /// The funds' channel was frozen.
+    FrozenChannel,
+}
+
+#[derive(Clone, Deserialize, CandidType, PartialEq, Eq)]
+/// How quarantined funds are to be moved out of quarantine.
+pub enum Resolution {
+    /// Return the funds to the holdings they were taken from.
+    Release,
+    /// Credit the funds to other holdings.
+    Reassign(Funding),
+    /// Remove the funds from the canister's accounting, e.g. because they were
+    /// never actually received.
+    Discard,
+}
+
+#[derive(Clone, Deserialize, CandidType)]
+/// Contested funds that were taken out of a participant's holdings and are
+/// parked until resolved. Quarantined funds can neither be withdrawn nor used
+/// for pool liquidity.
+pub struct QuarantineEntry {
+    /// The holdings the funds were taken from.
+    pub funding: Funding,
+    /// The quarantined amount.
+    pub amount: Amount,
+    /// Why the funds were quarantined.
+    pub reason: QuarantineReason,
+    /// When the funds were quarantined.
+    pub created_at: Timestamp,
+    /// The resolutions proposed so far, by approver.
+    pub approvals: Vec<(Principal, Resolution)>,
+}
+
+/// Bucket of contested holdings. Entries can only leave the quarantine once
+/// enough distinct approvers agree on the same resolution.
+pub struct Quarantine {
+    entries: BTreeMap<QuarantineId, QuarantineEntry>,
+    next_id: QuarantineId,
+    threshold: u32,
+    /// The thresholds proposed so far, by approver.
+    threshold_votes: BTreeMap<Principal, u32>,
+}
+
+impl Default for Quarantine {
+    fn default() -> Self {
+        Self {
+            entries: Default::default(),
+            next_id: 0,
+            threshold: DEFAULT_APPROVAL_THRESHOLD,
+            threshold_votes: Default::default(),
+        }
+    }
+}
+
+impl Quarantine {
+    /// Parks funds in quarantine and returns the new entry's id.
+    pub fn park(
+        &mut self,
+        funding: Funding,
+        amount: Amount,
+        reason: QuarantineReason,
+        now: Timestamp,
+    ) -> QuarantineId {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.entries.insert(
+            id,
+            QuarantineEntry {
+                funding,
+                amount,
+                reason,
+                created_at: now,
+                approvals: vec![],
+            },
+        );
+        id
+    }
+
+    /// Records an approver's vote for a resolution. Once the threshold of
+    /// distinct approvers agreeing on the same resolution is reached, the
+    /// entry is removed and returned together with the resolution to apply.
+    /// A later vote by the same approver replaces their earlier one.
+    pub fn approve(
+        &mut self,
+        id: QuarantineId,
+        approver: Principal,
+        resolution: Resolution,
+    ) -> Result<Option<(QuarantineEntry, Resolution)>> {
+        let entry = self.entries.get_mut(&id).ok_or(ErrorCode::NotFound)?;
+        entry.approvals.retain(|(p, _)| *p != approver);
+        entry.approvals.push((approver, resolution.clone()));
+        let votes = entry
+            .approvals
+            .iter()
+            .filter(|(_, r)| *r == resolution)
+            .count();
+        if votes < self.threshold as usize {
+            return Ok(None);
+        }
+        let entry = self.entries.remove(&id).ok_or(ErrorCode::NotFound)?;
+        Ok(Some((entry, resolution)))
+    }
+
+    /// Records an approver's vote for a new threshold. The threshold changes
+    /// once as many distinct approvers as the current threshold agree on it,
+    /// which clears all votes. Returns whether it changed. A later vote by the
+    /// same approver replaces their earlier one.
+    pub fn vote_threshold(&mut self, approver: Principal, threshold: u32) -> Result<bool> {
+        require!(threshold > 0, InvalidInput);
+        self.threshold_votes.insert(approver, threshold);
+        let votes = self
+            .threshold_votes
+            .values()
+            .filter(|t| **t == threshold)
+            .count();
+        if votes < self.threshold as usize {
+            return Ok(false);
+        }
+        self.threshold = threshold;
+        self.threshold_votes.clear();
+        Ok(true)
+    }
+
+    pub fn threshold(&self) -> u32 {
+        self.threshold
+    }
+
+    pub fn entries(&self) -> Vec<(QuarantineId, QuarantineEntry)> {
+        self.entries
+            .iter()
+            .map(|(id, e)| (*id, e.clone()))
+            .collect()
+    }
+
+    pub fn page(
+        &self,
+        cursor: Option<Cursor>,
+        limit: u32,
+    ) -> Result<Page<(QuarantineId, QuarantineEntry)>> {
+        paginate(
+            self.entries.iter().map(|(id, e)| (*id, (*id, e.clone()))),
+            cursor,
+            limit,
+        )
+    }
+
+    /// Sums all quarantined funds.
+    pub fn total(&self) -> Amount {
+        self.entries
+            .values()
+            .fold(Amount::default(), |acc, e| acc + e.amount.clone())
+    }
+
+    /// Sums the quarantined funds taken from the given holdings.
+    pub fn total_of(&self, funding: &Funding) -> Amount {
+        self.entries
+            .values()
+            .filter(|e| e.funding == *funding)
+            .fold(Amount::default(), |acc, e| acc + e.amount.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn approver(n: u8) -> Principal {
+        Principal::from_slice(&[n])
+    }
+
+    fn park_one(q: &mut Quarantine) -> QuarantineId {
+        q.park(
+            Funding {
+                channel: ChannelId::default(),
+                participant: L2Account(k256::SecretKey::from_slice(&[1; 32]).unwrap().public_key()),
+                asset: None,
+            },
+            Amount::from(5u32),
+            QuarantineReason::FrozenChannel,
+            0,
+        )
+    }
+
+    #[test]
+    fn test_resolution_needs_threshold_of_matching_votes() {
+        let mut q = Quarantine::default();
+        let id = park_one(&mut q);
+
+        assert!(
+            q.approve(id, approver(1), Resolution::Release)
+                .unwrap()
+                .is_none()
+        );
+        // The same approver voting again does not count twice.
+        assert!(
+            q.approve(id, approver(1), Resolution::Release)
+                .unwrap()
+                .is_none()
+        );
+        // A diverging vote does not complete the resolution.
+        assert!(
+            q.approve(id, approver(2), Resolution::Discard)
+                .unwrap()
+                .is_none()
+        );
+
+        let (entry, res) = q
+            .approve(id, approver(3), Resolution::Release)
+            .unwrap()
+            .unwrap();
+        assert!(res == Resolution::Release);
+        assert_eq!(entry.amount, Amount::from(5u32));
+        assert!(q.entries().is_empty());
+        assert!(matches!(
+            q.approve(id, approver(1), Resolution::Release),
+            Err(Error {
+                code: ErrorCode::NotFound,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn test_threshold_changes_need_threshold_of_votes() {
+        let mut q = Quarantine::default();
+        assert!(!q.vote_threshold(approver(1), 1).unwrap());
+        assert!(!q.vote_threshold(approver(1), 1).unwrap());
+        assert_eq!(q.threshold(), DEFAULT_APPROVAL_THRESHOLD);
+        assert!(!q.vote_threshold(approver(2), 3).unwrap());
+        assert!(q.vote_threshold(approver(3), 1).unwrap());
+        assert_eq!(q.threshold(), 1);
+    }
+}
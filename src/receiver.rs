@@ -11,6 +11,8 @@
 //  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
 //  See the License for the specific language governing permissions and
 //  limitations under the License.
+use crate::memory::{self, Memory, UNSPENT_FUNDS};
+use crate::store::StoredAmount;
 use crate::types::Amount;
 use crate::types::Funding;
 use async_trait::async_trait;
@@ -19,23 +21,40 @@ pub use candid::{
     types::{Serializer, Type},
 };
 use ic_ledger_types::{
-    AccountIdentifier, Block, DEFAULT_SUBACCOUNT, GetBlocksArgs, Operation, Transaction,
-    query_archived_blocks, query_blocks,
+    AccountIdentifier, Block, DEFAULT_SUBACCOUNT, GetBlocksArgs, Operation, Subaccount,
+    Transaction, query_archived_blocks, query_blocks,
 };
-use std::collections::{BTreeMap, BTreeSet};
+use ic_stable_structures::StableBTreeMap;
+use icrc_ledger_types::icrc::generic_value::{ICRC3Map, ICRC3Value};
+use icrc_ledger_types::icrc1::account::Account;
+use icrc_ledger_types::icrc3::blocks::{GetBlocksRequest, GetBlocksResult};
+use std::cell::RefCell;
+use std::collections::BTreeMap;
 
-pub const MAINNET_ICP_LEDGER: &str = "bkyz2-fmaaa-aaaaa-qaaaq-cai";
-pub const DEVNET_CKBTC_LEDGER: &str = "bd3sg-teaaa-aaaaa-qaaba-cai";
+/// bkyz2-fmaaa-aaaaa-qaaaq-cai
+pub const MAINNET_ICP_LEDGER: Principal = Principal::from_slice(&[128, 0, 0, 0, 0, 16, 0, 1, 1, 1]);
+/// bd3sg-teaaa-aaaaa-qaaba-cai
+pub const DEVNET_CKBTC_LEDGER: Principal =
+    Principal::from_slice(&[128, 0, 0, 0, 0, 16, 0, 2, 1, 1]);
 pub const DEFAULT_CKBTC_FEE: u64 = 1000;
 
 pub type Memo = u64;
 pub type BlockHeight = u64;
 
+/// Most blocks fetched per ledger scan.
+pub const MAX_SCAN_BLOCKS: u64 = 100;
+
 /// ICP token handling errors.
-#[derive(PartialEq, Eq, CandidType, Deserialize, Debug)]
+#[derive(Clone, PartialEq, Eq, CandidType, Deserialize, Debug)]
 pub enum ICPReceiverError {
     TransactionType,
     Recipient,
+    /// The transfer's memo is not the one of the funding.
+    Memo,
+    /// The transfer is smaller than declared.
+    Amount,
+    /// The transfer was not sent by the principal claiming it.
+    Sender,
     DuplicateTransaction,
     FailedToQuery,
 }
@@ -46,54 +65,133 @@ impl std::fmt::Display for ICPReceiverError {
     }
 }
 
+thread_local! {
+    // Received tokens per ledger and memo, kept across upgrades like the
+    // processed blocks they were credited from.
+    static UNSPENT: RefCell<StableBTreeMap<(Principal, Memo), StoredAmount, Memory>> =
+        RefCell::new(StableBTreeMap::init(memory::get(UNSPENT_FUNDS)));
+}
+
 /// ICP transaction receiver for receiving and tracking payments for separate purposes.
 pub struct Receiver<Q: TXQuerier> {
     tx_querier: Q,
+    my_principal: Principal,
     my_account: AccountIdentifier,
-    known_txs: BTreeSet<BlockHeight>, // set of block heights
-    unspent: BTreeMap<Memo, Amount>,  // received tokens per memo
+    ledger: Principal,
+    known_txs: BTreeMap<BlockHeight, (Memo, u64)>, // credited memo and amount per block
+    scan_cursor: Option<BlockHeight>,              // next block to scan, once started
+}
+
+/// A transfer or mint read from an ICRC-3 block.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct IcrcTransfer {
+    pub block: BlockHeight,
+    /// The debited account, none for mints.
+    pub from: Option<Account>,
+    pub to: Account,
+    pub amount: u64,
+    pub memo: Option<Vec<u8>>,
+}
+
+/// The transfers in a range of ICRC-3 blocks.
+pub struct IcrcBlocks {
+    pub transfers: Vec<IcrcTransfer>,
+    /// The block after the scanned range.
+    pub next: BlockHeight,
+    pub log_length: u64,
 }
 
 /// ICP transaction querier.
 #[async_trait]
-pub trait TXQuerier {
+pub trait TXQuerier: Clone + Sync {
     /// Allows the
     async fn query_tx(
         &self,
         block_height: BlockHeight,
     ) -> Result<TransactionNotification, ICPReceiverError>;
 
+    /// Reads the transfer in a block.
     async fn query_icrc_tx(
         &self,
         block_height: BlockHeight,
-        amount: u64,
-    ) -> Result<u64, ICPReceiverError>;
+    ) -> Result<IcrcTransfer, ICPReceiverError> {
+        self.icrc3_get_blocks(block_height, 1)
+            .await?
+            .transfers
+            .into_iter()
+            .find(|t| t.block == block_height)
+            .ok_or(ICPReceiverError::TransactionType)
+    }
+
+    /// Reads the transfers in up to `length` blocks from `start` on, using
+    /// `icrc3_get_blocks`.
+    async fn icrc3_get_blocks(
+        &self,
+        start: BlockHeight,
+        length: u64,
+    ) -> Result<IcrcBlocks, ICPReceiverError>;
+
+    /// A querier of another ledger.
+    fn for_ledger(&self, ledger: Principal) -> Self;
 }
 
 /// Mocked ICP transaction querier for simulation and testing purposes.
-#[derive(Default)]
+#[derive(Clone, Default)]
 pub struct MockTXQuerier {
     txs: BTreeMap<BlockHeight, TransactionNotification>,
+    icrc_txs: BTreeMap<BlockHeight, IcrcTransfer>,
+}
+
+#[async_trait]
+impl TXQuerier for MockTXQuerier {
+    async fn query_tx(
+        &self,
+        block_height: BlockHeight,
+    ) -> Result<TransactionNotification, ICPReceiverError> {
+        self.txs
+            .get(&block_height)
+            .cloned()
+            .ok_or(ICPReceiverError::FailedToQuery)
+    }
+
+    async fn icrc3_get_blocks(
+        &self,
+        start: BlockHeight,
+        length: u64,
+    ) -> Result<IcrcBlocks, ICPReceiverError> {
+        let log_length = self.icrc_txs.keys().last().map_or(0, |b| b + 1);
+        let next = start.saturating_add(length).min(log_length).max(start);
+        Ok(IcrcBlocks {
+            transfers: self
+                .icrc_txs
+                .range(start..next)
+                .map(|(_, t)| t.clone())
+                .collect(),
+            next,
+            log_length,
+        })
+    }
+
+    /// Shares the mocked blocks, whichever ledger is queried.
+    fn for_ledger(&self, _ledger: Principal) -> Self {
+        self.clone()
+    }
 }
 
-// #[async_trait]
-// impl TXQuerier for MockTXQuerier {
-//     async fn query_tx(&self, block_height: BlockHeight) -> Result<TransactionNotification, u64> {
-//         self.txs
-//             .get(&block_height)
-//             .cloned()
-//             .ok_or(ICPReceiverError::FailedToQuery)
-//     }
-// }
-
-// impl MockTXQuerier {
-//     /// Inserts a transaction so that it can be read via query_tx().
-//     pub fn register_tx(&mut self, block_height: BlockHeight, tx: TransactionNotification) {
-//         self.txs.insert(block_height, tx);
-//     }
-// }
+impl MockTXQuerier {
+    /// Inserts a transaction so that it can be read via query_tx().
+    pub fn register_tx(&mut self, block_height: BlockHeight, tx: TransactionNotification) {
+        self.txs.insert(block_height, tx);
+    }
+
+    /// Appends a transfer so that it can be read via icrc3_get_blocks().
+    pub fn register_icrc_transfer(&mut self, transfer: IcrcTransfer) {
+        self.icrc_txs.insert(transfer.block, transfer);
+    }
+}
 
 /// Real ICP transaction querier using inter-canister calls to the ICP ledger.
+#[derive(Clone)]
 pub struct CanisterTXQuerier {
     ledger: Principal,
 }
@@ -114,50 +212,92 @@ impl TXQuerier for CanisterTXQuerier {
         Err(ICPReceiverError::FailedToQuery)
     }
 
-    async fn query_icrc_tx(
+    async fn icrc3_get_blocks(
         &self,
-        block_height: BlockHeight,
-        amount: u64,
-    ) -> Result<u64, ICPReceiverError> {
-        Ok(amount)
+        start: BlockHeight,
+        length: u64,
+    ) -> Result<IcrcBlocks, ICPReceiverError> {
+        let _call = crate::LedgerCall::start();
+        let arg = vec![GetBlocksRequest {
+            start: Nat::from(start),
+            length: Nat::from(length),
+        }];
+        let result: GetBlocksResult =
+            ic_cdk::call::Call::unbounded_wait(self.ledger, "icrc3_get_blocks")
+                .with_arg(arg)
+                .await
+                .map_err(|_| ICPReceiverError::FailedToQuery)?
+                .candid()
+                .map_err(|_| ICPReceiverError::FailedToQuery)?;
+        let log_length = u64::try_from(result.log_length.0).unwrap_or(u64::MAX);
+        let mut next = start;
+        let mut transfers = vec![];
+        for b in result.blocks {
+            let Ok(block) = u64::try_from(b.id.0) else {
+                continue;
+            };
+            next = next.max(block + 1);
+            if let Some(t) = IcrcTransfer::decode(block, b.block) {
+                transfers.push(t);
+            }
+        }
+        // Archived blocks are skipped; their transfers can still be credited
+        // by scanning their blocks explicitly.
+        if transfers.is_empty() && !result.archived_blocks.is_empty() {
+            next = next.max(log_length.min(start.saturating_add(length)));
+        }
+        Ok(IcrcBlocks {
+            transfers,
+            next,
+            log_length,
+        })
+    }
+
+    fn for_ledger(&self, ledger: Principal) -> Self {
+        Self::new(ledger)
     }
 }
 
 impl CanisterTXQuerier {
     pub fn new(ledger: Principal) -> Self {
-        Self { ledger: ledger }
+        Self { ledger }
+    }
+
+    /// Targets another ledger canister.
+    pub fn set_ledger(&mut self, ledger: Principal) {
+        self.ledger = ledger;
     }
 
     /// Constructs a new canister TX querier targeting the mainnet ICP ledger canister.
     pub fn for_mainnet() -> Self {
         Self {
-            ledger: Principal::from_text(MAINNET_ICP_LEDGER).unwrap(),
+            ledger: MAINNET_ICP_LEDGER,
         }
     }
     pub fn for_ckbtc_devnet() -> Self {
         Self {
-            ledger: Principal::from_text(DEVNET_CKBTC_LEDGER).unwrap(),
+            ledger: DEVNET_CKBTC_LEDGER,
         }
     }
 
     /// Queries a block from the ICP ledger's internal blockchain.
     async fn get_block_from_ledger(&self, block_height: BlockHeight) -> Option<Block> {
+        let _call = crate::LedgerCall::start();
         let args = GetBlocksArgs {
             start: block_height,
             length: 1,
         };
         if let Ok(result) = query_blocks(self.ledger, &args.clone()).await {
-            if result.blocks.len() != 0 {
+            if !result.blocks.is_empty() {
                 return result.blocks.first().cloned();
             }
             if let Some(b) = result
                 .archived_blocks
                 .into_iter()
-                .find(|b| (b.start <= block_height && (block_height - b.start) < b.length))
+                .find(|b| b.start <= block_height && (block_height - b.start) < b.length)
+                && let Ok(Ok(range)) = query_archived_blocks(&b.callback, &args).await
             {
-                if let Ok(Ok(range)) = query_archived_blocks(&b.callback, &args).await {
-                    return range.blocks.get((block_height - b.start) as usize).cloned();
-                }
+                return range.blocks.get((block_height - b.start) as usize).cloned();
             }
         }
         None
@@ -168,84 +308,302 @@ impl<Q> Receiver<Q>
 where
     Q: TXQuerier,
 {
-    /// Creates a new transaction receiver for the specified canister principal.
-    pub fn new(q: Q, my_principal: Principal) -> Self {
+    /// Creates a new transaction receiver for the specified canister principal
+    /// on a ledger. Tokens received earlier on the ledger and not withdrawn
+    /// yet, e.g. before an upgrade, are still unspent.
+    pub fn new(q: Q, my_principal: Principal, ledger: Principal) -> Self {
         Self {
             tx_querier: q,
+            my_principal,
             my_account: AccountIdentifier::new(&my_principal, &DEFAULT_SUBACCOUNT),
+            ledger,
             known_txs: Default::default(),
-            unspent: Default::default(),
+            scan_cursor: None,
         }
     }
 
-    /// Verifies a transaction, and if it's valid and new, tracks its funds and
-    /// returns its amount.
-    pub async fn verify_icrc(
-        &mut self,
+    /// A new receiver of transfers to the same canister on another ledger.
+    pub fn for_ledger(&self, ledger: Principal) -> Self {
+        Self::new(
+            self.tx_querier.for_ledger(ledger),
+            self.my_principal,
+            ledger,
+        )
+    }
+
+    /// Switches to the unspent tokens of another ledger. The querier is
+    /// retargeted separately.
+    pub fn set_ledger(&mut self, ledger: Principal) {
+        self.ledger = ledger;
+    }
+
+    /// A copy of the querier, with which the ledger is queried without
+    /// borrowing the receiver across the call.
+    pub fn tx_querier(&self) -> Q {
+        self.tx_querier.clone()
+    }
+
+    /// Fails if a block was already verified.
+    pub fn require_new(
+        &self,
         block_height: BlockHeight,
+    ) -> std::result::Result<(), ICPReceiverError> {
+        if self.known_txs.contains_key(&block_height) {
+            return Err(ICPReceiverError::DuplicateTransaction);
+        }
+        Ok(())
+    }
+
+    /// Verifies that a transfer read from the ledger sends at least `amount`
+    /// to the canister's default account with the funding's memo, and if it's
+    /// new, tracks its funds and returns the transferred amount.
+    pub fn verify_icrc(
+        &mut self,
+        tx: &IcrcTransfer,
         amount: u64,
-        funding: Funding,
+        funding: &Funding,
     ) -> std::result::Result<Amount, ICPReceiverError> {
-        if self.known_txs.contains(&block_height) {
-            return Err(ICPReceiverError::DuplicateTransaction);
+        self.require_new(tx.block)?;
+        self.check_icrc(tx, amount, funding)?;
+        self.known_txs.insert(tx.block, (funding.memo(), tx.amount));
+        self.credit(funding.memo(), Amount::from(tx.amount));
+        Ok(Amount::from(tx.amount))
+    }
+
+    /// Checks that a transfer read from the ledger sends at least `amount` to
+    /// the canister's default account with the funding's memo, without
+    /// tracking it.
+    pub fn check_icrc(
+        &self,
+        tx: &IcrcTransfer,
+        amount: u64,
+        funding: &Funding,
+    ) -> std::result::Result<(), ICPReceiverError> {
+        if tx.to != self.icrc_account() {
+            return Err(ICPReceiverError::Recipient);
+        }
+        if tx.memo() != Some(funding.memo()) {
+            return Err(ICPReceiverError::Memo);
         }
+        if tx.amount < amount {
+            return Err(ICPReceiverError::Amount);
+        }
+        Ok(())
+    }
 
-        match self.tx_querier.query_icrc_tx(block_height, amount).await {
-            Ok(tx) => {
-                if !self.known_txs.insert(block_height) {
-                    return Err(ICPReceiverError::DuplicateTransaction);
-                }
-                // if tx.to != self.my_account {
-                //     return Err(ICPReceiverError::Recipient);
-                // }
-                *self.unspent.entry(funding.memo()).or_insert(0u64.into()) += amount;
-
-                Ok(Amount::from(amount)) // Return the argument amount as Amount
-            }
-            Err(e) => Err(e),
+    /// Verifies a transaction read from the ledger, and if it's new, tracks
+    /// its funds and returns its amount.
+    pub fn verify(
+        &mut self,
+        block_height: BlockHeight,
+        tx: &TransactionNotification,
+    ) -> std::result::Result<Amount, ICPReceiverError> {
+        self.require_new(block_height)?;
+        self.known_txs.insert(block_height, (tx.memo, tx.amount));
+        if tx.to != self.my_account {
+            return Err(ICPReceiverError::Recipient);
         }
+        self.credit(tx.memo, tx.get_amount());
+        Ok(tx.get_amount())
     }
 
-    pub async fn verify(
+    /// Verifies a transfer into one of the canister's subaccounts, and if it
+    /// is new, returns its amount. Unlike memo transfers, the funds are not
+    /// tracked here but must be credited by the caller.
+    pub fn verify_subaccount(
         &mut self,
         block_height: BlockHeight,
+        tx: &TransactionNotification,
+        subaccount: [u8; 32],
     ) -> std::result::Result<Amount, ICPReceiverError> {
-        if self.known_txs.contains(&block_height) {
-            return Err(ICPReceiverError::DuplicateTransaction);
+        self.require_new(block_height)?;
+        if tx.to != self.subaccount(subaccount) {
+            return Err(ICPReceiverError::Recipient);
         }
+        self.known_txs.insert(block_height, (tx.memo, tx.amount));
+        Ok(tx.get_amount())
+    }
 
-        match self.tx_querier.query_tx(block_height).await {
-            Ok(tx) => {
-                if !self.known_txs.insert(block_height) {
-                    return Err(ICPReceiverError::DuplicateTransaction);
-                }
-                if tx.to != self.my_account {
-                    return Err(ICPReceiverError::Recipient);
-                }
-                *self.unspent.entry(tx.memo).or_insert(0u64.into()) += tx.get_amount();
-
-                Ok(tx.get_amount())
-            }
-            Err(e) => Err(e),
+    /// The start and length of the blocks to scan next. The first scan reads
+    /// no blocks but only notes the ledger's tip, so that blocks from before
+    /// scanning started are not scanned.
+    pub fn scan_range(&self) -> (BlockHeight, u64) {
+        match self.scan_cursor {
+            Some(start) => (start, MAX_SCAN_BLOCKS),
+            None => (0, 0),
+        }
+    }
+
+    /// Advances the scan past blocks read from the range of `scan_range` and
+    /// returns their new transfers to the canister's accounts. The caller
+    /// must note the transfers it credits with `scanned`; the others can
+    /// still be notified.
+    pub fn scan(&mut self, blocks: IcrcBlocks) -> Vec<IcrcTransfer> {
+        let Some(cursor) = self.scan_cursor else {
+            self.scan_cursor = Some(blocks.log_length);
+            return vec![];
+        };
+        self.scan_cursor = Some(blocks.next.max(cursor));
+        blocks
+            .transfers
+            .into_iter()
+            .filter(|t| t.to.owner == self.my_principal && !self.known_txs.contains_key(&t.block))
+            .collect()
+    }
+
+    /// Notes a scanned transfer as credited to a memo.
+    pub fn scanned(&mut self, t: &IcrcTransfer, memo: Memo) {
+        self.known_txs.insert(t.block, (memo, t.amount));
+    }
+
+    /// The canister's default account, which transfers must be sent to.
+    pub fn account(&self) -> AccountIdentifier {
+        self.my_account
+    }
+
+    /// The canister's default account as an ICRC-1 account.
+    pub fn icrc_account(&self) -> Account {
+        Account {
+            owner: self.my_principal,
+            subaccount: None,
         }
     }
 
+    /// One of the canister's subaccounts.
+    pub fn subaccount(&self, subaccount: [u8; 32]) -> AccountIdentifier {
+        AccountIdentifier::new(&self.my_principal, &Subaccount(subaccount))
+    }
+
+    pub fn querier(&mut self) -> &mut Q {
+        &mut self.tx_querier
+    }
+
+    /// Returns the memo and amount credited by a previously verified block.
+    pub fn credited(&self, block_height: BlockHeight) -> Option<(Memo, u64)> {
+        self.known_txs.get(&block_height).cloned()
+    }
+
+    /// Withdraws up to the requested amount of funds from a memo.
+    pub fn take(&mut self, memo: Memo, max: Amount) -> Amount {
+        let sum = self.unspent(memo);
+        let taken = sum.clone().min(max);
+        self.set_unspent(memo, sum - taken.clone());
+        taken
+    }
+
+    /// The funds received for a memo and not withdrawn yet.
+    pub fn unspent(&self, memo: Memo) -> Amount {
+        UNSPENT
+            .with(|u| u.borrow().get(&(self.ledger, memo)))
+            .map(|s| s.0)
+            .unwrap_or_default()
+    }
+
     /// Withdraws all funds from the requested memo.
     pub fn drain(&mut self, memo: Memo) -> Amount {
-        return self.unspent.remove(&memo).unwrap_or(0u64.into()).into();
+        UNSPENT
+            .with(|u| u.borrow_mut().remove(&(self.ledger, memo)))
+            .map(|s| s.0)
+            .unwrap_or_default()
     }
 
     /// Withdraws all funds from the requested memo if it is above a threshold.
     pub fn drain_if_at_least(&mut self, memo: Memo, amount: Amount) -> Option<Amount> {
-        if let Some(sum) = self.unspent.get(&memo) {
-            if sum >= &amount {
-                return self.unspent.remove(&memo).unwrap().into();
+        let sum = self.unspent(memo);
+        (sum > Amount::default() && sum >= amount).then(|| self.drain(memo))
+    }
+
+    /// Adds received funds to a memo.
+    fn credit(&mut self, memo: Memo, amount: Amount) {
+        let sum = self.unspent(memo) + amount;
+        self.set_unspent(memo, sum);
+    }
+
+    fn set_unspent(&mut self, memo: Memo, sum: Amount) {
+        UNSPENT.with(|u| {
+            let mut u = u.borrow_mut();
+            match sum == Amount::default() {
+                true => u.remove(&(self.ledger, memo)),
+                false => u.insert((self.ledger, memo), StoredAmount(sum)),
             }
+        });
+    }
+}
+
+impl IcrcTransfer {
+    /// Reads a transfer or mint from an ICRC-3 block, in either the legacy
+    /// `op` or the typed `btype` layout.
+    pub fn decode(block: BlockHeight, value: ICRC3Value) -> Option<Self> {
+        let ICRC3Value::Map(fields) = value else {
+            return None;
+        };
+        let Some(ICRC3Value::Map(tx)) = fields.get("tx") else {
+            return None;
+        };
+        let op = match (tx.get("op"), fields.get("btype")) {
+            (Some(ICRC3Value::Text(op)), _) | (None, Some(ICRC3Value::Text(op))) => op.as_str(),
+            _ => return None,
+        };
+        if !matches!(op, "xfer" | "mint" | "1xfer" | "1mint") {
+            return None;
         }
-        None
+        let Some(ICRC3Value::Nat(amount)) = tx.get("amt") else {
+            return None;
+        };
+        let memo = match tx.get("memo") {
+            Some(ICRC3Value::Blob(memo)) => Some(memo.to_vec()),
+            _ => None,
+        };
+        let from = match tx.get("from") {
+            Some(_) => Some(decode_account(tx, "from")?),
+            None => None,
+        };
+        Some(Self {
+            block,
+            from,
+            to: decode_account(tx, "to")?,
+            amount: u64::try_from(amount.0.clone()).ok()?,
+            memo,
+        })
+    }
+
+    /// The memo as registered memo, if it has eight bytes (big-endian).
+    pub fn memo(&self) -> Option<Memo> {
+        let bytes: [u8; 8] = self.memo.as_deref()?.try_into().ok()?;
+        Some(Memo::from_be_bytes(bytes))
+    }
+
+    /// Whether the transfer was sent from an account of the principal.
+    pub fn sent_by(&self, principal: &Principal) -> bool {
+        self.from.is_some_and(|from| from.owner == *principal)
+    }
+
+    /// Fails with `Sender` unless the transfer was sent from an account of the
+    /// principal.
+    pub fn require_sender(&self, principal: &Principal) -> Result<(), ICPReceiverError> {
+        if !self.sent_by(principal) {
+            return Err(ICPReceiverError::Sender);
+        }
+        Ok(())
     }
 }
 
+fn decode_account(tx: &ICRC3Map, field: &str) -> Option<Account> {
+    let Some(ICRC3Value::Array(account)) = tx.get(field) else {
+        return None;
+    };
+    let owner = match account.first()? {
+        ICRC3Value::Blob(owner) => Principal::try_from_slice(owner).ok()?,
+        _ => return None,
+    };
+    let subaccount = match account.get(1) {
+        Some(ICRC3Value::Blob(sub)) => Some(sub.as_slice().try_into().ok()?),
+        Some(_) => return None,
+        None => None,
+    };
+    Some(Account { owner, subaccount })
+}
+
 /// Contents of a received transaction.
 #[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)] //Hash,
 pub struct TransactionNotification {
@@ -257,21 +615,17 @@ pub struct TransactionNotification {
 impl TransactionNotification {
     /// Creates a transaction notification from an ICP ledger transaction. If the transaction is neither a transfer nor a mint, returns nothing.
     pub fn from_tx(tx: Transaction) -> Option<Self> {
-        if tx.operation.is_none() {
-            return None;
-        }
-
-        match tx.operation.unwrap() {
+        match tx.operation? {
             Operation::Transfer { to, amount, .. } => {
                 return Some(Self {
-                    to: to,
+                    to,
                     amount: amount.e8s(),
                     memo: tx.memo.0,
                 });
             }
             Operation::Mint { to, amount, .. } => {
                 return Some(Self {
-                    to: to,
+                    to,
                     amount: amount.e8s(),
                     memo: tx.memo.0,
                 });
@@ -0,0 +1,132 @@
+//  Copyright 2025 PolyCrypt GmbH
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+
+//! Reconciliation of the canister's accounting with its ckBTC ledger balance.
+//! The report breaks down the funds the canister owes and tells by how much
+//! the ledger balance exceeds or falls short of them, so that operators
+//! notice accounting drift or lost notifications. A difference is expected
+//! while ledger calls are in flight, and a surplus from deposits that were
+//! not credited yet.
+
+use crate::admin;
+use crate::error::*;
+use crate::receiver::TXQuerier;
+use crate::types::*;
+use crate::{CanisterState, LedgerCall, icrc1_balance_of, read_state};
+use candid::candid_method;
+use ic_cdk::update;
+use icrc_ledger_types::icrc1::account::Account;
+
+#[derive(Clone, Deserialize, CandidType, PartialEq, Eq, Debug)]
+pub struct ReconciliationReport {
+    /// The canister's balance on the ckBTC ledger.
+    pub ledger_balance: Amount,
+    /// Funds held by channels.
+    pub holdings: Amount,
+    /// Funds deposited into the ckBTC liquidity pool.
+    pub pool: Amount,
+    /// Withdrawals queued or submitted but not paid out yet.
+    pub pending_withdrawals: Amount,
+    /// Everything else owed: balances, quarantined funds, swept dust and
+    /// funds locked in swaps and invoices.
+    pub other: Amount,
+    /// The sum of all funds owed.
+    pub liabilities: Amount,
+    /// By how much the ledger balance exceeds the liabilities.
+    pub surplus: Amount,
+    /// By how much the ledger balance falls short of the liabilities.
+    pub deficit: Amount,
+    /// Ledger calls awaiting their response when the report was made.
+    pub calls_in_flight: u32,
+}
+
+#[update]
+#[candid_method(update)]
+/// Compares the funds the canister owes with its ckBTC ledger balance.
+/// Admin only.
+async fn reconcile() -> Result<ReconciliationReport> {
+    admin::require_admin()?;
+    let (ledger, owner) = (read_state(|s| s.ckbtc()), ic_cdk::api::canister_self());
+    let account = Account {
+        owner,
+        subaccount: None,
+    };
+    let balance = icrc1_balance_of(ledger, account).await.map_err(|e| {
+        ic_cdk::println!("querying the ledger balance failed: {:?}", e);
+        Error::from(e)
+    })?;
+    Ok(read_state(|s| {
+        s.reconciliation(balance, LedgerCall::in_flight())
+    }))
+}
+
+impl<Q: TXQuerier> CanisterState<Q> {
+    /// Breaks down the liabilities and compares them with a ledger balance.
+    pub fn reconciliation(
+        &self,
+        ledger_balance: Amount,
+        calls_in_flight: u32,
+    ) -> ReconciliationReport {
+        let holdings = self
+            .user_holdings
+            .iter()
+            .fold(Amount::default(), |acc, (_, amount)| acc + amount);
+        let pool = self
+            .liq_pools
+            .get(&self.ckbtc())
+            .map(|p| p.value().clone())
+            .unwrap_or_default();
+        let pending_withdrawals = self
+            .queued_withdrawals_of(self.ckbtc())
+            .fold(Amount::default(), |acc, a| acc + a.clone());
+        let liabilities = self.liabilities();
+        let listed = holdings.clone() + pool.clone() + pending_withdrawals.clone();
+        let other = liabilities.clone() - listed.min(liabilities.clone());
+        let surplus = ledger_balance.clone() - liabilities.clone().min(ledger_balance.clone());
+        let deficit = liabilities.clone() - ledger_balance.clone().min(liabilities.clone());
+        ReconciliationReport {
+            ledger_balance,
+            holdings,
+            pool,
+            pending_withdrawals,
+            other,
+            liabilities,
+            surplus,
+            deficit,
+            calls_in_flight,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+
+    #[test]
+    fn test_reconciliation_reports_discrepancy() {
+        let mut s = new_state();
+        concluded(&mut s, 111, 1, 2);
+        let report = s.reconciliation(Amount::from(150u32), 1);
+        assert_eq!(report.holdings, Amount::from(200u32));
+        assert_eq!(report.liabilities, Amount::from(200u32));
+        assert_eq!(report.deficit, Amount::from(50u32));
+        assert_eq!(report.surplus, Amount::default());
+        assert_eq!(report.calls_in_flight, 1);
+
+        let report = s.reconciliation(Amount::from(260u32), 0);
+        assert_eq!(report.surplus, Amount::from(60u32));
+        assert_eq!(report.deficit, Amount::default());
+    }
+}
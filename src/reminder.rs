@@ -0,0 +1,378 @@
+//  Copyright 2025 PolyCrypt GmbH
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+
+use crate::error::*;
+use crate::receiver::TXQuerier;
+use crate::types::*;
+use crate::{CanisterState, require};
+use std::collections::{BTreeSet, HashMap};
+
+/// How often pending disputes are checked for due reminders (one minute).
+pub const REMINDER_CHECK_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60);
+
+/// At which percentages of an elapsed challenge window reminders are sent by
+/// default.
+pub const DEFAULT_REMINDER_PERCENTAGES: [u32; 2] = [50, 90];
+
+/// Maximum number of reminder subscribers per channel.
+pub const MAX_REMINDER_SUBSCRIBERS: usize = 8;
+
+#[derive(Clone, Deserialize, CandidType)]
+/// A participant's subscription or unsubscription of a callback to a
+/// channel's reminders.
+pub struct SubscriptionChange {
+    pub callback: Callback,
+    /// Whether the callback is subscribed or unsubscribed.
+    pub subscribe: bool,
+    /// Increases with each change of the channel's subscribers so that old
+    /// changes cannot be replayed.
+    pub seq: u64,
+}
+
+/// A reminder that a channel's challenge window is running out.
+pub struct Reminder {
+    /// The disputed state.
+    pub state: RegisteredState,
+    /// How much of the challenge window has elapsed, in percent.
+    pub elapsed_percent: u32,
+    /// Who to notify about the reminder.
+    pub subscribers: Vec<Callback>,
+}
+
+/// Schedules reminders for channels under dispute at configurable fractions of
+/// their challenge window.
+pub struct ReminderSchedule {
+    /// Ascending percentages of the challenge window at which to remind.
+    percentages: Vec<u32>,
+    /// The highest percentage reminded of per channel, along with the timeout
+    /// it refers to. A changed timeout restarts the reminders.
+    sent: HashMap<ChannelId, (Timestamp, u32)>,
+    /// When each disputed channel's next reminder is due, ordered by time.
+    queue: BTreeSet<(Timestamp, ChannelId)>,
+    /// The time each channel is queued at.
+    next: HashMap<ChannelId, Timestamp>,
+    subscribers: HashMap<ChannelId, Vec<Callback>>,
+    /// The sequence number of each channel's latest subscription change.
+    seqs: HashMap<ChannelId, u64>,
+}
+
+impl Default for ReminderSchedule {
+    fn default() -> Self {
+        Self {
+            percentages: DEFAULT_REMINDER_PERCENTAGES.to_vec(),
+            sent: Default::default(),
+            queue: Default::default(),
+            next: Default::default(),
+            subscribers: Default::default(),
+            seqs: Default::default(),
+        }
+    }
+}
+
+impl ReminderSchedule {
+    /// Sets the percentages of the challenge window at which to remind. Each
+    /// percentage must lie strictly between 0 and 100.
+    pub fn set_percentages(&mut self, mut percentages: Vec<u32>) -> Result<()> {
+        require!(percentages.iter().all(|p| *p > 0 && *p < 100), InvalidInput);
+        percentages.sort_unstable();
+        percentages.dedup();
+        self.percentages = percentages;
+        Ok(())
+    }
+
+    pub fn percentages(&self) -> Vec<u32> {
+        self.percentages.clone()
+    }
+
+    /// Subscribes a callback to be notified with the channel id whenever a
+    /// reminder for the channel is due, or unsubscribes it.
+    pub fn change_subscription(
+        &mut self,
+        ch: &ChannelId,
+        change: SubscriptionChange,
+    ) -> Result<()> {
+        if let Some(seq) = self.seqs.get(ch) {
+            require!(
+                change.seq > *seq,
+                OutdatedState {
+                    registered_version: *seq,
+                    submitted_version: change.seq,
+                }
+            );
+        }
+        let subs = self.subscribers.entry(ch.clone()).or_default();
+        let known = subs.contains(&change.callback);
+        match change.subscribe {
+            true if !known => {
+                require!(subs.len() < MAX_REMINDER_SUBSCRIBERS, InvalidInput);
+                subs.push(change.callback);
+            }
+            false => subs.retain(|cb| *cb != change.callback),
+            true => (),
+        }
+        if subs.is_empty() {
+            self.subscribers.remove(ch);
+        }
+        self.seqs.insert(ch.clone(), change.seq);
+        Ok(())
+    }
+
+    pub fn subscribers(&self, ch: &ChannelId) -> Vec<Callback> {
+        self.subscribers.get(ch).cloned().unwrap_or_default()
+    }
+
+    /// Queues the next reminder for a state registered at `start`, replacing
+    /// the channel's queued reminder. Nothing is queued for states without a
+    /// challenge window or whose reminders were all sent.
+    pub fn schedule(&mut self, start: Timestamp, state: &RegisteredState) {
+        let ch = &state.state.channel;
+        if let Some(at) = self.next.remove(ch) {
+            self.queue.remove(&(at, ch.clone()));
+        }
+        if state.timeout <= start {
+            self.sent.remove(ch);
+            return;
+        }
+        let already = match self.sent.get(ch) {
+            Some((timeout, p)) if *timeout == state.timeout => *p,
+            _ => 0,
+        };
+        let Some(next) = self.percentages.iter().find(|p| **p > already) else {
+            return;
+        };
+        // The first time at which `due` computes an elapsed percentage of at
+        // least `next`.
+        let window = (state.timeout - start) as u128;
+        let at = start + (window * *next as u128).div_ceil(100) as Timestamp;
+        self.queue.insert((at, ch.clone()));
+        self.next.insert(ch.clone(), at);
+    }
+
+    /// Removes the channels whose queued reminder is due at `now` from the
+    /// queue and returns them.
+    pub fn take_due(&mut self, now: Timestamp) -> Vec<ChannelId> {
+        let later = self
+            .queue
+            .split_off(&(now.saturating_add(1), ChannelId::default()));
+        let due = std::mem::replace(&mut self.queue, later);
+        due.into_iter()
+            .map(|(_, ch)| {
+                self.next.remove(&ch);
+                ch
+            })
+            .collect()
+    }
+
+    /// Determines the reminders due for a disputed state whose challenge
+    /// window started at `start`, and marks them as sent. If several
+    /// percentages passed since the last check, only the highest one is
+    /// reported.
+    pub fn due(
+        &mut self,
+        start: Timestamp,
+        state: &RegisteredState,
+        now: Timestamp,
+    ) -> Option<Reminder> {
+        let ch = &state.state.channel;
+        if state.settled(now) || state.timeout <= start {
+            self.sent.remove(ch);
+            return None;
+        }
+        let elapsed =
+            (now.saturating_sub(start) as u128 * 100 / (state.timeout - start) as u128) as u32;
+        let already = match self.sent.get(ch) {
+            Some((timeout, p)) if *timeout == state.timeout => *p,
+            _ => 0,
+        };
+        let next = self
+            .percentages
+            .iter()
+            .rev()
+            .find(|p| **p <= elapsed && **p > already)?;
+        self.sent.insert(ch.clone(), (state.timeout, *next));
+        Some(Reminder {
+            state: state.clone(),
+            elapsed_percent: *next,
+            subscribers: self.subscribers(ch),
+        })
+    }
+}
+
+impl<Q: TXQuerier> CanisterState<Q> {
+    /// Subscribes or unsubscribes a callback to a known channel's reminders.
+    /// The signature is by one of the channel's participants, who may sign
+    /// changes for the watchtowers they delegate to. Callbacks must name a
+    /// registered callback canister.
+    pub fn set_reminder_subscriber(
+        &mut self,
+        channel: &ChannelId,
+        change: SubscriptionChange,
+        sig: &[u8],
+    ) -> Result<()> {
+        crate::validation::name(&change.callback.method)?;
+        let params = self
+            .channel_params(channel)
+            .ok_or_else(|| Error::from(ErrorCode::NotFound).with("channel", channel))?;
+        let msg = change.signing_bytes(channel);
+        require!(
+            params.participants.iter().any(|p| p.verify(&msg, sig)),
+            Authentication {
+                participant_index: None
+            }
+        );
+        let canister = change.callback.canister;
+        require!(
+            !change.subscribe || self.callback_canisters.contains(&canister),
+            Error::from(ErrorCode::Unauthorized).with("callback", canister)
+        );
+        self.reminders.change_subscription(channel, change)
+    }
+
+    /// Collects the reminders due for disputed channels, as queued by
+    /// `ReminderSchedule::schedule`, and queues their next ones. A dispute's
+    /// challenge window starts when its state was registered.
+    pub fn due_reminders(&mut self, now: Timestamp) -> Vec<Reminder> {
+        let mut due = vec![];
+        for id in self.reminders.take_due(now) {
+            let Some(state) = self.channels.get(&id) else {
+                continue;
+            };
+            let Some(start) = self.history.get(&id).and_then(|h| h.back()) else {
+                continue;
+            };
+            let start = start.registered_at;
+            if let Some(r) = self.reminders.due(start, &state, now) {
+                due.push(r);
+            }
+            self.reminders.schedule(start, &state);
+        }
+        due
+    }
+}
+
+impl SubscriptionChange {
+    /// The bytes a participant signs to make the change: the channel id, the
+    /// callback's length-prefixed canister and method, the subscribe flag and
+    /// the sequence number (LE).
+    pub fn signing_bytes(&self, channel: &ChannelId) -> Vec<u8> {
+        let mut data = b"ckLightning reminder subscription".to_vec();
+        data.extend_from_slice(&channel.0);
+        let canister = self.callback.canister.as_slice();
+        data.extend_from_slice(&(canister.len() as u32).to_le_bytes());
+        data.extend_from_slice(canister);
+        data.extend_from_slice(&(self.callback.method.len() as u32).to_le_bytes());
+        data.extend_from_slice(self.callback.method.as_bytes());
+        data.push(self.subscribe as u8);
+        data.extend_from_slice(&self.seq.to_le_bytes());
+        data
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+    use candid::Principal;
+
+    fn disputed(timeout: Timestamp) -> RegisteredState {
+        RegisteredState {
+            state: State::default(),
+            timeout,
+        }
+    }
+
+    #[test]
+    fn test_reminders_at_percentages() {
+        let mut r = ReminderSchedule::default();
+        let state = disputed(1000);
+
+        assert!(r.due(0, &state, 499).is_none());
+        assert_eq!(r.due(0, &state, 500).unwrap().elapsed_percent, 50);
+        assert!(r.due(0, &state, 600).is_none());
+        assert_eq!(r.due(0, &state, 950).unwrap().elapsed_percent, 90);
+        assert!(r.due(0, &state, 999).is_none());
+
+        // A refuted dispute with a new timeout restarts the reminders.
+        assert_eq!(r.due(0, &disputed(2000), 1900).unwrap().elapsed_percent, 90);
+    }
+
+    #[test]
+    fn test_reminders_are_queued_by_deadline() {
+        let mut r = ReminderSchedule::default();
+        let state = disputed(1000);
+        let ch = state.state.channel.clone();
+        r.schedule(0, &state);
+
+        assert!(r.take_due(499).is_empty());
+        assert!(r.take_due(500) == vec![ch.clone()]);
+        assert_eq!(r.due(0, &state, 500).unwrap().elapsed_percent, 50);
+        r.schedule(0, &state);
+        assert!(r.take_due(899).is_empty());
+        assert!(r.take_due(900) == vec![ch]);
+        assert_eq!(r.due(0, &state, 900).unwrap().elapsed_percent, 90);
+
+        // Nothing is queued once all reminders were sent.
+        r.schedule(0, &state);
+        assert!(r.take_due(u64::MAX).is_empty());
+    }
+
+    #[test]
+    fn test_reminder_subscriptions_are_signed_by_participants() {
+        let mut s = new_state();
+        let ch = concluded(&mut s, 1, 1, 2);
+        let callback = Callback {
+            canister: Principal::from_slice(&[9]),
+            method: "remind".into(),
+        };
+        let change = |subscribe, seq| SubscriptionChange {
+            callback: callback.clone(),
+            subscribe,
+            seq,
+        };
+        s.callback_canisters.insert(callback.canister);
+
+        let sub = change(true, 1);
+        assert_eq!(
+            s.set_reminder_subscriber(&ch, sub.clone(), &sign(3, &sub.signing_bytes(&ch))),
+            Err(ErrorCode::Authentication {
+                participant_index: None
+            }
+            .into())
+        );
+        let unknown = ChannelId([7; 32]);
+        assert_eq!(
+            s.set_reminder_subscriber(
+                &unknown,
+                sub.clone(),
+                &sign(1, &sub.signing_bytes(&unknown))
+            ),
+            Err(ErrorCode::NotFound.into())
+        );
+        let sig = sign(1, &sub.signing_bytes(&ch));
+        s.set_reminder_subscriber(&ch, sub.clone(), &sig).unwrap();
+        assert!(s.reminders.subscribers(&ch) == vec![callback.clone()]);
+        assert!(matches!(
+            s.set_reminder_subscriber(&ch, sub, &sig),
+            Err(Error {
+                code: ErrorCode::OutdatedState { .. },
+                ..
+            })
+        ));
+
+        let unsub = change(false, 2);
+        let sig = sign(2, &unsub.signing_bytes(&ch));
+        s.set_reminder_subscriber(&ch, unsub, &sig).unwrap();
+        assert!(s.reminders.subscribers(&ch).is_empty());
+    }
+}
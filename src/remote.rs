@@ -0,0 +1,263 @@
+//  Copyright 2025 PolyCrypt GmbH
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+
+//! Funding channels from the outcome of channels on other Perun canisters.
+//! A trusted remote canister attests a settled state of one of its channels,
+//! as exported by `export_balance_proof`. A participant of that channel can
+//! present the attestation to fund a channel on this canister with their
+//! outcome. Each remote outcome funds at most one channel.
+
+use crate::audit;
+use crate::error::*;
+use crate::holdings::ChangeCause;
+use crate::receiver::TXQuerier;
+use crate::types::*;
+use crate::validation::Validate;
+use crate::{CanisterState, mutate_state, notify, read_state, require};
+use candid::{Principal, candid_method};
+use ic_cdk::api::time as blocktime;
+use ic_cdk::{query, update};
+use k256::ecdsa::{Signature, VerifyingKey, signature::hazmat::PrehashVerifier};
+
+#[derive(Clone, Deserialize, CandidType)]
+/// Proof that a participant's outcome in a remote channel funds a channel on
+/// this canister.
+pub struct RemoteFunding {
+    /// The remote channel's parameters.
+    pub params: Params,
+    /// The remote canister's attestation of the remote channel's state.
+    pub proof: BalanceProof,
+    /// The participant whose outcome is used, also funded locally.
+    pub participant: L2Account,
+    /// The channel on this canister to fund.
+    pub channel: ChannelId,
+    /// The participant's signature over `RemoteFunding::signing_bytes`.
+    pub signature: Vec<u8>,
+}
+
+#[update]
+#[candid_method(update)]
+/// Trusts a remote Perun canister's attestations, verified with its SEC1
+/// encoded public key. Controller only.
+fn register_remote_canister(canister: Principal, public_key: Vec<u8>) -> Result<()> {
+    let hash = audit::args_hash((canister, &public_key));
+    audit::logged("register_remote_canister", hash, || {
+        crate::require_controller()?;
+        require!(
+            VerifyingKey::from_sec1_bytes(&public_key).is_ok(),
+            InvalidInput
+        );
+        mutate_state(|s| s.remote_canisters.insert(canister, public_key));
+        Ok(())
+    })
+}
+
+#[update]
+#[candid_method(update)]
+/// Stops trusting a remote Perun canister. Controller only.
+fn remove_remote_canister(canister: Principal) -> Result<()> {
+    audit::logged(
+        "remove_remote_canister",
+        audit::args_hash((canister,)),
+        || {
+            crate::require_controller()?;
+            mutate_state(|s| s.remote_canisters.remove(&canister));
+            Ok(())
+        },
+    )
+}
+
+#[query]
+#[candid_method(query)]
+fn query_remote_canisters() -> Vec<(Principal, Vec<u8>)> {
+    read_state(|s| {
+        s.remote_canisters
+            .iter()
+            .map(|(c, pk)| (*c, pk.clone()))
+            .collect()
+    })
+}
+
+#[update]
+#[candid_method(update)]
+/// Funds a channel with a participant's outcome in a settled remote channel.
+/// Returns the credited amount.
+fn fund_from_remote(funding: RemoteFunding) -> Result<Amount> {
+    crate::unpaused!();
+    let now = blocktime();
+    mutate_state(|state| {
+        let amount = state.fund_from_remote(&funding, now)?;
+        if let Some(cb) = state.complete_funding(&funding.channel, now) {
+            notify(&cb, &funding.channel);
+        }
+        Ok(amount)
+    })
+}
+
+impl RemoteFunding {
+    /// The message the participant signs to direct their remote outcome.
+    pub fn signing_bytes(&self) -> Vec<u8> {
+        let mut data = b"ckLightning remote funding".to_vec();
+        data.extend_from_slice(self.proof.canister.as_slice());
+        data.extend_from_slice(&self.proof.record.state.state.channel.0);
+        data.extend_from_slice(&self.channel.0);
+        data
+    }
+}
+
+impl<Q: TXQuerier> CanisterState<Q> {
+    /// Credits the local funding with the participant's remote outcome after
+    /// checking the attestation and the participant's authorization.
+    pub fn fund_from_remote(&mut self, f: &RemoteFunding, now: Timestamp) -> Result<Amount> {
+        self.accepting()?;
+        f.params.validate()?;
+        let proof = &f.proof;
+        let public_key = self
+            .remote_canisters
+            .get(&proof.canister)
+            .ok_or(ErrorCode::Unauthorized)?;
+        let hash = proof.record.attestation_hash(&proof.canister);
+        require!(
+            proof.message_hash == hash,
+            Authentication {
+                participant_index: None
+            }
+        );
+        let key =
+            VerifyingKey::from_sec1_bytes(public_key).map_err(|_| ErrorCode::Authentication {
+                participant_index: None,
+            })?;
+        let sig =
+            Signature::from_slice(&proof.signature).map_err(|_| ErrorCode::Authentication {
+                participant_index: None,
+            })?;
+        require!(
+            key.verify_prehash(&hash, &sig).is_ok(),
+            Authentication {
+                participant_index: None
+            }
+        );
+
+        let state = &proof.record.state;
+        require!(f.params.id() == state.state.channel, InvalidInput);
+        require!(state.settled(now), NotFinalized);
+        let index = f
+            .params
+            .participants
+            .iter()
+            .position(|p| *p == f.participant)
+            .ok_or(ErrorCode::InvalidInput)?;
+        let amount = state
+            .state
+            .allocation
+            .get(index)
+            .cloned()
+            .ok_or(ErrorCode::InvalidInput)?;
+        require!(
+            f.participant.verify(&f.signing_bytes(), &f.signature),
+            Authentication {
+                participant_index: Some(index as u32)
+            }
+        );
+
+        let remote = (
+            proof.canister,
+            Funding::new(state.state.channel.clone(), f.participant.clone()),
+        );
+        require!(!self.remote_fundings.contains(&remote), AlreadyConcluded);
+        self.remote_fundings.insert(remote);
+        self.credit(
+            Funding::new(f.channel.clone(), f.participant.clone()),
+            amount.clone(),
+            ChangeCause::RemoteFunding,
+        );
+        Ok(amount)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+    use k256::ecdsa::signature::hazmat::PrehashSigner;
+
+    fn remote_funding(canister: Principal, local: ChannelId) -> RemoteFunding {
+        let params = Params {
+            nonce: Nonce([5; 32]),
+            participants: vec![account(1), account(2)],
+            challenge_duration: 0,
+            expiry: None,
+        };
+        let record = StateRecord {
+            state: RegisteredState {
+                state: State {
+                    channel: params.id(),
+                    version: 3,
+                    allocation: vec![Amount::from(30u32), Amount::from(70u32)],
+                    finalized: true,
+                    assets: vec![],
+                },
+                timeout: 0,
+            },
+            registered_at: 0,
+        };
+        let message_hash = record.attestation_hash(&canister);
+        let sig: Signature = key(9).sign_prehash(&message_hash).unwrap();
+        let mut f = RemoteFunding {
+            params,
+            proof: BalanceProof {
+                record,
+                canister,
+                message_hash: message_hash.to_vec(),
+                signature: sig.to_bytes().to_vec(),
+            },
+            participant: account(2),
+            channel: local,
+            signature: vec![],
+        };
+        f.signature = sign(2, &f.signing_bytes());
+        f
+    }
+
+    #[test]
+    fn test_remote_outcome_funds_channel_once() {
+        let mut s = new_state();
+        let remote = Principal::from_slice(&[42]);
+        let local = ChannelId([8; 32]);
+        let f = remote_funding(remote, local.clone());
+        assert_eq!(
+            s.fund_from_remote(&f, 1),
+            Err(ErrorCode::Unauthorized.into())
+        );
+
+        let pk = account(9).0.to_sec1_bytes().to_vec();
+        s.remote_canisters.insert(remote, pk);
+        assert_eq!(s.fund_from_remote(&f, 1), Ok(Amount::from(70u32)));
+        assert_eq!(holdings(&s, &local, 2), Amount::from(70u32));
+        assert_eq!(
+            s.fund_from_remote(&f, 1),
+            Err(ErrorCode::AlreadyConcluded.into())
+        );
+
+        let mut forged = remote_funding(remote, ChannelId([9; 32]));
+        forged.signature = sign(1, &forged.signing_bytes());
+        assert_eq!(
+            s.fund_from_remote(&forged, 1),
+            Err(ErrorCode::Authentication {
+                participant_index: Some(1)
+            }
+            .into())
+        );
+    }
+}
@@ -0,0 +1,380 @@
+//  Copyright 2025 PolyCrypt GmbH
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+
+//! Capacity reservations for scheduled payments. A participant can reserve
+//! part of their holdings in a channel until the end of a payment window, so
+//! that a merchant expecting a settlement at a known time can rely on the
+//! channel still holding the funds. Until the window ends, withdrawals and
+//! cooperative conclusions must leave the participant at least the reserved
+//! amount. Disputes are not restricted, so that funds can always be
+//! protected. Reservations cannot be cancelled; they lapse with their window.
+
+use crate::error::*;
+use crate::receiver::TXQuerier;
+use crate::types::*;
+use crate::{CanisterState, mutate_state, read_state, require};
+use candid::candid_method;
+use ic_cdk::api::time as blocktime;
+use ic_cdk::{query, update};
+use k256::elliptic_curve::sec1::ToEncodedPoint;
+use std::collections::BTreeMap;
+
+pub type ReservationId = u64;
+
+#[derive(Clone, Copy, Deserialize, CandidType, PartialEq, Eq, Debug)]
+/// The period in which a scheduled payment is expected.
+pub struct Window {
+    pub start: Timestamp,
+    pub end: Timestamp,
+}
+
+#[derive(Clone, Deserialize, CandidType, PartialEq)]
+pub struct Reservation {
+    pub funding: Funding,
+    pub amount: Amount,
+    pub window: Window,
+}
+
+#[derive(Default)]
+pub struct Reservations {
+    entries: BTreeMap<ReservationId, Reservation>,
+    next_id: ReservationId,
+}
+
+#[update]
+#[candid_method(update)]
+/// Reserves `amount` of a participant's holdings in a channel until the end
+/// of `window`. The participant authorizes the reservation by signing
+/// `Reservation::signing_bytes`. Reserving the same amount for the same
+/// window again returns the existing reservation.
+fn reserve_capacity(
+    channel_id: ChannelId,
+    amount: Amount,
+    window: Window,
+    participant: L2Account,
+    signature: Vec<u8>,
+) -> Result<ReservationId> {
+    let reservation = Reservation {
+        funding: Funding::new(channel_id, participant),
+        amount,
+        window,
+    };
+    mutate_state(|s| s.reserve_capacity(reservation, &signature, blocktime()))
+}
+
+#[query]
+#[candid_method(query)]
+/// Lists the reservations of a channel whose window has not ended yet.
+fn query_reservations(channel_id: ChannelId) -> Vec<(ReservationId, Reservation)> {
+    read_state(|s| s.reservations_of(&channel_id, blocktime()))
+}
+
+impl Reservation {
+    /// The message the participant signs to authorize the reservation: the
+    /// channel id, the participant's SEC1 key, the amount as length-prefixed
+    /// LE bytes and the window's start and end (LE).
+    pub fn signing_bytes(&self) -> Vec<u8> {
+        let mut data = b"ckLightning reservation".to_vec();
+        data.extend_from_slice(&self.funding.channel.0);
+        data.extend_from_slice(self.funding.participant.0.to_encoded_point(true).as_bytes());
+        let amount = self.amount.0.to_bytes_le();
+        data.extend_from_slice(&(amount.len() as u32).to_le_bytes());
+        data.extend_from_slice(&amount);
+        data.extend_from_slice(&self.window.start.to_le_bytes());
+        data.extend_from_slice(&self.window.end.to_le_bytes());
+        data
+    }
+
+    fn active(&self, now: Timestamp) -> bool {
+        now < self.window.end
+    }
+}
+
+impl Reservations {
+    /// The total amount reserved from a funding's holdings at `now`.
+    pub fn reserved(&self, funding: &Funding, now: Timestamp) -> Amount {
+        self.entries
+            .values()
+            .filter(|r| r.funding == *funding && r.active(now))
+            .fold(Amount::default(), |acc, r| acc + r.amount.clone())
+    }
+
+    fn prune(&mut self, now: Timestamp) {
+        self.entries.retain(|_, r| r.active(now));
+    }
+}
+
+impl<Q: TXQuerier> CanisterState<Q> {
+    pub fn reserve_capacity(
+        &mut self,
+        reservation: Reservation,
+        signature: &[u8],
+        now: Timestamp,
+    ) -> Result<ReservationId> {
+        self.accepting()?;
+        let funding = &reservation.funding;
+        require!(
+            funding
+                .participant
+                .verify(&reservation.signing_bytes(), signature),
+            Authentication {
+                participant_index: None
+            }
+        );
+        let window = reservation.window;
+        require!(window.start < window.end && now < window.end, InvalidInput);
+        let finalized = self
+            .channels
+            .get(&funding.channel)
+            .is_some_and(|r| r.state.finalized);
+        require!(!finalized, AlreadyConcluded);
+
+        self.reservations.prune(now);
+        if let Some((id, _)) = self
+            .reservations
+            .entries
+            .iter()
+            .find(|(_, r)| **r == reservation)
+        {
+            return Ok(*id);
+        }
+        let held = self.user_holdings.get(funding).unwrap_or_default();
+        let reserved = self.reservations.reserved(funding, now);
+        require!(
+            held >= reserved.clone() + reservation.amount.clone(),
+            Error::from(ErrorCode::InsufficientFunding {
+                required: reserved.clone() + reservation.amount.clone(),
+                available: held,
+            })
+            .with("reserved", reserved)
+        );
+        let id = self.reservations.next_id;
+        self.reservations.next_id += 1;
+        self.reservations.entries.insert(id, reservation);
+        Ok(id)
+    }
+
+    pub fn reservations_of(
+        &self,
+        channel: &ChannelId,
+        now: Timestamp,
+    ) -> Vec<(ReservationId, Reservation)> {
+        self.reservations
+            .entries
+            .iter()
+            .filter(|(_, r)| r.funding.channel == *channel && r.active(now))
+            .map(|(id, r)| (*id, r.clone()))
+            .collect()
+    }
+
+    /// Fails if taking `amount` from a funding's holdings `held` would eat
+    /// into its reservations.
+    pub fn require_unreserved(
+        &self,
+        funding: &Funding,
+        held: &Amount,
+        amount: &Amount,
+        now: Timestamp,
+    ) -> Result<()> {
+        let reserved = self.reservations.reserved(funding, now);
+        require!(
+            *held >= reserved.clone() + amount.clone(),
+            Error::from(ErrorCode::CapacityReserved).with("reserved", reserved)
+        );
+        Ok(())
+    }
+
+    /// Fails if a cooperatively concluded state allocates a participant less
+    /// than their reservations in the channel.
+    pub fn require_reservations_kept(
+        &self,
+        params: &Params,
+        state: &State,
+        now: Timestamp,
+    ) -> Result<()> {
+        for (p, amount) in params.participants.iter().zip(&state.allocation) {
+            let funding = Funding::new(state.channel.clone(), p.clone());
+            let reserved = self.reservations.reserved(&funding, now);
+            require!(
+                *amount >= reserved,
+                Error::from(ErrorCode::CapacityReserved)
+                    .with("participant", p)
+                    .with("reserved", reserved)
+            );
+        }
+        Ok(())
+    }
+
+    /// The part of a funding's holdings that is not reserved.
+    pub fn unreserved(&self, funding: &Funding, held: Amount, now: Timestamp) -> Amount {
+        let reserved = self.reservations.reserved(funding, now);
+        if held > reserved {
+            held - reserved
+        } else {
+            Amount::default()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::htlc::{ForwardTerms, Leg};
+    use crate::operator::Direction;
+    use crate::swap::{SWAP_CLAIM_WINDOW, SwapRequest};
+    use crate::testing::*;
+    use icrc_ledger_types::icrc1::account::Account;
+
+    #[test]
+    fn test_reserved_capacity_is_kept() {
+        let mut s = new_state();
+        let params = Params {
+            nonce: Nonce([7; 32]),
+            participants: vec![account(1), account(2)],
+            challenge_duration: 0,
+            expiry: None,
+        };
+        let ch = params.id();
+        let funding = Funding::new(ch.clone(), account(1));
+        s.deposit(funding.clone(), Amount::from(100u32)).unwrap();
+        let reservation = Reservation {
+            funding: funding.clone(),
+            amount: Amount::from(60u32),
+            window: Window { start: 5, end: 10 },
+        };
+        let sig = sign(1, &reservation.signing_bytes());
+        assert_eq!(
+            s.reserve_capacity(
+                reservation.clone(),
+                &sign(2, &reservation.signing_bytes()),
+                0
+            ),
+            Err(ErrorCode::Authentication {
+                participant_index: None
+            }
+            .into())
+        );
+        let id = s.reserve_capacity(reservation.clone(), &sig, 0).unwrap();
+        assert_eq!(s.reserve_capacity(reservation.clone(), &sig, 1), Ok(id));
+        assert_eq!(s.reservations_of(&ch, 1).len(), 1);
+
+        let more = Reservation {
+            amount: Amount::from(41u32),
+            ..reservation
+        };
+        let more_sig = sign(1, &more.signing_bytes());
+        assert!(s.reserve_capacity(more, &more_sig, 1).is_err());
+
+        let held = Amount::from(100u32);
+        s.require_unreserved(&funding, &held, &Amount::from(40u32), 9)
+            .unwrap();
+        assert_eq!(
+            s.require_unreserved(&funding, &held, &Amount::from(41u32), 9),
+            Err(ErrorCode::CapacityReserved.into())
+        );
+        assert_eq!(s.unreserved(&funding, held.clone(), 9), Amount::from(40u32));
+
+        // A conclusion must leave the participant the reserved amount.
+        let mut state = State {
+            channel: ch.clone(),
+            version: 1,
+            allocation: vec![Amount::from(59u32), Amount::from(41u32)],
+            finalized: true,
+            assets: vec![],
+        };
+        assert_eq!(
+            s.require_reservations_kept(&params, &state, 9),
+            Err(ErrorCode::CapacityReserved.into())
+        );
+        state.allocation = vec![Amount::from(60u32), Amount::from(40u32)];
+        s.require_reservations_kept(&params, &state, 9).unwrap();
+
+        // Reservations lapse at the end of their window.
+        s.require_unreserved(&funding, &held, &held, 10).unwrap();
+        assert!(s.reservations_of(&ch, 10).is_empty());
+    }
+
+    #[test]
+    fn test_forwards_and_swaps_keep_reserved_capacity() {
+        let mut s = new_state();
+        let (a, b) = (concluded(&mut s, 21, 1, 2), concluded(&mut s, 22, 2, 3));
+        let reserved = Reservation {
+            funding: Funding::new(a.clone(), account(1)),
+            amount: Amount::from(80u32),
+            window: Window { start: 0, end: 10 },
+        };
+        s.reservations.entries.insert(0, reserved.clone());
+
+        let terms = ForwardTerms {
+            hash: payment_hash(b"reserved"),
+            amount: Amount::from(30u32),
+            max_fee: Amount::default(),
+            expiry: 10,
+            incoming: Leg {
+                channel: a.clone(),
+                from: account(1),
+                to: account(2),
+            },
+            outgoing: Leg {
+                channel: b,
+                from: account(2),
+                to: account(3),
+            },
+        };
+        let msg = terms.signing_bytes();
+        assert_eq!(
+            s.lock_forward(terms, &sign(1, &msg), &sign(2, &msg), 1),
+            Err(ErrorCode::CapacityReserved.into())
+        );
+
+        let op = operator(&mut s, 10, 10);
+        advertise(&mut s, op, Direction::ToLightning, 0);
+        let req = SwapRequest {
+            invoice: "lnbc1".into(),
+            hash: payment_hash(b"reserved"),
+            amount: Amount::from(30u32),
+            max_fee: Amount::default(),
+            nonce: 0,
+            expiry: 10 * SWAP_CLAIM_WINDOW,
+            funding: reserved.funding,
+            operator: None,
+            refund_to: Account {
+                owner: op,
+                subaccount: None,
+            },
+        };
+        let sig = sign(1, &req.signing_bytes());
+        assert_eq!(
+            s.create_swap(op, req, &sig, 1),
+            Err(ErrorCode::CapacityReserved.into())
+        );
+        assert_eq!(holdings(&s, &a, 1), Amount::from(100u32));
+    }
+
+    #[test]
+    fn test_concluded_channels_cannot_be_reserved() {
+        let mut s = new_state();
+        let ch = concluded(&mut s, 8, 1, 2);
+        let reservation = Reservation {
+            funding: Funding::new(ch, account(1)),
+            amount: Amount::from(1u32),
+            window: Window { start: 0, end: 10 },
+        };
+        let sig = sign(1, &reservation.signing_bytes());
+        assert_eq!(
+            s.reserve_capacity(reservation, &sig, 0),
+            Err(ErrorCode::AlreadyConcluded.into())
+        );
+    }
+}
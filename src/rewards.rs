@@ -0,0 +1,299 @@
+//  Copyright 2025 PolyCrypt GmbH
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+
+//! Rewards for liquidity pool depositors. A rewards program pays out a budget
+//! evenly over its duration. Each accrual splits the rewards released since
+//! the previous one among the depositors in proportion to their pool
+//! shares, so that a depositor's rewards grow with both the liquidity
+//! provided and the time it was provided for. Rewards released while the
+//! pool is empty are not paid out. Only the ckBTC pool takes part.
+
+use crate::audit;
+use crate::error::*;
+use crate::page::{Cursor, Page, paginate};
+use crate::receiver::TXQuerier;
+use crate::types::*;
+use crate::{CanisterState, mutate_state, read_state, require};
+use candid::{Principal, candid_method};
+use ic_cdk::api::time as blocktime;
+use ic_cdk::{query, update};
+use std::collections::{HashMap, VecDeque};
+
+/// Scale of the accrued rewards per pool share, so that small rewards per
+/// share are not rounded away.
+const REWARD_SCALE: u64 = 1_000_000_000_000_000_000;
+/// Most accrual snapshots retained.
+pub const MAX_ACCRUAL_SNAPSHOTS: usize = 256;
+
+#[derive(Clone, Deserialize, CandidType, PartialEq, Eq, Debug)]
+/// A budget paid out to pool depositors between two points in time.
+pub struct RewardsProgram {
+    pub budget: Amount,
+    pub start: Timestamp,
+    pub end: Timestamp,
+}
+
+#[derive(Clone, Deserialize, CandidType, PartialEq, Eq, Debug)]
+/// The rewards accrued per pool share up to a point in time.
+pub struct Accrual {
+    pub at: Timestamp,
+    /// Accrued rewards per share, scaled by 10^18.
+    pub per_unit: Nat,
+    /// The pool shares the rewards since the previous accrual were split
+    /// among.
+    pub shares: Amount,
+}
+
+#[derive(Default)]
+struct DepositorRewards {
+    /// The accrued rewards per unit when the depositor was last settled.
+    per_unit_settled: Nat,
+    owed: Amount,
+}
+
+#[derive(Default)]
+pub struct Rewards {
+    program: Option<RewardsProgram>,
+    /// Rewards accrued per share, scaled by `REWARD_SCALE`.
+    per_unit: Nat,
+    accrued_at: Timestamp,
+    depositors: HashMap<L1Account, DepositorRewards>,
+    snapshots: VecDeque<Accrual>,
+}
+
+#[update]
+#[candid_method(update)]
+/// Starts a rewards program, replacing the current one, or ends the current
+/// one if `program` is empty. Rewards accrued so far stay claimable.
+/// Admin only.
+fn set_rewards_program(program: Option<RewardsProgram>) -> Result<()> {
+    let hash = audit::args_hash((&program,));
+    audit::logged("set_rewards_program", hash, || {
+        crate::admin::require_admin()?;
+        mutate_state(|s| s.set_rewards_program(program, blocktime()))
+    })
+}
+
+#[query]
+#[candid_method(query)]
+fn query_rewards_program() -> Option<RewardsProgram> {
+    read_state(|s| s.rewards.program.clone())
+}
+
+#[query]
+#[candid_method(query)]
+/// Lists the accrual snapshots, oldest first. At most
+/// `MAX_ACCRUAL_SNAPSHOTS` are retained.
+fn query_accrual_snapshots(cursor: Option<Cursor>, limit: u32) -> Result<Page<Accrual>> {
+    read_state(|s| s.rewards.snapshots(cursor, limit))
+}
+
+#[query]
+#[candid_method(query)]
+/// Returns the rewards a pool depositor can claim.
+fn query_rewards(depositor: L1Account) -> Amount {
+    read_state(|s| s.claimable_rewards(&depositor, blocktime()))
+}
+
+#[update]
+#[candid_method(update)]
+/// Moves the caller's rewards into their withdrawable balance and returns
+/// the claimed amount.
+fn claim_rewards() -> Result<Amount> {
+    crate::unpaused!();
+    let caller = ic_cdk::api::msg_caller();
+    mutate_state(|s| s.claim_rewards(caller, blocktime()))
+}
+
+impl Rewards {
+    /// Snapshots are keyed by their accrual time, which strictly increases.
+    pub fn snapshots(&self, cursor: Option<Cursor>, limit: u32) -> Result<Page<Accrual>> {
+        paginate(
+            self.snapshots.iter().map(|a| (a.at, a.clone())),
+            cursor,
+            limit,
+        )
+    }
+
+    /// The rewards released between two points in time.
+    fn released(&self, from: Timestamp, to: Timestamp) -> Amount {
+        let Some(p) = &self.program else {
+            return Amount::default();
+        };
+        let (from, to) = (from.max(p.start), to.min(p.end));
+        if to <= from {
+            return Amount::default();
+        }
+        p.budget.clone() * Amount::from(to - from) / Amount::from(p.end - p.start)
+    }
+
+    /// The accrued rewards per share at `now`, for the given pool shares.
+    fn per_unit_at(&self, shares: &Amount, now: Timestamp) -> Nat {
+        if *shares == Amount::default() {
+            return self.per_unit.clone();
+        }
+        let released = self.released(self.accrued_at, now);
+        self.per_unit.clone() + released * Nat::from(REWARD_SCALE) / shares.clone()
+    }
+
+    /// Splits the rewards released since the last accrual among `shares`.
+    fn accrue(&mut self, shares: &Amount, now: Timestamp) {
+        if now <= self.accrued_at {
+            return;
+        }
+        self.per_unit = self.per_unit_at(shares, now);
+        self.accrued_at = now;
+        if self.snapshots.len() == MAX_ACCRUAL_SNAPSHOTS {
+            self.snapshots.pop_front();
+        }
+        self.snapshots.push_back(Accrual {
+            at: now,
+            per_unit: self.per_unit.clone(),
+            shares: shares.clone(),
+        });
+    }
+
+    /// The rewards owed to a depositor holding `held` shares since their
+    /// last settlement, given the accrued rewards per share.
+    fn owed(&self, depositor: &L1Account, held: &Amount, per_unit: &Nat) -> Amount {
+        let d = self.depositors.get(depositor);
+        let settled = d.map(|d| d.per_unit_settled.clone()).unwrap_or_default();
+        let owed = d.map(|d| d.owed.clone()).unwrap_or_default();
+        owed + held.clone() * (per_unit.clone() - settled) / Nat::from(REWARD_SCALE)
+    }
+
+    /// Credits a depositor with the rewards accrued on their shares. Must be
+    /// called after `accrue` and before their shares change.
+    fn settle(&mut self, depositor: &L1Account, held: &Amount) {
+        let owed = self.owed(depositor, held, &self.per_unit);
+        let d = self.depositors.entry(depositor.clone()).or_default();
+        d.per_unit_settled = self.per_unit.clone();
+        d.owed = owed;
+    }
+}
+
+impl<Q: TXQuerier> CanisterState<Q> {
+    /// The shares of the ckBTC pool, which rewards are paid out on.
+    fn rewarded_shares(&self) -> Amount {
+        self.liq_pools
+            .get(&self.ckbtc())
+            .map(|p| p.total_shares().clone())
+            .unwrap_or_default()
+    }
+
+    fn rewarded_shares_of(&self, depositor: &L1Account) -> Amount {
+        self.liq_pools
+            .get(&self.ckbtc())
+            .map(|p| p.shares_of(depositor))
+            .unwrap_or_default()
+    }
+
+    /// Accrues the rewards and settles a depositor. Must be called before
+    /// the depositor's pool shares change.
+    pub fn settle_rewards(&mut self, depositor: &L1Account, now: Timestamp) {
+        let shares = self.rewarded_shares();
+        self.rewards.accrue(&shares, now);
+        let held = self.rewarded_shares_of(depositor);
+        self.rewards.settle(depositor, &held);
+    }
+
+    pub fn set_rewards_program(
+        &mut self,
+        program: Option<RewardsProgram>,
+        now: Timestamp,
+    ) -> Result<()> {
+        if let Some(p) = &program {
+            require!(p.start < p.end, InvalidInput);
+        }
+        let shares = self.rewarded_shares();
+        self.rewards.accrue(&shares, now);
+        self.rewards.program = program;
+        Ok(())
+    }
+
+    pub fn claimable_rewards(&self, depositor: &L1Account, now: Timestamp) -> Amount {
+        let per_unit = self.rewards.per_unit_at(&self.rewarded_shares(), now);
+        let held = self.rewarded_shares_of(depositor);
+        self.rewards.owed(depositor, &held, &per_unit)
+    }
+
+    pub fn claim_rewards(&mut self, caller: Principal, now: Timestamp) -> Result<Amount> {
+        let depositor = L1Account(caller);
+        self.settle_rewards(&depositor, now);
+        let owed = match self.rewards.depositors.get_mut(&depositor) {
+            Some(d) => std::mem::take(&mut d.owed),
+            None => Amount::default(),
+        };
+        if owed > Amount::default() {
+            *self.balances.entry(caller).or_default() += owed.clone();
+        }
+        Ok(owed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+
+    #[test]
+    fn test_rewards_are_time_weighted() {
+        let mut s = new_state();
+        let (a, b) = (Principal::from_slice(&[1]), Principal::from_slice(&[2]));
+        let program = RewardsProgram {
+            budget: Amount::from(1_000u32),
+            start: 0,
+            end: 100,
+        };
+        s.set_rewards_program(Some(program), 0).unwrap();
+
+        s.deposit_liq_pool(&s.ckbtc(), Amount::from(10u32), L1Account(a), 0)
+            .unwrap();
+        // Bob provides the same liquidity for the second half only.
+        s.deposit_liq_pool(&s.ckbtc(), Amount::from(10u32), L1Account(b), 50)
+            .unwrap();
+        assert_eq!(
+            s.claimable_rewards(&L1Account(a), 100),
+            Amount::from(750u32)
+        );
+        assert_eq!(
+            s.claimable_rewards(&L1Account(b), 100),
+            Amount::from(250u32)
+        );
+
+        assert_eq!(s.claim_rewards(a, 200), Ok(Amount::from(750u32)));
+        assert_eq!(s.claim_rewards(a, 200), Ok(Amount::default()));
+        assert_eq!(s.balances[&a], Amount::from(750u32));
+        assert_eq!(s.claim_rewards(b, 300), Ok(Amount::from(250u32)));
+        assert_eq!(s.rewards.snapshots.len(), 3);
+        let page = s.rewards.snapshots(None, 2).unwrap();
+        let page = s.rewards.snapshots(page.next, 2).unwrap();
+        assert_eq!(
+            page.items.iter().map(|a| a.at).collect::<Vec<_>>(),
+            vec![300]
+        );
+        assert!(!page.has_more);
+    }
+
+    #[test]
+    fn test_rewards_program_needs_duration() {
+        let mut s = new_state();
+        let program = RewardsProgram {
+            budget: Amount::from(1u32),
+            start: 5,
+            end: 5,
+        };
+        assert!(s.set_rewards_program(Some(program), 0).is_err());
+    }
+}
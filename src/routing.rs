@@ -0,0 +1,150 @@
+//  Copyright 2025 PolyCrypt GmbH
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+
+//! Route discovery through the network of concluded canister channels. The
+//! graph is derived from the registered channels and their holdings, so it is
+//! always in sync with the canister's balances.
+
+use crate::htlc::Leg;
+use crate::receiver::TXQuerier;
+use crate::types::*;
+use crate::{CanisterState, read_state};
+use candid::{CandidType, candid_method};
+use ic_cdk::api::time as blocktime;
+use ic_cdk::query;
+use std::collections::{HashMap, VecDeque};
+
+/// Maximum number of hops in a route.
+pub const MAX_ROUTE_HOPS: usize = 4;
+
+#[derive(Clone, Deserialize, CandidType)]
+/// A directed edge of the routing graph: `leg.from` can pay up to `capacity`
+/// to `leg.to` within `leg.channel`.
+pub struct RouteEdge {
+    pub leg: Leg,
+    pub capacity: Amount,
+}
+
+#[query]
+#[candid_method(query)]
+/// Finds a shortest route of at most `MAX_ROUTE_HOPS` hops through concluded
+/// channels along which `amount` can be paid from `from` to `to`.
+fn find_route(from: L2Account, to: L2Account, amount: Amount) -> Option<Vec<Leg>> {
+    read_state(|s| s.find_route(&from, &to, &amount, blocktime()))
+}
+
+impl<Q: TXQuerier> CanisterState<Q> {
+    /// Lists the payment capacities between the participants of all concluded
+    /// channels.
+    pub fn routing_edges(&self, now: Timestamp) -> Vec<RouteEdge> {
+        let mut edges = vec![];
+        for (id, state) in self.channels.iter() {
+            let Some(params) = self.params.get(&id) else {
+                continue;
+            };
+            if !state.settled(now) {
+                continue;
+            }
+            for from in params.participants.iter() {
+                let capacity = self
+                    .query_holdings(Funding::new(id.clone(), from.clone()))
+                    .unwrap_or_default();
+                for to in params.participants.iter().filter(|p| *p != from) {
+                    edges.push(RouteEdge {
+                        leg: Leg {
+                            channel: id.clone(),
+                            from: from.clone(),
+                            to: to.clone(),
+                        },
+                        capacity: capacity.clone(),
+                    });
+                }
+            }
+        }
+        edges
+    }
+
+    /// Breadth-first search for a shortest route whose every hop can carry
+    /// the amount.
+    pub fn find_route(
+        &self,
+        from: &L2Account,
+        to: &L2Account,
+        amount: &Amount,
+        now: Timestamp,
+    ) -> Option<Vec<Leg>> {
+        let mut adjacent: HashMap<L2Account, Vec<Leg>> = HashMap::new();
+        for e in self.routing_edges(now) {
+            if &e.capacity >= amount {
+                adjacent.entry(e.leg.from.clone()).or_default().push(e.leg);
+            }
+        }
+
+        let mut via: HashMap<L2Account, Leg> = HashMap::new();
+        let mut queue = VecDeque::from([(from.clone(), 0)]);
+        while let Some((node, hops)) = queue.pop_front() {
+            if &node == to {
+                let mut route = vec![];
+                let mut cur = node;
+                while &cur != from {
+                    let leg = via.remove(&cur)?;
+                    cur = leg.from.clone();
+                    route.push(leg);
+                }
+                route.reverse();
+                return Some(route);
+            }
+            if hops == MAX_ROUTE_HOPS {
+                continue;
+            }
+            for leg in adjacent.get(&node).into_iter().flatten() {
+                if &leg.to != from && !via.contains_key(&leg.to) {
+                    via.insert(leg.to.clone(), leg.clone());
+                    queue.push_back((leg.to.clone(), hops + 1));
+                }
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+
+    #[test]
+    fn test_route_through_hub() {
+        let mut s = new_state();
+        let a = concluded(&mut s, 1, 1, 2);
+        let b = concluded(&mut s, 2, 2, 3);
+
+        let route = s
+            .find_route(&account(1), &account(3), &Amount::from(50u32), 0)
+            .unwrap();
+        assert_eq!(route.len(), 2);
+        assert!(route[0].channel == a && route[0].to == account(2));
+        assert!(route[1].channel == b && route[1].to == account(3));
+
+        // No hop can carry more than the payer's holdings.
+        assert!(
+            s.find_route(&account(1), &account(3), &Amount::from(101u32), 0)
+                .is_none()
+        );
+        assert!(
+            s.find_route(&account(1), &account(4), &Amount::from(1u32), 0)
+                .is_none()
+        );
+    }
+}
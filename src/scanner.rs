@@ -0,0 +1,218 @@
+//  Copyright 2025 PolyCrypt GmbH
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+
+//! Automatic deposit detection. A timer reads new ledger blocks through
+//! `icrc3_get_blocks` and credits transfers to fundings registered with
+//! `register_memo`, whether they carry the funding's memo or go into its
+//! deposit subaccount. Depositors no longer need to notify the canister.
+//! Transfers that cannot be attributed are tracked as unattributed deposits.
+
+use crate::config;
+use crate::pause::Flow;
+use crate::receiver::{IcrcBlocks, IcrcTransfer, TXQuerier};
+use crate::types::*;
+use crate::{CanisterState, mutate_state, read_state};
+use ic_cdk::api::time as blocktime;
+use std::time::Duration as StdDuration;
+
+/// How often the ledger is scanned for deposits.
+pub const LEDGER_SCAN_INTERVAL: StdDuration = StdDuration::from_secs(10);
+
+/// Scans the ledger and credits new deposits.
+pub fn scan_ledger() {
+    ic_cdk::futures::spawn(scan());
+}
+
+async fn scan() {
+    let ledger = config::current().ledger;
+    if read_state(|s| {
+        s.require_running()
+            .and(s.require_unpaused(&ledger, Flow::Deposit))
+    })
+    .is_err()
+    {
+        return;
+    }
+    let (querier, (start, length)) =
+        read_state(|s| (s.icrc_receiver.tx_querier(), s.icrc_receiver.scan_range()));
+    match querier.icrc3_get_blocks(start, length).await {
+        Ok(blocks) => {
+            mutate_state(|s| s.scan_ledger(blocks, blocktime()));
+        }
+        Err(e) => ic_cdk::println!("ledger scan failed: {}", e),
+    }
+}
+
+impl<Q: TXQuerier> CanisterState<Q> {
+    /// Credits the transfers in blocks read from the receiver's scan range
+    /// to registered fundings. Returns the credited amounts.
+    pub fn scan_ledger(&mut self, blocks: IcrcBlocks, now: Timestamp) -> Vec<(Funding, Amount)> {
+        let transfers = self.icrc_receiver.scan(blocks);
+        let mut credited = vec![];
+        for t in transfers {
+            let Some(funding) = self.attribute(&t) else {
+                self.track_unattributed(t);
+                continue;
+            };
+            if self.mark_processed(&self.ckbtc(), t.block).is_err() {
+                continue;
+            }
+            self.icrc_receiver.scanned(&t, funding.memo());
+            let amount = Amount::from(t.amount);
+            self.credit_deposit(funding.clone(), amount.clone(), now);
+            credited.push((funding, amount));
+        }
+        credited
+    }
+
+    /// The funding a transfer to the canister is for. Transfers to the
+    /// default account are attributed by their registered memo, or else by
+    /// the hash memo of a participant of an announced or registered channel.
+    fn attribute(&self, t: &IcrcTransfer) -> Option<Funding> {
+        let memo = match t.to.subaccount {
+            Some(sub) if sub != [0; 32] => {
+                return self.memo_registry.subaccount_funding(&sub).cloned();
+            }
+            _ => t.memo()?,
+        };
+        if let Some(funding) = self.memo_registry.funding(memo) {
+            return Some(funding.clone());
+        }
+        let announced = self.funding.values().map(|f| &f.intent.params);
+        announced
+            .chain(self.params.values())
+            .flat_map(|params| {
+                let id = params.id();
+                params
+                    .participants
+                    .iter()
+                    .map(move |p| Funding::new(id.clone(), p.clone()))
+            })
+            .find(|f| f.memo() == memo)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::receiver::MockTXQuerier;
+    use crate::subaccount::deposit_subaccount;
+    use crate::testing::*;
+    use candid::Principal;
+    use icrc_ledger_types::icrc1::account::Account;
+
+    #[test]
+    fn test_scan_credits_registered_fundings() {
+        let mut s = new_state();
+        let f = Funding::new(ChannelId([1; 32]), account(1));
+        let g = Funding::new(ChannelId([2; 32]), account(1));
+        let memo = s.memo_registry.register(f.clone());
+        s.memo_registry.register(g.clone());
+        let me = Principal::anonymous();
+        let transfer = |block, subaccount, memo: Option<u64>| IcrcTransfer {
+            block,
+            from: None,
+            to: Account {
+                owner: me,
+                subaccount,
+            },
+            amount: 10,
+            memo: memo.map(|m| m.to_be_bytes().to_vec()),
+        };
+        let scan = |s: &mut CanisterState<MockTXQuerier>| {
+            let (start, length) = s.icrc_receiver.scan_range();
+            let blocks = block_on(s.icrc_receiver.querier().icrc3_get_blocks(start, length));
+            s.scan_ledger(blocks.unwrap(), 0)
+        };
+        let q = s.icrc_receiver.querier();
+        q.register_icrc_transfer(transfer(0, None, Some(memo)));
+
+        // The first scan starts at the tip and skips older blocks.
+        assert!(scan(&mut s).is_empty());
+        let q = s.icrc_receiver.querier();
+        q.register_icrc_transfer(transfer(1, None, Some(memo)));
+        q.register_icrc_transfer(transfer(2, Some(deposit_subaccount(&g)), None));
+        q.register_icrc_transfer(transfer(3, None, Some(memo + 1)));
+        let credited = scan(&mut s);
+        assert_eq!(credited.len(), 2);
+        assert_eq!(holdings(&s, &f.channel, 1), Amount::from(10u32));
+        assert_eq!(holdings(&s, &g.channel, 1), Amount::from(10u32));
+
+        // Scanned blocks are not credited again, neither by explicit scans.
+        assert!(scan(&mut s).is_empty());
+        assert_eq!(s.icrc_receiver.credited(1), Some((memo, 10)));
+        assert_eq!(s.unattributed.keys().copied().collect::<Vec<_>>(), vec![3]);
+    }
+
+    #[test]
+    fn test_scanned_blocks_can_be_notified() {
+        let mut s = new_state();
+        let f = Funding::new(ChannelId([1; 32]), account(1));
+        let t = IcrcTransfer {
+            block: 0,
+            from: None,
+            to: Account {
+                owner: Principal::anonymous(),
+                subaccount: None,
+            },
+            amount: 10,
+            memo: Some(f.memo().to_be_bytes().to_vec()),
+        };
+        s.icrc_receiver.scan(IcrcBlocks {
+            transfers: vec![],
+            next: 0,
+            log_length: 0,
+        });
+        let blocks = IcrcBlocks {
+            transfers: vec![t.clone()],
+            next: 1,
+            log_length: 1,
+        };
+        assert!(s.scan_ledger(blocks, 0).is_empty());
+
+        let credited = s.process_icrc_tx(&t, 10, f).unwrap();
+        assert_eq!(credited.amount, Amount::from(10u32));
+        assert!(s.unattributed.is_empty());
+    }
+
+    #[test]
+    fn test_scan_attributes_hash_memos() {
+        let mut s = new_state();
+        let ch = concluded(&mut s, 1, 1, 2);
+        let f = Funding::new(ch.clone(), account(2));
+        let t = IcrcTransfer {
+            block: 0,
+            from: None,
+            to: Account {
+                owner: Principal::anonymous(),
+                subaccount: None,
+            },
+            amount: 10,
+            memo: Some(f.memo().to_be_bytes().to_vec()),
+        };
+        s.icrc_receiver.scan(IcrcBlocks {
+            transfers: vec![],
+            next: 0,
+            log_length: 0,
+        });
+        let blocks = IcrcBlocks {
+            transfers: vec![t],
+            next: 1,
+            log_length: 1,
+        };
+        assert_eq!(s.scan_ledger(blocks, 0).len(), 1);
+        assert_eq!(holdings(&s, &ch, 2), Amount::from(110u32));
+        assert!(s.unattributed.is_empty());
+    }
+}
@@ -0,0 +1,132 @@
+//  Copyright 2025 PolyCrypt GmbH
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+
+//! Automatic conclusion of disputes. A disputed state becomes final once its
+//! timeout has passed. A timer scheduled at the timeout then settles the
+//! channel: it lifts the channel's lock and emits a `Concluded` event, so
+//! that clients learn about the outcome without polling `query_state`. The
+//! registered state itself is kept as signed. Timers do not survive
+//! upgrades, so they are scheduled again for all open disputes on upgrade,
+//! and `process_expired_disputes` settles expired disputes on demand.
+
+use crate::error::*;
+use crate::events::Event;
+use crate::load::{self, Priority};
+use crate::receiver::TXQuerier;
+use crate::types::*;
+use crate::{CanisterState, mutate_state, read_state};
+use candid::candid_method;
+use ic_cdk::api::time as blocktime;
+use ic_cdk::update;
+use std::time::Duration as StdDuration;
+
+#[update]
+#[candid_method(update)]
+/// Settles all disputes whose timeout has passed and returns their channels.
+/// Disputes are settled by timers as well; this is a fallback.
+fn process_expired_disputes() -> Result<Vec<ChannelId>> {
+    load::admit(Priority::Low)?;
+    Ok(mutate_state(|s| s.settle_expired_disputes(blocktime())))
+}
+
+/// Schedules the settlement of a channel's dispute at its timeout.
+pub fn schedule_settlement(channel: &ChannelId) {
+    let Some(timeout) = read_state(|s| s.state(channel)).map(|r| r.timeout) else {
+        return;
+    };
+    let delay = StdDuration::from_nanos(timeout.saturating_sub(blocktime()));
+    ic_cdk_timers::set_timer(delay, || {
+        mutate_state(|s| s.settle_expired_disputes(blocktime()));
+    });
+}
+
+/// Schedules the settlement of all open disputes.
+pub fn schedule_open_disputes() {
+    for channel in read_state(|s| s.locked.iter().cloned().collect::<Vec<_>>()) {
+        schedule_settlement(&channel);
+    }
+}
+
+impl<Q: TXQuerier> CanisterState<Q> {
+    /// Settles all disputes whose timeout has passed, in channel order.
+    pub fn settle_expired_disputes(&mut self, now: Timestamp) -> Vec<ChannelId> {
+        let mut expired: Vec<_> = self
+            .locked
+            .iter()
+            .filter(|ch| self.channels.get(ch).is_none_or(|r| r.settled(now)))
+            .cloned()
+            .collect();
+        expired.sort();
+        for channel in &expired {
+            self.settle_dispute(channel, now);
+        }
+        expired
+    }
+
+    /// Lifts a settled dispute's lock and emits a `Concluded` event for it.
+    pub(crate) fn settle_dispute(&mut self, channel: &ChannelId, now: Timestamp) {
+        if !self.locked.remove(channel) {
+            return;
+        }
+        if let Some(state) = self.channels.get(channel) {
+            self.touch_channel(channel);
+            let event = Event::Concluded {
+                state,
+                timestamp: now,
+            };
+            self.events.push(now, channel.clone(), event);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+
+    #[test]
+    fn test_expired_disputes_are_settled() {
+        let mut s = new_state();
+        let params = Params {
+            nonce: Nonce([4; 32]),
+            participants: vec![account(1), account(2)],
+            challenge_duration: 10,
+            expiry: None,
+        };
+        let id = params.id();
+        for p in [1, 2] {
+            s.deposit(Funding::new(id.clone(), account(p)), Amount::from(100u32))
+                .unwrap();
+        }
+        let state = State {
+            channel: id.clone(),
+            version: 1,
+            allocation: vec![Amount::from(120u32), Amount::from(80u32)],
+            finalized: false,
+            assets: vec![],
+        };
+        let sigs = [1, 2].map(|p| sign(p, &state.signing_bytes()));
+        s.dispute(&params, state, &sigs, 5).unwrap();
+
+        assert!(s.settle_expired_disputes(14).is_empty());
+        let settled = s.settle_expired_disputes(15);
+        assert!(settled == vec![id.clone()]);
+        assert!(s.settle_expired_disputes(16).is_empty());
+        assert!(!s.locked.contains(&id));
+        let events = s.events.events_after(&id, 15);
+        assert!(matches!(events[..], [Event::Concluded { .. }]));
+        // The registered state stays as signed.
+        assert!(!s.state(&id).unwrap().state.finalized);
+    }
+}
@@ -0,0 +1,203 @@
+//  Copyright 2025 PolyCrypt GmbH
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+
+//! Shadow execution of candidate accounting engines. While shadow mode is
+//! on, every holdings change is also applied to the candidate engine, and
+//! the engine's result is compared with the holdings. Divergences are logged
+//! and recorded, but never affect the holdings. This allows running a new
+//! engine on production inputs before switching to it.
+
+use crate::audit;
+use crate::error::*;
+use crate::holdings::ChangeCause;
+use crate::page::{Cursor, Page, paginate};
+use crate::receiver::TXQuerier;
+use crate::types::*;
+use crate::{CanisterState, mutate_state, read_state};
+use candid::candid_method;
+use ic_cdk::{query, update};
+use std::collections::{HashMap, VecDeque};
+
+/// How many divergences are retained.
+pub const MAX_DIVERGENCES: usize = 1_000;
+
+#[derive(Clone, Deserialize, CandidType)]
+/// A holdings change whose result differs between the holdings and the
+/// shadow engine.
+pub struct Divergence {
+    /// The sequence number of the holdings change.
+    pub seq: u64,
+    pub funding: Funding,
+    pub cause: ChangeCause,
+    /// The funding's holdings after the change.
+    pub primary: Amount,
+    /// The shadow engine's result, if it could compute one.
+    pub shadow: Option<u64>,
+}
+
+#[derive(Clone, Deserialize, CandidType, PartialEq, Debug)]
+pub struct ShadowStatus {
+    pub enabled: bool,
+    /// Changes compared since shadow mode was enabled.
+    pub checked: u64,
+    /// Divergences found since shadow mode was enabled.
+    pub diverged: u64,
+}
+
+/// Holdings in satoshis as `u64`, the candidate replacing arbitrary
+/// precision amounts.
+#[derive(Default)]
+pub struct SatsEngine {
+    holdings: HashMap<Funding, u64>,
+}
+
+#[derive(Default)]
+pub struct Shadow {
+    engine: Option<SatsEngine>,
+    divergences: VecDeque<Divergence>,
+    checked: u64,
+    diverged: u64,
+}
+
+#[update]
+#[candid_method(update)]
+/// Turns shadow mode on or off. Enabling it starts the shadow engine from the
+/// current holdings. Admin only.
+fn set_shadow_mode(enabled: bool) -> Result<()> {
+    audit::logged("set_shadow_mode", audit::args_hash((enabled,)), || {
+        crate::admin::require_admin()?;
+        mutate_state(|s| s.set_shadow_mode(enabled));
+        Ok(())
+    })
+}
+
+#[query]
+#[candid_method(query)]
+fn query_shadow_status() -> ShadowStatus {
+    read_state(|state| ShadowStatus {
+        enabled: state.shadow.engine.is_some(),
+        checked: state.shadow.checked,
+        diverged: state.shadow.diverged,
+    })
+}
+
+#[query]
+#[candid_method(query)]
+/// Lists the retained divergences, ordered by the sequence number of their
+/// holdings change.
+fn query_shadow_divergences(cursor: Option<Cursor>, limit: u32) -> Result<Page<Divergence>> {
+    read_state(|s| s.shadow_divergences(cursor, limit))
+}
+
+impl SatsEngine {
+    /// Applies a change and returns the new holdings, or nothing if they are
+    /// not representable.
+    fn apply(&mut self, funding: &Funding, delta: &Int) -> Option<u64> {
+        let held = self.holdings.get(funding).copied().unwrap_or(0);
+        let delta = i128::try_from(delta.0.clone()).ok()?;
+        let new = u64::try_from(i128::from(held).checked_add(delta)?).ok()?;
+        self.set(funding, new);
+        Some(new)
+    }
+
+    fn set(&mut self, funding: &Funding, amount: u64) {
+        match amount {
+            0 => self.holdings.remove(funding),
+            _ => self.holdings.insert(funding.clone(), amount),
+        };
+    }
+}
+
+impl<Q: TXQuerier> CanisterState<Q> {
+    pub fn shadow_divergences(
+        &self,
+        cursor: Option<Cursor>,
+        limit: u32,
+    ) -> Result<Page<Divergence>> {
+        paginate(
+            self.shadow.divergences.iter().map(|d| (d.seq, d.clone())),
+            cursor,
+            limit,
+        )
+    }
+
+    pub fn set_shadow_mode(&mut self, enabled: bool) {
+        self.shadow = Shadow::default();
+        if enabled {
+            let mut engine = SatsEngine::default();
+            for (funding, amount) in self.user_holdings.iter() {
+                engine.set(&funding, u64::try_from(amount.0).unwrap_or(0));
+            }
+            self.shadow.engine = Some(engine);
+        }
+    }
+
+    /// Applies a holdings change to the shadow engine, if enabled, and
+    /// records a divergence from the holdings.
+    pub(crate) fn shadow_change(&mut self, funding: &Funding, delta: &Int, cause: ChangeCause) {
+        let Some(engine) = self.shadow.engine.as_mut() else {
+            return;
+        };
+        let primary = self.user_holdings.get(funding).unwrap_or_default();
+        let shadow = engine.apply(funding, delta);
+        self.shadow.checked += 1;
+        if shadow.is_some_and(|s| primary == s) {
+            return;
+        }
+        // Continue from the holdings, so that one divergence is reported once.
+        engine.set(funding, u64::try_from(primary.0.clone()).unwrap_or(0));
+        let seq = self.holdings_log.last_seq();
+        ic_cdk::println!("shadow divergence at change {}: {:?}", seq, shadow);
+        if self.shadow.divergences.len() == MAX_DIVERGENCES {
+            self.shadow.divergences.pop_front();
+        }
+        self.shadow.divergences.push_back(Divergence {
+            seq,
+            funding: funding.clone(),
+            cause,
+            primary,
+            shadow,
+        });
+        self.shadow.diverged += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+
+    #[test]
+    fn test_shadow_reports_unrepresentable_holdings() {
+        let mut s = new_state();
+        let ch = concluded(&mut s, 1, 1, 2);
+        s.set_shadow_mode(true);
+        let f = Funding::new(ch, account(1));
+        s.debit(&f, &Amount::from(30u32), ChangeCause::Withdrawal);
+        assert_eq!((s.shadow.checked, s.shadow.diverged), (1, 0));
+
+        let huge = Amount::from(u128::from(u64::MAX) + 1);
+        s.credit(f.clone(), huge, ChangeCause::Deposit);
+        assert_eq!(s.shadow.diverged, 1);
+        assert_eq!(s.shadow.divergences[0].shadow, None);
+        assert_eq!(s.shadow.divergences[0].seq, 3);
+        let page = s.shadow_divergences(None, 10).unwrap();
+        assert_eq!(page.next, Some(3u64.to_be_bytes().to_vec()));
+        assert!(!page.has_more);
+
+        s.set_shadow_mode(false);
+        s.debit(&f, &Amount::from(1u32), ChangeCause::Withdrawal);
+        assert_eq!(s.shadow.checked, 0);
+    }
+}
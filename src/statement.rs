@@ -0,0 +1,207 @@
+//  Copyright 2025 PolyCrypt GmbH
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+
+//! Periodic settlement statements of bridge operators. Every served or
+//! missed assignment is booked into the operator's statement of the period
+//! it happened in, so that operators can invoice their liquidity providers
+//! and reconcile their Lightning-side accounting against the canister. The
+//! statement of the current period is still open and may change.
+
+use crate::error::*;
+use crate::operator::Direction;
+use crate::page::{Cursor, Page, paginate};
+use crate::receiver::TXQuerier;
+use crate::types::*;
+use crate::{CanisterState, read_state};
+use candid::{Principal, candid_method};
+use ic_cdk::query;
+
+/// The length of a statement period (one day).
+pub const STATEMENT_PERIOD: Duration = 86_400_000_000_000;
+
+/// Index of a statement period, counted from the Unix epoch.
+pub type Period = u64;
+
+#[derive(Clone, Deserialize, CandidType, PartialEq, Debug)]
+pub struct Statement {
+    pub operator: Principal,
+    pub period: Period,
+    /// Swaps into Lightning the operator completed.
+    pub swaps_served: u64,
+    /// Outbound Lightning liquidity used by the served swaps.
+    pub swap_volume: Amount,
+    /// Invoices issued by the operator that were paid.
+    pub invoices_served: u64,
+    /// Inbound Lightning liquidity used by the paid invoices.
+    pub invoice_volume: Amount,
+    /// Fees earned on served swaps and invoices.
+    pub fees_earned: Amount,
+    /// Assignments the operator failed to serve in time.
+    pub missed: u64,
+}
+
+#[query]
+#[candid_method(query)]
+/// Lists the statements of an operator, ordered by period. Periods without
+/// activity have no statement.
+fn query_statements(
+    operator: Principal,
+    cursor: Option<Cursor>,
+    limit: u32,
+) -> Result<Page<Statement>> {
+    read_state(|s| s.statements(operator, cursor, limit))
+}
+
+#[query]
+#[candid_method(query)]
+/// Exports an operator's statement of a period as CSV.
+fn export_statement(operator: Principal, period: Period) -> Result<String> {
+    read_state(|s| {
+        s.statements
+            .get(&(operator, period))
+            .map(Statement::to_csv)
+            .ok_or_else(|| Error::from(ErrorCode::NotFound).with("operator", operator))
+    })
+}
+
+/// The period a timestamp falls into.
+pub fn period(t: Timestamp) -> Period {
+    t / STATEMENT_PERIOD
+}
+
+impl Statement {
+    pub fn new(operator: Principal, period: Period) -> Self {
+        Self {
+            operator,
+            period,
+            swaps_served: 0,
+            swap_volume: Amount::default(),
+            invoices_served: 0,
+            invoice_volume: Amount::default(),
+            fees_earned: Amount::default(),
+            missed: 0,
+        }
+    }
+
+    pub fn start(&self) -> Timestamp {
+        self.period * STATEMENT_PERIOD
+    }
+
+    pub fn to_csv(&self) -> String {
+        let mut csv = String::from("field,value\n");
+        let rows: [(&str, String); 10] = [
+            ("operator", self.operator.to_text()),
+            ("period", self.period.to_string()),
+            ("start", self.start().to_string()),
+            ("end", (self.start() + STATEMENT_PERIOD).to_string()),
+            ("swaps_served", self.swaps_served.to_string()),
+            ("swap_volume", self.swap_volume.0.to_string()),
+            ("invoices_served", self.invoices_served.to_string()),
+            ("invoice_volume", self.invoice_volume.0.to_string()),
+            ("fees_earned", self.fees_earned.0.to_string()),
+            ("missed", self.missed.to_string()),
+        ];
+        for (field, value) in rows {
+            csv.push_str(&format!("{field},{value}\n"));
+        }
+        csv
+    }
+}
+
+impl<Q: TXQuerier> CanisterState<Q> {
+    fn statement(&mut self, operator: Principal, now: Timestamp) -> &mut Statement {
+        let period = period(now);
+        self.statements
+            .entry((operator, period))
+            .or_insert_with(|| Statement::new(operator, period))
+    }
+
+    /// Books an assignment the operator served, moving `amount` across the
+    /// bridge for `fee`.
+    pub(crate) fn record_served(
+        &mut self,
+        operator: Principal,
+        direction: Direction,
+        amount: Amount,
+        fee: Amount,
+        now: Timestamp,
+    ) {
+        self.reputation.entry(operator).or_default().completed += 1;
+        let s = self.statement(operator, now);
+        match direction {
+            Direction::ToLightning => {
+                s.swaps_served += 1;
+                s.swap_volume += amount;
+            }
+            Direction::FromLightning => {
+                s.invoices_served += 1;
+                s.invoice_volume += amount;
+            }
+        }
+        s.fees_earned += fee;
+    }
+
+    /// Books an assignment the operator failed to serve in time.
+    pub(crate) fn record_missed(&mut self, operator: Principal, now: Timestamp) {
+        self.reputation.entry(operator).or_default().missed += 1;
+        self.statement(operator, now).missed += 1;
+    }
+
+    pub fn statements(
+        &self,
+        operator: Principal,
+        cursor: Option<Cursor>,
+        limit: u32,
+    ) -> Result<Page<Statement>> {
+        paginate(
+            self.statements
+                .range((operator, 0)..=(operator, Period::MAX))
+                .map(|((_, period), s)| (*period, s.clone())),
+            cursor,
+            limit,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+
+    #[test]
+    fn test_statements_per_period() {
+        let mut s = new_state();
+        let (op, other) = (Principal::from_slice(&[10]), Principal::from_slice(&[11]));
+        let amount = |x: u32| Amount::from(x);
+        s.record_served(op, Direction::ToLightning, amount(50), amount(1), 0);
+        s.record_served(op, Direction::FromLightning, amount(20), amount(2), 1);
+        s.record_missed(op, STATEMENT_PERIOD);
+        s.record_missed(other, 0);
+
+        let statements = s.statements(op, None, 10).unwrap().items;
+        assert_eq!(statements.len(), 2);
+        assert_eq!(statements[0].swap_volume, amount(50));
+        assert_eq!(statements[0].invoices_served, 1);
+        assert_eq!(statements[0].fees_earned, amount(3));
+        assert_eq!(statements[1].period, 1);
+        assert_eq!(statements[1].missed, 1);
+        let first = s.statements(op, None, 1).unwrap();
+        assert!(first.has_more);
+        let rest = s.statements(op, first.next, 10).unwrap();
+        assert_eq!(rest.items, statements[1..]);
+        assert!(!rest.has_more);
+        assert_eq!(s.reputation[&op].completed, 2);
+        assert!(statements[0].to_csv().contains("\nfees_earned,3\n"));
+    }
+}
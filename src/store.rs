@@ -0,0 +1,284 @@
+//  Copyright 2025 PolyCrypt GmbH
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+
+//! Registered channels, holdings and used nonces in stable memory. They grow
+//! with every channel, so they are kept out of the heap: they survive
+//! upgrades and are not bounded by the heap's size.
+
+use crate::certified::{self, MerkleMap};
+use crate::memory::Memory;
+use crate::types::*;
+use candid::{Decode, Encode, Principal};
+use ic_stable_structures::StableBTreeMap;
+use ic_stable_structures::storable::{Bound, Storable};
+use k256::PublicKey as SecpPublicKey;
+use k256::elliptic_curve::sec1::ToEncodedPoint;
+use std::borrow::Cow;
+
+/// Length of a compressed secp256k1 public key.
+const L2_ACCOUNT_LEN: usize = 33;
+
+/// The holdings of fundings, without empty entries.
+pub struct HoldingsMap {
+    map: StableBTreeMap<Funding, StoredAmount, Memory>,
+    /// The holdings in assets other than ckBTC, whose keys do not fit `map`.
+    assets: StableBTreeMap<AssetFunding, StoredAmount, Memory>,
+    /// The certified tree over the holdings, kept on the heap.
+    tree: MerkleMap,
+}
+
+/// An amount as stored: its LEB128 encoding.
+pub struct StoredAmount(pub Amount);
+
+/// A funding in an asset other than ckBTC as stored: the funding followed by
+/// the asset's ledger.
+#[derive(PartialEq, Eq, PartialOrd, Ord, Clone)]
+struct AssetFunding(Funding);
+
+/// The nonces of signed requests that were used, by funding. Surviving
+/// upgrades, a used request cannot be replayed after one.
+pub struct NonceSet {
+    map: StableBTreeMap<UsedNonce, (), Memory>,
+}
+
+/// A funding and a nonce it used, stored as the funding's key followed by
+/// the nonce.
+#[derive(PartialEq, Eq, PartialOrd, Ord, Clone)]
+struct UsedNonce(Funding, u64);
+
+impl HoldingsMap {
+    /// Loads the holdings kept in `memory` and those in other assets kept in
+    /// `asset_memory`, or starts empty holdings there.
+    pub fn init(memory: Memory, asset_memory: Memory) -> Self {
+        let mut holdings = Self {
+            map: StableBTreeMap::init(memory),
+            assets: StableBTreeMap::init(asset_memory),
+            tree: MerkleMap::default(),
+        };
+        holdings.tree = holdings.certified_tree();
+        holdings
+    }
+
+    pub fn get(&self, funding: &Funding) -> Option<Amount> {
+        match funding.asset {
+            None => self.map.get(funding),
+            Some(_) => self.assets.get(&AssetFunding(funding.clone())),
+        }
+        .map(|a| a.0)
+    }
+
+    pub fn insert(&mut self, funding: Funding, amount: Amount) {
+        let leaf = certified::leaf_bytes(&amount);
+        self.tree.insert(funding.key(), &leaf);
+        match funding.asset {
+            None => self.map.insert(funding, StoredAmount(amount)),
+            Some(_) => self
+                .assets
+                .insert(AssetFunding(funding), StoredAmount(amount)),
+        };
+    }
+
+    pub fn remove(&mut self, funding: &Funding) {
+        self.tree.remove(&funding.key());
+        match funding.asset {
+            None => self.map.remove(funding),
+            Some(_) => self.assets.remove(&AssetFunding(funding.clone())),
+        };
+    }
+
+    pub fn tree(&self) -> &MerkleMap {
+        &self.tree
+    }
+
+    pub fn tree_mut(&mut self) -> &mut MerkleMap {
+        &mut self.tree
+    }
+
+    /// Iterates over all ckBTC holdings, ordered by funding.
+    pub fn iter(&self) -> impl Iterator<Item = (Funding, Amount)> + '_ {
+        self.map.iter().map(|(f, a)| (f, a.0))
+    }
+
+    /// Iterates over all holdings in other assets, ordered by funding.
+    pub fn iter_assets(&self) -> impl Iterator<Item = (Funding, Amount)> + '_ {
+        self.assets.iter().map(|(f, a)| (f.0, a.0))
+    }
+}
+
+impl NonceSet {
+    /// Loads the nonces kept in `memory`, or starts an empty set there.
+    pub fn init(memory: Memory) -> Self {
+        Self {
+            map: StableBTreeMap::init(memory),
+        }
+    }
+
+    pub fn contains(&self, funding: &Funding, nonce: u64) -> bool {
+        self.map.contains_key(&UsedNonce(funding.clone(), nonce))
+    }
+
+    pub fn insert(&mut self, funding: &Funding, nonce: u64) {
+        self.map.insert(UsedNonce(funding.clone(), nonce), ());
+    }
+
+    pub fn remove(&mut self, funding: &Funding, nonce: u64) {
+        self.map.remove(&UsedNonce(funding.clone(), nonce));
+    }
+}
+
+impl Funding {
+    /// The key of the funding's holdings in the certified tree: its stored
+    /// bytes, followed by its asset's ledger unless ckBTC.
+    pub fn key(&self) -> Vec<u8> {
+        match self.asset {
+            None => self.to_bytes().into_owned(),
+            Some(_) => AssetFunding(self.clone()).to_bytes().into_owned(),
+        }
+    }
+}
+
+impl Storable for StoredAmount {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        let mut bytes = vec![];
+        self.0.encode(&mut bytes).expect("encoding amount");
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Self(Nat::decode(&mut bytes.as_ref()).expect("decoding amount"))
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for ChannelId {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Borrowed(&self.0)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Self(bytes.as_ref().try_into().expect("decoding channel id"))
+    }
+
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 32,
+        is_fixed_size: true,
+    };
+}
+
+/// Stores the channel and participant only; the asset of fundings not in
+/// ckBTC is stored by `AssetFunding`.
+impl Storable for Funding {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        let mut bytes = self.channel.0.to_vec();
+        bytes.extend_from_slice(self.participant.0.to_encoded_point(true).as_bytes());
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        let (channel, participant) = bytes.split_at(32);
+        let participant =
+            SecpPublicKey::from_sec1_bytes(participant).expect("decoding participant");
+        Self::new(
+            ChannelId(channel.try_into().expect("decoding channel id")),
+            L2Account(participant),
+        )
+    }
+
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 32 + L2_ACCOUNT_LEN as u32,
+        is_fixed_size: true,
+    };
+}
+
+impl Storable for AssetFunding {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        let mut bytes = self.0.to_bytes().into_owned();
+        if let Some(asset) = self.0.asset {
+            bytes.extend_from_slice(asset.as_slice());
+        }
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        let (funding, asset) = bytes.split_at(32 + L2_ACCOUNT_LEN);
+        let asset = Principal::from_slice(asset);
+        Self(Funding::from_bytes(Cow::Borrowed(funding)).in_asset(Some(asset)))
+    }
+
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 32 + L2_ACCOUNT_LEN as u32 + 29,
+        is_fixed_size: false,
+    };
+}
+
+impl Storable for UsedNonce {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        let mut bytes = self.0.key();
+        bytes.extend_from_slice(&self.1.to_be_bytes());
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        let (funding, nonce) = bytes.split_at(bytes.len() - 8);
+        let funding = match funding.len() {
+            len if len == 32 + L2_ACCOUNT_LEN => Funding::from_bytes(Cow::Borrowed(funding)),
+            _ => AssetFunding::from_bytes(Cow::Borrowed(funding)).0,
+        };
+        let nonce = u64::from_be_bytes(nonce.try_into().expect("decoding nonce"));
+        Self(funding, nonce)
+    }
+
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 32 + L2_ACCOUNT_LEN as u32 + 29 + 8,
+        is_fixed_size: false,
+    };
+}
+
+impl Storable for RegisteredState {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).expect("encoding registered state"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(&bytes, Self).expect("decoding registered state")
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+
+    #[test]
+    fn test_stored_values_roundtrip() {
+        let funding = Funding::new(ChannelId([3; 32]), account(1));
+        assert!(Funding::from_bytes(funding.to_bytes()) == funding);
+        let usdc = funding
+            .clone()
+            .in_asset(Some(Principal::from_slice(&[4; 10])));
+        for f in [funding, usdc] {
+            let used = UsedNonce(f, 9);
+            assert!(UsedNonce::from_bytes(used.to_bytes()) == used);
+        }
+
+        let huge = Amount::from(u128::MAX) * Amount::from(3u32);
+        for amount in [Amount::default(), Amount::from(7u32), huge] {
+            let stored = StoredAmount(amount.clone());
+            assert_eq!(StoredAmount::from_bytes(stored.to_bytes()).0, amount);
+        }
+    }
+}
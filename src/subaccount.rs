@@ -0,0 +1,116 @@
+//  Copyright 2025 PolyCrypt GmbH
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+
+//! Funding through per-funding subaccounts. Every funding has its own
+//! subaccount of the canister, derived from the full hash of the funding, so
+//! that deposits are attributed without a memo. This suits wallets that
+//! cannot set memos and avoids the collisions of the 8-byte memos.
+
+use crate::error::*;
+use crate::pause::Flow;
+use crate::receiver::{BlockHeight, TXQuerier, TransactionNotification};
+use crate::types::*;
+use crate::{CanisterState, mutate_state, read_state, require};
+use candid::candid_method;
+use ic_cdk::api::time as blocktime;
+use ic_cdk::{query, update};
+use icrc_ledger_types::icrc1::account::Account;
+use k256::elliptic_curve::sec1::ToEncodedPoint;
+use k256::sha2::{Digest, Sha256};
+
+#[query]
+#[candid_method(query)]
+/// Returns the account to which deposits for `funding` are transferred.
+fn get_deposit_account(funding: Funding) -> Account {
+    Account {
+        owner: ic_cdk::api::canister_self(),
+        subaccount: Some(deposit_subaccount(&funding)),
+    }
+}
+
+#[update]
+#[candid_method(update)]
+/// Scans a ledger block for a transfer into the deposit account of `funding`
+/// and credits it. Returns the credited amount.
+async fn scan_deposit(funding: Funding, block_height: BlockHeight) -> Result<Amount> {
+    crate::unpaused!();
+    let querier = read_state(|s| s.block_querier(&s.ckbtc(), block_height))?;
+    let tx = querier
+        .query_tx(block_height)
+        .await
+        .map_err(ErrorCode::ReceiverError)?;
+    mutate_state(|s| s.scan_deposit(funding, block_height, &tx, blocktime()))
+}
+
+/// The subaccount holding the deposits of a funding.
+pub fn deposit_subaccount(funding: &Funding) -> [u8; 32] {
+    let mut h = Sha256::new();
+    h.update(b"ckLightning deposit");
+    h.update(funding.channel.0);
+    h.update(funding.participant.0.to_encoded_point(true).as_bytes());
+    h.finalize().into()
+}
+
+impl<Q: TXQuerier> CanisterState<Q> {
+    /// Verifies the transfer read from a block and credits it to the funding
+    /// whose deposit subaccount it was sent to.
+    pub fn scan_deposit(
+        &mut self,
+        funding: Funding,
+        block_height: BlockHeight,
+        tx: &TransactionNotification,
+        now: Timestamp,
+    ) -> Result<Amount> {
+        require!(funding.asset.is_none(), InvalidInput);
+        self.require_unpaused(&self.config.ledger, Flow::Deposit)?;
+        self.require_unprocessed(&self.ckbtc(), block_height)?;
+        let amount = self
+            .icrc_receiver
+            .verify_subaccount(block_height, tx, deposit_subaccount(&funding))
+            .map_err(ErrorCode::ReceiverError)?;
+        self.mark_processed(&self.ckbtc(), block_height)?;
+        self.credit_deposit(funding.clone(), amount.clone(), now);
+        Ok(amount)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::receiver::ICPReceiverError;
+    use crate::testing::*;
+
+    #[test]
+    fn test_subaccount_transfer_credits_funding() {
+        let mut s = new_state();
+        let f = Funding::new(ChannelId([1; 32]), account(1));
+        let other = Funding::new(ChannelId([1; 32]), account(2));
+        assert_ne!(deposit_subaccount(&f), deposit_subaccount(&other));
+        let to = s.icrc_receiver.subaccount(deposit_subaccount(&f));
+        let tx = TransactionNotification {
+            to,
+            amount: 40,
+            memo: 0,
+        };
+
+        let recipient = Err(ErrorCode::ReceiverError(ICPReceiverError::Recipient).into());
+        assert_eq!(s.scan_deposit(other, 1, &tx, 0), recipient);
+        assert_eq!(
+            s.scan_deposit(f.clone(), 1, &tx, 0),
+            Ok(Amount::from(40u32))
+        );
+        assert_eq!(holdings(&s, &f.channel, 1), Amount::from(40u32));
+        assert!(s.scan_deposit(f, 1, &tx, 0).is_err());
+    }
+}
@@ -0,0 +1,821 @@
+//  Copyright 2025 PolyCrypt GmbH
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+
+//! Swaps pay a Lightning invoice out of a participant's channel holdings. The
+//! swapped amount plus the operator's fee is locked at creation and assigned
+//! to a bridge operator, who must claim the swap within a claim window and
+//! then prove payment by revealing the invoice's preimage. Operators that miss
+//! their claim deadline lose the swap to the next candidate. Failed swaps are
+//! refunded to the ledger account registered at creation, less the ledger
+//! fee. The refund's creation time is fixed on its first attempt, so that the
+//! ledger deduplicates its retries. If the ledger rejects the refund for
+//! good, the locked funds are returned to the swap's funding instead.
+
+use crate::audit;
+use crate::bridge::BridgeRequestId;
+use crate::config;
+use crate::error::*;
+use crate::holdings::ChangeCause;
+use crate::operator::Direction;
+use crate::page::{Cursor, Page, paginate};
+use crate::pause::Flow;
+use crate::permission::Scope;
+use crate::receiver::TXQuerier;
+use crate::transfer::{self, Disposition};
+use crate::types::*;
+use crate::validation;
+use crate::{CanisterState, mutate_state, read_state, require};
+use candid::{Principal, candid_method};
+use ic_cdk::api::time as blocktime;
+use ic_cdk::{query, update};
+use icrc_ledger_types::icrc1::account::Account;
+use icrc_ledger_types::icrc1::transfer::TransferArg;
+use k256::elliptic_curve::sec1::ToEncodedPoint;
+
+/// Identifies a swap.
+pub type SwapId = u64;
+
+/// How long an assigned operator has to claim a swap (five minutes).
+pub const SWAP_CLAIM_WINDOW: Duration = 300_000_000_000;
+
+/// How often swaps are checked for missed deadlines (one minute).
+pub const SWAP_CHECK_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60);
+
+/// Default cap on the open swaps and invoice requests of one creator.
+pub const DEFAULT_MAX_OPEN_PER_USER: u32 = 16;
+
+/// Default cap on the open swaps and invoice requests of one operator.
+pub const DEFAULT_MAX_OPEN_PER_OPERATOR: u32 = 256;
+
+#[derive(Clone, Copy, Deserialize, CandidType, PartialEq, Eq, Debug)]
+/// Caps on simultaneously open swaps and invoice requests. They bound the
+/// funds one principal can have locked and the work of the periodic checks.
+pub struct SwapLimits {
+    /// Most open swaps and invoice requests created by one principal.
+    pub per_user: u32,
+    /// Most open swaps and invoice requests assigned to one operator.
+    pub per_operator: u32,
+}
+
+#[derive(Clone, Deserialize, CandidType)]
+/// A request to pay a Lightning invoice, signed by the funding participant.
+pub struct SwapRequest {
+    /// The BOLT11 invoice to pay.
+    pub invoice: String,
+    /// The invoice's payment hash.
+    pub hash: PaymentHash,
+    /// The invoice amount.
+    pub amount: Amount,
+    /// The most the participant pays the operator serving the swap.
+    pub max_fee: Amount,
+    /// Distinguishes the participant's swaps from the funding, each of which
+    /// can be created once.
+    pub nonce: u64,
+    /// After this time, the swap is refunded unless completed.
+    pub expiry: Timestamp,
+    /// The holdings paying for the swap. Swaps are paid in ckBTC only.
+    pub funding: Funding,
+    /// The operator to serve the swap. If none is given, the canister selects
+    /// one and fails over to further candidates.
+    pub operator: Option<Principal>,
+    /// The ledger account failed swaps are refunded to.
+    pub refund_to: Account,
+}
+
+#[derive(Clone, Deserialize, CandidType, PartialEq, Eq, Debug)]
+pub enum SwapStatus {
+    /// Waiting for the operator to claim the swap.
+    Assigned {
+        operator: Principal,
+        fee: Amount,
+        claim_deadline: Timestamp,
+    },
+    /// The operator is paying the invoice.
+    Claimed { operator: Principal, fee: Amount },
+    /// The invoice was paid and the operator was credited.
+    Completed {
+        operator: Principal,
+        preimage: Vec<u8>,
+    },
+    /// The swap failed and its locked funds await their refund.
+    RefundPending,
+    /// The refund transfer is in flight.
+    Refunding,
+    /// The swap failed and its locked funds were refunded.
+    Refunded { block_height: Nat },
+    /// The swap failed and the ledger rejected its refund, so its locked
+    /// funds were returned to the swap's funding.
+    Returned { error: Error },
+}
+
+#[derive(Clone, Deserialize, CandidType)]
+pub struct Swap {
+    pub request: SwapRequest,
+    /// Who created the swap.
+    pub creator: Principal,
+    /// The funds locked for the swap: the amount plus the fee of the first
+    /// assigned operator. Later candidates may not charge more.
+    pub locked: Amount,
+    /// The operators assigned so far, in order.
+    pub attempts: Vec<Principal>,
+    pub status: SwapStatus,
+    pub created_at: Timestamp,
+    /// The creation time of the refund transfer, once attempted.
+    pub refund_created_at: Option<Timestamp>,
+}
+
+#[update]
+#[candid_method(update)]
+/// Creates a swap. `sig` is the funding participant's signature over the
+/// request.
+fn create_swap(req: SwapRequest, sig: Vec<u8>) -> Result<SwapId> {
+    crate::unpaused!();
+    mutate_state(|s| s.create_swap(ic_cdk::api::msg_caller(), req, &sig, blocktime()))
+}
+
+#[update]
+#[candid_method(update)]
+/// Claims a swap assigned to the calling operator.
+fn claim_swap(request_id: BridgeRequestId, id: SwapId) -> Result<()> {
+    let caller = ic_cdk::api::msg_caller();
+    audit::logged("claim_swap", audit::args_hash((request_id, id)), || {
+        mutate_state(|s| {
+            s.once(caller, request_id, |s| {
+                s.claim_swap(caller, id, blocktime())
+            })
+        })
+    })
+}
+
+#[update]
+#[candid_method(update)]
+/// Completes a swap claimed by the calling operator by revealing the paid
+/// invoice's preimage.
+fn complete_swap(request_id: BridgeRequestId, id: SwapId, preimage: Vec<u8>) -> Result<()> {
+    crate::unpaused!();
+    let caller = ic_cdk::api::msg_caller();
+    let hash = audit::args_hash((request_id, id, &preimage));
+    audit::logged("complete_swap", hash, || {
+        mutate_state(|s| {
+            s.once(caller, request_id, |s| {
+                s.complete_swap(caller, id, preimage, blocktime())
+            })
+        })
+    })
+}
+
+#[query]
+#[candid_method(query)]
+fn query_swap(id: SwapId) -> Option<Swap> {
+    read_state(|s| s.swaps.get(&id).cloned())
+}
+
+#[query]
+#[candid_method(query)]
+/// Lists the swaps, ordered by swap id.
+fn list_swaps(cursor: Option<Cursor>, limit: u32) -> Result<Page<(SwapId, Swap)>> {
+    read_state(|s| {
+        paginate(
+            s.swaps.iter().map(|(id, swap)| (*id, (*id, swap.clone()))),
+            cursor,
+            limit,
+        )
+    })
+}
+
+#[update]
+#[candid_method(update)]
+/// Sets the caps on open swaps and invoice requests. Admin only.
+fn set_swap_limits(limits: SwapLimits) -> Result<()> {
+    audit::logged("set_swap_limits", audit::args_hash((limits,)), || {
+        crate::admin::require_admin()?;
+        mutate_state(|s| s.swap_limits = limits);
+        Ok(())
+    })
+}
+
+#[query]
+#[candid_method(query)]
+fn query_swap_limits() -> SwapLimits {
+    read_state(|s| s.swap_limits)
+}
+
+/// Reassigns or refunds swaps whose operators missed their deadlines, and
+/// pays out pending refunds, unless the canister is halted.
+pub fn check_swaps() {
+    if read_state(|s| s.require_running()).is_err() {
+        return;
+    }
+    let refunds = mutate_state(|state| {
+        state.check_swaps(blocktime());
+        state.take_refunds(blocktime())
+    });
+    for (id, to, locked, created_at) in refunds {
+        ic_cdk::futures::spawn(refund(id, to, locked, created_at));
+    }
+}
+
+/// Transfers a failed swap's locked funds, less the ledger fee, to its refund
+/// account. Transfers the ledger could not process, or whose outcome is
+/// unknown, are retried with the next check.
+async fn refund(id: SwapId, to: Account, locked: Amount, created_at: Timestamp) {
+    let ledger = config::current().ledger;
+    if read_state(|s| s.require_unpaused(&ledger, Flow::Withdrawal)).is_err() {
+        mutate_state(|s| s.finish_refund(id, Disposition::Unavailable));
+        return;
+    }
+    let fee = crate::ledger::fee(ledger).await;
+    let outcome = match locked > fee {
+        true => {
+            let mut arg = TransferArg {
+                from_subaccount: None,
+                to,
+                amount: locked - fee.clone(),
+                fee: Some(fee),
+                memo: None,
+                created_at_time: Some(created_at),
+            };
+            transfer::transfer(ledger, &mut arg).await
+        }
+        false => Disposition::Failed(
+            ErrorCode::InsufficientFunding {
+                required: fee + Amount::from(1u32),
+                available: locked,
+            }
+            .into(),
+        ),
+    };
+    mutate_state(|s| s.finish_refund(id, outcome));
+}
+
+impl Default for SwapLimits {
+    fn default() -> Self {
+        Self {
+            per_user: DEFAULT_MAX_OPEN_PER_USER,
+            per_operator: DEFAULT_MAX_OPEN_PER_OPERATOR,
+        }
+    }
+}
+
+impl SwapStatus {
+    /// The operator currently responsible for the swap, if any.
+    pub fn operator(&self) -> Option<Principal> {
+        match self {
+            Self::Assigned { operator, .. } | Self::Claimed { operator, .. } => Some(*operator),
+            _ => None,
+        }
+    }
+
+    /// Whether the swap still holds locked funds.
+    pub fn is_open(&self) -> bool {
+        !matches!(
+            self,
+            Self::Completed { .. } | Self::Refunded { .. } | Self::Returned { .. }
+        )
+    }
+}
+
+impl SwapRequest {
+    /// The bytes signed by the funding participant: the payment hash, the
+    /// amount and the maximum fee as length-prefixed LE bytes, the expiry and
+    /// the nonce (LE), the channel id, the participant's SEC1 key, the
+    /// funding's asset and the operator each as a presence byte followed by
+    /// the length-prefixed principal if present, the length-prefixed invoice
+    /// and the refund account's length-prefixed owner and subaccount.
+    pub fn signing_bytes(&self) -> Vec<u8> {
+        let mut data = b"ckLightning swap".to_vec();
+        data.extend_from_slice(&self.hash);
+        for amount in [&self.amount, &self.max_fee] {
+            let amount = amount.0.to_bytes_le();
+            data.extend_from_slice(&(amount.len() as u32).to_le_bytes());
+            data.extend_from_slice(&amount);
+        }
+        data.extend_from_slice(&self.expiry.to_le_bytes());
+        data.extend_from_slice(&self.nonce.to_le_bytes());
+        data.extend_from_slice(&self.funding.channel.0);
+        data.extend_from_slice(self.funding.participant.0.to_encoded_point(true).as_bytes());
+        for principal in [&self.funding.asset, &self.operator] {
+            match principal {
+                Some(p) => {
+                    data.push(1);
+                    data.extend_from_slice(&(p.as_slice().len() as u32).to_le_bytes());
+                    data.extend_from_slice(p.as_slice());
+                }
+                None => data.push(0),
+            }
+        }
+        data.extend_from_slice(&(self.invoice.len() as u32).to_le_bytes());
+        data.extend_from_slice(self.invoice.as_bytes());
+        let owner = self.refund_to.owner.as_slice();
+        data.extend_from_slice(&(owner.len() as u32).to_le_bytes());
+        data.extend_from_slice(owner);
+        data.extend_from_slice(&self.refund_to.effective_subaccount()[..]);
+        data
+    }
+}
+
+impl<Q: TXQuerier> CanisterState<Q> {
+    pub fn create_swap(
+        &mut self,
+        creator: Principal,
+        req: SwapRequest,
+        sig: &[u8],
+        now: Timestamp,
+    ) -> Result<SwapId> {
+        self.accepting()?;
+        validation::data(req.invoice.as_bytes())?;
+        // Operators are credited and refunds paid in ckBTC.
+        require!(req.funding.asset.is_none(), InvalidInput);
+        require!(req.amount > Amount::default(), InvalidInput);
+        require!(req.expiry > now + SWAP_CLAIM_WINDOW, Expired);
+        require!(
+            self.state(&req.funding.channel)
+                .is_some_and(|s| s.settled(now)),
+            NotFinalized
+        );
+        require!(
+            req.funding.participant.verify(&req.signing_bytes(), sig),
+            Authentication {
+                participant_index: None
+            }
+        );
+        require!(
+            !self.swap_nonces.contains(&req.funding, req.nonce),
+            NonceReused { nonce: req.nonce }
+        );
+        self.require_below_user_limit(creator)?;
+        // Operators charging more than the signed maximum are no candidates.
+        let candidates: Vec<_> = self
+            .operator_candidates(Direction::ToLightning, &req.amount, now, &[])
+            .into_iter()
+            .filter(|(_, ad)| ad.fee(&req.amount) <= req.max_fee)
+            .collect();
+        let (operator, ad) = match &req.operator {
+            Some(op) => candidates
+                .into_iter()
+                .find(|(o, _)| o == op)
+                .ok_or(ErrorCode::NotFound)?,
+            // Skip operators at their cap, unless all candidates are.
+            None => {
+                let first = candidates
+                    .first()
+                    .cloned()
+                    .ok_or(ErrorCode::InsufficientLiquidity)?;
+                candidates
+                    .into_iter()
+                    .find(|(o, _)| self.below_operator_limit(o))
+                    .unwrap_or(first)
+            }
+        };
+        self.require_below_operator_limit(operator)?;
+        let fee = ad.fee(&req.amount);
+        let locked = req.amount.clone() + fee.clone();
+        let held = self.query_holdings(req.funding.clone()).unwrap_or_default();
+        require!(
+            held >= locked,
+            InsufficientFunding {
+                required: locked.clone(),
+                available: held.clone(),
+            }
+        );
+        self.require_unreserved(&req.funding, &held, &locked, now)?;
+
+        self.debit(&req.funding, &locked, ChangeCause::Swap);
+        self.swap_nonces.insert(&req.funding, req.nonce);
+        let hash = req.hash;
+        let id = self.next_swap_id;
+        self.next_swap_id += 1;
+        self.swaps.insert(
+            id,
+            Swap {
+                request: req,
+                creator,
+                locked,
+                attempts: vec![operator],
+                status: SwapStatus::Assigned {
+                    operator,
+                    fee,
+                    claim_deadline: now + SWAP_CLAIM_WINDOW,
+                },
+                created_at: now,
+                refund_created_at: None,
+            },
+        );
+        self.payment_hashes.add_swap(hash, id);
+        Ok(id)
+    }
+
+    pub fn claim_swap(&mut self, caller: Principal, id: SwapId, now: Timestamp) -> Result<()> {
+        self.require_scope(&caller, Scope::ConsumeQueue)?;
+        let swap = self.swaps.get_mut(&id).ok_or(ErrorCode::NotFound)?;
+        let SwapStatus::Assigned {
+            operator,
+            fee,
+            claim_deadline,
+        } = swap.status.clone()
+        else {
+            return Err(ErrorCode::AlreadyConcluded.into());
+        };
+        require!(operator == caller, Unauthorized);
+        require!(now < claim_deadline, Expired);
+        swap.status = SwapStatus::Claimed { operator, fee };
+        Ok(())
+    }
+
+    /// Credits the operator with the amount and its fee, and returns the
+    /// remaining locked funds to the swap's holdings. Forwards and invoices
+    /// sharing the invoice's payment hash are released as well.
+    pub fn complete_swap(
+        &mut self,
+        caller: Principal,
+        id: SwapId,
+        preimage: Vec<u8>,
+        now: Timestamp,
+    ) -> Result<()> {
+        self.require_scope(&caller, Scope::SubmitProofs)?;
+        let swap = self.swaps.get(&id).ok_or(ErrorCode::NotFound)?;
+        let SwapStatus::Claimed { operator, .. } = swap.status.clone() else {
+            return Err(ErrorCode::AlreadyConcluded.into());
+        };
+        require!(operator == caller, Unauthorized);
+        require!(now < swap.request.expiry, Expired);
+        require!(
+            payment_hash(&preimage) == swap.request.hash,
+            Authentication {
+                participant_index: None
+            }
+        );
+        self.release(preimage, now);
+        Ok(())
+    }
+
+    /// Whether the swap was claimed and can still be completed at `now`.
+    pub(crate) fn swap_completable(&self, id: SwapId, now: Timestamp) -> bool {
+        self.swaps.get(&id).is_some_and(|s| {
+            matches!(s.status, SwapStatus::Claimed { .. }) && now < s.request.expiry
+        })
+    }
+
+    /// Credits the operator of a claimed swap.
+    pub(crate) fn pay_out_swap(&mut self, id: SwapId, preimage: Vec<u8>, now: Timestamp) {
+        let Some(swap) = self.swaps.get(&id) else {
+            return;
+        };
+        let SwapStatus::Claimed { operator, fee } = swap.status.clone() else {
+            return;
+        };
+        let earned = swap.request.amount.clone() + fee.clone();
+        let rest = swap.locked.clone() - earned.clone();
+        let (funding, amount) = (swap.request.funding.clone(), swap.request.amount.clone());
+        self.credit(funding, rest, ChangeCause::Swap);
+        *self.balances.entry(operator).or_default() += earned;
+        self.record_served(operator, Direction::ToLightning, amount, fee, now);
+        if let Some(swap) = self.swaps.get_mut(&id) {
+            swap.status = SwapStatus::Completed { operator, preimage };
+        }
+    }
+
+    /// Hands swaps whose operator missed the claim deadline to the next
+    /// candidate, if the swap was not bound to an operator and a candidate
+    /// serves it for at most the locked fee. Other overdue swaps are marked
+    /// for refunding.
+    pub fn check_swaps(&mut self, now: Timestamp) {
+        let overdue: Vec<SwapId> = self
+            .swaps
+            .iter()
+            .filter(|(_, s)| match &s.status {
+                SwapStatus::Assigned { claim_deadline, .. } => now >= *claim_deadline,
+                SwapStatus::Claimed { .. } => now >= s.request.expiry,
+                _ => false,
+            })
+            .map(|(id, _)| *id)
+            .collect();
+
+        for id in overdue {
+            if let SwapStatus::Assigned { operator, .. } | SwapStatus::Claimed { operator, .. } =
+                self.swaps[&id].status
+            {
+                self.record_missed(operator, now);
+            }
+            let swap = &self.swaps[&id];
+            let next = match (&swap.status, &swap.request.operator) {
+                (SwapStatus::Assigned { .. }, None)
+                    if now + SWAP_CLAIM_WINDOW < swap.request.expiry =>
+                {
+                    let max_fee = swap.locked.clone() - swap.request.amount.clone();
+                    self.operator_candidates(
+                        Direction::ToLightning,
+                        &swap.request.amount,
+                        now,
+                        &swap.attempts,
+                    )
+                    .into_iter()
+                    .filter(|(op, _)| self.below_operator_limit(op))
+                    .map(|(op, ad)| (op, ad.fee(&swap.request.amount)))
+                    .find(|(_, fee)| *fee <= max_fee)
+                }
+                _ => None,
+            };
+
+            let Some(swap) = self.swaps.get_mut(&id) else {
+                continue;
+            };
+            match next {
+                Some((operator, fee)) => {
+                    swap.attempts.push(operator);
+                    swap.status = SwapStatus::Assigned {
+                        operator,
+                        fee,
+                        claim_deadline: now + SWAP_CLAIM_WINDOW,
+                    };
+                }
+                None => {
+                    swap.status = SwapStatus::RefundPending;
+                    let hash = swap.request.hash;
+                    self.payment_hashes.remove_swap(&hash, id);
+                }
+            }
+        }
+    }
+
+    /// The open swaps and invoice requests created by `user`.
+    pub fn open_by_user(&self, user: Principal) -> u32 {
+        let swaps = self
+            .swaps
+            .values()
+            .filter(|s| s.creator == user && s.status.is_open());
+        let invoices = self
+            .invoices
+            .values()
+            .filter(|i| i.requester == user && i.is_open());
+        (swaps.count() + invoices.count()) as u32
+    }
+
+    /// The open swaps and invoice requests assigned to `operator`.
+    pub fn open_at_operator(&self, operator: Principal) -> u32 {
+        let swaps = self
+            .swaps
+            .values()
+            .filter(|s| s.status.operator() == Some(operator));
+        let invoices = self
+            .invoices
+            .values()
+            .filter(|i| i.operator == operator && i.is_open());
+        (swaps.count() + invoices.count()) as u32
+    }
+
+    pub fn below_operator_limit(&self, operator: &Principal) -> bool {
+        self.open_at_operator(*operator) < self.swap_limits.per_operator
+    }
+
+    pub fn require_below_user_limit(&self, user: Principal) -> Result<()> {
+        let limit = self.swap_limits.per_user;
+        require!(
+            self.open_by_user(user) < limit,
+            ErrorCode::TooManyOpenSwaps { limit }
+        );
+        Ok(())
+    }
+
+    pub fn require_below_operator_limit(&self, operator: Principal) -> Result<()> {
+        let limit = self.swap_limits.per_operator;
+        require!(
+            self.below_operator_limit(&operator),
+            ErrorCode::TooManyOpenSwaps { limit }
+        );
+        Ok(())
+    }
+
+    /// Marks all pending refunds as in flight and returns them along with
+    /// the creation time of their transfer, which is fixed on the first
+    /// attempt.
+    pub fn take_refunds(&mut self, now: Timestamp) -> Vec<(SwapId, Account, Amount, Timestamp)> {
+        self.swaps
+            .iter_mut()
+            .filter(|(_, s)| s.status == SwapStatus::RefundPending)
+            .map(|(id, s)| {
+                s.status = SwapStatus::Refunding;
+                let created_at = *s.refund_created_at.get_or_insert(now);
+                (*id, s.request.refund_to, s.locked.clone(), created_at)
+            })
+            .collect()
+    }
+
+    /// Records the outcome of a refund transfer. A transfer the ledger could
+    /// not process, or whose outcome is unknown, leaves the refund pending.
+    /// If the ledger rejected it, the locked funds are returned to the
+    /// swap's funding.
+    pub fn finish_refund(&mut self, id: SwapId, outcome: Disposition) {
+        let Some(swap) = self.swaps.get_mut(&id) else {
+            return;
+        };
+        match outcome {
+            Disposition::Completed(block_height) => {
+                swap.status = SwapStatus::Refunded { block_height };
+            }
+            Disposition::Unavailable | Disposition::Unknown(_) => {
+                swap.status = SwapStatus::RefundPending;
+            }
+            Disposition::Failed(error) => {
+                swap.status = SwapStatus::Returned { error };
+                let (funding, locked) = (swap.request.funding.clone(), swap.locked.clone());
+                self.credit(funding, locked, ChangeCause::Swap);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::receiver::MockTXQuerier;
+    use crate::testing::*;
+
+    fn setup() -> (CanisterState<MockTXQuerier>, SwapRequest, SwapId) {
+        let mut s = new_state();
+        let ch = concluded(&mut s, 1, 1, 2);
+        let req = SwapRequest {
+            invoice: "lnbc1".into(),
+            hash: payment_hash(b"secret"),
+            amount: Amount::from(50u32),
+            max_fee: Amount::from(1u32),
+            nonce: 0,
+            expiry: 10 * SWAP_CLAIM_WINDOW,
+            funding: Funding::new(ch, account(1)),
+            operator: None,
+            refund_to: Account {
+                owner: Principal::from_slice(&[99]),
+                subaccount: Some([1; 32]),
+            },
+        };
+        let sig = sign(1, &req.signing_bytes());
+        let op = operator(&mut s, 10, 10);
+        advertise(&mut s, op, Direction::ToLightning, 20_000);
+        let op = operator(&mut s, 11, 11);
+        advertise(&mut s, op, Direction::ToLightning, 20_000);
+        let op = operator(&mut s, 12, 12);
+        advertise(&mut s, op, Direction::ToLightning, 40_000);
+        s.reputation
+            .entry(Principal::from_slice(&[11]))
+            .or_default()
+            .missed = 1;
+        let id = s
+            .create_swap(Principal::anonymous(), req.clone(), &sig, 0)
+            .unwrap();
+        (s, req, id)
+    }
+
+    #[test]
+    fn test_swap_fails_over_and_completes() {
+        let (mut s, req, id) = setup();
+        let (first, second) = (Principal::from_slice(&[10]), Principal::from_slice(&[11]));
+        assert_eq!(s.swaps[&id].attempts, vec![first]);
+        assert_eq!(holdings(&s, &req.funding.channel, 1), Amount::from(49u32));
+
+        // The first operator misses its deadline, the second one at the same
+        // price takes over. The more expensive one is never considered.
+        s.check_swaps(SWAP_CLAIM_WINDOW);
+        assert_eq!(s.swaps[&id].attempts, vec![first, second]);
+        assert!(matches!(
+            s.claim_swap(first, id, SWAP_CLAIM_WINDOW),
+            Err(Error {
+                code: ErrorCode::Unauthorized,
+                ..
+            })
+        ));
+        s.claim_swap(second, id, SWAP_CLAIM_WINDOW).unwrap();
+        s.complete_swap(second, id, b"secret".to_vec(), SWAP_CLAIM_WINDOW)
+            .unwrap();
+        assert_eq!(s.balances[&second], Amount::from(51u32));
+        assert_eq!(s.reputation[&first].missed, 1);
+        assert_eq!(s.reputation[&second].completed, 1);
+    }
+
+    #[test]
+    fn test_open_swaps_are_capped() {
+        let (mut s, mut req, id) = setup();
+        let (first, creator) = (Principal::from_slice(&[10]), Principal::anonymous());
+        req.amount = Amount::from(10u32);
+        req.nonce = 1;
+        let sig = sign(1, &req.signing_bytes());
+        s.swap_limits = SwapLimits {
+            per_user: 1,
+            per_operator: 1,
+        };
+        assert_eq!(
+            s.create_swap(creator, req.clone(), &sig, 0),
+            Err(ErrorCode::TooManyOpenSwaps { limit: 1 }.into())
+        );
+
+        // Saturated operators are skipped, unless explicitly requested.
+        s.swap_limits.per_user = 2;
+        let next = s.create_swap(creator, req.clone(), &sig, 0).unwrap();
+        assert_ne!(s.swaps[&next].attempts, vec![first]);
+        req.operator = Some(first);
+        req.nonce = 2;
+        let sig = sign(1, &req.signing_bytes());
+        s.swap_limits.per_user = 3;
+        assert_eq!(
+            s.create_swap(creator, req.clone(), &sig, 0),
+            Err(ErrorCode::TooManyOpenSwaps { limit: 1 }.into())
+        );
+
+        // Finished swaps free their slots.
+        s.claim_swap(first, id, 0).unwrap();
+        s.complete_swap(first, id, b"secret".to_vec(), 0).unwrap();
+        assert!(s.create_swap(creator, req, &sig, 0).is_ok());
+    }
+
+    #[test]
+    fn test_swap_requests_are_used_once_within_their_fee() {
+        let (mut s, mut req, _) = setup();
+        let creator = Principal::from_slice(&[42]);
+        let sig = sign(1, &req.signing_bytes());
+
+        // A replayed request, by anyone, locks no further funds.
+        assert_eq!(
+            s.create_swap(creator, req.clone(), &sig, 0),
+            Err(ErrorCode::NonceReused { nonce: 0 }.into())
+        );
+
+        // Operators charging more than the signed maximum are not assigned.
+        req.nonce = 1;
+        req.max_fee = Amount::default();
+        let sig = sign(1, &req.signing_bytes());
+        assert_eq!(
+            s.create_swap(creator, req.clone(), &sig, 0),
+            Err(ErrorCode::InsufficientLiquidity.into())
+        );
+        assert_eq!(holdings(&s, &req.funding.channel, 1), Amount::from(49u32));
+    }
+
+    #[test]
+    fn test_swaps_of_other_assets_are_rejected() {
+        let (mut s, mut req, _) = setup();
+        let asset = Principal::from_slice(&[77]);
+        req.nonce = 1;
+        req.funding = req.funding.in_asset(Some(asset));
+        s.credit(
+            req.funding.clone(),
+            Amount::from(100u32),
+            ChangeCause::Deposit,
+        );
+        let sig = sign(1, &req.signing_bytes());
+        assert!(matches!(
+            s.create_swap(Principal::anonymous(), req.clone(), &sig, 0),
+            Err(Error {
+                code: ErrorCode::InvalidInput,
+                ..
+            })
+        ));
+        assert_eq!(s.query_holdings(req.funding), Some(Amount::from(100u32)));
+    }
+
+    #[test]
+    fn test_swap_refunded_without_candidates() {
+        let (mut s, req, id) = setup();
+        s.check_swaps(SWAP_CLAIM_WINDOW);
+        s.check_swaps(2 * SWAP_CLAIM_WINDOW);
+        assert_eq!(s.swaps[&id].status, SwapStatus::RefundPending);
+
+        // The refund goes to the registered account, not the creator, and is
+        // retried until its transfer succeeds.
+        let refunds = s.take_refunds(5);
+        assert_eq!(refunds.len(), 1);
+        assert_eq!(refunds[0].1, req.refund_to);
+        assert_eq!(refunds[0].2, Amount::from(51u32));
+        assert!(s.take_refunds(6).is_empty());
+        s.finish_refund(id, Disposition::Unknown(ErrorCode::NotFound.into()));
+        // Retries keep the creation time of the first attempt.
+        assert_eq!(s.take_refunds(7)[0].3, 5);
+        s.finish_refund(id, Disposition::Completed(Nat::from(7u32)));
+        assert!(matches!(s.swaps[&id].status, SwapStatus::Refunded { .. }));
+    }
+
+    #[test]
+    fn test_rejected_refund_returns_funds() {
+        let (mut s, req, id) = setup();
+        let held = s.query_holdings(req.funding.clone()).unwrap_or_default();
+        s.check_swaps(SWAP_CLAIM_WINDOW);
+        s.check_swaps(2 * SWAP_CLAIM_WINDOW);
+        s.take_refunds(0);
+        s.finish_refund(id, Disposition::Failed(ErrorCode::TransferTooOld.into()));
+        assert!(matches!(s.swaps[&id].status, SwapStatus::Returned { .. }));
+        assert_eq!(
+            s.query_holdings(req.funding),
+            Some(held + Amount::from(51u32))
+        );
+        assert!(s.take_refunds(1).is_empty());
+    }
+}
@@ -0,0 +1,130 @@
+//  Copyright 2025 PolyCrypt GmbH
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+
+//! Helpers shared by the unit tests.
+
+use crate::CanisterState;
+use crate::attestation::SoftwareSigner;
+use crate::operator::{Direction, LiquidityAd, OperatorInfo};
+use crate::permission::Scope;
+use crate::receiver::MockTXQuerier;
+use crate::types::*;
+use candid::Principal;
+use k256::ecdsa::{Signature, SigningKey, signature::Signer};
+use std::sync::Arc;
+use std::task::{Context, Poll, Waker};
+
+pub fn new_state() -> CanisterState<MockTXQuerier> {
+    CanisterState::new(
+        MockTXQuerier::default(),
+        Principal::anonymous(),
+        Arc::new(SoftwareSigner::new(key(0x7f))),
+    )
+}
+
+/// Runs a future that completes without waiting, such as the software
+/// signer's.
+pub fn block_on<F: Future>(fut: F) -> F::Output {
+    let mut cx = Context::from_waker(Waker::noop());
+    match std::pin::pin!(fut).poll(&mut cx) {
+        Poll::Ready(out) => out,
+        Poll::Pending => panic!("future did not complete"),
+    }
+}
+
+pub fn key(seed: u8) -> SigningKey {
+    SigningKey::from_slice(&[seed; 32]).unwrap()
+}
+
+pub fn account(seed: u8) -> L2Account {
+    L2Account(key(seed).verifying_key().into())
+}
+
+pub fn sign(seed: u8, msg: &[u8]) -> Vec<u8> {
+    let sig: Signature = key(seed).sign(msg);
+    sig.to_bytes().to_vec()
+}
+
+/// Registers a concluded channel between two participants holding 100 each.
+pub fn concluded(s: &mut CanisterState<MockTXQuerier>, nonce: u8, a: u8, b: u8) -> ChannelId {
+    let params = Params {
+        nonce: Nonce([nonce; 32]),
+        participants: vec![account(a), account(b)],
+        challenge_duration: 0,
+        expiry: None,
+    };
+    let channel = params.id();
+    for p in [a, b] {
+        s.deposit(
+            Funding::new(channel.clone(), account(p)),
+            Amount::from(100u32),
+        )
+        .unwrap();
+    }
+    let state = RegisteredState {
+        state: State {
+            channel: channel.clone(),
+            version: 1,
+            allocation: vec![Amount::from(100u32), Amount::from(100u32)],
+            finalized: true,
+            assets: vec![],
+        },
+        timeout: 0,
+    };
+    s.register_channel(&params, state, 0).unwrap();
+    channel
+}
+
+pub fn holdings(s: &CanisterState<MockTXQuerier>, ch: &ChannelId, p: u8) -> Amount {
+    s.query_holdings(Funding::new(ch.clone(), account(p)))
+        .unwrap_or_default()
+}
+
+/// Registers the operator `[n]` with hub `account(hub)` and grants it all
+/// scopes.
+pub fn operator(s: &mut CanisterState<MockTXQuerier>, n: u8, hub: u8) -> Principal {
+    let p = Principal::from_slice(&[n]);
+    let info = OperatorInfo {
+        hub: account(hub),
+        latency_secs: 10,
+    };
+    s.operators.insert(p, info);
+    s.scopes.insert(
+        p,
+        [
+            Scope::ConsumeQueue,
+            Scope::SubmitProofs,
+            Scope::ApproveWithdrawals,
+            Scope::ManageLiquidity,
+        ]
+        .into(),
+    );
+    p
+}
+
+/// Advertises liquidity up to 1000 that does not expire.
+pub fn advertise(
+    s: &mut CanisterState<MockTXQuerier>,
+    op: Principal,
+    direction: Direction,
+    fee_ppm: u32,
+) {
+    let ad = LiquidityAd {
+        direction,
+        max_amount: Amount::from(1000u32),
+        fee_ppm,
+        expiry: u64::MAX,
+    };
+    s.advertise_liquidity(op, ad, 0).unwrap();
+}
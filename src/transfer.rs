@@ -0,0 +1,526 @@
+//  Copyright 2025 PolyCrypt GmbH
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+
+//! Ledger transfers with error recovery, and the queue of outgoing
+//! withdrawals. A transfer whose fee the ledger rejects is retried once with
+//! a freshly fetched fee, and a transfer the ledger reports as a duplicate
+//! counts as done in the original block. The creation time is fixed when a
+//! withdrawal is queued, so that the ledger deduplicates all of its attempts.
+//!
+//! Withdrawals are authorized and deducted when they are requested, then
+//! queued and paid out by a timer. A withdrawal the ledger cannot process at
+//! the moment stays queued until it completes, fails or runs out of attempts.
+//! Failed withdrawals are reverted. A withdrawal whose transfer call failed
+//! may have been executed nonetheless, so it is retried until the ledger
+//! answers, however many attempts it takes. The funds a queued withdrawal is
+//! taken from stay claimed until it is done.
+
+use crate::asset::AssetId;
+use crate::error::*;
+use crate::guard::GuardKey;
+use crate::page::{Cursor, Page, paginate};
+use crate::permission::Scope;
+use crate::receiver::TXQuerier;
+use crate::types::*;
+use crate::{CanisterState, icrc1_transfer, ledger, mutate_state, read_state, require};
+use candid::{Principal, candid_method};
+use ic_cdk::api::time as blocktime;
+use ic_cdk::query;
+use icrc_ledger_types::icrc1::account::Account;
+use icrc_ledger_types::icrc1::transfer::{TransferArg, TransferError};
+use std::collections::BTreeMap;
+use std::time::Duration as StdDuration;
+
+/// How often the withdrawal queue is drained (one minute).
+pub const WITHDRAWAL_QUEUE_INTERVAL: StdDuration = StdDuration::from_secs(60);
+
+/// How often a withdrawal is attempted before it fails for good. The ledger
+/// deduplicates transfers for a day, well beyond the last attempt.
+pub const MAX_WITHDRAWAL_ATTEMPTS: u32 = 30;
+
+/// How often a transfer whose outcome is unknown is retried within a call.
+pub const MAX_UNKNOWN_RETRIES: u32 = 3;
+
+/// The outcome of a transfer after recovery.
+pub enum Disposition {
+    Completed(Nat),
+    /// The ledger is temporarily unavailable.
+    Unavailable,
+    Failed(Error),
+    /// The call failed, so the ledger may have executed the transfer or not.
+    /// Retrying the transfer with the same creation time is safe.
+    Unknown(Error),
+}
+
+#[derive(Clone, Deserialize, CandidType, PartialEq, Debug)]
+pub enum WithdrawalStatus {
+    /// Queued for its next transfer attempt.
+    Pending {
+        attempts: u32,
+    },
+    /// The transfer is in flight.
+    Submitted,
+    Confirmed {
+        block_height: Nat,
+    },
+    /// The transfer failed and the funds were returned.
+    Failed {
+        error: Error,
+    },
+}
+
+#[derive(Clone)]
+/// Where a withdrawal's funds were deducted from.
+pub enum Source {
+    /// The holdings of the request's participant, with the ledger fee
+    /// withheld from the payout.
+    Holdings,
+    /// The entire holdings of the request's participant, with the ledger fee
+    /// withheld from the payout.
+    Sweep { fee: Amount },
+    /// The pool, with the shares of the request's receiver burnt for the
+    /// withdrawal and the pool's fee withheld from the payout.
+    Pool { shares: Amount, fee: Amount },
+}
+
+#[derive(Clone)]
+/// An authorized withdrawal awaiting its transfer.
+pub struct QueuedWithdrawal {
+    pub req: WithdrawalReq,
+    pub source: Source,
+    /// Swept dust paid out on top of the requested amount.
+    pub dust: Amount,
+    pub ledger: Principal,
+    pub arg: TransferArg,
+    pub attempts: u32,
+}
+
+#[derive(Default)]
+pub struct WithdrawalQueue {
+    queued: BTreeMap<WithdrawalId, QueuedWithdrawal>,
+    statuses: BTreeMap<WithdrawalId, WithdrawalStatus>,
+}
+
+#[query]
+#[candid_method(query)]
+/// The status of a queued withdrawal, by `WithdrawalReq::id`.
+fn withdrawal_status(id: WithdrawalId) -> Option<WithdrawalStatus> {
+    read_state(|s| s.withdrawal_queue.statuses.get(&id).cloned())
+}
+
+#[query]
+#[candid_method(query)]
+/// Lists the statuses of queued withdrawals, ordered by withdrawal id.
+fn query_withdrawals(
+    cursor: Option<Cursor>,
+    limit: u32,
+) -> Result<Page<(WithdrawalId, WithdrawalStatus)>> {
+    read_state(|s| s.withdrawal_queue.page(cursor, limit))
+}
+
+impl WithdrawalQueue {
+    pub fn page(
+        &self,
+        cursor: Option<Cursor>,
+        limit: u32,
+    ) -> Result<Page<(WithdrawalId, WithdrawalStatus)>> {
+        paginate(
+            self.statuses
+                .iter()
+                .map(|(id, status)| (*id, (*id, status.clone()))),
+            cursor,
+            limit,
+        )
+    }
+}
+
+impl Disposition {
+    /// The block height of a completed transfer, or why it did not complete.
+    pub fn into_result(self) -> Result<Nat> {
+        match self {
+            Disposition::Completed(block_height) => Ok(block_height),
+            Disposition::Unavailable => Err(ErrorCode::LedgerUnavailable.into()),
+            Disposition::Failed(e) | Disposition::Unknown(e) => Err(e),
+        }
+    }
+
+    /// Whether the ledger did not execute the transfer, so that its funds
+    /// can be returned.
+    pub fn failed(&self) -> bool {
+        matches!(self, Disposition::Unavailable | Disposition::Failed(_))
+    }
+}
+
+impl QueuedWithdrawal {
+    /// The funds claimed until the withdrawal is done.
+    fn keys(&self) -> Vec<GuardKey> {
+        match &self.source {
+            Source::Holdings | Source::Sweep { .. } => {
+                vec![GuardKey::Funding(self.req.funding())]
+            }
+            Source::Pool { .. } => vec![],
+        }
+    }
+}
+
+/// Executes a transfer, recovering from a stale fee and from duplicates. The
+/// fee and creation time the transfer was last attempted with are left in
+/// `arg`, so that retrying it cannot transfer twice.
+pub async fn transfer(ledger: Principal, arg: &mut TransferArg) -> Disposition {
+    arg.created_at_time.get_or_insert(blocktime());
+    let mut refetched = false;
+    loop {
+        match icrc1_transfer(ledger, arg.clone()).await {
+            Ok(Ok(block_height)) => return Disposition::Completed(block_height),
+            Ok(Err(TransferError::Duplicate { duplicate_of })) => {
+                return Disposition::Completed(duplicate_of);
+            }
+            Ok(Err(TransferError::BadFee { .. })) if !refetched => {
+                refetched = true;
+                arg.fee = Some(ledger::refetch_fee(ledger).await);
+            }
+            Ok(Err(TransferError::TemporarilyUnavailable)) => return Disposition::Unavailable,
+            Ok(Err(e)) => return Disposition::Failed(e.into()),
+            Err(e) => {
+                ic_cdk::println!("CallResult error: {:?}", e);
+                return Disposition::Unknown(e.into());
+            }
+        }
+    }
+}
+
+/// Executes a transfer like `transfer`, retrying it while its outcome is
+/// unknown, up to `MAX_UNKNOWN_RETRIES` times. The retries keep the creation
+/// time, so that the ledger executes the transfer once at most.
+pub async fn transfer_retrying(ledger: Principal, arg: &mut TransferArg) -> Disposition {
+    let mut outcome = transfer(ledger, arg).await;
+    for _ in 0..MAX_UNKNOWN_RETRIES {
+        if !matches!(outcome, Disposition::Unknown(_)) {
+            break;
+        }
+        outcome = transfer(ledger, arg).await;
+    }
+    outcome
+}
+
+/// Drains the queue right after the current call, instead of waiting for
+/// the next interval.
+pub fn schedule_drain() {
+    ic_cdk_timers::set_timer(StdDuration::ZERO, drain_withdrawals);
+}
+
+/// Submits the transfers of all queued withdrawals, unless the canister is
+/// halted.
+pub fn drain_withdrawals() {
+    if read_state(|s| s.require_running()).is_err() {
+        return;
+    }
+    let queued = mutate_state(|s| s.take_queued_withdrawals());
+    for w in queued {
+        ic_cdk::futures::spawn(submit(w));
+    }
+}
+
+async fn submit(mut w: QueuedWithdrawal) {
+    let outcome = transfer(w.ledger, &mut w.arg).await;
+    mutate_state(|s| s.finish_withdrawal(w, outcome, blocktime()));
+}
+
+impl<Q: TXQuerier> CanisterState<Q> {
+    /// Authorizes a participant's withdrawal, which must exceed the ledger
+    /// fee, deducts it from the holdings and queues its transfer less the
+    /// fee, along with the participant's dust credit.
+    pub fn queue_withdrawal(
+        &mut self,
+        req: WithdrawalReq,
+        sig: &[u8],
+        fee: Nat,
+        now: Timestamp,
+    ) -> Result<WithdrawalId> {
+        require!(
+            req.amount > fee,
+            Error::from(ErrorCode::InsufficientFunding {
+                required: fee.clone() + Amount::from(1u32),
+                available: req.amount.clone(),
+            })
+            .with("fee", &fee)
+        );
+        let keys = [GuardKey::Funding(req.funding())];
+        self.guards.require_free(&keys)?;
+        self.authorize_withdrawal(&req, sig, now)?;
+        self.guards.claim(&keys);
+        // Dust is credited in ckBTC.
+        let dust = match req.asset {
+            None => self.dust.take_credit(&req.participant),
+            Some(_) => Amount::default(),
+        };
+        Ok(self.enqueue(req, Source::Holdings, dust, fee, now))
+    }
+
+    /// Authorizes the sweep of a participant's entire holdings, deducts them
+    /// and queues their transfer less the ledger fee.
+    pub fn queue_withdraw_all(
+        &mut self,
+        funding: &Funding,
+        receiver: &L1Account,
+        nonce: u64,
+        sig: &[u8],
+        fee: Nat,
+        now: Timestamp,
+    ) -> Result<WithdrawalId> {
+        let keys = [GuardKey::Funding(funding.clone())];
+        self.guards.require_free(&keys)?;
+        let req = self.authorize_withdraw_all(funding, receiver, nonce, sig, &fee, now)?;
+        self.guards.claim(&keys);
+        let source = Source::Sweep { fee: fee.clone() };
+        Ok(self.enqueue(req, source, Amount::default(), fee, now))
+    }
+
+    /// Deducts a pool withdrawal from the holdings it is taken from and
+    /// queues its transfer.
+    pub fn queue_pool_withdrawal(
+        &mut self,
+        caller: Principal,
+        req: WithdrawalReq,
+        fee: Nat,
+        now: Timestamp,
+    ) -> Result<WithdrawalId> {
+        self.require_scope(&caller, Scope::ApproveWithdrawals)?;
+        self.accepting()?;
+        let asset = self.asset_or_ckbtc(req.asset);
+        self.require_unpaused(&asset, crate::pause::Flow::Withdrawal)?;
+        let id = req.id();
+        let known = self.withdrawal_queue.statuses.get(&id);
+        require!(
+            known.is_none_or(|s| matches!(s, WithdrawalStatus::Failed { .. })),
+            AlreadyConcluded
+        );
+        let (shares, pool_fee) = self.withdraw_from_liq_pool(&req, now)?;
+        let source = Source::Pool {
+            shares,
+            fee: pool_fee,
+        };
+        Ok(self.enqueue(req, source, Amount::default(), fee, now))
+    }
+
+    fn enqueue(
+        &mut self,
+        req: WithdrawalReq,
+        source: Source,
+        dust: Amount,
+        fee: Nat,
+        now: Timestamp,
+    ) -> WithdrawalId {
+        let id = req.id();
+        let amount = match &source {
+            Source::Holdings => req.amount.clone() + dust.clone() - fee.clone(),
+            Source::Sweep { fee } | Source::Pool { fee, .. } => req.amount.clone() - fee.clone(),
+        };
+        let arg = TransferArg {
+            from_subaccount: None,
+            to: Account {
+                owner: req.receiver,
+                subaccount: None,
+            },
+            amount,
+            fee: Some(fee),
+            memo: None,
+            created_at_time: Some(now),
+        };
+        let ledger = self.asset_or_ckbtc(req.asset);
+        let queued = QueuedWithdrawal {
+            req,
+            source,
+            dust,
+            ledger,
+            arg,
+            attempts: 0,
+        };
+        let queue = &mut self.withdrawal_queue;
+        queue
+            .statuses
+            .insert(id, WithdrawalStatus::Pending { attempts: 0 });
+        queue.queued.insert(id, queued);
+        id
+    }
+
+    /// Marks the pending withdrawals as submitted and returns them.
+    pub fn take_queued_withdrawals(&mut self) -> Vec<QueuedWithdrawal> {
+        let queue = &mut self.withdrawal_queue;
+        let mut taken = vec![];
+        for (id, w) in &queue.queued {
+            let status = queue.statuses.get_mut(id);
+            if let Some(status @ WithdrawalStatus::Pending { .. }) = status {
+                *status = WithdrawalStatus::Submitted;
+                taken.push(w.clone());
+            }
+        }
+        taken
+    }
+
+    /// Records the outcome of a withdrawal's transfer. A withdrawal the
+    /// ledger could not process is queued again, unless it ran out of
+    /// attempts, and one whose outcome is unknown is queued again in any
+    /// case. Failed withdrawals are reverted.
+    pub fn finish_withdrawal(
+        &mut self,
+        mut w: QueuedWithdrawal,
+        outcome: Disposition,
+        now: Timestamp,
+    ) {
+        let id = w.req.id();
+        w.attempts += 1;
+        self.withdrawal_queue.queued.remove(&id);
+        let retry = match outcome {
+            Disposition::Unknown(_) => true,
+            Disposition::Unavailable => w.attempts < MAX_WITHDRAWAL_ATTEMPTS,
+            _ => false,
+        };
+        if retry {
+            let status = WithdrawalStatus::Pending {
+                attempts: w.attempts,
+            };
+            self.withdrawal_queue.statuses.insert(id, status);
+            self.withdrawal_queue.queued.insert(id, w);
+            return;
+        }
+        let status = match outcome {
+            Disposition::Completed(block_height) => {
+                if let Source::Holdings | Source::Sweep { .. } = w.source {
+                    self.record_withdrawal(&w.req, now);
+                }
+                WithdrawalStatus::Confirmed { block_height }
+            }
+            outcome => {
+                let error = match outcome {
+                    Disposition::Failed(e) => e,
+                    _ => ErrorCode::LedgerUnavailable.into(),
+                };
+                let participant = w.req.participant.clone();
+                self.dust.add_credit(participant, w.dust.clone());
+                match &w.source {
+                    Source::Holdings | Source::Sweep { .. } => self.revert_withdrawal(&w.req),
+                    Source::Pool { shares, fee } => {
+                        self.revert_pool_withdrawal(&w.req, shares.clone(), fee, now)
+                    }
+                }
+                WithdrawalStatus::Failed { error }
+            }
+        };
+        self.guards.release(&w.keys());
+        self.withdrawal_queue.statuses.insert(id, status);
+    }
+
+    /// The amounts of the withdrawals that are deducted but not paid out yet.
+    pub fn queued_withdrawals(&self) -> impl Iterator<Item = &Amount> {
+        self.withdrawal_queue.queued.values().map(|w| &w.arg.amount)
+    }
+
+    /// The amounts of queued withdrawals paid out by a ledger.
+    pub fn queued_withdrawals_of(&self, ledger: AssetId) -> impl Iterator<Item = &Amount> {
+        self.withdrawal_queue
+            .queued
+            .values()
+            .filter(move |w| w.ledger == ledger)
+            .map(|w| &w.arg.amount)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+
+    #[test]
+    fn test_unavailable_withdrawal_is_queued_then_reverted() {
+        let mut s = new_state();
+        let ch = concluded(&mut s, 31, 1, 2);
+        let req = WithdrawalReq {
+            channel: ch.clone(),
+            participant: account(1),
+            amount: Amount::from(40u32),
+            receiver: Principal::anonymous(),
+            nonce: 1,
+            expiry: None,
+            asset: None,
+        };
+        let sig = sign(1, &req.signing_bytes());
+        let id = s
+            .queue_withdrawal(req.clone(), &sig, 10u32.into(), 0)
+            .unwrap();
+        assert_eq!(id, req.id());
+        let paid: Vec<_> = s.queued_withdrawals().cloned().collect();
+        assert_eq!(paid, vec![Amount::from(30u32)]);
+        let status = |s: &CanisterState<_>| s.withdrawal_queue.statuses[&id].clone();
+        assert_eq!(status(&s), WithdrawalStatus::Pending { attempts: 0 });
+        let page = s.withdrawal_queue.page(None, 10).unwrap();
+        assert_eq!(page.items, vec![(id, status(&s))]);
+        assert_eq!(page.next, Some(id.to_vec()));
+        assert_eq!(holdings(&s, &ch, 1), Amount::from(60u32));
+        assert_eq!(
+            s.queue_withdrawal(req, &sig, 10u32.into(), 0),
+            Err(ErrorCode::Busy.into())
+        );
+
+        let mut queued = s.take_queued_withdrawals();
+        assert_eq!(status(&s), WithdrawalStatus::Submitted);
+        assert!(s.take_queued_withdrawals().is_empty());
+        let mut w = queued.pop().unwrap();
+        w.attempts = MAX_WITHDRAWAL_ATTEMPTS - 2;
+        s.finish_withdrawal(w, Disposition::Unavailable, 1);
+        let attempts = MAX_WITHDRAWAL_ATTEMPTS - 1;
+        assert_eq!(status(&s), WithdrawalStatus::Pending { attempts });
+
+        // A transfer with an unknown outcome is retried beyond the attempts.
+        let w = s.take_queued_withdrawals().pop().unwrap();
+        let unknown = Disposition::Unknown(ErrorCode::NotFound.into());
+        s.finish_withdrawal(w, unknown, 2);
+        let attempts = MAX_WITHDRAWAL_ATTEMPTS;
+        assert_eq!(status(&s), WithdrawalStatus::Pending { attempts });
+        assert_eq!(holdings(&s, &ch, 1), Amount::from(60u32));
+
+        let w = s.take_queued_withdrawals().pop().unwrap();
+        s.finish_withdrawal(w, Disposition::Unavailable, 2);
+        let error = ErrorCode::LedgerUnavailable.into();
+        assert_eq!(status(&s), WithdrawalStatus::Failed { error });
+        assert_eq!(holdings(&s, &ch, 1), Amount::from(100u32));
+        assert!(!s.guards.is_claimed(&GuardKey::Channel(ch)));
+    }
+
+    #[test]
+    fn test_withdraw_all_is_queued_less_fee() {
+        let mut s = new_state();
+        let ch = concluded(&mut s, 32, 1, 2);
+        let funding = Funding::new(ch.clone(), account(2));
+        let receiver = L1Account(Principal::from_slice(&[7]));
+        let sig = sign(2, &funding.withdraw_all_bytes(&receiver, 5));
+        let id = s
+            .queue_withdraw_all(&funding, &receiver, 5, &sig, 10u32.into(), 0)
+            .unwrap();
+        assert_eq!(holdings(&s, &ch, 2), Amount::default());
+
+        let w = s.take_queued_withdrawals().pop().unwrap();
+        assert_eq!(w.arg.amount, Amount::from(90u32));
+        assert_eq!(w.arg.to.owner, receiver.0);
+        let error: Error = ErrorCode::LedgerUnavailable.into();
+        s.finish_withdrawal(w, Disposition::Failed(error.clone()), 1);
+        assert_eq!(
+            s.withdrawal_queue.statuses[&id],
+            WithdrawalStatus::Failed { error }
+        );
+        assert_eq!(holdings(&s, &ch, 2), Amount::from(100u32));
+        s.queue_withdraw_all(&funding, &receiver, 5, &sig, 10u32.into(), 2)
+            .unwrap();
+    }
+}
@@ -16,9 +16,11 @@ use digest::{FixedOutputDirty, Update};
 use ed25519_dalek::Sha512 as Hasher;
 use k256::EncodedPoint;
 use k256::PublicKey as SecpPublicKey;
+use k256::ecdsa::signature::Verifier;
+use k256::ecdsa::{Signature, VerifyingKey};
 use k256::elliptic_curve::sec1::ToEncodedPoint;
 
-#[derive(PartialEq, Debug, Clone, Eq)]
+#[derive(PartialEq, Debug, Clone, Eq, PartialOrd, Ord)]
 pub struct L2Account(pub SecpPublicKey);
 
 use candid::{CandidType, Principal};
@@ -40,7 +42,7 @@ use serde_bytes::ByteBuf;
 /// A hash as used by the signature scheme.
 pub struct Hash(pub digest::Output<Hasher>);
 
-#[derive(PartialEq, Clone, Deserialize, Eq, CandidType, Hash)]
+#[derive(PartialEq, Clone, Deserialize, Eq, PartialOrd, Ord, CandidType, Hash)]
 /// Identifies the funds belonging to a certain layer 2 identity within a
 /// certain channel.
 pub struct Funding {
@@ -49,6 +51,8 @@ pub struct Funding {
     /// The funds' owner's layer-2 identity within the channel.
     pub participant: L2Account,
     // pub receiver: L1Account,
+    /// The ledger of the funds' asset, or none for ckBTC.
+    pub asset: Option<Principal>,
 }
 
 #[derive(PartialEq, Clone, Deserialize, Eq, CandidType, Hash)]
@@ -60,6 +64,18 @@ pub struct NotifyArgs {
     pub funding: Funding,
 }
 
+#[derive(Clone, Deserialize, CandidType, PartialEq, Eq, Debug)]
+/// A notified transfer credited to a funding.
+pub struct CreditedDeposit {
+    /// The amount of the transfer.
+    pub amount: Amount,
+    /// The memo of the funding the transfer carried.
+    pub memo: u64,
+    /// The funding's holdings plus its notified funds not yet moved into them
+    /// by `deposit`, this transfer included.
+    pub total: Amount,
+}
+
 #[derive(PartialEq, Clone, Deserialize, Eq, CandidType, Hash)]
 pub struct PoolFunding {
     /// The funds' owner's layer-2 identity within the channel.
@@ -70,20 +86,23 @@ pub struct PoolFunding {
 
 /// An amount of a currency.
 pub type Amount = Nat;
+/// A SHA-256 payment hash, as used by Lightning invoices.
+pub type PaymentHash = [u8; 32];
+/// Identifies a withdrawal request: the SHA-256 digest of its signing bytes.
+pub type WithdrawalId = [u8; 32];
 /// Duration in nanoseconds (same as ICP timestamps).
 pub type Duration = u64;
 /// Timestamp in nanoseconds (same as ICP timestamps).
 pub type Timestamp = u64;
 /// Unique channel identifier.
-#[derive(PartialEq, Eq, Ord, PartialOrd, Hash)]
+#[derive(PartialEq, Eq, Ord, PartialOrd, Hash, Default)]
 pub struct ChannelId(pub [u8; 32]);
 
 #[derive(Hash, PartialEq, Eq, Ord, PartialOrd, Clone, Deserialize, CandidType)]
 pub struct L1Account(pub Principal);
 
 /// A channel's unique nonce.
-#[derive(PartialEq, Eq, Ord, PartialOrd)]
-
+#[derive(PartialEq, Eq, Ord, PartialOrd, Default)]
 pub struct Nonce(pub [u8; 32]);
 
 /// Channel state version identifier.
@@ -98,6 +117,9 @@ pub struct Params {
     pub participants: Vec<L2Account>,
     /// When a dispute occurs, how long to wait for responses.
     pub challenge_duration: Duration,
+    /// After this time, any participant can force the channel's conclusion,
+    /// so that abandoned channels do not lock funds indefinitely.
+    pub expiry: Option<Timestamp>,
 }
 
 #[derive(Deserialize, CandidType, Default, Clone)]
@@ -110,6 +132,9 @@ pub struct State {
     /// The channel's asset allocation. Contains each participant's current
     /// balance in the order of the channel parameters' participant list.
     pub allocation: Vec<Amount>,
+    /// The allocations of the channel's further assets, such as stablecoins,
+    /// with `allocation` holding its ckBTC.
+    pub assets: Vec<AssetAllocation>,
     /// Whether the channel is finalized, i.e., no more updates can be made and
     /// funds can be withdrawn immediately. A non-finalized channel has to be
     /// finalized via the canister after the channel's challenge duration
@@ -119,6 +144,16 @@ pub struct State {
     // shows the phase the channel is in
 }
 
+#[derive(Clone, Deserialize, CandidType, PartialEq, Eq, Debug)]
+/// A channel's allocation of an asset other than ckBTC.
+pub struct AssetAllocation {
+    /// The asset's ledger.
+    pub asset: Principal,
+    /// Each participant's balance of the asset, in the order of the channel
+    /// parameters' participant list.
+    pub allocation: Vec<Amount>,
+}
+
 #[derive(Clone, Deserialize, CandidType)]
 /// A registered channel's state, as seen by the canister. Represents a channel
 /// after a call to "conclude" or "dispute" on the canister. The timeout, in
@@ -133,9 +168,80 @@ pub struct RegisteredState {
     pub timeout: Timestamp,
 }
 
+#[derive(Clone, Deserialize, CandidType, PartialEq, Eq)]
+/// A canister method to be called by this canister to notify about channel
+/// progress. The method receives the affected channel's id.
+pub struct Callback {
+    /// The canister to call.
+    pub canister: Principal,
+    /// The method to call on the canister.
+    pub method: String,
+}
+
+#[derive(Clone, Deserialize, CandidType)]
+/// Announces a channel that is about to be funded, along with what each
+/// participant is expected to deposit.
+pub struct FundingIntent {
+    /// The channel's parameters.
+    pub params: Params,
+    /// The amount each participant owes, in the order of the channel
+    /// parameters' participant list.
+    pub allocation: Vec<Amount>,
+    /// Notified once all participants have deposited their owed funds.
+    pub callback: Option<Callback>,
+}
+
+#[derive(Clone, Deserialize, CandidType)]
+/// The canister's view of a channel's funding phase.
+pub struct ChannelFunding {
+    /// The funding intent as registered.
+    pub intent: FundingIntent,
+    /// When the funding intent was registered.
+    pub created_at: Timestamp,
+    /// When the last owed deposit arrived, if the channel is fully funded.
+    pub funded_at: Option<Timestamp>,
+    /// The version of the state the channel was last topped up with, or 0.
+    pub top_up_version: Version,
+}
+
+#[derive(Clone, Deserialize, CandidType)]
+/// An announced channel whose funding is still incomplete.
+pub struct UnderfundedChannel {
+    /// The channel's unique identifier.
+    pub channel: ChannelId,
+    /// When the channel's funding intent was registered.
+    pub created_at: Timestamp,
+    /// The participants that still owe funds, and how much.
+    pub missing: Vec<(L2Account, Amount)>,
+}
+
+#[derive(Clone, Deserialize, CandidType)]
+/// A registered state together with the time at which the canister accepted
+/// it. The canister keeps a bounded history of these per channel.
+pub struct StateRecord {
+    /// The registered state.
+    pub state: RegisteredState,
+    /// When the state was registered.
+    pub registered_at: Timestamp,
+}
+
+#[derive(Clone, Deserialize, CandidType)]
+/// A historic registered state, signed by the canister's threshold ECDSA key.
+/// Allows off-chain parties to prove what the canister registered and when.
+pub struct BalanceProof {
+    /// The attested state record.
+    pub record: StateRecord,
+    /// The attesting canister.
+    pub canister: Principal,
+    /// The 32-byte hash that was signed, see `StateRecord::attestation_hash`.
+    pub message_hash: Vec<u8>,
+    /// The canister's secp256k1 signature over the message hash.
+    pub signature: Vec<u8>,
+}
+
 #[derive(Deserialize, CandidType, Clone)]
-// / Contains the payload of a request to withdraw a participant's funds from a
-// / registered channel. Does not contain the authorization signature.
+/// Contains the payload of a request to withdraw a participant's funds from a
+/// registered channel. Does not contain the authorization signature.
 pub struct WithdrawalReq {
     /// The funds to be withdrawn.
     pub channel: ChannelId,
@@ -143,6 +249,13 @@ pub struct WithdrawalReq {
     pub amount: Nat,
     /// The layer-1 identity to send the funds to.
     pub receiver: Principal,
+    /// Distinguishes the participant's withdrawals from the funding, each of
+    /// which can be paid out once.
+    pub nonce: u64,
+    /// When the request stops being valid, if ever.
+    pub expiry: Option<Timestamp>,
+    /// The ledger of the withdrawn asset, or none for ckBTC.
+    pub asset: Option<Principal>,
 }
 
 impl<'de> Deserialize<'de> for ChannelId {
@@ -186,7 +299,7 @@ impl CandidType for Hash {
     where
         S: Serializer,
     {
-        serializer.serialize_blob(&*self.0)
+        serializer.serialize_blob(&self.0)
     }
 }
 
@@ -206,7 +319,7 @@ impl std::hash::Hash for L2Account {
 }
 impl std::hash::Hash for Hash {
     fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
-        self.0.as_slice().hash(state);
+        self.0[..].hash(state);
     }
 }
 
@@ -220,6 +333,17 @@ impl Hash {
     }
 }
 
+impl L2Account {
+    /// Verifies a 64-byte `r || s` secp256k1 ECDSA signature over the SHA-256
+    /// digest of a message.
+    pub fn verify(&self, msg: &[u8], sig: &[u8]) -> bool {
+        let Ok(sig) = Signature::from_slice(sig) else {
+            return false;
+        };
+        VerifyingKey::from(&self.0).verify(msg, &sig).is_ok()
+    }
+}
+
 impl<'de> Deserialize<'de> for L2Account {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
     where
@@ -273,37 +397,15 @@ impl CandidType for Nonce {
     }
 }
 
-impl Default for ChannelId {
-    fn default() -> Self {
-        ChannelId([0; 32])
-    }
-}
-
-impl Default for L2Account {
-    fn default() -> Self {
-        // 33-byte compressed public key of all zeros
-        let zero_pk_bytes = [0u8; 33];
-        let zero_pk = SecpPublicKey::from_sec1_bytes(&zero_pk_bytes)
-            .expect("Hardcoded valid zero public key");
-        L2Account(zero_pk)
-    }
-}
-
-impl Default for Nonce {
-    fn default() -> Self {
-        Nonce([0; 32])
-    }
-}
-
 impl Clone for ChannelId {
     fn clone(&self) -> Self {
-        ChannelId(self.0.clone())
+        ChannelId(self.0)
     }
 }
 
 impl Clone for Nonce {
     fn clone(&self) -> Self {
-        Nonce(self.0.clone())
+        Nonce(self.0)
     }
 }
 
@@ -320,22 +422,42 @@ impl State {
     pub fn may_be_underfunded(&self) -> bool {
         self.version == 0 && !self.finalized
     }
-}
 
-impl Params {
-    pub fn id(&self) -> ChannelId {
-        let mut params_bytes = Vec::new();
-        params_bytes.extend_from_slice(&self.nonce.0);
+    /// The allocations of all the channel's assets, ckBTC first as none.
+    pub fn allocations(&self) -> impl Iterator<Item = (Option<Principal>, &Vec<Amount>)> {
+        std::iter::once((None, &self.allocation))
+            .chain(self.assets.iter().map(|a| (Some(a.asset), &a.allocation)))
+    }
 
-        for participant in &self.participants {
-            // Serialize using to_encoded_point and get bytes
-            params_bytes.extend_from_slice(participant.0.to_encoded_point(false).as_bytes());
-        }
+    /// The encoding participants sign to agree on the state, see
+    /// `encode_state`.
+    pub fn signing_bytes(&self) -> Vec<u8> {
+        encode_state(self)
+    }
+
+    /// The SHA-256 digest of `encode_state`, which participants' signatures
+    /// are made over.
+    pub fn state_hash(&self) -> [u8; 32] {
+        use k256::sha2::{Digest, Sha256};
+        Sha256::digest(encode_state(self)).into()
+    }
+}
 
-        let challenge_duration_bytes = self.challenge_duration.to_le_bytes();
-        params_bytes.extend_from_slice(&challenge_duration_bytes);
+// Params
 
-        let hash = Hash::digest(&params_bytes);
+impl Params {
+    /// The message a participant signs to force the channel's conclusion
+    /// after its expiry.
+    pub fn force_conclusion_bytes(&self) -> Vec<u8> {
+        let mut data = b"ckLightning force conclusion".to_vec();
+        data.extend_from_slice(&self.id().0);
+        data
+    }
+
+    /// The channel id: the SHA-512 digest of `encode_params`, truncated to
+    /// 32 bytes.
+    pub fn id(&self) -> ChannelId {
+        let hash = Hash::digest(&encode_params(self));
         let mut arr = [0u8; 32];
         arr.copy_from_slice(&hash.0[..32]); // Take only first 32 bytes
         ChannelId(arr)
@@ -350,6 +472,104 @@ impl RegisteredState {
     }
 }
 
+// StateRecord
+
+impl StateRecord {
+    /// Hashes the record as attested by the given canister. The encoding is the
+    /// canister principal, the channel id, the version (LE), each allocation
+    /// entry as length-prefixed LE bytes, the finalized flag, the timeout (LE),
+    /// the registration time (LE) and the further assets' allocations as in
+    /// `State::signing_bytes`, truncated to 32 bytes.
+    pub fn attestation_hash(&self, canister: &Principal) -> [u8; 32] {
+        let state = &self.state.state;
+        let mut data = Vec::new();
+        data.extend_from_slice(canister.as_slice());
+        data.extend_from_slice(&state.channel.0);
+        data.extend_from_slice(&state.version.to_le_bytes());
+        encode_allocation(&mut data, &state.allocation);
+        data.push(state.finalized as u8);
+        data.extend_from_slice(&self.state.timeout.to_le_bytes());
+        data.extend_from_slice(&self.registered_at.to_le_bytes());
+        encode_assets(&mut data, &state.assets);
+        let h = Hash::digest(&data);
+        let mut arr = [0u8; 32];
+        arr.copy_from_slice(&h.0[..32]);
+        arr
+    }
+}
+
+// FundingIntent
+
+impl FundingIntent {
+    /// The message a participant signs to register the intent: the channel
+    /// id, each owed amount as length-prefixed LE bytes, and the callback as
+    /// a presence byte followed by the length-prefixed canister and method if
+    /// present.
+    pub fn signing_bytes(&self) -> Vec<u8> {
+        let mut data = b"ckLightning funding intent".to_vec();
+        data.extend_from_slice(&self.params.id().0);
+        data.extend_from_slice(&(self.allocation.len() as u32).to_le_bytes());
+        for amount in &self.allocation {
+            let amount = amount.0.to_bytes_le();
+            data.extend_from_slice(&(amount.len() as u32).to_le_bytes());
+            data.extend_from_slice(&amount);
+        }
+        match &self.callback {
+            Some(cb) => {
+                data.push(1);
+                let canister = cb.canister.as_slice();
+                data.extend_from_slice(&(canister.len() as u32).to_le_bytes());
+                data.extend_from_slice(canister);
+                data.extend_from_slice(&(cb.method.len() as u32).to_le_bytes());
+                data.extend_from_slice(cb.method.as_bytes());
+            }
+            None => data.push(0),
+        }
+        data
+    }
+}
+
+// WithdrawalReq
+
+impl WithdrawalReq {
+    /// The message the participant signs to authorize the withdrawal: the
+    /// channel id, the participant's SEC1 key, the amount as length-prefixed
+    /// LE bytes, the receiver, the nonce (LE), the expiry as a presence
+    /// byte followed by the timestamp (LE) if present and the asset's ledger,
+    /// unless ckBTC.
+    pub fn signing_bytes(&self) -> Vec<u8> {
+        let mut data = b"ckLightning withdrawal".to_vec();
+        data.extend_from_slice(&self.channel.0);
+        data.extend_from_slice(self.participant.0.to_encoded_point(true).as_bytes());
+        let amount = self.amount.0.to_bytes_le();
+        data.extend_from_slice(&(amount.len() as u32).to_le_bytes());
+        data.extend_from_slice(&amount);
+        data.extend_from_slice(self.receiver.as_slice());
+        data.extend_from_slice(&self.nonce.to_le_bytes());
+        match self.expiry {
+            Some(expiry) => {
+                data.push(1);
+                data.extend_from_slice(&expiry.to_le_bytes());
+            }
+            None => data.push(0),
+        }
+        if let Some(asset) = self.asset {
+            data.extend_from_slice(asset.as_slice());
+        }
+        data
+    }
+
+    /// The funds the withdrawal is taken from.
+    pub fn funding(&self) -> Funding {
+        Funding::new(self.channel.clone(), self.participant.clone()).in_asset(self.asset)
+    }
+
+    pub fn id(&self) -> WithdrawalId {
+        use k256::sha2::{Digest, Sha256};
+        Sha256::digest(self.signing_bytes()).into()
+    }
+}
+
 // Funding
 
 impl Funding {
@@ -357,21 +577,180 @@ impl Funding {
         Self {
             channel,
             participant,
+            asset: None,
         }
     }
 
+    /// The same funds in another asset, or in ckBTC if `asset` is none.
+    pub fn in_asset(self, asset: Option<Principal>) -> Self {
+        Self { asset, ..self }
+    }
+
     pub fn memo(&self) -> u64 {
         let mut data = Vec::new();
         data.extend_from_slice(&self.channel.0);
         data.extend_from_slice(self.participant.0.to_encoded_point(false).as_bytes());
+        if let Some(asset) = self.asset {
+            data.extend_from_slice(asset.as_slice());
+        }
         let h = Hash::digest(&data);
         let arr: [u8; 8] = [
             h.0[0], h.0[1], h.0[2], h.0[3], h.0[4], h.0[5], h.0[6], h.0[7],
         ];
         u64::from_le_bytes(arr)
     }
+
+    /// The message the participant signs to sweep the funding's holdings to
+    /// a receiver: the channel id, the participant's SEC1 key, the receiver,
+    /// the nonce (LE) and the asset's ledger, unless ckBTC.
+    pub fn withdraw_all_bytes(&self, receiver: &L1Account, nonce: u64) -> Vec<u8> {
+        let mut data = b"ckLightning withdraw all".to_vec();
+        data.extend_from_slice(&self.channel.0);
+        data.extend_from_slice(self.participant.0.to_encoded_point(true).as_bytes());
+        data.extend_from_slice(receiver.0.as_slice());
+        data.extend_from_slice(&nonce.to_le_bytes());
+        if let Some(asset) = self.asset {
+            data.extend_from_slice(asset.as_slice());
+        }
+        data
+    }
+}
+
+/// Encodes a state as off-chain signers must reproduce it: the channel id,
+/// the version (LE), each allocation entry as length-prefixed LE bytes and the
+/// finalized flag, followed by each further asset's ledger and allocation,
+/// both length-prefixed.
+pub fn encode_state(state: &State) -> Vec<u8> {
+    let mut data = Vec::new();
+    data.extend_from_slice(&state.channel.0);
+    data.extend_from_slice(&state.version.to_le_bytes());
+    encode_allocation(&mut data, &state.allocation);
+    data.push(state.finalized as u8);
+    encode_assets(&mut data, &state.assets);
+    data
+}
+
+/// Encodes channel parameters as hashed into the channel id: the nonce, each
+/// participant's uncompressed SEC1 key, the challenge duration (LE) and the
+/// expiry (LE), if any.
+pub fn encode_params(params: &Params) -> Vec<u8> {
+    let mut data = Vec::new();
+    data.extend_from_slice(&params.nonce.0);
+    for participant in &params.participants {
+        data.extend_from_slice(participant.0.to_encoded_point(false).as_bytes());
+    }
+    data.extend_from_slice(&params.challenge_duration.to_le_bytes());
+    if let Some(expiry) = params.expiry {
+        data.extend_from_slice(&expiry.to_le_bytes());
+    }
+    data
+}
+
+/// Appends each allocation entry as length-prefixed LE bytes.
+fn encode_allocation(data: &mut Vec<u8>, allocation: &[Amount]) {
+    for amount in allocation {
+        let bytes = amount.0.to_bytes_le();
+        data.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+        data.extend_from_slice(&bytes);
+    }
+}
+
+/// Appends each further asset's ledger, length-prefixed, and its allocation,
+/// prefixed by its number of entries (LE).
+fn encode_assets(data: &mut Vec<u8>, assets: &[AssetAllocation]) {
+    for a in assets {
+        data.push(a.asset.as_slice().len() as u8);
+        data.extend_from_slice(a.asset.as_slice());
+        data.extend_from_slice(&(a.allocation.len() as u32).to_le_bytes());
+        encode_allocation(data, &a.allocation);
+    }
+}
+
+/// Computes the SHA-256 digest of a payment preimage.
+pub fn payment_hash(preimage: &[u8]) -> PaymentHash {
+    use k256::sha2::{Digest, Sha256};
+    Sha256::digest(preimage).into()
 }
 
 pub fn to_nanoseconds(seconds: u64) -> u64 {
     seconds * 1_000_000_000
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+
+    // The vectors below were computed with this implementation. They pin the
+    // encodings against accidental changes and are not taken from go-perun.
+    fn sample_state() -> State {
+        State {
+            channel: ChannelId([1; 32]),
+            version: 2,
+            allocation: vec![Amount::from(5u32), Amount::from(300u32)],
+            assets: vec![AssetAllocation {
+                asset: Principal::from_slice(&[9]),
+                allocation: vec![Amount::from(7u32)],
+            }],
+            finalized: true,
+        }
+    }
+
+    #[test]
+    fn test_encode_state_vector() {
+        let expected = [
+            "01".repeat(32).as_str(),
+            "0200000000000000",
+            "0100000005",
+            "020000002c01",
+            "01",
+            "0109",
+            "01000000",
+            "0100000007",
+        ]
+        .concat();
+        let state = sample_state();
+        assert_eq!(hex::encode(encode_state(&state)), expected);
+        assert_eq!(state.signing_bytes(), encode_state(&state));
+        assert_eq!(
+            hex::encode(state.state_hash()),
+            "c5e4f26455e0dd36ffea4fda8b742c802fa0a7d0c648bcb33e67a308eee87684"
+        );
+    }
+
+    #[test]
+    fn test_encode_params_vector() {
+        let params = Params {
+            nonce: Nonce([2; 32]),
+            participants: vec![account(1)],
+            challenge_duration: 3,
+            expiry: Some(4),
+        };
+        let expected = [
+            "02".repeat(32).as_str(),
+            "041b84c5567b126440995d3ed5aaba0565d71e1834604819ff9c17f5e9d5dd078f",
+            "70beaf8f588b541507fed6a642c5ab42dfdf8120a7f639de5122d47a69a8e8d1",
+            "0300000000000000",
+            "0400000000000000",
+        ]
+        .concat();
+        assert_eq!(hex::encode(encode_params(&params)), expected);
+        assert_eq!(
+            hex::encode(params.id().0),
+            "f1262d43234ddb45a84600fad59c27710cddf4bceea4688ee1a93b7c8a1b8423"
+        );
+    }
+
+    #[test]
+    fn test_signature_over_state_hash_verifies() {
+        use k256::ecdsa::signature::hazmat::PrehashSigner;
+        let state = sample_state();
+        let sig: Signature = key(1).sign_prehash(&state.state_hash()).unwrap();
+        let sig = sig.to_bytes();
+        assert!(account(1).verify(&state.signing_bytes(), &sig));
+        assert!(!account(2).verify(&state.signing_bytes(), &sig));
+        let mut newer = state.clone();
+        newer.version += 1;
+        assert!(!account(1).verify(&newer.signing_bytes(), &sig));
+    }
+}
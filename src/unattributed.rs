@@ -0,0 +1,247 @@
+//  Copyright 2025 PolyCrypt GmbH
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+
+//! Deposits the ledger scan could not attribute to a funding, because they
+//! carry neither a registered memo nor the memo of a known funding and did
+//! not go into a registered deposit subaccount. They are tracked by block, so
+//! that admins can list them and sweep them to an account of their choice,
+//! e.g. to return them to their sender. A deposit credited later, e.g. when
+//! its funding is notified of it, is no longer tracked. Transfers into a
+//! subaccount are swept from it, so each subaccount is swept with a transfer
+//! of its own, which must cover the ledger fee.
+
+use crate::admin;
+use crate::audit;
+use crate::error::*;
+use crate::ledger;
+use crate::page::{Cursor, Page, paginate};
+use crate::processed;
+use crate::receiver::{IcrcTransfer, TXQuerier};
+use crate::transfer;
+use crate::types::*;
+use crate::{CanisterState, mutate_state, read_state};
+use candid::candid_method;
+use ic_cdk::{query, update};
+use icrc_ledger_types::icrc1::account::{Account, Subaccount};
+use icrc_ledger_types::icrc1::transfer::TransferArg;
+use std::collections::BTreeMap;
+
+#[derive(Clone, Deserialize, CandidType, PartialEq, Eq, Debug)]
+/// A deposit to the canister that no funding claims.
+pub struct Unattributed {
+    pub block: u64,
+    /// The account the deposit came from, none for mints.
+    pub from: Option<Account>,
+    /// The canister's account the deposit went into.
+    pub to: Account,
+    pub amount: Amount,
+    pub memo: Option<Vec<u8>>,
+}
+
+impl From<IcrcTransfer> for Unattributed {
+    fn from(t: IcrcTransfer) -> Self {
+        Self {
+            block: t.block,
+            from: t.from,
+            to: t.to,
+            amount: Amount::from(t.amount),
+            memo: t.memo,
+        }
+    }
+}
+
+#[query]
+#[candid_method(query)]
+/// Lists the deposits the ledger scan could not attribute, oldest first.
+/// Admin only.
+fn list_unattributed(cursor: Option<Cursor>, limit: u32) -> Result<Page<Unattributed>> {
+    admin::require_admin()?;
+    read_state(|s| s.unattributed_page(cursor, limit))
+}
+
+#[update]
+#[candid_method(update)]
+/// Transfers the unattributed deposits to `to`, less a ledger fee per swept
+/// subaccount, and returns the swept amount. Deposits of subaccounts holding
+/// no more than the fee, or whose transfer fails, stay listed. Admin only.
+async fn sweep_unattributed(to: Account) -> Result<Amount> {
+    let caller = ic_cdk::api::msg_caller();
+    let hash = audit::args_hash((to,));
+    let outcome = match admin::require_admin() {
+        Ok(()) => sweep(to).await,
+        Err(e) => Err(e),
+    };
+    audit::record("sweep_unattributed", caller, hash, &outcome);
+    outcome
+}
+
+async fn sweep(to: Account) -> Result<Amount> {
+    crate::unpaused!();
+    let ledger = read_state(|s| s.ckbtc());
+    let fee = ledger::fee(ledger).await;
+    let taken = mutate_state(|s| s.take_unattributed(&fee));
+    let mut swept = Amount::default();
+    let mut failure = None;
+    for (subaccount, deposits) in taken {
+        let amount = deposits
+            .iter()
+            .fold(Amount::default(), |acc, d| acc + d.amount.clone());
+        let mut arg = TransferArg {
+            from_subaccount: subaccount,
+            to,
+            amount: amount.clone() - fee.clone(),
+            fee: Some(fee.clone()),
+            memo: None,
+            created_at_time: None,
+        };
+        let outcome = transfer::transfer_retrying(ledger, &mut arg).await;
+        let failed = outcome.failed();
+        match outcome.into_result() {
+            Ok(_) => swept += amount - fee.clone(),
+            Err(e) => {
+                ic_cdk::println!("sweeping unattributed deposits failed: {:?}", e);
+                if failed {
+                    mutate_state(|s| s.restore_unattributed(deposits));
+                }
+                failure.get_or_insert(e);
+            }
+        }
+    }
+    match failure {
+        Some(e) if swept == Amount::default() => Err(e),
+        _ => Ok(swept),
+    }
+}
+
+impl<Q: TXQuerier> CanisterState<Q> {
+    /// Tracks a scanned deposit that no funding claims.
+    pub fn track_unattributed(&mut self, t: IcrcTransfer) {
+        self.unattributed.insert(t.block, t.into());
+    }
+
+    pub fn unattributed_page(
+        &self,
+        cursor: Option<Cursor>,
+        limit: u32,
+    ) -> Result<Page<Unattributed>> {
+        paginate(
+            self.unattributed.iter().map(|(b, d)| (*b, d.clone())),
+            cursor,
+            limit,
+        )
+    }
+
+    /// Takes the unattributed deposits of each subaccount whose deposits
+    /// exceed the fee, grouped by subaccount, and marks their blocks as
+    /// processed, so that they cannot be credited while being swept.
+    /// Deposits whose blocks were credited meanwhile are dropped.
+    pub fn take_unattributed(
+        &mut self,
+        fee: &Amount,
+    ) -> Vec<(Option<Subaccount>, Vec<Unattributed>)> {
+        let ckbtc = self.ckbtc();
+        self.unattributed
+            .retain(|block, _| !processed::is_processed(ckbtc, *block));
+        let mut by_subaccount: BTreeMap<Option<Subaccount>, (Amount, Vec<u64>)> = BTreeMap::new();
+        for d in self.unattributed.values() {
+            let subaccount = d.to.subaccount.filter(|sub| *sub != [0; 32]);
+            let (total, blocks) = by_subaccount.entry(subaccount).or_default();
+            *total += d.amount.clone();
+            blocks.push(d.block);
+        }
+        by_subaccount
+            .into_iter()
+            .filter(|(_, (total, _))| total > fee)
+            .map(|(subaccount, (_, blocks))| {
+                let deposits = blocks
+                    .iter()
+                    .filter(|b| processed::mark(ckbtc, **b).is_ok())
+                    .filter_map(|b| self.unattributed.remove(b))
+                    .collect();
+                (subaccount, deposits)
+            })
+            .collect()
+    }
+
+    /// Lists deposits again whose sweep failed, so that they can also be
+    /// credited again.
+    pub fn restore_unattributed(&mut self, deposits: Vec<Unattributed>) {
+        let ckbtc = self.ckbtc();
+        for d in deposits {
+            processed::unmark(ckbtc, d.block);
+            self.unattributed.insert(d.block, d);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+    use candid::Principal;
+
+    #[test]
+    fn test_unattributed_deposits_are_taken_per_subaccount() {
+        let mut s = new_state();
+        let me = Principal::anonymous();
+        let deposit = |block, subaccount, amount| IcrcTransfer {
+            block,
+            from: None,
+            to: Account {
+                owner: me,
+                subaccount,
+            },
+            amount,
+            memo: None,
+        };
+        s.track_unattributed(deposit(1, None, 10));
+        s.track_unattributed(deposit(2, Some([0; 32]), 10));
+        s.track_unattributed(deposit(3, Some([1; 32]), 5));
+        s.track_unattributed(deposit(4, Some([2; 32]), 30));
+        s.track_unattributed(deposit(5, Some([2; 32]), 30));
+        processed::mark(s.ckbtc(), 5).unwrap();
+
+        let taken = s.take_unattributed(&Amount::from(10u32));
+        let blocks: Vec<_> = taken
+            .iter()
+            .map(|(sub, ds)| (*sub, ds.iter().map(|d| d.block).collect::<Vec<_>>()))
+            .collect();
+        assert_eq!(blocks, vec![(None, vec![1, 2]), (Some([2; 32]), vec![4])]);
+        assert_eq!(s.unattributed.keys().copied().collect::<Vec<_>>(), vec![3]);
+        assert!(processed::is_processed(s.ckbtc(), 4));
+        assert!(!processed::is_processed(s.ckbtc(), 3));
+
+        let page = s.unattributed_page(None, 1).unwrap();
+        assert_eq!(page.items.len(), 1);
+        assert!(!page.has_more);
+
+        let (_, deposits) = taken.into_iter().next().unwrap();
+        s.restore_unattributed(deposits);
+        assert_eq!(s.unattributed.len(), 3);
+        assert!(!processed::is_processed(s.ckbtc(), 1));
+        let page = s.unattributed_page(None, 2).unwrap();
+        let blocks: Vec<_> = page.items.iter().map(|d| d.block).collect();
+        assert_eq!(blocks, vec![1, 2]);
+        assert!(page.has_more);
+        let page = s.unattributed_page(page.next, 2).unwrap();
+        assert_eq!(
+            page.items.iter().map(|d| d.block).collect::<Vec<_>>(),
+            vec![3]
+        );
+
+        // Crediting a deposit claims it.
+        s.mark_processed(&s.ckbtc(), 3).unwrap();
+        assert!(!s.unattributed.contains_key(&3));
+    }
+}
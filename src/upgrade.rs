@@ -0,0 +1,302 @@
+//  Copyright 2025 PolyCrypt GmbH
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+
+//! Preparing upgrades. In drain mode, the canister accepts no new
+//! fund-moving requests while in-flight jobs and ledger calls complete. The
+//! pre-upgrade check tells whether the canister can safely be upgraded;
+//! upgrade automation should only proceed on a go verdict. Upgrades are
+//! refused outright while withdrawals are queued, as the upgrade would lose
+//! their deducted funds. Drain mode is lifted by the upgrade.
+
+use crate::audit;
+use crate::bridge::CommandStatus;
+use crate::config;
+use crate::error::*;
+use crate::invoice::InvoiceStatus;
+use crate::receiver::TXQuerier;
+use crate::swap::SwapStatus;
+use crate::types::*;
+use crate::{CanisterState, LedgerCall, icrc1_balance_of, mutate_state, read_state, require};
+use candid::candid_method;
+use ic_cdk::{query, update};
+use icrc_ledger_types::icrc1::account::Account;
+
+/// Largest tolerated difference between the canister's ledger balance and
+/// the funds it owes (satoshi).
+pub const MAX_RECONCILIATION_DELTA: u64 = 10_000;
+
+#[derive(Clone, Deserialize, CandidType, PartialEq, Eq, Debug)]
+/// Something that makes an upgrade unsafe right now.
+pub enum UpgradeBlocker {
+    /// Ledger calls are awaiting their response.
+    CallsInFlight { count: u32 },
+    /// The ledger balance deviates from the owed funds by more than
+    /// `MAX_RECONCILIATION_DELTA`.
+    ReconciliationDelta {
+        ledger_balance: Amount,
+        liabilities: Amount,
+    },
+    /// The ledger balance could not be queried.
+    LedgerUnavailable,
+    /// Bridge commands are still open.
+    OpenBridgeCommands { count: u64 },
+    /// Refunds of failed swaps are not paid out yet.
+    PendingRefunds { count: u64 },
+    /// Queued withdrawals are not paid out yet.
+    QueuedWithdrawals { count: u64 },
+}
+
+#[derive(Clone, Deserialize, CandidType)]
+pub struct UpgradeVerdict {
+    /// Whether the upgrade may proceed.
+    pub go: bool,
+    /// Why the upgrade may not proceed, if it may not.
+    pub blockers: Vec<UpgradeBlocker>,
+}
+
+#[derive(Clone, Deserialize, CandidType)]
+pub struct DrainStatus {
+    pub draining: bool,
+    /// Ledger calls awaiting their response.
+    pub calls_in_flight: u32,
+    /// Whether the canister is draining and all jobs completed.
+    pub quiescent: bool,
+}
+
+#[update]
+#[candid_method(update)]
+/// Stops accepting new fund-moving requests until the next upgrade.
+/// Admin only.
+fn drain() -> Result<()> {
+    audit::logged("drain", audit::args_hash(()), || {
+        crate::admin::require_admin()?;
+        mutate_state(|s| s.draining = true);
+        Ok(())
+    })
+}
+
+#[update]
+#[candid_method(update)]
+/// Leaves drain mode without upgrading. Admin only.
+fn resume() -> Result<()> {
+    audit::logged("resume", audit::args_hash(()), || {
+        crate::admin::require_admin()?;
+        mutate_state(|s| s.draining = false);
+        Ok(())
+    })
+}
+
+#[query]
+#[candid_method(query)]
+fn drain_status() -> DrainStatus {
+    read_state(|s| s.drain_status(LedgerCall::in_flight()))
+}
+
+#[update]
+#[candid_method(update)]
+/// Validates the invariants an upgrade relies on. Controller only.
+async fn pre_upgrade_check() -> Result<UpgradeVerdict> {
+    crate::require_controller()?;
+    let ledger = config::current().ledger;
+    let account = Account {
+        owner: ic_cdk::api::canister_self(),
+        subaccount: None,
+    };
+    let balance = icrc1_balance_of(ledger, account).await.ok();
+    let blockers = read_state(|s| s.upgrade_blockers(LedgerCall::in_flight(), balance));
+    Ok(UpgradeVerdict {
+        go: blockers.is_empty(),
+        blockers,
+    })
+}
+
+impl<Q: TXQuerier> CanisterState<Q> {
+    /// Fails while the canister is draining.
+    pub fn accepting(&self) -> Result<()> {
+        require!(!self.draining, Draining);
+        Ok(())
+    }
+
+    /// Fails with `Busy` while withdrawals are queued. Their funds are
+    /// deducted from the holdings in stable memory, but the queue lives on
+    /// the heap, which an upgrade discards.
+    pub fn require_upgradable(&self) -> Result<()> {
+        require!(self.queued_withdrawals().next().is_none(), Busy);
+        Ok(())
+    }
+
+    pub fn drain_status(&self, calls_in_flight: u32) -> DrainStatus {
+        let jobs = self
+            .bridge
+            .count(|s| matches!(s, CommandStatus::Pending | CommandStatus::Acked))
+            + self.pending_refunds()
+            + self.queued_withdrawals().count() as u64;
+        DrainStatus {
+            draining: self.draining,
+            calls_in_flight,
+            quiescent: self.draining && calls_in_flight == 0 && jobs == 0,
+        }
+    }
+
+    fn pending_refunds(&self) -> u64 {
+        self.swaps
+            .values()
+            .filter(|s| matches!(s.status, SwapStatus::RefundPending | SwapStatus::Refunding))
+            .count() as u64
+    }
+
+    /// Sums all funds the canister owes: holdings, balances, pool deposits,
+    /// quarantined funds, funds locked in swaps and invoices, queued
+    /// withdrawals and swept dust.
+    pub fn liabilities(&self) -> Amount {
+        let locked_swaps = self.swaps.values().filter_map(|s| match s.status {
+            SwapStatus::Completed { .. }
+            | SwapStatus::Refunded { .. }
+            | SwapStatus::Returned { .. } => None,
+            _ => Some(&s.locked),
+        });
+        let locked_invoices = self.invoices.values().filter_map(|i| match i.status {
+            InvoiceStatus::Issued { .. } => Some(i.credit()),
+            _ => None,
+        });
+        let other = self
+            .balances
+            .values()
+            .chain(self.liq_pools.get(&self.ckbtc()).map(|p| p.value()))
+            .chain(locked_swaps)
+            .chain(self.queued_withdrawals_of(self.ckbtc()))
+            .cloned();
+        self.user_holdings
+            .iter()
+            .map(|(_, amount)| amount)
+            .chain(other)
+            .chain(locked_invoices)
+            .fold(self.quarantine.total() + self.dust.total(), |acc, a| {
+                acc + a
+            })
+    }
+
+    /// Lists what currently makes an upgrade unsafe, given the number of
+    /// outstanding ledger calls and the canister's ledger balance, if known.
+    pub fn upgrade_blockers(
+        &self,
+        calls_in_flight: u32,
+        ledger_balance: Option<Amount>,
+    ) -> Vec<UpgradeBlocker> {
+        let mut blockers = vec![];
+        if calls_in_flight > 0 {
+            blockers.push(UpgradeBlocker::CallsInFlight {
+                count: calls_in_flight,
+            });
+        }
+        match ledger_balance {
+            Some(ledger_balance) => {
+                let liabilities = self.liabilities();
+                let delta = match ledger_balance > liabilities {
+                    true => ledger_balance.clone() - liabilities.clone(),
+                    false => liabilities.clone() - ledger_balance.clone(),
+                };
+                if delta > MAX_RECONCILIATION_DELTA {
+                    blockers.push(UpgradeBlocker::ReconciliationDelta {
+                        ledger_balance,
+                        liabilities,
+                    });
+                }
+            }
+            None => blockers.push(UpgradeBlocker::LedgerUnavailable),
+        }
+        let open = self
+            .bridge
+            .count(|s| matches!(s, CommandStatus::Pending | CommandStatus::Acked));
+        if open > 0 {
+            blockers.push(UpgradeBlocker::OpenBridgeCommands { count: open });
+        }
+        let refunds = self.pending_refunds();
+        if refunds > 0 {
+            blockers.push(UpgradeBlocker::PendingRefunds { count: refunds });
+        }
+        let withdrawals = self.queued_withdrawals().count() as u64;
+        if withdrawals > 0 {
+            blockers.push(UpgradeBlocker::QueuedWithdrawals { count: withdrawals });
+        }
+        blockers
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+
+    #[test]
+    fn test_blockers() {
+        let mut s = new_state();
+        concluded(&mut s, 1, 1, 2);
+        assert_eq!(s.liabilities(), Amount::from(200u32));
+        assert!(s.upgrade_blockers(0, Some(Amount::from(200u32))).is_empty());
+
+        let off = Amount::from(200 + MAX_RECONCILIATION_DELTA + 1);
+        let blockers = s.upgrade_blockers(2, Some(off));
+        assert_eq!(blockers.len(), 2);
+        assert_eq!(blockers[0], UpgradeBlocker::CallsInFlight { count: 2 });
+        assert!(matches!(
+            blockers[1],
+            UpgradeBlocker::ReconciliationDelta { .. }
+        ));
+        assert_eq!(
+            s.upgrade_blockers(0, None),
+            vec![UpgradeBlocker::LedgerUnavailable]
+        );
+    }
+
+    #[test]
+    fn test_upgrade_refused_while_withdrawals_queued() {
+        let mut s = new_state();
+        let ch = concluded(&mut s, 1, 1, 2);
+        s.require_upgradable().unwrap();
+        let req = WithdrawalReq {
+            channel: ch,
+            participant: account(1),
+            amount: Amount::from(40u32),
+            receiver: candid::Principal::anonymous(),
+            nonce: 1,
+            expiry: None,
+            asset: None,
+        };
+        let sig = sign(1, &req.signing_bytes());
+        s.queue_withdrawal(req, &sig, 10u32.into(), 0).unwrap();
+        assert_eq!(s.require_upgradable(), Err(ErrorCode::Busy.into()));
+        assert_eq!(
+            s.upgrade_blockers(0, Some(s.liabilities())),
+            vec![UpgradeBlocker::QueuedWithdrawals { count: 1 }]
+        );
+    }
+
+    #[test]
+    fn test_drain_blocks_new_requests() {
+        let mut s = new_state();
+        s.draining = true;
+        assert_eq!(s.accepting(), Err(ErrorCode::Draining.into()));
+        assert!(s.drain_status(0).quiescent);
+        assert!(!s.drain_status(1).quiescent);
+
+        let op = operator(&mut s, 10, 10);
+        advertise(&mut s, op, crate::operator::Direction::FromLightning, 0);
+        assert_eq!(
+            s.request_invoice(op, Amount::from(1u32), "".into(), 0)
+                .err(),
+            Some(ErrorCode::Draining.into())
+        );
+    }
+}
@@ -0,0 +1,236 @@
+//  Copyright 2025 PolyCrypt GmbH
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+
+//! Staging area for data exceeding the ingress message limit, such as
+//! signature bundles, SPV proofs and app data. A caller begins an upload,
+//! appends chunks in order and commits the upload under the SHA-256 hash of
+//! its content. Other calls then reference the committed blob by its hash.
+
+use crate::error::*;
+use crate::load::Priority;
+use crate::receiver::TXQuerier;
+use crate::types::*;
+use crate::{CanisterState, mutate_state, read_state, require};
+use candid::{Principal, candid_method};
+use ic_cdk::api::time as blocktime;
+use ic_cdk::{query, update};
+use k256::sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+
+/// Identifies an upload in progress.
+pub type UploadId = u64;
+
+/// The SHA-256 hash identifying a committed blob.
+pub type BlobHash = [u8; 32];
+
+/// Largest accepted blob (8 MiB).
+pub const MAX_BLOB_LEN: u64 = 8 << 20;
+
+/// Most bytes staged or committed at once, across all callers (256 MiB).
+pub const MAX_STORED_BYTES: u64 = 256 << 20;
+
+/// How long an upload may stay uncommitted (one hour).
+pub const UPLOAD_TTL: Duration = 3_600_000_000_000;
+
+/// An upload in progress.
+struct Upload {
+    owner: Principal,
+    len: u64,
+    data: Vec<u8>,
+    started_at: Timestamp,
+}
+
+#[derive(Default)]
+pub struct Uploads {
+    staged: BTreeMap<UploadId, Upload>,
+    blobs: BTreeMap<BlobHash, Vec<u8>>,
+    next_id: UploadId,
+}
+
+#[update]
+#[candid_method(update)]
+/// Starts an upload of `len` bytes.
+fn begin_upload(len: u64) -> Result<UploadId> {
+    crate::load::admit(Priority::Low)?;
+    let caller = ic_cdk::api::msg_caller();
+    mutate_state(|s| s.uploads.begin(caller, len, blocktime()))
+}
+
+#[update]
+#[candid_method(update)]
+/// Appends a chunk at `offset`, which must be where the previous chunk ended.
+/// Repeating an already appended chunk has no effect.
+fn put_chunk(id: UploadId, offset: u64, chunk: Vec<u8>) -> Result<()> {
+    crate::load::admit(Priority::Low)?;
+    let caller = ic_cdk::api::msg_caller();
+    mutate_state(|s| s.uploads.put_chunk(caller, id, offset, &chunk))
+}
+
+#[update]
+#[candid_method(update)]
+/// Completes an upload. Fails unless all bytes were uploaded and their
+/// SHA-256 hash matches.
+fn commit_upload(id: UploadId, hash: BlobHash) -> Result<()> {
+    crate::load::admit(Priority::Low)?;
+    let caller = ic_cdk::api::msg_caller();
+    mutate_state(|s| s.uploads.commit(caller, id, hash))
+}
+
+#[query]
+#[candid_method(query)]
+/// Returns the length of a committed blob, if it exists.
+fn query_blob(hash: BlobHash) -> Option<u64> {
+    read_state(|s| s.uploads.blobs.get(&hash).map(|b| b.len() as u64))
+}
+
+impl Uploads {
+    pub fn begin(&mut self, owner: Principal, len: u64, now: Timestamp) -> Result<UploadId> {
+        self.expire(now);
+        require!(len > 0 && len <= MAX_BLOB_LEN, InvalidInput);
+        require!(self.stored_bytes() + len <= MAX_STORED_BYTES, InvalidInput);
+        let id = self.next_id;
+        self.next_id += 1;
+        self.staged.insert(
+            id,
+            Upload {
+                owner,
+                len,
+                data: Vec::with_capacity(len as usize),
+                started_at: now,
+            },
+        );
+        Ok(id)
+    }
+
+    pub fn put_chunk(
+        &mut self,
+        caller: Principal,
+        id: UploadId,
+        offset: u64,
+        chunk: &[u8],
+    ) -> Result<()> {
+        let upload = self.staged.get_mut(&id).ok_or(ErrorCode::NotFound)?;
+        require!(upload.owner == caller, Unauthorized);
+        let end = offset.saturating_add(chunk.len() as u64);
+        let uploaded = upload.data.len() as u64;
+        if end <= uploaded {
+            let range = offset as usize..end as usize;
+            require!(upload.data[range] == *chunk, InvalidInput);
+            return Ok(());
+        }
+        require!(offset == uploaded && end <= upload.len, InvalidInput);
+        upload.data.extend_from_slice(chunk);
+        Ok(())
+    }
+
+    pub fn commit(&mut self, caller: Principal, id: UploadId, hash: BlobHash) -> Result<()> {
+        let upload = self.staged.get(&id).ok_or(ErrorCode::NotFound)?;
+        require!(upload.owner == caller, Unauthorized);
+        require!(upload.data.len() as u64 == upload.len, InvalidInput);
+        require!(
+            blob_hash(&upload.data) == hash,
+            Authentication {
+                participant_index: None
+            }
+        );
+        let Some(upload) = self.staged.remove(&id) else {
+            return Err(ErrorCode::NotFound.into());
+        };
+        self.blobs.insert(hash, upload.data);
+        Ok(())
+    }
+
+    /// Returns a committed blob.
+    pub fn blob(&self, hash: &BlobHash) -> Result<&[u8]> {
+        self.blobs
+            .get(hash)
+            .map(Vec::as_slice)
+            .ok_or_else(|| Error::from(ErrorCode::NotFound).with("blob", hex::encode(hash)))
+    }
+
+    /// Removes a committed blob once the call referencing it consumed it.
+    pub fn take_blob(&mut self, hash: &BlobHash) -> Result<Vec<u8>> {
+        self.blobs
+            .remove(hash)
+            .ok_or_else(|| Error::from(ErrorCode::NotFound).with("blob", hex::encode(hash)))
+    }
+
+    /// Drops uploads that were not committed in time.
+    fn expire(&mut self, now: Timestamp) {
+        self.staged.retain(|_, u| now < u.started_at + UPLOAD_TTL);
+    }
+
+    /// The bytes reserved by staged uploads and held by committed blobs.
+    fn stored_bytes(&self) -> u64 {
+        let staged: u64 = self.staged.values().map(|u| u.len).sum();
+        let blobs: u64 = self.blobs.values().map(|b| b.len() as u64).sum();
+        staged + blobs
+    }
+}
+
+impl<Q: TXQuerier> CanisterState<Q> {
+    /// Returns a committed blob referenced by hash in a call.
+    pub fn blob(&self, hash: &BlobHash) -> Result<&[u8]> {
+        self.uploads.blob(hash)
+    }
+}
+
+pub fn blob_hash(data: &[u8]) -> BlobHash {
+    Sha256::digest(data).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_chunked_upload() {
+        let mut u = Uploads::default();
+        let (alice, bob) = (Principal::from_slice(&[1]), Principal::from_slice(&[2]));
+        let data = b"signature bundle".to_vec();
+        let id = u.begin(alice, data.len() as u64, 0).unwrap();
+
+        u.put_chunk(alice, id, 0, &data[..9]).unwrap();
+        u.put_chunk(alice, id, 0, &data[..9]).unwrap();
+        assert_eq!(
+            u.put_chunk(bob, id, 9, &data[9..]),
+            Err(ErrorCode::Unauthorized.into())
+        );
+        assert_eq!(
+            u.put_chunk(alice, id, 10, &data[9..]),
+            Err(ErrorCode::InvalidInput.into())
+        );
+        assert_eq!(
+            u.commit(alice, id, blob_hash(&data)),
+            Err(ErrorCode::InvalidInput.into())
+        );
+        u.put_chunk(alice, id, 9, &data[9..]).unwrap();
+        assert_eq!(
+            u.commit(alice, id, [0; 32]),
+            Err(ErrorCode::Authentication {
+                participant_index: None
+            }
+            .into())
+        );
+        u.commit(alice, id, blob_hash(&data)).unwrap();
+        assert_eq!(u.blob(&blob_hash(&data)).unwrap(), &data[..]);
+
+        let stale = u.begin(alice, 1, 0).unwrap();
+        u.begin(alice, 1, UPLOAD_TTL).unwrap();
+        assert_eq!(
+            u.put_chunk(alice, stale, 0, b"x"),
+            Err(ErrorCode::NotFound.into())
+        );
+    }
+}
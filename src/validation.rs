@@ -0,0 +1,274 @@
+//  Copyright 2025 PolyCrypt GmbH
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+
+//! Central limits on call sizes and argument shapes. Candid only checks that
+//! arguments decode into the expected types; handlers validate their
+//! arguments here before acting on them, and rejections carry a `Violation`
+//! naming the broken limit. Ingress messages exceeding their method's size
+//! limit are already rejected before execution.
+
+use crate::audit;
+use crate::error::*;
+use crate::types::*;
+use crate::{mutate_state, read_state, require};
+use candid::candid_method;
+use ic_cdk::{inspect_message, query, update};
+
+/// Most participants a channel can have.
+pub const MAX_PARTICIPANTS: usize = 16;
+
+/// Most bytes of metadata or app data passed inline, such as memos or
+/// invoices. Larger data goes through the chunked upload.
+pub const MAX_DATA_LEN: usize = 64 << 10;
+
+/// Most bytes of a method or symbol name.
+pub const MAX_NAME_LEN: usize = 256;
+
+/// Most entries of a list argument.
+pub const MAX_BATCH_LEN: usize = 256;
+
+/// Default size limit of a call's encoded arguments (128 KiB).
+pub const DEFAULT_MAX_ARG_LEN: u64 = 128 << 10;
+
+/// Size limit of `put_chunk` calls, up to the ingress limit (2 MiB).
+pub const MAX_CHUNK_ARG_LEN: u64 = 2 << 20;
+
+#[derive(Clone, Copy, Deserialize, CandidType, PartialEq, Eq, Debug)]
+/// The limit violated by an invalid argument.
+pub enum Violation {
+    /// A channel has no or more than `MAX_PARTICIPANTS` participants.
+    ParticipantCount,
+    /// A participant occurs more than once.
+    DuplicateParticipant,
+    /// An allocation does not have one entry per participant.
+    AllocationLength,
+    /// An asset is allocated more than once.
+    DuplicateAsset,
+    /// Inline data exceeds `MAX_DATA_LEN` or a name `MAX_NAME_LEN`.
+    DataTooLarge,
+    /// A list exceeds `MAX_BATCH_LEN`.
+    BatchTooLong,
+}
+
+/// Arguments that are checked against the limits before use.
+pub trait Validate {
+    fn validate(&self) -> Result<()>;
+}
+
+#[inspect_message]
+fn inspect_message() {
+    let method = ic_cdk::api::msg_method_name();
+    let len = ic_cdk::api::msg_arg_data().len() as u64;
+    if len <= read_state(|s| s.call_size_limit(&method)) {
+        ic_cdk::api::accept_message();
+    }
+}
+
+#[update]
+#[candid_method(update)]
+/// Overrides the size limit of a method's encoded arguments, or restores its
+/// default if `limit` is empty. Admin only.
+fn set_call_size_limit(method: String, limit: Option<u64>) -> Result<()> {
+    let hash = audit::args_hash((&method, limit));
+    audit::logged("set_call_size_limit", hash, || {
+        crate::admin::require_admin()?;
+        name(&method)?;
+        mutate_state(|state| {
+            match limit {
+                Some(limit) => state.call_size_limits.insert(method, limit),
+                None => state.call_size_limits.remove(&method),
+            };
+            Ok(())
+        })
+    })
+}
+
+#[query]
+#[candid_method(query)]
+fn query_call_size_limit(method: String) -> u64 {
+    read_state(|s| s.call_size_limit(&method))
+}
+
+/// The default size limit of a method's encoded arguments.
+pub fn default_call_size_limit(method: &str) -> u64 {
+    match method {
+        "put_chunk" => MAX_CHUNK_ARG_LEN,
+        _ => DEFAULT_MAX_ARG_LEN,
+    }
+}
+
+/// Checks the length of inline data.
+pub fn data(bytes: &[u8]) -> Result<()> {
+    require!(
+        bytes.len() <= MAX_DATA_LEN,
+        violation(Violation::DataTooLarge)
+            .with("length", bytes.len())
+            .with("max", MAX_DATA_LEN)
+    );
+    Ok(())
+}
+
+/// Checks the length of a name.
+pub fn name(name: &str) -> Result<()> {
+    require!(
+        name.len() <= MAX_NAME_LEN,
+        violation(Violation::DataTooLarge)
+            .with("length", name.len())
+            .with("max", MAX_NAME_LEN)
+    );
+    Ok(())
+}
+
+/// Checks the length of a list.
+pub fn batch<T>(items: &[T]) -> Result<()> {
+    require!(
+        items.len() <= MAX_BATCH_LEN,
+        violation(Violation::BatchTooLong)
+            .with("length", items.len())
+            .with("max", MAX_BATCH_LEN)
+    );
+    Ok(())
+}
+
+fn violation(v: Violation) -> Error {
+    ErrorCode::Invalid(v).into()
+}
+
+impl Validate for Params {
+    fn validate(&self) -> Result<()> {
+        let n = self.participants.len();
+        require!(
+            (1..=MAX_PARTICIPANTS).contains(&n),
+            violation(Violation::ParticipantCount).with("participants", n)
+        );
+        for (i, p) in self.participants.iter().enumerate() {
+            require!(
+                !self.participants[..i].contains(p),
+                violation(Violation::DuplicateParticipant).with("participant", p)
+            );
+        }
+        Ok(())
+    }
+}
+
+impl Validate for (&Params, &State) {
+    fn validate(&self) -> Result<()> {
+        let (params, state) = self;
+        params.validate()?;
+        require!(
+            state.allocation.len() == params.participants.len(),
+            violation(Violation::AllocationLength)
+                .with("allocation", state.allocation.len())
+                .with("participants", params.participants.len())
+        );
+        batch(&state.assets)?;
+        for (i, a) in state.assets.iter().enumerate() {
+            require!(
+                a.allocation.len() == params.participants.len(),
+                violation(Violation::AllocationLength)
+                    .with("asset", a.asset)
+                    .with("allocation", a.allocation.len())
+            );
+            require!(
+                state.assets[..i].iter().all(|b| b.asset != a.asset),
+                violation(Violation::DuplicateAsset).with("asset", a.asset)
+            );
+        }
+        Ok(())
+    }
+}
+
+impl Validate for FundingIntent {
+    fn validate(&self) -> Result<()> {
+        self.params.validate()?;
+        require!(
+            self.allocation.len() == self.params.participants.len(),
+            violation(Violation::AllocationLength)
+                .with("allocation", self.allocation.len())
+                .with("participants", self.params.participants.len())
+        );
+        if let Some(cb) = &self.callback {
+            name(&cb.method)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+
+    #[test]
+    fn test_params_limits() {
+        let mut params = Params {
+            nonce: Nonce::default(),
+            participants: vec![],
+            challenge_duration: 0,
+            expiry: None,
+        };
+        let invalid = |v| Err(ErrorCode::Invalid(v).into());
+        assert_eq!(params.validate(), invalid(Violation::ParticipantCount));
+        params.participants = vec![account(1), account(2), account(1)];
+        assert_eq!(params.validate(), invalid(Violation::DuplicateParticipant));
+        params.participants = (1..=MAX_PARTICIPANTS as u8 + 1).map(account).collect();
+        assert_eq!(params.validate(), invalid(Violation::ParticipantCount));
+        params.participants.pop();
+        assert_eq!(params.validate(), Ok(()));
+
+        let state = State {
+            allocation: vec![Amount::default()],
+            ..Default::default()
+        };
+        assert_eq!(
+            (&params, &state).validate(),
+            invalid(Violation::AllocationLength)
+        );
+        assert_eq!(
+            batch(&[0u8; MAX_BATCH_LEN + 1]),
+            invalid(Violation::BatchTooLong)
+        );
+    }
+
+    #[test]
+    fn test_errors_carry_offending_values() {
+        let err = batch(&[0u8; MAX_BATCH_LEN + 1]).unwrap_err();
+        assert_eq!(
+            err.context,
+            vec![
+                ("length".to_string(), (MAX_BATCH_LEN + 1).to_string()),
+                ("max".to_string(), MAX_BATCH_LEN.to_string()),
+            ]
+        );
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "Invalid(BatchTooLong), length: {}, max: {}",
+                MAX_BATCH_LEN + 1,
+                MAX_BATCH_LEN
+            )
+        );
+
+        fn positive(n: u32) -> Result<()> {
+            require!(n > 0, InvalidInput);
+            Ok(())
+        }
+        let err = positive(0).unwrap_err();
+        assert_eq!(err.code, ErrorCode::InvalidInput);
+        assert_eq!(
+            err.context,
+            vec![("requirement".to_string(), "n > 0".to_string())]
+        );
+    }
+}
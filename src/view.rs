@@ -0,0 +1,234 @@
+//  Copyright 2025 PolyCrypt GmbH
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+
+//! A consolidated view of a channel. `get_channel` combines what the
+//! canister knows about a channel, from its announcement to its conclusion,
+//! so that explorers and wallets need a single query instead of combining
+//! `query_state`, `query_funding_status`, `query_holdings` and others.
+
+use crate::htlc::ForwardStatus;
+use crate::receiver::TXQuerier;
+use crate::swap::{SwapId, SwapStatus};
+use crate::types::*;
+use crate::{CanisterState, read_state};
+use candid::{CandidType, candid_method};
+use ic_cdk::api::time as blocktime;
+use ic_cdk::query;
+
+#[derive(Clone, Copy, Deserialize, CandidType, PartialEq, Eq, Debug)]
+pub enum ChannelPhase {
+    /// The channel was announced and still awaits deposits.
+    Funding,
+    /// The channel is funded and no state was registered.
+    Open,
+    /// A non-finalized state is registered and its challenge duration has
+    /// not passed yet.
+    Disputed,
+    /// The channel's registered state is final and its funds withdrawable.
+    Concluded,
+}
+
+#[derive(Clone, Deserialize, CandidType, PartialEq, Debug)]
+/// The funds of one participant of a channel.
+pub struct ParticipantHoldings {
+    pub participant: L2Account,
+    /// The deposits, or the withdrawable funds once the channel concluded.
+    pub holdings: Amount,
+    /// Funds taken from the holdings while they are contested.
+    pub quarantined: Amount,
+}
+
+#[derive(Clone, Deserialize, CandidType, PartialEq, Debug)]
+/// An operation that holds funds of the channel until it completes.
+pub enum PendingOperation {
+    /// An unfinished swap paid from a participant's holdings.
+    Swap {
+        id: SwapId,
+        participant: L2Account,
+        locked: Amount,
+    },
+    /// A locked forward with a leg in the channel.
+    Forward { hash: PaymentHash, amount: Amount },
+}
+
+#[derive(Clone, Deserialize, CandidType)]
+pub struct ChannelView {
+    pub id: ChannelId,
+    pub params: Params,
+    pub phase: ChannelPhase,
+    /// The latest registered state and its timeout, if any.
+    pub state: Option<RegisteredState>,
+    /// The holdings of each participant, in the order of the parameters'
+    /// participant list.
+    pub holdings: Vec<ParticipantHoldings>,
+    /// When the channel's funding intent was registered, if announced.
+    pub announced_at: Option<Timestamp>,
+    /// When the last owed deposit of the funding intent arrived.
+    pub funded_at: Option<Timestamp>,
+    /// When the latest state was registered.
+    pub registered_at: Option<Timestamp>,
+    /// Whether withdrawals are locked by an unsettled dispute.
+    pub withdrawals_locked: bool,
+    pub pending: Vec<PendingOperation>,
+}
+
+#[query]
+#[candid_method(query)]
+/// Returns everything the canister knows about a channel: its parameters,
+/// phase, latest registered state, holdings, timestamps and pending
+/// operations. This is the query explorers and wallets should use to follow
+/// a channel. In privacy mode, only callers entitled to read the channel see
+/// it.
+fn get_channel(channel_id: ChannelId) -> Option<ChannelView> {
+    let caller = ic_cdk::api::msg_caller();
+    read_state(|s| {
+        s.may_read_channel(&caller, &channel_id)
+            .then(|| s.channel_view(&channel_id, blocktime()))
+            .flatten()
+    })
+}
+
+impl<Q: TXQuerier> CanisterState<Q> {
+    /// The view of a channel whose parameters are known, either from its
+    /// registration or from its funding intent.
+    pub fn channel_view(&self, id: &ChannelId, now: Timestamp) -> Option<ChannelView> {
+        let funding = self.funding.get(id);
+        let params = self
+            .params
+            .get(id)
+            .or(funding.map(|f| &f.intent.params))?
+            .clone();
+        let state = self.state(id);
+        let phase = match (&state, funding) {
+            (Some(s), _) if s.settled(now) => ChannelPhase::Concluded,
+            (Some(_), _) => ChannelPhase::Disputed,
+            (None, Some(f)) if f.funded_at.is_none() => ChannelPhase::Funding,
+            (None, _) => ChannelPhase::Open,
+        };
+        let holdings = params
+            .participants
+            .iter()
+            .map(|p| {
+                let funding = Funding::new(id.clone(), p.clone());
+                ParticipantHoldings {
+                    participant: p.clone(),
+                    quarantined: self.quarantine.total_of(&funding),
+                    holdings: self.query_holdings(funding).unwrap_or_default(),
+                }
+            })
+            .collect();
+        Some(ChannelView {
+            id: id.clone(),
+            params,
+            phase,
+            withdrawals_locked: phase == ChannelPhase::Disputed && self.locked.contains(id),
+            state,
+            holdings,
+            announced_at: funding.map(|f| f.created_at),
+            funded_at: funding.and_then(|f| f.funded_at),
+            registered_at: self
+                .history
+                .get(id)
+                .and_then(|h| h.back())
+                .map(|r| r.registered_at),
+            pending: self.pending_operations(id),
+        })
+    }
+
+    /// The unfinished swaps and forwards involving a channel.
+    fn pending_operations(&self, id: &ChannelId) -> Vec<PendingOperation> {
+        let swaps = self
+            .swaps
+            .iter()
+            .filter(|(_, s)| s.request.funding.channel == *id && s.status.is_open())
+            .filter(|(_, s)| !matches!(s.status, SwapStatus::Refunding))
+            .map(|(id, s)| PendingOperation::Swap {
+                id: *id,
+                participant: s.request.funding.participant.clone(),
+                locked: s.locked.clone(),
+            });
+        let forwards = self
+            .forwards
+            .values()
+            .filter(|f| f.status == ForwardStatus::Locked)
+            .filter(|f| f.terms.incoming.channel == *id || f.terms.outgoing.channel == *id)
+            .map(|f| PendingOperation::Forward {
+                hash: f.terms.hash,
+                amount: f.terms.amount.clone(),
+            });
+        swaps.chain(forwards).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+
+    #[test]
+    fn test_view_follows_channel_lifecycle() {
+        let mut s = new_state();
+        let params = Params {
+            nonce: Nonce([7; 32]),
+            participants: vec![account(1), account(2)],
+            challenge_duration: 10,
+            expiry: None,
+        };
+        let id = params.id();
+        assert!(s.channel_view(&id, 0).is_none());
+
+        s.register_funding_intent(
+            FundingIntent {
+                params: params.clone(),
+                allocation: vec![Amount::from(5u32), Amount::from(5u32)],
+                callback: None,
+            },
+            1,
+        )
+        .unwrap();
+        s.deposit(Funding::new(id.clone(), account(1)), Amount::from(5u32))
+            .unwrap();
+        let view = s.channel_view(&id, 2).unwrap();
+        assert_eq!(view.phase, ChannelPhase::Funding);
+        assert_eq!(view.announced_at, Some(1));
+        assert_eq!(view.holdings[0].holdings, Amount::from(5u32));
+        assert_eq!(view.holdings[1].holdings, Amount::default());
+
+        s.deposit(Funding::new(id.clone(), account(2)), Amount::from(5u32))
+            .unwrap();
+        s.complete_funding(&id, 3);
+        let view = s.channel_view(&id, 3).unwrap();
+        assert_eq!(view.phase, ChannelPhase::Open);
+        assert_eq!(view.funded_at, Some(3));
+
+        let state = RegisteredState {
+            state: State {
+                channel: id.clone(),
+                version: 1,
+                allocation: vec![Amount::from(3u32), Amount::from(7u32)],
+                finalized: false,
+                assets: vec![],
+            },
+            timeout: 20,
+        };
+        s.register_channel(&params, state, 10).unwrap();
+        let view = s.channel_view(&id, 15).unwrap();
+        assert_eq!(view.phase, ChannelPhase::Disputed);
+        assert_eq!(view.registered_at, Some(10));
+        assert!(view.withdrawals_locked);
+        let view = s.channel_view(&id, 20).unwrap();
+        assert_eq!(view.phase, ChannelPhase::Concluded);
+        assert!(!view.withdrawals_locked);
+    }
+}
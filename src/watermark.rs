@@ -0,0 +1,243 @@
+//  Copyright 2025 PolyCrypt GmbH
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//    http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+
+//! Memory watermarks. The heap and stable memory sizes are sampled
+//! periodically and their high-water marks kept. When a size crosses a
+//! configured threshold, an alert is recorded, so that operators can archive
+//! or collect garbage before the heap hits the wasm limit of 4 GiB. A size
+//! alerts again only after dropping below its threshold in between.
+
+use crate::audit;
+use crate::error::*;
+use crate::load;
+use crate::page::{Cursor, Page, paginate};
+use crate::receiver::TXQuerier;
+use crate::types::*;
+use crate::{CanisterState, mutate_state, read_state};
+use candid::candid_method;
+use ic_cdk::api::time as blocktime;
+use ic_cdk::{query, update};
+use std::collections::VecDeque;
+use std::time::Duration as StdDuration;
+
+/// How often memory usage is sampled (one minute).
+pub const MEMORY_CHECK_INTERVAL: StdDuration = StdDuration::from_secs(60);
+
+/// Most alerts retained.
+pub const MAX_MEMORY_ALERTS: usize = 64;
+
+#[derive(Clone, Copy, Deserialize, CandidType, PartialEq, Eq, Debug)]
+/// Sizes in bytes above which an alert is recorded.
+pub struct MemoryThresholds {
+    pub heap_bytes: u64,
+    pub stable_bytes: u64,
+}
+
+#[derive(Clone, Copy, Deserialize, CandidType, PartialEq, Eq, Debug)]
+pub enum MemoryKind {
+    Heap,
+    Stable,
+}
+
+#[derive(Clone, Deserialize, CandidType, PartialEq, Eq, Debug)]
+/// A memory size crossed its threshold.
+pub struct MemoryAlert {
+    /// Numbers the alerts in the order they were recorded.
+    pub seq: u64,
+    pub kind: MemoryKind,
+    pub bytes: u64,
+    pub threshold: u64,
+    pub timestamp: Timestamp,
+}
+
+#[derive(Clone, Deserialize, CandidType, PartialEq, Eq, Debug)]
+pub struct MemoryStatus {
+    pub heap_bytes: u64,
+    pub stable_bytes: u64,
+    /// The largest sampled heap size since the canister was installed or
+    /// upgraded.
+    pub heap_high_water: u64,
+    /// The largest sampled stable memory size since the canister was
+    /// installed or upgraded.
+    pub stable_high_water: u64,
+}
+
+#[derive(Default)]
+pub struct Watermarks {
+    heap_high: u64,
+    stable_high: u64,
+    /// The kinds of memory currently above their threshold.
+    above: Vec<MemoryKind>,
+    alerts: VecDeque<MemoryAlert>,
+    next_alert: u64,
+}
+
+#[update]
+#[candid_method(update)]
+/// Sets the thresholds for memory alerts, or disables the alerts if
+/// `thresholds` is empty. Admin only.
+fn set_memory_thresholds(thresholds: Option<MemoryThresholds>) -> Result<()> {
+    audit::logged(
+        "set_memory_thresholds",
+        audit::args_hash((thresholds,)),
+        || {
+            crate::admin::require_admin()?;
+            mutate_state(|s| s.memory_thresholds = thresholds);
+            Ok(())
+        },
+    )
+}
+
+#[query]
+#[candid_method(query)]
+fn query_memory_thresholds() -> Option<MemoryThresholds> {
+    read_state(|s| s.memory_thresholds)
+}
+
+#[query]
+#[candid_method(query)]
+fn query_memory() -> MemoryStatus {
+    read_state(|s| MemoryStatus {
+        heap_bytes: load::heap_bytes(),
+        stable_bytes: stable_bytes(),
+        heap_high_water: s.watermarks.heap_high,
+        stable_high_water: s.watermarks.stable_high,
+    })
+}
+
+#[query]
+#[candid_method(query)]
+/// Lists the memory alerts, oldest first. At most `MAX_MEMORY_ALERTS` are
+/// retained.
+fn query_memory_alerts(cursor: Option<Cursor>, limit: u32) -> Result<Page<MemoryAlert>> {
+    read_state(|s| s.watermarks.alerts(cursor, limit))
+}
+
+/// Samples the memory sizes.
+pub fn check_memory() {
+    let (heap, stable) = (load::heap_bytes(), stable_bytes());
+    mutate_state(|s| s.observe_memory(heap, stable, blocktime()));
+}
+
+/// The size of the canister's stable memory.
+pub fn stable_bytes() -> u64 {
+    #[cfg(target_arch = "wasm32")]
+    return ic_cdk::stable::stable_size() * 65_536;
+    #[cfg(not(target_arch = "wasm32"))]
+    return 0;
+}
+
+impl Watermarks {
+    pub fn alerts(&self, cursor: Option<Cursor>, limit: u32) -> Result<Page<MemoryAlert>> {
+        paginate(
+            self.alerts.iter().map(|a| (a.seq, a.clone())),
+            cursor,
+            limit,
+        )
+    }
+
+    fn observe(&mut self, kind: MemoryKind, bytes: u64, threshold: Option<u64>, now: Timestamp) {
+        let high = match kind {
+            MemoryKind::Heap => &mut self.heap_high,
+            MemoryKind::Stable => &mut self.stable_high,
+        };
+        *high = bytes.max(*high);
+        let was_above = self.above.contains(&kind);
+        match threshold {
+            Some(threshold) if bytes >= threshold => {
+                if was_above {
+                    return;
+                }
+                self.above.push(kind);
+                if self.alerts.len() == MAX_MEMORY_ALERTS {
+                    self.alerts.pop_front();
+                }
+                ic_cdk::println!("{:?} memory at {} bytes", kind, bytes);
+                self.alerts.push_back(MemoryAlert {
+                    seq: self.next_alert,
+                    kind,
+                    bytes,
+                    threshold,
+                    timestamp: now,
+                });
+                self.next_alert += 1;
+            }
+            _ => self.above.retain(|k| *k != kind),
+        }
+    }
+}
+
+impl<Q: TXQuerier> CanisterState<Q> {
+    pub fn observe_memory(&mut self, heap_bytes: u64, stable_bytes: u64, now: Timestamp) {
+        let thresholds = self.memory_thresholds;
+        let w = &mut self.watermarks;
+        w.observe(
+            MemoryKind::Heap,
+            heap_bytes,
+            thresholds.map(|t| t.heap_bytes),
+            now,
+        );
+        w.observe(
+            MemoryKind::Stable,
+            stable_bytes,
+            thresholds.map(|t| t.stable_bytes),
+            now,
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+
+    #[test]
+    fn test_threshold_crossings_alert_once() {
+        let mut s = new_state();
+        s.memory_thresholds = Some(MemoryThresholds {
+            heap_bytes: 100,
+            stable_bytes: 1_000,
+        });
+        s.observe_memory(50, 10, 1);
+        s.observe_memory(120, 10, 2);
+        s.observe_memory(110, 10, 3);
+        s.observe_memory(90, 1_000, 4);
+        s.observe_memory(100, 10, 5);
+
+        let kinds: Vec<_> = s
+            .watermarks
+            .alerts
+            .iter()
+            .map(|a| (a.kind, a.timestamp))
+            .collect();
+        assert_eq!(
+            kinds,
+            vec![
+                (MemoryKind::Heap, 2),
+                (MemoryKind::Stable, 4),
+                (MemoryKind::Heap, 5)
+            ]
+        );
+        let page = s.watermarks.alerts(None, 2).unwrap();
+        assert!(page.has_more);
+        let page = s.watermarks.alerts(page.next, 2).unwrap();
+        assert_eq!(
+            page.items.iter().map(|a| a.seq).collect::<Vec<_>>(),
+            vec![2]
+        );
+        assert!(!page.has_more);
+        assert_eq!(s.watermarks.heap_high, 120);
+        assert_eq!(s.watermarks.stable_high, 1_000);
+    }
+}